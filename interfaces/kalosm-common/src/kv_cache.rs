@@ -33,6 +33,25 @@ impl KvCache {
         self.cache.reset()
     }
 
+    /// Remove every token after the first `len` tokens from the cache. The remaining tokens are copied into a new allocation, so clones of this cache are not affected.
+    pub fn truncate(&mut self, len: usize) -> candle_core::Result<()> {
+        if len >= self.cache.current_seq_len() {
+            return Ok(());
+        }
+        let mut new_cache =
+            candle_nn::kv_cache::KvCache::new(self.concat_dim, self.cache.k_cache().max_seq_len());
+        if len > 0 {
+            if let (Ok(Some(k)), Ok(Some(v))) = (self.cache.k(), self.cache.v()) {
+                new_cache.append(
+                    &k.narrow(self.concat_dim, 0, len)?.contiguous()?,
+                    &v.narrow(self.concat_dim, 0, len)?.contiguous()?,
+                )?;
+            }
+        }
+        self.cache = new_cache;
+        Ok(())
+    }
+
     /// Append a new key/value pair to the cache.
     pub fn append(&mut self, k: &Tensor, v: &Tensor) -> candle_core::Result<(Tensor, Tensor)> {
         let k = k.contiguous()?;
@@ -0,0 +1,105 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use kalosm_language::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+criterion_group!(benches, quantization);
+criterion_main!(benches);
+
+const DIMENSIONS: usize = 384;
+const EMBEDDINGS: usize = 20_000;
+const QUERIES: usize = 100;
+const K: usize = 10;
+
+// Random embeddings grouped around a few hundred topics, which is closer to real embeddings than uniform noise
+fn embeddings(rng: &mut StdRng, count: usize) -> Vec<Vec<f32>> {
+    let topics: Vec<Vec<f32>> = (0..256)
+        .map(|_| (0..DIMENSIONS).map(|_| rng.gen_range(-1.0..1.0)).collect())
+        .collect();
+    (0..count)
+        .map(|_| {
+            let topic = &topics[rng.gen_range(0..topics.len())];
+            topic.iter().map(|x| x + rng.gen_range(-0.8..0.8)).collect()
+        })
+        .collect()
+}
+
+fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let a: f32 = a.iter().map(|a| a * a).sum();
+    let b: f32 = b.iter().map(|b| b * b).sum();
+    1.0 - dot / (a * b).sqrt()
+}
+
+fn exact_closest(embeddings: &[Vec<f32>], query: &[f32]) -> Vec<u32> {
+    let mut distances: Vec<_> = embeddings
+        .iter()
+        .enumerate()
+        .map(|(id, embedding)| (id as u32, cosine_distance(embedding, query)))
+        .collect();
+    distances.sort_by(|a, b| a.1.total_cmp(&b.1));
+    distances.into_iter().take(K).map(|(id, _)| id).collect()
+}
+
+fn quantization(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let data = embeddings(&mut rng, EMBEDDINGS);
+    let queries = embeddings(&mut rng, QUERIES);
+    let expected: Vec<_> = queries
+        .iter()
+        .map(|query| exact_closest(&data, query))
+        .collect();
+
+    let configurations = [
+        ("f32", VectorQuantization::None, 0),
+        ("int8", VectorQuantization::Int8, 0),
+        ("int8 rescore x4", VectorQuantization::Int8, 4),
+        ("binary", VectorQuantization::Binary, 0),
+        ("binary rescore x4", VectorQuantization::Binary, 4),
+        ("binary rescore x10", VectorQuantization::Binary, 10),
+    ];
+
+    println!(
+        "{EMBEDDINGS} embeddings with {DIMENSIONS} dimensions, recall@{K} over {QUERIES} queries"
+    );
+    println!(
+        "{:<20} {:>10} {:>16} {:>16}",
+        "storage", "recall", "scanned bytes", "stored bytes"
+    );
+    for (name, quantization, rescore) in configurations {
+        let db: VectorDB = VectorDBBuilder::new()
+            .with_quantization(quantization)
+            .with_rescoring(rescore)
+            .build()
+            .unwrap();
+        let ids = db
+            .add_embeddings(data.iter().map(|e| Embedding::from(e.iter().copied())))
+            .unwrap();
+        assert_eq!(ids.last().map(|id| id.0), Some(EMBEDDINGS as u32 - 1));
+
+        let mut found = 0;
+        for (query, expected) in queries.iter().zip(&expected) {
+            let results = db
+                .get_closest(Embedding::from(query.iter().copied()), K)
+                .unwrap();
+            found += results
+                .iter()
+                .filter(|result| expected.contains(&result.value.0))
+                .count();
+        }
+        let recall = found as f32 / (QUERIES * K) as f32;
+
+        let scanned = quantization.bytes_per_embedding(DIMENSIONS);
+        let stored = if quantization != VectorQuantization::None && rescore > 0 {
+            scanned + VectorQuantization::None.bytes_per_embedding(DIMENSIONS)
+        } else {
+            scanned
+        };
+        println!("{name:<20} {recall:>10.3} {scanned:>16} {stored:>16}");
+
+        c.bench_function(&format!("search {name}"), |b| {
+            let query = Embedding::from(queries[0].iter().copied());
+            b.iter(|| db.get_closest(query.clone(), K).unwrap())
+        });
+    }
+}
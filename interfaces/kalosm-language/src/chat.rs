@@ -1,6 +1,7 @@
 //! A chat interface that builds on top of [`kalosm_language_model::Model`]
 
 use std::{
+    collections::HashMap,
     fmt::Display,
     path::PathBuf,
     sync::{Arc, Mutex, RwLock},
@@ -9,16 +10,20 @@ use std::{
 use anyhow::Result;
 use futures_util::Future;
 use kalosm_language_model::ChatMarkers;
+use kalosm_language_model::ModelFeedback;
 use kalosm_language_model::Session;
 use kalosm_language_model::{GenerationParameters, Model, ModelExt, SyncModel, SyncModelExt};
 use kalosm_sample::{ArcParser, CreateParserState, ParserExt, SendCreateParserState};
 use kalosm_streams::text_stream::ChannelTextStream;
 use llm_samplers::types::Sampler;
+use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc::unbounded_channel, oneshot};
 
 type ResponseConstraintGenerator =
     Arc<Mutex<Box<dyn FnMut(&[ChatHistoryItem]) -> ArcParser<()> + Send + Sync>>>;
 
+type CompactionHandler = Arc<dyn Fn(ContextCompaction) + Send + Sync>;
+
 const DEFAULT_SYSTEM_PROMPT: &str = "Always assist with care, respect, and truth. Respond with utmost utility yet securely. Avoid harmful, unethical, prejudiced, or negative content. Ensure replies promote fairness and positivity.";
 
 /// A simple helper function for prompting the user for input.
@@ -33,7 +38,8 @@ pub fn prompt_input(prompt: impl Display) -> Result<String> {
 }
 
 /// The type of a chat message
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum MessageType {
     /// A system prompt.
     SystemPrompt,
@@ -44,10 +50,17 @@ pub enum MessageType {
 }
 
 /// A single item in the chat history.
-#[derive(Clone, Debug)]
+///
+/// Chat history items are independent of the model and can be serialized to store a transcript of the chat. You can restore a chat from a transcript with [`Chat::from_transcript`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ChatHistoryItem {
+    #[serde(rename = "type")]
     ty: MessageType,
     contents: String,
+    #[serde(default = "chrono::Utc::now")]
+    timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, serde_json::Value>,
 }
 
 impl ChatHistoryItem {
@@ -56,9 +69,42 @@ impl ChatHistoryItem {
         Self {
             ty,
             contents: contents.into(),
+            timestamp: chrono::Utc::now(),
+            metadata: HashMap::new(),
         }
     }
 
+    /// Sets the time the item was added to the chat.
+    pub fn with_timestamp(mut self, timestamp: chrono::DateTime<chrono::Utc>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Adds a metadata entry to the item.
+    pub fn with_metadata(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<serde_json::Value>,
+    ) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Returns the time the item was added to the chat.
+    pub fn timestamp(&self) -> chrono::DateTime<chrono::Utc> {
+        self.timestamp
+    }
+
+    /// Returns the metadata attached to the item.
+    pub fn metadata(&self) -> &HashMap<String, serde_json::Value> {
+        &self.metadata
+    }
+
+    /// Returns a mutable reference to the metadata attached to the item.
+    pub fn metadata_mut(&mut self) -> &mut HashMap<String, serde_json::Value> {
+        &mut self.metadata
+    }
+
     /// Returns the type of the item.
     pub fn ty(&self) -> MessageType {
         self.ty
@@ -70,6 +116,76 @@ impl ChatHistoryItem {
     }
 }
 
+/// The strategy a [`Chat`] uses to make room when the conversation no longer fits in the model's context window.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ContextOverflowStrategy {
+    /// Drop the oldest user and assistant turns until the conversation fits. The system prompt is always kept.
+    #[default]
+    DropOldest,
+    /// Summarize the oldest turns with the chat model and fold the summary into the system prompt. Chats without a system prompt get a system prompt with just the summary.
+    /// If the summarized conversation still does not fit, the oldest remaining turns are dropped.
+    Summarize {
+        /// The number of most recent messages that are always kept word for word.
+        keep_recent_messages: usize,
+        /// The maximum number of tokens the summary may use.
+        max_summary_tokens: u32,
+    },
+    /// Keep the first `sink_tokens` tokens of the session (the attention sinks) and the most recent tokens that fit in the window.
+    /// The kept tokens are re-fed into a fresh session.
+    SlidingWindow {
+        /// The number of tokens at the start of the session that are never evicted.
+        sink_tokens: usize,
+    },
+}
+
+impl ContextOverflowStrategy {
+    /// Summarize older turns, keeping the four most recent messages word for word.
+    pub fn summarize() -> Self {
+        Self::Summarize {
+            keep_recent_messages: 4,
+            max_summary_tokens: 256,
+        }
+    }
+
+    /// Use a sliding window that keeps the first four tokens of the session as attention sinks.
+    pub fn sliding_window() -> Self {
+        Self::SlidingWindow { sink_tokens: 4 }
+    }
+}
+
+/// A report of a compaction of the chat context. Chats report compactions to the handler set with [`ChatBuilder::with_compaction_handler`].
+#[derive(Clone, Debug)]
+pub struct ContextCompaction {
+    /// The strategy that was used to compact the context.
+    pub strategy: ContextOverflowStrategy,
+    /// The number of tokens in the context (including the new message) before compaction.
+    pub tokens_before: usize,
+    /// The number of tokens in the context (including the new message) after compaction.
+    pub tokens_after: usize,
+    /// The number of messages that were removed from the context or folded into a summary.
+    pub messages_removed: usize,
+}
+
+/// The context window settings for a chat session.
+#[derive(Clone)]
+struct ContextWindow {
+    strategy: Option<ContextOverflowStrategy>,
+    max_tokens: Option<usize>,
+    reserved_response_tokens: usize,
+    on_compaction: Option<CompactionHandler>,
+}
+
+impl Default for ContextWindow {
+    fn default() -> Self {
+        Self {
+            strategy: None,
+            max_tokens: None,
+            reserved_response_tokens: 512,
+            on_compaction: None,
+        }
+    }
+}
+
 /// The history of a chat session.
 struct ChatSession<Model: SyncModel> {
     logits_scratch: Vec<f32>,
@@ -80,6 +196,15 @@ struct ChatSession<Model: SyncModel> {
     assistant_marker: String,
     end_assistant_marker: String,
     history: Arc<RwLock<Vec<ChatHistoryItem>>>,
+    /// The messages that are currently in the model's context window. This may be shorter than the history if the context has been compacted.
+    context: Vec<ChatHistoryItem>,
+    /// A summary of messages that were compacted out of the context window.
+    summary: Option<String>,
+    /// The tokens that have been fed into the session.
+    context_tokens: Vec<u32>,
+    /// The tokens of a restored session that the chat has no history for. They are kept at the start of the session when the context is compacted.
+    restored_tokens: Vec<u32>,
+    context_window: ContextWindow,
     session: Model::Session,
     unfed_text: String,
     bot_constraints: Option<ResponseConstraintGenerator>,
@@ -103,9 +228,22 @@ impl<Model: SyncModel> ChatSession<Model> {
         session: Option<Model::Session>,
         initial_history: Vec<ChatHistoryItem>,
         shared_history: Arc<RwLock<Vec<ChatHistoryItem>>>,
+        context_window: ContextWindow,
     ) -> Self {
+        // If we have both a session and a history, we need to check that the session matches the history before we use it
+        let (session, warm_session) = match session {
+            Some(session) if !initial_history.is_empty() => (None, Some(session)),
+            session => (session, None),
+        };
         let feed_initial_messages = session.is_none();
         let session = session.unwrap_or_else(|| model.new_session().unwrap());
+        let context_tokens = session.tokens().to_vec();
+        // A session restored without a history still contains the earlier conversation, so it can't be rebuilt from the messages in the context
+        let restored_tokens = if feed_initial_messages {
+            Vec::new()
+        } else {
+            context_tokens.clone()
+        };
         let unfed_text = String::new();
         shared_history.write().unwrap().clear();
 
@@ -120,6 +258,11 @@ impl<Model: SyncModel> ChatSession<Model> {
             session,
             unfed_text,
             history: shared_history,
+            context: Vec::new(),
+            summary: None,
+            context_tokens,
+            restored_tokens,
+            context_window,
             bot_constraints,
             sampler,
         };
@@ -128,30 +271,56 @@ impl<Model: SyncModel> ChatSession<Model> {
             // If the first item is not a system prompt, add one
             if initial_history
                 .first()
-                .filter(|item| item.ty() != MessageType::SystemPrompt)
+                .filter(|item| item.ty() == MessageType::SystemPrompt)
                 .is_none()
             {
                 let system_prompt = system_prompt.unwrap_or(DEFAULT_SYSTEM_PROMPT.into());
-                myself.add_system_message(system_prompt);
+                myself.add_item(ChatHistoryItem::new(
+                    MessageType::SystemPrompt,
+                    system_prompt,
+                ));
             }
             for item in initial_history {
-                match item.ty() {
-                    MessageType::SystemPrompt => {
-                        myself.add_system_message(item.contents);
-                    }
-                    MessageType::UserMessage => {
-                        myself.add_user_message(item.contents);
-                    }
-                    MessageType::ModelAnswer => {
-                        myself.add_bot_message(item.contents);
-                    }
-                }
+                myself.add_item(item);
+            }
+        }
+
+        if let Some(warm_session) = warm_session {
+            if let Err(err) = myself.try_warm_start(model, warm_session) {
+                tracing::warn!("Failed to warm start the chat from the session: {err}");
             }
         }
 
         myself
     }
 
+    /// Tries to reuse a session that has already been fed a prefix of the unfed history.
+    /// If the session doesn't match the history, the full history will be fed into a fresh session instead.
+    fn try_warm_start(&mut self, model: &Model, warm_session: Model::Session) -> Result<()> {
+        let cached_tokens = warm_session.tokens();
+        if cached_tokens.is_empty() {
+            anyhow::bail!("the session does not expose its tokens, so it cannot be checked against the history");
+        }
+        let history_tokens = tokenize(model, &self.unfed_text)?;
+        if !history_tokens.starts_with(cached_tokens) {
+            anyhow::bail!("the session was created from a different model or history");
+        }
+
+        let remaining_tokens = &history_tokens[cached_tokens.len()..];
+        self.session = warm_session;
+        if !remaining_tokens.is_empty() {
+            model.feed_tokens(
+                &mut self.session,
+                remaining_tokens,
+                &mut self.logits_scratch,
+            )?;
+        }
+        self.context_tokens = history_tokens;
+        self.unfed_text.clear();
+
+        Ok(())
+    }
+
     /// Adds a message to the history.
     fn add_message(
         &mut self,
@@ -159,9 +328,10 @@ impl<Model: SyncModel> ChatSession<Model> {
         model: &mut Model,
         stream: tokio::sync::mpsc::UnboundedSender<String>,
     ) -> Result<()> {
-        self.add_user_message(message);
+        self.add_item(ChatHistoryItem::new(MessageType::UserMessage, message));
         let mut bot_response = String::new();
         self.unfed_text += &self.assistant_marker;
+        self.fit_context_window(model)?;
         let prompt = std::mem::take(&mut self.unfed_text);
         let bot_constraints = &self.bot_constraints;
 
@@ -212,48 +382,322 @@ impl<Model: SyncModel> ChatSession<Model> {
                     self.sampler.clone(),
                     |tok| {
                         on_token(tok)?;
-                        Ok(kalosm_language_model::ModelFeedback::Continue)
+                        Ok(ModelFeedback::Continue)
                     },
                 )?;
             }
         }
 
+        let fed_text = prompt + &bot_response + &self.end_assistant_marker;
+        self.record_fed_text(model, &fed_text)?;
+        let answer = ChatHistoryItem::new(MessageType::ModelAnswer, bot_response);
+        self.context.push(answer.clone());
+        self.history.write().unwrap().push(answer);
+
         Ok(())
     }
 
-    fn add_system_message(&mut self, message: String) {
-        self.unfed_text += &self.system_prompt_marker;
-        self.unfed_text += &message;
-        self.unfed_text += &self.end_system_prompt_marker;
+    fn add_item(&mut self, item: ChatHistoryItem) {
+        self.unfed_text += &self.render_item(&item);
         let mut history = self.history.write().unwrap();
-        if !history.is_empty() {
+        if item.ty == MessageType::SystemPrompt && !history.is_empty() {
             tracing::error!("System prompt should be the first message in the history. System prompt was added to the end of the history: {history:?}");
         }
-        history.push(ChatHistoryItem {
-            ty: MessageType::SystemPrompt,
-            contents: message,
-        });
+        self.context.push(item.clone());
+        history.push(item);
     }
 
-    fn add_user_message(&mut self, message: String) {
-        self.unfed_text += &self.user_marker;
-        self.unfed_text += &message;
-        self.unfed_text += &self.end_user_marker;
-        self.history.write().unwrap().push(ChatHistoryItem {
-            ty: MessageType::UserMessage,
-            contents: message,
-        });
+    /// Keep track of the tokens that were fed into the session.
+    fn record_fed_text(&mut self, model: &Model, text: &str) -> Result<()> {
+        // Prefer the exact tokens from the session if the model keeps track of them
+        let session_tokens = self.session.tokens();
+        if !session_tokens.is_empty() {
+            self.context_tokens = session_tokens.to_vec();
+        } else {
+            self.context_tokens.extend(tokenize(model, text)?);
+        }
+        Ok(())
     }
 
-    fn add_bot_message(&mut self, message: String) {
-        self.unfed_text += &self.assistant_marker;
-        self.unfed_text += &message;
-        self.unfed_text += &self.end_assistant_marker;
-        self.history.write().unwrap().push(ChatHistoryItem {
-            ty: MessageType::ModelAnswer,
-            contents: message,
-        });
+    /// Renders a message with the chat markers.
+    fn render_item(&self, item: &ChatHistoryItem) -> String {
+        let (start, end) = match item.ty {
+            MessageType::SystemPrompt => {
+                (&self.system_prompt_marker, &self.end_system_prompt_marker)
+            }
+            MessageType::UserMessage => (&self.user_marker, &self.end_user_marker),
+            MessageType::ModelAnswer => (&self.assistant_marker, &self.end_assistant_marker),
+        };
+        let mut rendered = start.clone();
+        rendered += &item.contents;
+        if let (MessageType::SystemPrompt, Some(summary)) = (item.ty, &self.summary) {
+            rendered += "\n\nSummary of the earlier conversation:\n";
+            rendered += summary;
+        }
+        rendered += end;
+        rendered
+    }
+
+    /// Starts a fresh session with the restored tokens and the current context as the unfed prompt.
+    fn rebuild_session(&mut self, model: &Model) -> Result<()> {
+        self.session = model.new_session()?;
+        self.context_tokens.clear();
+        if !self.restored_tokens.is_empty() {
+            model.feed_tokens(
+                &mut self.session,
+                &self.restored_tokens,
+                &mut self.logits_scratch,
+            )?;
+            self.context_tokens = self.restored_tokens.clone();
+        }
+        let mut unfed_text = self.render_standalone_summary().unwrap_or_default();
+        for item in &self.context {
+            unfed_text += &self.render_item(item);
+        }
+        unfed_text += &self.assistant_marker;
+        self.unfed_text = unfed_text;
+        Ok(())
+    }
+
+    /// Renders the summary as a system prompt of its own if the context doesn't start with a system prompt the summary can be folded into.
+    fn render_standalone_summary(&self) -> Option<String> {
+        let summary = self.summary.as_ref()?;
+        if self.first_removable_message() != 0 {
+            return None;
+        }
+        Some(format!(
+            "{}Summary of the earlier conversation:\n{summary}{}",
+            self.system_prompt_marker, self.end_system_prompt_marker
+        ))
+    }
+
+    /// The number of tokens the session will contain once the unfed text is fed.
+    fn pending_context_len(&self, model: &Model) -> Result<usize> {
+        Ok(self.context_tokens.len() + tokenize(model, &self.unfed_text)?.len())
+    }
+
+    /// Compacts the context if feeding the unfed text would overflow the context window.
+    fn fit_context_window(&mut self, model: &Model) -> Result<()> {
+        let Some(strategy) = self.context_window.strategy.clone() else {
+            return Ok(());
+        };
+        let Some(max_tokens) = self
+            .context_window
+            .max_tokens
+            .or_else(|| model.context_length())
+        else {
+            tracing::warn!("A context overflow strategy was set, but the model does not report a context length. Set one with `ChatBuilder::with_max_context_length`");
+            return Ok(());
+        };
+        let budget = max_tokens.saturating_sub(self.context_window.reserved_response_tokens);
+
+        let tokens_before = self.pending_context_len(model)?;
+        if tokens_before <= budget {
+            return Ok(());
+        }
+
+        let context_len_before = self.context.len();
+        match &strategy {
+            ContextOverflowStrategy::DropOldest => self.drop_oldest_turns(model, budget)?,
+            ContextOverflowStrategy::Summarize {
+                keep_recent_messages,
+                max_summary_tokens,
+            } => self.summarize_oldest_turns(
+                model,
+                budget,
+                *keep_recent_messages,
+                *max_summary_tokens,
+            )?,
+            ContextOverflowStrategy::SlidingWindow { sink_tokens } => {
+                self.slide_window(model, budget, *sink_tokens)?
+            }
+        }
+        let tokens_after = self.pending_context_len(model)?;
+
+        let compaction = ContextCompaction {
+            strategy,
+            tokens_before,
+            tokens_after,
+            messages_removed: context_len_before - self.context.len(),
+        };
+        tracing::info!(
+            "Compacted chat context from {} to {} tokens with {:?}",
+            compaction.tokens_before,
+            compaction.tokens_after,
+            compaction.strategy
+        );
+        if let Some(on_compaction) = &self.context_window.on_compaction {
+            on_compaction(compaction);
+        }
+
+        Ok(())
+    }
+
+    /// The index of the first message in the context that can be removed.
+    fn first_removable_message(&self) -> usize {
+        match self.context.first() {
+            Some(item) if item.ty == MessageType::SystemPrompt => 1,
+            _ => 0,
+        }
     }
+
+    /// Drops the oldest turns (a user message and the model answer that follows it) until the context fits in the budget.
+    fn drop_oldest_turns(&mut self, model: &Model, budget: usize) -> Result<()> {
+        let first_removable = self.first_removable_message();
+        let mut item_lengths = Vec::with_capacity(self.context.len());
+        for item in &self.context {
+            item_lengths.push(tokenize(model, &self.render_item(item))?.len());
+        }
+        let summary_len = match self.render_standalone_summary() {
+            Some(summary) => tokenize(model, &summary)?.len(),
+            None => 0,
+        };
+        let mut total: usize = self.restored_tokens.len()
+            + summary_len
+            + item_lengths.iter().sum::<usize>()
+            + tokenize(model, &self.assistant_marker)?.len();
+
+        // Never remove the newest message
+        let mut remove_until = first_removable;
+        while total > budget && remove_until + 1 < self.context.len() {
+            total -= item_lengths[remove_until];
+            remove_until += 1;
+            // Remove the model's answer along with the user message it answers
+            if self.context[remove_until].ty == MessageType::ModelAnswer
+                && remove_until + 1 < self.context.len()
+            {
+                total -= item_lengths[remove_until];
+                remove_until += 1;
+            }
+        }
+        if total > budget {
+            anyhow::bail!("The system prompt and newest message do not fit in the context window ({total} tokens, {budget} available)");
+        }
+
+        self.context.drain(first_removable..remove_until);
+        self.rebuild_session(model)
+    }
+
+    /// Summarizes the oldest messages with the model and folds the summary into the system prompt, or renders it as a system prompt of its own if the chat doesn't have one.
+    fn summarize_oldest_turns(
+        &mut self,
+        model: &Model,
+        budget: usize,
+        keep_recent_messages: usize,
+        max_summary_tokens: u32,
+    ) -> Result<()> {
+        let first_removable = self.first_removable_message();
+        // Always keep the newest message
+        let keep_recent_messages = keep_recent_messages.max(1);
+        let summarize_until = self.context.len().saturating_sub(keep_recent_messages);
+        if summarize_until <= first_removable {
+            return self.drop_oldest_turns(model, budget);
+        }
+
+        let mut transcript = String::new();
+        if let Some(summary) = &self.summary {
+            transcript += "Summary of the earlier conversation: ";
+            transcript += summary;
+            transcript += "\n";
+        }
+        for item in &self.context[first_removable..summarize_until] {
+            let speaker = match item.ty {
+                MessageType::SystemPrompt => "System",
+                MessageType::UserMessage => "User",
+                MessageType::ModelAnswer => "Assistant",
+            };
+            transcript += &format!("{speaker}: {}\n", item.contents);
+        }
+
+        let prompt = format!(
+            "{}You summarize conversations. Keep every fact, name, number and decision that may be needed to continue the conversation.{}{}Summarize the following conversation:\n{}{}{}",
+            self.system_prompt_marker,
+            self.end_system_prompt_marker,
+            self.user_marker,
+            transcript,
+            self.end_user_marker,
+            self.assistant_marker,
+        );
+        let mut summary_session = model.new_session()?;
+        let mut summary = String::new();
+        model.stream_text_with_sampler(
+            &mut summary_session,
+            &prompt,
+            Some(max_summary_tokens),
+            Some(&self.end_assistant_marker),
+            self.sampler.clone(),
+            |tok| {
+                summary += &tok;
+                Ok(ModelFeedback::Continue)
+            },
+        )?;
+
+        self.summary = Some(summary.trim().to_string());
+        self.context.drain(first_removable..summarize_until);
+        self.rebuild_session(model)?;
+
+        // If the summary wasn't enough, fall back to dropping the oldest turns
+        if self.pending_context_len(model)? > budget {
+            self.drop_oldest_turns(model, budget)?;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the attention sink tokens and the most recent tokens of the session that fit in the budget.
+    fn slide_window(&mut self, model: &Model, budget: usize, sink_tokens: usize) -> Result<()> {
+        let unfed_len = tokenize(model, &self.unfed_text)?.len();
+        let sink_tokens = sink_tokens.min(self.context_tokens.len());
+        // The new text is never cut off. If it doesn't fit next to the sink, fall back to dropping whole turns
+        if unfed_len + sink_tokens > budget {
+            return self.drop_oldest_turns(model, budget);
+        }
+
+        let recent_tokens =
+            (budget - unfed_len - sink_tokens).min(self.context_tokens.len() - sink_tokens);
+        let evicted = sink_tokens..self.context_tokens.len() - recent_tokens;
+        let mut window = self.context_tokens[..sink_tokens].to_vec();
+        window.extend_from_slice(&self.context_tokens[evicted.end..]);
+
+        // Remove the fed messages that were evicted completely so they are reported and never rendered again. Partially evicted messages are kept
+        let mut position = self.restored_tokens.len()
+            + match self.render_standalone_summary() {
+                Some(summary) => tokenize(model, &summary)?.len(),
+                None => 0,
+            };
+        let first_removable = self.first_removable_message();
+        let mut evicted_messages = Vec::new();
+        for (i, item) in self.context.iter().enumerate() {
+            let end = position + tokenize(model, &self.render_item(item))?.len();
+            if end > evicted.end {
+                break;
+            }
+            if i >= first_removable && position >= evicted.start {
+                evicted_messages.push(i);
+            }
+            position = end;
+        }
+        for i in evicted_messages.into_iter().rev() {
+            self.context.remove(i);
+        }
+
+        self.session = model.new_session()?;
+        if !window.is_empty() {
+            model.feed_tokens(&mut self.session, &window, &mut self.logits_scratch)?;
+        }
+        self.context_tokens = window;
+
+        Ok(())
+    }
+}
+
+/// Tokenizes some text with the model's tokenizer.
+fn tokenize<M: SyncModel + ?Sized>(model: &M, text: &str) -> Result<Vec<u32>> {
+    let encoded = model
+        .tokenizer()
+        .encode(text, false)
+        .map_err(|e| anyhow::anyhow!(e))?;
+    Ok(encoded.get_ids().to_vec())
 }
 
 /// A builder for [`Chat`].
@@ -265,6 +709,7 @@ pub struct ChatBuilder<M: Model> {
     sampler: Arc<Mutex<dyn Sampler + Send + Sync>>,
     bot_constraints: Option<ResponseConstraintGenerator>,
     initial_history: Vec<ChatHistoryItem>,
+    context_window: ContextWindow,
 }
 
 impl<M: Model> ChatBuilder<M> {
@@ -279,6 +724,7 @@ impl<M: Model> ChatBuilder<M> {
             sampler: Arc::new(Mutex::new(GenerationParameters::default().sampler())),
             bot_constraints: None,
             initial_history: Vec::new(),
+            context_window: ContextWindow::default(),
         }
     }
 }
@@ -357,11 +803,14 @@ impl<M: Model> ChatBuilder<M> {
             )
                 as Box<dyn FnMut(&[ChatHistoryItem]) -> ArcParser + Send + Sync>))),
             initial_history: self.initial_history,
+            context_window: self.context_window,
         }
     }
 
     /// Starts the chat instance with the given model session. This can be useful for resuming a chat session with a long context that has already been processed.
     ///
+    /// If an initial history is also set, the session is only used if the tokens it has processed are a prefix of the history. Otherwise the history is fed into a fresh session.
+    ///
     /// # Example
     /// ```rust, no_run
     /// # use kalosm::language::*;
@@ -408,7 +857,7 @@ impl<M: Model> ChatBuilder<M> {
 
     /// Set the initial history of the chat. Each message in the original history will be added to the chat history, and the model will be fed the user messages.
     ///
-    /// > **Note**: The system prompt automatically added to the chat history and should not be included in the initial history.
+    /// > **Note**: If the initial history does not start with a system prompt, the system prompt from [`ChatBuilder::with_system_prompt`] (or the default system prompt) is added before the history.
     ///
     /// # Example
     /// ```rust, no_run
@@ -429,6 +878,56 @@ impl<M: Model> ChatBuilder<M> {
         self
     }
 
+    /// Sets the strategy the chat uses when the conversation no longer fits in the model's context window.
+    /// Without a strategy, the chat keeps appending to the session until the model's context length is exceeded.
+    ///
+    /// The context length is read from the model if it reports one. You can override it with [`ChatBuilder::with_max_context_length`].
+    ///
+    /// > **Note**: If the chat starts from a session without a history (like [`ChatBuilder::with_try_session_path`] without [`Chat::from_transcript`]), the chat can't rebuild the conversation in that session. The restored tokens are always kept and only messages added after the session was loaded are dropped or summarized.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// # use kalosm::language::*;
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let mut chat = Chat::builder(Llama::new_chat().await.unwrap())
+    ///     // Summarize older turns once the conversation is too long
+    ///     .with_context_overflow_strategy(ContextOverflowStrategy::summarize())
+    ///     .with_compaction_handler(|compaction| {
+    ///         println!(
+    ///             "Compacted the chat from {} to {} tokens",
+    ///             compaction.tokens_before, compaction.tokens_after
+    ///         )
+    ///     })
+    ///     .build();
+    /// # }
+    /// ```
+    pub fn with_context_overflow_strategy(mut self, strategy: ContextOverflowStrategy) -> Self {
+        self.context_window.strategy = Some(strategy);
+        self
+    }
+
+    /// Sets the maximum number of tokens the chat session may use. Defaults to the context length the model reports.
+    pub fn with_max_context_length(mut self, max_tokens: usize) -> Self {
+        self.context_window.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Sets the number of tokens that are kept free for the model's response when the context is compacted. (Defaults to 512)
+    pub fn with_reserved_response_tokens(mut self, tokens: usize) -> Self {
+        self.context_window.reserved_response_tokens = tokens;
+        self
+    }
+
+    /// Sets a handler that is called every time the chat context is compacted with the [`ContextOverflowStrategy`].
+    pub fn with_compaction_handler(
+        mut self,
+        handler: impl Fn(ContextCompaction) + Send + Sync + 'static,
+    ) -> Self {
+        self.context_window.on_compaction = Some(Arc::new(handler));
+        self
+    }
+
     /// Builds a [`Chat`] instance.
     pub fn build(self) -> Chat
     where
@@ -442,6 +941,7 @@ impl<M: Model> ChatBuilder<M> {
             bot_constraints,
             session,
             initial_history,
+            context_window,
         } = self;
         let system_prompt_marker = chat_markers.system_prompt_marker.to_string();
         let end_system_prompt_marker = chat_markers.end_system_prompt_marker.to_string();
@@ -474,6 +974,7 @@ impl<M: Model> ChatBuilder<M> {
                                     session,
                                     initial_history,
                                     shared_history,
+                                    context_window,
                                 ));
                             })
                         })
@@ -720,6 +1221,49 @@ impl Chat {
         }
     }
 
+    /// Creates a new builder for a chat session that continues from a JSON transcript created with [`Chat::transcript`].
+    ///
+    /// Transcripts don't depend on the model, so you can continue a conversation with a different model than the one that started it.
+    /// If you also load a session with [`ChatBuilder::with_try_session_path`], the session is used to skip processing the transcript as long as it was created by the same model from a prefix of the transcript.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// # use kalosm::language::*;
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let transcript = std::fs::read_to_string("./chat.json").unwrap();
+    /// let mut chat = Chat::from_transcript(Llama::new_chat().await.unwrap(), &transcript)
+    ///     .unwrap()
+    ///     // Try to reuse the session from the last run to avoid processing the whole transcript again
+    ///     .with_try_session_path("./chat.llama")
+    ///     .build();
+    /// chat.add_message("What did we talk about?").to_std_out().await.unwrap();
+    /// # }
+    /// ```
+    pub fn from_transcript<M: Model>(model: M, transcript: &str) -> Result<ChatBuilder<M>>
+    where
+        <M::SyncModel as SyncModel>::Session: Send,
+    {
+        let history: Vec<ChatHistoryItem> = serde_json::from_str(transcript)?;
+        Ok(Self::builder(model).with_initial_history(history))
+    }
+
+    /// Serializes the chat history into a JSON transcript. The transcript can be restored with [`Chat::from_transcript`].
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// # use kalosm::language::*;
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let mut chat = Chat::new(Llama::new_chat().await.unwrap());
+    /// chat.add_message("Hello, world!").to_std_out().await.unwrap();
+    /// std::fs::write("./chat.json", chat.transcript().unwrap()).unwrap();
+    /// # }
+    /// ```
+    pub fn transcript(&self) -> Result<String> {
+        Ok(serde_json::to_string(&self.history())?)
+    }
+
     /// Get the current chat history.
     ///
     /// # Example
@@ -739,3 +1283,291 @@ impl Chat {
         self.shared_history.read().unwrap().clone()
     }
 }
+
+/// A model with one token for every word that always predicts the word "short".
+#[cfg(test)]
+struct WordModel {
+    tokenizer: Arc<tokenizers::Tokenizer>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct WordSession {
+    tokens: Vec<u32>,
+}
+
+#[cfg(test)]
+impl Session for WordSession {
+    fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+}
+
+#[cfg(test)]
+impl WordModel {
+    fn new() -> Self {
+        let tokenizer = crate::test_util::word_level_tokenizer(&["[UNK]", "short", "EOS"]);
+        Self {
+            tokenizer: Arc::new(tokenizer),
+        }
+    }
+
+    /// Create a chat session with one token markers around every message.
+    fn chat(
+        &mut self,
+        history: Vec<ChatHistoryItem>,
+        session: Option<WordSession>,
+        strategy: ContextOverflowStrategy,
+        max_tokens: usize,
+    ) -> ChatSession<Self> {
+        ChatSession::new(
+            self,
+            "SYSTEM ".into(),
+            " END ".into(),
+            "USER ".into(),
+            " END ".into(),
+            "ASSISTANT ".into(),
+            " END ".into(),
+            None,
+            None,
+            Arc::new(Mutex::new(llm_samplers::prelude::SampleGreedy::default())),
+            session,
+            history,
+            Arc::new(RwLock::new(Vec::new())),
+            ContextWindow {
+                strategy: Some(strategy),
+                max_tokens: Some(max_tokens),
+                reserved_response_tokens: 0,
+                on_compaction: None,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+impl SyncModel for WordModel {
+    type Session = WordSession;
+
+    fn new_session(&self) -> Result<Self::Session> {
+        Ok(WordSession::default())
+    }
+
+    fn feed_text(
+        &self,
+        session: &mut WordSession,
+        prompt: &str,
+        into: &mut Vec<f32>,
+    ) -> Result<()> {
+        self.feed_tokens(session, &tokenize(self, prompt)?, into)
+    }
+
+    fn feed_tokens(
+        &self,
+        session: &mut WordSession,
+        tokens: &[u32],
+        into: &mut Vec<f32>,
+    ) -> Result<()> {
+        session.tokens.extend_from_slice(tokens);
+        into.clear();
+        into.extend([0., 10., 0.]);
+        Ok(())
+    }
+
+    fn stop_token(&self) -> Result<u32> {
+        Ok(2)
+    }
+
+    fn tokenizer(&self) -> Arc<tokenizers::Tokenizer> {
+        self.tokenizer.clone()
+    }
+}
+
+#[cfg(test)]
+fn word_history(messages: usize) -> Vec<ChatHistoryItem> {
+    let mut history = vec![ChatHistoryItem::new(MessageType::SystemPrompt, "be brief")];
+    for i in 0..messages {
+        let ty = if i % 2 == 0 {
+            MessageType::UserMessage
+        } else {
+            MessageType::ModelAnswer
+        };
+        history.push(ChatHistoryItem::new(ty, format!("message {i}")));
+    }
+    history
+}
+
+#[test]
+fn drop_oldest_keeps_system_prompt_and_recent_turns() {
+    let mut model = WordModel::new();
+    // Every message is four tokens: the start marker, two words and the end marker
+    let mut chat = model.chat(
+        word_history(5),
+        None,
+        ContextOverflowStrategy::DropOldest,
+        20,
+    );
+    chat.unfed_text += &chat.assistant_marker;
+    assert_eq!(chat.pending_context_len(&model).unwrap(), 25);
+
+    chat.fit_context_window(&model).unwrap();
+    assert!(chat.pending_context_len(&model).unwrap() <= 20);
+    let contents: Vec<_> = chat.context.iter().map(|item| item.contents()).collect();
+    // The first user message and its answer are dropped together
+    assert_eq!(
+        contents,
+        ["be brief", "message 2", "message 3", "message 4"]
+    );
+    // The full history is kept even though the context was compacted
+    assert_eq!(chat.history.read().unwrap().len(), 6);
+}
+
+#[test]
+fn drop_oldest_keeps_restored_session() {
+    let mut model = WordModel::new();
+    let mut restored = WordSession::default();
+    model
+        .feed_text(&mut restored, "SYSTEM be brief END", &mut Vec::new())
+        .unwrap();
+    let mut chat = model.chat(
+        Vec::new(),
+        Some(restored),
+        ContextOverflowStrategy::DropOldest,
+        12,
+    );
+    for item in word_history(3).into_iter().skip(1) {
+        chat.add_item(item);
+    }
+    chat.unfed_text += &chat.assistant_marker;
+    assert_eq!(chat.pending_context_len(&model).unwrap(), 17);
+
+    chat.fit_context_window(&model).unwrap();
+    // The restored tokens are never removed because the chat has no history to rebuild them from
+    assert_eq!(&chat.session.tokens[..4], &chat.restored_tokens[..]);
+    let contents: Vec<_> = chat.context.iter().map(|item| item.contents()).collect();
+    assert_eq!(contents, ["message 2"]);
+    assert_eq!(chat.pending_context_len(&model).unwrap(), 9);
+}
+
+#[test]
+fn summarize_folds_old_turns_into_system_prompt() {
+    let mut model = WordModel::new();
+    let mut chat = model.chat(
+        word_history(5),
+        None,
+        ContextOverflowStrategy::Summarize {
+            keep_recent_messages: 2,
+            max_summary_tokens: 2,
+        },
+        22,
+    );
+    chat.unfed_text += &chat.assistant_marker;
+
+    chat.fit_context_window(&model).unwrap();
+    let contents: Vec<_> = chat.context.iter().map(|item| item.contents()).collect();
+    assert_eq!(contents, ["be brief", "message 3", "message 4"]);
+    assert!(chat.summary.as_deref().unwrap().contains("short"));
+    // The summary is rendered as part of the system prompt
+    assert!(chat
+        .unfed_text
+        .starts_with("SYSTEM be brief\n\nSummary of the earlier conversation:\n"));
+    assert!(chat.pending_context_len(&model).unwrap() <= 22);
+}
+
+#[test]
+fn sliding_window_keeps_sink_tokens() {
+    let mut model = WordModel::new();
+    let mut chat = model.chat(
+        word_history(4),
+        None,
+        ContextOverflowStrategy::SlidingWindow { sink_tokens: 2 },
+        14,
+    );
+    // Feed the history into the session
+    let history = std::mem::take(&mut chat.unfed_text);
+    model
+        .feed_text(&mut chat.session, &history, &mut Vec::new())
+        .unwrap();
+    chat.record_fed_text(&model, &history).unwrap();
+    assert_eq!(chat.context_tokens.len(), 20);
+    let fed_tokens = chat.context_tokens.clone();
+
+    chat.add_item(ChatHistoryItem::new(MessageType::UserMessage, "message 4"));
+    chat.unfed_text += &chat.assistant_marker;
+    chat.fit_context_window(&model).unwrap();
+
+    // The new message (5 tokens) and the sinks fit, so 7 of the most recent tokens are kept
+    assert_eq!(chat.context_tokens.len(), 9);
+    assert_eq!(&chat.context_tokens[..2], &fed_tokens[..2]);
+    assert_eq!(&chat.context_tokens[2..], &fed_tokens[13..]);
+    assert_eq!(chat.session.tokens, chat.context_tokens);
+    assert_eq!(chat.pending_context_len(&model).unwrap(), 14);
+    // The first two messages were evicted completely, the third is only partially evicted
+    let contents: Vec<_> = chat.context.iter().map(|item| item.contents()).collect();
+    assert_eq!(
+        contents,
+        ["be brief", "message 2", "message 3", "message 4"]
+    );
+}
+
+#[test]
+fn summarize_without_system_prompt_keeps_summary() {
+    let mut model = WordModel::new();
+    // A chat restored from a session without a history doesn't have a system prompt in its context
+    let mut restored = WordSession::default();
+    model
+        .feed_text(&mut restored, "SYSTEM be brief END", &mut Vec::new())
+        .unwrap();
+    let mut chat = model.chat(
+        Vec::new(),
+        Some(restored),
+        ContextOverflowStrategy::Summarize {
+            keep_recent_messages: 2,
+            max_summary_tokens: 2,
+        },
+        23,
+    );
+    for item in word_history(5).into_iter().skip(1) {
+        chat.add_item(item);
+    }
+    chat.unfed_text += &chat.assistant_marker;
+    assert_eq!(chat.pending_context_len(&model).unwrap(), 25);
+
+    chat.fit_context_window(&model).unwrap();
+    let contents: Vec<_> = chat.context.iter().map(|item| item.contents()).collect();
+    assert_eq!(contents, ["message 3", "message 4"]);
+    // The summary gets a system prompt of its own
+    assert!(chat
+        .unfed_text
+        .starts_with("SYSTEM Summary of the earlier conversation:\nshort"));
+    assert!(chat.pending_context_len(&model).unwrap() <= 23);
+}
+
+#[test]
+fn chat_history_round_trips_through_json() {
+    let timestamp = chrono::DateTime::parse_from_rfc3339("2024-05-01T12:30:00Z")
+        .unwrap()
+        .with_timezone(&chrono::Utc);
+    let history = vec![
+        ChatHistoryItem::new(MessageType::SystemPrompt, "be brief").with_timestamp(timestamp),
+        ChatHistoryItem::new(MessageType::UserMessage, "Hello!")
+            .with_timestamp(timestamp)
+            .with_metadata("user", "evan"),
+        ChatHistoryItem::new(MessageType::ModelAnswer, "Hi").with_timestamp(timestamp),
+    ];
+
+    let transcript = serde_json::to_string(&history).unwrap();
+    // Pin the on disk format so old transcripts keep loading
+    assert_eq!(
+        transcript,
+        r#"[{"type":"system_prompt","contents":"be brief","timestamp":"2024-05-01T12:30:00Z"},{"type":"user_message","contents":"Hello!","timestamp":"2024-05-01T12:30:00Z","metadata":{"user":"evan"}},{"type":"model_answer","contents":"Hi","timestamp":"2024-05-01T12:30:00Z"}]"#
+    );
+    let restored: Vec<ChatHistoryItem> = serde_json::from_str(&transcript).unwrap();
+    assert_eq!(restored, history);
+
+    // The timestamp and metadata are optional
+    let restored: ChatHistoryItem =
+        serde_json::from_str(r#"{"type":"user_message","contents":"Hello!"}"#).unwrap();
+    assert_eq!(restored.ty(), MessageType::UserMessage);
+    assert_eq!(restored.contents(), "Hello!");
+    assert!(restored.metadata().is_empty());
+}
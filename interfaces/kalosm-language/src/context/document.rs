@@ -1,7 +1,12 @@
+use std::ops::Range;
 use url::Url;
 pub use whatlang::Lang;
 
+use super::DocumentSection;
+
 /// A document is a piece of text with a title.
+///
+/// Documents read from structured formats also have an outline of [`DocumentSection`]s with the headings and pages of the document.
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Document {
     title: String,
@@ -9,6 +14,8 @@ pub struct Document {
     summary: Option<String>,
     created_at: Option<chrono::DateTime<chrono::Utc>>,
     updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    sections: Vec<DocumentSection>,
 }
 
 impl Document {
@@ -30,6 +37,7 @@ impl Document {
             summary: None,
             created_at: None,
             updated_at: None,
+            sections: Vec::new(),
         }
     }
 
@@ -48,6 +56,11 @@ impl Document {
         self.updated_at = Some(updated_at);
     }
 
+    /// Set the sections of the document. The byte ranges of the sections must point into the body of the document.
+    pub fn set_sections(&mut self, sections: Vec<DocumentSection>) {
+        self.sections = sections;
+    }
+
     /// Get the title of the document.
     pub fn title(&self) -> &str {
         &self.title
@@ -57,6 +70,68 @@ impl Document {
     pub fn body(&self) -> &str {
         &self.body
     }
+
+    /// Get the sections of the document in the order they start. Sections with a heading contain their subsections, so they can overlap.
+    pub fn sections(&self) -> &[DocumentSection] {
+        &self.sections
+    }
+
+    /// Get the innermost section with a heading that contains the byte.
+    pub fn section_at(&self, byte: usize) -> Option<&DocumentSection> {
+        self.sections
+            .iter()
+            .filter(|section| !section.heading_path.is_empty() && contains(section, byte))
+            .max_by_key(|section| section.heading_path.len())
+    }
+
+    /// Get the page that contains the byte, if the document has pages.
+    pub fn page_at(&self, byte: usize) -> Option<usize> {
+        self.sections
+            .iter()
+            .filter(|section| contains(section, byte))
+            .find_map(|section| section.page)
+    }
+
+    /// Describe where a byte range of the body is in the document, like `page 12, §3.2`. Returns `None` if the document has no pages or numbered sections at that position.
+    pub fn location(&self, byte_range: Range<usize>) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(page) = self.page_at(byte_range.start) {
+            parts.push(format!("page {page}"));
+        }
+        if let Some(number) = self
+            .section_at(byte_range.start)
+            .and_then(|section| section.number_string())
+        {
+            parts.push(format!("§{number}"));
+        }
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+
+    /// Split the body into the ranges between section boundaries. Chunkers chunk each range separately so no chunk spans two sections. If the document has no sections, this is the whole body.
+    pub fn segments(&self) -> Vec<Range<usize>> {
+        let mut boundaries = self
+            .sections
+            .iter()
+            .flat_map(|section| [section.byte_range.start, section.byte_range.end])
+            .filter(|boundary| *boundary > 0 && *boundary < self.body.len())
+            .collect::<Vec<_>>();
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        let mut segments = Vec::with_capacity(boundaries.len() + 1);
+        let mut start = 0;
+        for boundary in boundaries {
+            segments.push(start..boundary);
+            start = boundary;
+        }
+        segments.push(start..self.body.len());
+        segments
+    }
+}
+
+fn contains(section: &DocumentSection, byte: usize) -> bool {
+    section.byte_range.contains(&byte)
+        || (section.byte_range.is_empty() && section.byte_range.start == byte)
 }
 
 impl From<String> for Document {
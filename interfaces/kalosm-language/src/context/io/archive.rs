@@ -0,0 +1,93 @@
+//! Helpers for the zipped xml formats (epub, odt, pptx and xlsx).
+
+use std::io::{Read, Seek};
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use zip::ZipArchive;
+
+/// Read a file from a zip archive as a string.
+pub(crate) fn read_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+) -> anyhow::Result<String> {
+    let mut file = archive.by_name(name)?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// Collect the text inside every `text` element, grouped by the `paragraph` element they are in. A `br` element inside a paragraph is a line break. Element names are compared without their namespace prefix.
+pub(crate) fn paragraphs(xml: &str, paragraph: &[u8], text: &[u8]) -> anyhow::Result<Vec<String>> {
+    let mut reader = Reader::from_str(xml);
+    let mut paragraphs = Vec::new();
+    let mut current = None;
+    let mut text_depth = 0;
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => {
+                let name = element.local_name();
+                if name.as_ref() == paragraph {
+                    current = Some(String::new());
+                }
+                if name.as_ref() == text {
+                    text_depth += 1;
+                }
+                if name.as_ref() == b"br" {
+                    if let Some(current) = &mut current {
+                        current.push('\n');
+                    }
+                }
+            }
+            Event::Empty(element) if element.local_name().as_ref() == b"br" => {
+                if let Some(current) = &mut current {
+                    current.push('\n');
+                }
+            }
+            Event::End(element) => {
+                let name = element.local_name();
+                if name.as_ref() == paragraph {
+                    if let Some(current) = current.take() {
+                        let current = current.trim();
+                        if !current.is_empty() {
+                            paragraphs.push(current.to_string());
+                        }
+                    }
+                }
+                if name.as_ref() == text {
+                    text_depth -= 1;
+                }
+            }
+            Event::Text(contents) if text_depth > 0 => {
+                if let Some(current) = &mut current {
+                    current.push_str(&contents.unescape()?);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(paragraphs)
+}
+
+/// Read the title from the `docProps/core.xml` metadata of an office document.
+pub(crate) fn core_title<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Option<String> {
+    let core = read_entry(archive, "docProps/core.xml").ok()?;
+    let title = paragraphs(&core, b"title", b"title").ok()?;
+    title.into_iter().next()
+}
+
+/// Build a zip archive in memory with the given files.
+#[cfg(test)]
+pub(crate) fn zip_archive(files: &[(&str, &str)]) -> ZipArchive<std::io::Cursor<Vec<u8>>> {
+    use std::io::Write;
+
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (name, contents) in files {
+        writer
+            .start_file(*name, zip::write::FileOptions::default())
+            .unwrap();
+        writer.write_all(contents.as_bytes()).unwrap();
+    }
+    ZipArchive::new(writer.finish().unwrap()).unwrap()
+}
@@ -0,0 +1,123 @@
+use std::path::{Path, PathBuf};
+
+use crate::context::document::{Document, IntoDocument};
+use crate::context::OutlineBuilder;
+
+/// File extensions and the programming language they are written in.
+const LANGUAGES: &[(&str, &str)] = &[
+    ("rs", "rust"),
+    ("py", "python"),
+    ("js", "javascript"),
+    ("mjs", "javascript"),
+    ("jsx", "javascript"),
+    ("ts", "typescript"),
+    ("tsx", "typescript"),
+    ("go", "go"),
+    ("java", "java"),
+    ("kt", "kotlin"),
+    ("swift", "swift"),
+    ("c", "c"),
+    ("h", "c"),
+    ("cc", "cpp"),
+    ("cpp", "cpp"),
+    ("hpp", "cpp"),
+    ("cs", "csharp"),
+    ("rb", "ruby"),
+    ("php", "php"),
+    ("scala", "scala"),
+    ("sh", "bash"),
+    ("bash", "bash"),
+    ("zsh", "bash"),
+    ("sql", "sql"),
+    ("lua", "lua"),
+    ("r", "r"),
+    ("dart", "dart"),
+    ("ex", "elixir"),
+    ("exs", "elixir"),
+    ("hs", "haskell"),
+    ("ml", "ocaml"),
+    ("zig", "zig"),
+    ("toml", "toml"),
+    ("yaml", "yaml"),
+    ("yml", "yaml"),
+    ("css", "css"),
+];
+
+/// Detect the programming language of a source file from its extension.
+pub fn detect_language(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, language)| *language)
+}
+
+/// A source code file that can be read from the file system.
+///
+/// The title of the document is the name of the file and the body is the code in a fenced code block tagged with the language, so models know what language they are reading. The whole file is a single section named after the file.
+#[derive(Debug, Clone)]
+pub struct SourceCodeDocument {
+    path: PathBuf,
+    language: &'static str,
+}
+
+impl SourceCodeDocument {
+    /// Get the programming language of the file.
+    pub fn language(&self) -> &'static str {
+        self.language
+    }
+}
+
+impl TryFrom<PathBuf> for SourceCodeDocument {
+    type Error = anyhow::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        if !path.is_file() {
+            return Err(anyhow::anyhow!("Path is not a file"));
+        }
+        let Some(language) = detect_language(&path) else {
+            return Err(anyhow::anyhow!("Path is not a supported source code file"));
+        };
+        Ok(Self { path, language })
+    }
+}
+
+#[async_trait::async_trait]
+impl IntoDocument for SourceCodeDocument {
+    async fn into_document(self) -> anyhow::Result<Document> {
+        let title = self
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let code = tokio::fs::read_to_string(&self.path).await?;
+        Ok(read_code(title, self.language, &code))
+    }
+}
+
+/// Wrap the code in a fenced code block inside a single section named after the file.
+fn read_code(name: String, language: &str, code: &str) -> Document {
+    let body = format!("```{language}\n{}\n```", code.trim_end());
+    let mut outline = OutlineBuilder::default();
+    outline.heading(1, name.as_str(), 0, None);
+    let sections = outline.finish(body.len());
+    let mut document = Document::from_parts(name, body);
+    document.set_sections(sections);
+    document
+}
+
+#[test]
+fn source_code_is_fenced_with_its_language() {
+    let path = Path::new("src/main.rs");
+    assert_eq!(detect_language(path), Some("rust"));
+    assert_eq!(detect_language(Path::new("Query.SQL")), Some("sql"));
+    assert_eq!(detect_language(Path::new("notes.txt")), None);
+    assert_eq!(detect_language(Path::new("Makefile")), None);
+
+    let document = read_code("main.rs".to_string(), "rust", "fn main() {}\n\n");
+    assert_eq!(document.title(), "main.rs");
+    assert_eq!(document.body(), "```rust\nfn main() {}\n```");
+    let section = document.section_at(0).unwrap();
+    assert_eq!(section.heading_path, ["main.rs"]);
+    assert_eq!(section.byte_range, 0..document.body().len());
+}
@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+use crate::context::document::{Document, IntoDocument};
+use crate::context::{DocumentSection, OutlineBuilder};
+
+use super::title_from_path;
+
+/// A csv or tsv document that can be read from the file system.
+///
+/// Each row is written on its own line with the column headers, so every line of the document can be understood without the rest of the table. Every row is a `Row n` section, so chunks never mix rows.
+#[derive(Debug, Clone)]
+pub struct CsvDocument {
+    path: PathBuf,
+    delimiter: u8,
+}
+
+impl CsvDocument {
+    /// Set the delimiter between fields. Defaults to `\t` for tsv files and `,` for everything else.
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+}
+
+impl TryFrom<PathBuf> for CsvDocument {
+    type Error = anyhow::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        if !path.is_file() {
+            return Err(anyhow::anyhow!("Path is not a file"));
+        }
+        let delimiter = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => b',',
+            Some("tsv") => b'\t',
+            _ => return Err(anyhow::anyhow!("Path is not a csv or tsv file")),
+        };
+        Ok(Self { path, delimiter })
+    }
+}
+
+#[async_trait::async_trait]
+impl IntoDocument for CsvDocument {
+    async fn into_document(self) -> anyhow::Result<Document> {
+        let title = title_from_path(&self.path);
+        let (text, sections) = read_table(File::open(&self.path)?, self.delimiter)?;
+        let mut document = Document::from_parts(title, text);
+        document.set_sections(sections);
+        Ok(document)
+    }
+}
+
+/// Write every row of a csv table on its own line with the column headers. Returns the text and a section for each row.
+fn read_table(table: impl Read, delimiter: u8) -> anyhow::Result<(String, Vec<DocumentSection>)> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(table);
+    let headers = reader
+        .headers()?
+        .iter()
+        .map(|header| header.trim().to_string())
+        .collect::<Vec<_>>();
+
+    let mut text = String::new();
+    let mut outline = OutlineBuilder::default();
+    for (index, record) in reader.records().enumerate() {
+        let record = record?;
+        outline.heading(1, format!("Row {}", index + 1), text.len(), None);
+        text += &format_row(&headers, record.iter());
+        text += "\n";
+    }
+    let sections = outline.finish(text.len());
+    Ok((text, sections))
+}
+
+/// Format a row of a table as `header: value` pairs. Empty cells are skipped and cells without a header are written as is.
+pub(crate) fn format_row<'a>(
+    headers: &[String],
+    values: impl IntoIterator<Item = &'a str>,
+) -> String {
+    values
+        .into_iter()
+        .enumerate()
+        .filter(|(_, value)| !value.trim().is_empty())
+        .map(|(index, value)| match headers.get(index) {
+            Some(header) if !header.is_empty() => format!("{header}: {}", value.trim()),
+            _ => value.trim().to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[test]
+fn csv_rows_include_their_headers() {
+    let csv = "name, age ,city\nAda,36,London\nGrace,,\"Arlington, VA\"\nAlan,41,Wilmslow,extra\n";
+    let (text, sections) = read_table(csv.as_bytes(), b',').unwrap();
+    assert_eq!(
+        text,
+        "name: Ada, age: 36, city: London\nname: Grace, city: Arlington, VA\nname: Alan, age: 41, city: Wilmslow, extra\n"
+    );
+    let rows = sections
+        .iter()
+        .map(|section| {
+            (
+                section.heading().unwrap(),
+                &text[section.byte_range.clone()],
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rows,
+        [
+            ("Row 1", "name: Ada, age: 36, city: London\n"),
+            ("Row 2", "name: Grace, city: Arlington, VA\n"),
+            ("Row 3", "name: Alan, age: 41, city: Wilmslow, extra\n"),
+        ]
+    );
+
+    let tsv = "name\tage\nAda\t36\n";
+    assert_eq!(
+        read_table(tsv.as_bytes(), b'\t').unwrap().0,
+        "name: Ada, age: 36\n"
+    );
+}
@@ -4,6 +4,7 @@ use std::path::PathBuf;
 use std::fs::File;
 
 use crate::context::document::{Document, IntoDocument};
+use crate::context::OutlineBuilder;
 
 /// A docx document that can be read from the file system.
 #[derive(Debug, Clone)]
@@ -30,54 +31,108 @@ impl IntoDocument for DocxDocument {
     async fn into_document(self) -> anyhow::Result<Document> {
         let file = File::open(self.path)?;
         let reader = std::io::BufReader::new(file);
-        let docx = DocxFile::from_xml(reader)?;
-        let mut text = String::new();
-        for section in docx.children {
-            match section {
-                docx_rs::DocumentChild::Paragraph(paragraph) => {
-                    for child in paragraph.children {
-                        match child {
-                            docx_rs::ParagraphChild::Run(run) => {
-                                for child in run.children {
-                                    match child {
-                                        docx_rs::RunChild::Text(text_child) => {
-                                            text += &text_child.text;
-                                        }
-                                        docx_rs::RunChild::Sym(_) => {}
-                                        docx_rs::RunChild::DeleteText(_) => {}
-                                        docx_rs::RunChild::Tab(_) => {}
-                                        docx_rs::RunChild::Break(_) => {}
-                                        docx_rs::RunChild::Drawing(_) => {}
-                                        docx_rs::RunChild::Shape(_) => {}
-                                        docx_rs::RunChild::CommentStart(_) => {}
-                                        docx_rs::RunChild::CommentEnd(_) => {}
-                                        docx_rs::RunChild::FieldChar(_) => {}
-                                        docx_rs::RunChild::InstrText(_) => {}
-                                        docx_rs::RunChild::DeleteInstrText(_) => {}
-                                        docx_rs::RunChild::InstrTextString(_) => {}
+        Ok(read_docx(DocxFile::from_xml(reader)?))
+    }
+}
+
+/// Collect the text of every paragraph in a docx document. Paragraphs with a heading style start a new section.
+fn read_docx(docx: DocxFile) -> Document {
+    let mut text = String::new();
+    let mut outline = OutlineBuilder::default();
+    for section in docx.children {
+        match section {
+            docx_rs::DocumentChild::Paragraph(paragraph) => {
+                let start = text.len();
+                let level = paragraph
+                    .property
+                    .style
+                    .as_ref()
+                    .and_then(|style| heading_level(&style.val));
+                for child in paragraph.children {
+                    match child {
+                        docx_rs::ParagraphChild::Run(run) => {
+                            for child in run.children {
+                                match child {
+                                    docx_rs::RunChild::Text(text_child) => {
+                                        text += &text_child.text;
                                     }
+                                    docx_rs::RunChild::Sym(_) => {}
+                                    docx_rs::RunChild::DeleteText(_) => {}
+                                    docx_rs::RunChild::Tab(_) => {}
+                                    docx_rs::RunChild::Break(_) => {}
+                                    docx_rs::RunChild::Drawing(_) => {}
+                                    docx_rs::RunChild::Shape(_) => {}
+                                    docx_rs::RunChild::CommentStart(_) => {}
+                                    docx_rs::RunChild::CommentEnd(_) => {}
+                                    docx_rs::RunChild::FieldChar(_) => {}
+                                    docx_rs::RunChild::InstrText(_) => {}
+                                    docx_rs::RunChild::DeleteInstrText(_) => {}
+                                    docx_rs::RunChild::InstrTextString(_) => {}
                                 }
                             }
-                            docx_rs::ParagraphChild::Insert(_) => {}
-                            docx_rs::ParagraphChild::Delete(_) => {}
-                            docx_rs::ParagraphChild::BookmarkStart(_) => {}
-                            docx_rs::ParagraphChild::Hyperlink(_) => {}
-                            docx_rs::ParagraphChild::BookmarkEnd(_) => {}
-                            docx_rs::ParagraphChild::CommentStart(_) => {}
-                            docx_rs::ParagraphChild::CommentEnd(_) => {}
-                            docx_rs::ParagraphChild::StructuredDataTag(_) => {}
                         }
+                        docx_rs::ParagraphChild::Insert(_) => {}
+                        docx_rs::ParagraphChild::Delete(_) => {}
+                        docx_rs::ParagraphChild::BookmarkStart(_) => {}
+                        docx_rs::ParagraphChild::Hyperlink(_) => {}
+                        docx_rs::ParagraphChild::BookmarkEnd(_) => {}
+                        docx_rs::ParagraphChild::CommentStart(_) => {}
+                        docx_rs::ParagraphChild::CommentEnd(_) => {}
+                        docx_rs::ParagraphChild::StructuredDataTag(_) => {}
                     }
                 }
-                docx_rs::DocumentChild::Table(_) => {}
-                docx_rs::DocumentChild::BookmarkStart(_) => {}
-                docx_rs::DocumentChild::BookmarkEnd(_) => {}
-                docx_rs::DocumentChild::CommentStart(_) => {}
-                docx_rs::DocumentChild::CommentEnd(_) => {}
-                docx_rs::DocumentChild::StructuredDataTag(_) => {}
-                docx_rs::DocumentChild::TableOfContents(_) => {}
+                if let Some(level) = level {
+                    outline.heading(level, text[start..].trim(), start, None);
+                }
+                if text.len() > start {
+                    text += "\n\n";
+                }
             }
+            docx_rs::DocumentChild::Table(_) => {}
+            docx_rs::DocumentChild::BookmarkStart(_) => {}
+            docx_rs::DocumentChild::BookmarkEnd(_) => {}
+            docx_rs::DocumentChild::CommentStart(_) => {}
+            docx_rs::DocumentChild::CommentEnd(_) => {}
+            docx_rs::DocumentChild::StructuredDataTag(_) => {}
+            docx_rs::DocumentChild::TableOfContents(_) => {}
         }
-        Ok(Document::from_parts("", text))
     }
+    let sections = outline.finish(text.len());
+    let mut document = Document::from_parts("", text);
+    document.set_sections(sections);
+    document
+}
+
+/// Get the outline level of a paragraph style like `Heading2`. The title of the document is the outermost level.
+fn heading_level(style: &str) -> Option<usize> {
+    if style == "Title" {
+        return Some(1);
+    }
+    let level = style.strip_prefix("Heading")?.parse::<usize>().ok()?;
+    (1..=9).contains(&level).then_some(level + 1)
+}
+
+#[test]
+fn docx_headings_start_sections() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main">
+    <w:body>
+        <w:p><w:pPr><w:pStyle w:val="Title"/></w:pPr><w:r><w:t>Report</w:t></w:r></w:p>
+        <w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr><w:r><w:t>Intro</w:t></w:r></w:p>
+        <w:p><w:r><w:t xml:space="preserve">Hello </w:t></w:r><w:r><w:t>world.</w:t></w:r></w:p>
+        <w:p></w:p>
+    </w:body>
+</w:document>"#;
+    let document = read_docx(DocxFile::from_xml(xml.as_bytes()).unwrap());
+    assert_eq!(document.body(), "Report\n\nIntro\n\nHello world.\n\n");
+    let hello = document.body().find("Hello").unwrap();
+    assert_eq!(
+        document.section_at(hello).unwrap().heading_path,
+        ["Report", "Intro"]
+    );
+
+    assert_eq!(heading_level("Title"), Some(1));
+    assert_eq!(heading_level("Heading3"), Some(4));
+    assert_eq!(heading_level("Heading10"), None);
+    assert_eq!(heading_level("Normal"), None);
 }
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use scraper::{Html, Selector};
+use zip::ZipArchive;
+
+use crate::context::document::{Document, IntoDocument};
+use crate::context::markdown_outline;
+
+use super::archive::{paragraphs, read_entry};
+use super::title_from_path;
+
+/// An epub book that can be read from the file system.
+///
+/// The chapters are read in reading order. Headings are kept as markdown style `#` lines so each chapter starts a new section.
+#[derive(Debug, Clone)]
+pub struct EpubDocument {
+    path: PathBuf,
+}
+
+impl TryFrom<PathBuf> for EpubDocument {
+    type Error = anyhow::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        if !path.is_file() {
+            return Err(anyhow::anyhow!("Path is not a file"));
+        }
+        if path.extension().unwrap() != "epub" {
+            return Err(anyhow::anyhow!("Path is not an epub file"));
+        }
+        Ok(Self { path })
+    }
+}
+
+#[async_trait::async_trait]
+impl IntoDocument for EpubDocument {
+    async fn into_document(self) -> anyhow::Result<Document> {
+        let mut archive = ZipArchive::new(File::open(&self.path)?)?;
+
+        let (title, text) = read_epub(&mut archive)?;
+        let title = title.unwrap_or_else(|| title_from_path(&self.path));
+
+        let sections = markdown_outline(&text);
+        let mut document = Document::from_parts(title, text);
+        document.set_sections(sections);
+        Ok(document)
+    }
+}
+
+/// Read the title of the book from its package file and the text of every chapter in reading order.
+fn read_epub<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+) -> anyhow::Result<(Option<String>, String)> {
+    // The container points to the package file that lists the chapters
+    let container = read_entry(archive, "META-INF/container.xml")?;
+    let package_path = find_attribute(&container, b"rootfile", b"full-path")?
+        .ok_or_else(|| anyhow::anyhow!("The epub has no package file"))?;
+    let package_dir = match package_path.rfind('/') {
+        Some(index) => &package_path[..=index],
+        None => "",
+    };
+    let package = read_entry(archive, &package_path)?;
+    let (manifest, spine) = read_package(&package)?;
+
+    let title = paragraphs(&package, b"title", b"title")?.into_iter().next();
+
+    let block_selector = Selector::parse("h1, h2, h3, h4, h5, h6, p, li, pre, blockquote")
+        .map_err(|err| anyhow::anyhow!("{err}"))?;
+    let mut text = String::new();
+    for id in spine {
+        let Some(href) = manifest.get(&id) else {
+            continue;
+        };
+        let chapter = read_entry(archive, &format!("{package_dir}{href}"))?;
+        let html = Html::parse_document(&chapter);
+        for element in html.select(&block_selector) {
+            let name = element.value().name();
+            // Nested blocks are read with their parent
+            if element
+                .ancestors()
+                .filter_map(|node| node.value().as_element())
+                .any(|parent| matches!(parent.name(), "p" | "li" | "pre" | "blockquote"))
+            {
+                continue;
+            }
+            let block = element.text().collect::<String>();
+            let block = block.trim();
+            if block.is_empty() {
+                continue;
+            }
+            if let Some(level) = name.strip_prefix('h').and_then(|l| l.parse::<usize>().ok()) {
+                text += &"#".repeat(level);
+                text += " ";
+            }
+            text += block;
+            text += "\n\n";
+        }
+    }
+    Ok((title, text))
+}
+
+/// Find the value of an attribute on the first element with the given name.
+fn find_attribute(xml: &str, element: &[u8], attribute: &[u8]) -> anyhow::Result<Option<String>> {
+    let mut reader = Reader::from_str(xml);
+    loop {
+        match reader.read_event()? {
+            Event::Start(start) | Event::Empty(start) if start.local_name().as_ref() == element => {
+                if let Some(value) = start.try_get_attribute(attribute)? {
+                    return Ok(Some(value.unescape_value()?.to_string()));
+                }
+            }
+            Event::Eof => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Read the manifest (item id to path) and spine (item ids in reading order) of an epub package.
+fn read_package(package: &str) -> anyhow::Result<(HashMap<String, String>, Vec<String>)> {
+    let mut reader = Reader::from_str(package);
+    let mut manifest = HashMap::new();
+    let mut spine = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element) => match element.local_name().as_ref() {
+                b"item" => {
+                    let id = element.try_get_attribute("id")?;
+                    let href = element.try_get_attribute("href")?;
+                    if let (Some(id), Some(href)) = (id, href) {
+                        manifest.insert(
+                            id.unescape_value()?.to_string(),
+                            href.unescape_value()?.to_string(),
+                        );
+                    }
+                }
+                b"itemref" => {
+                    if let Some(id) = element.try_get_attribute("idref")? {
+                        spine.push(id.unescape_value()?.to_string());
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok((manifest, spine))
+}
+
+#[test]
+fn reads_chapters_in_reading_order() {
+    let mut archive = super::archive::zip_archive(&[
+        (
+            "META-INF/container.xml",
+            r#"<container xmlns="urn:oasis:names:tc:opendocument:xmlns:container"><rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles></container>"#,
+        ),
+        (
+            "OEBPS/content.opf",
+            r#"<package xmlns="http://www.idpf.org/2007/opf" xmlns:dc="http://purl.org/dc/elements/1.1/">
+                <metadata><dc:title>A Short Book</dc:title></metadata>
+                <manifest>
+                    <item id="one" href="text/one.xhtml" media-type="application/xhtml+xml"/>
+                    <item id="two" href="text/two.xhtml" media-type="application/xhtml+xml"/>
+                    <item id="css" href="style.css" media-type="text/css"/>
+                </manifest>
+                <spine><itemref idref="two"/><itemref idref="missing"/><itemref idref="one"/></spine>
+            </package>"#,
+        ),
+        (
+            "OEBPS/text/one.xhtml",
+            r#"<html><body><h1>Chapter Two</h1><p>The end.</p></body></html>"#,
+        ),
+        (
+            "OEBPS/text/two.xhtml",
+            r#"<html><head><title>ignored</title></head><body>
+                <h1>Chapter One</h1>
+                <h2>A Scene</h2>
+                <p>It was a <em>dark</em> night.</p>
+                <blockquote><p>Quoted</p></blockquote>
+                <ul><li>First</li><li></li></ul>
+            </body></html>"#,
+        ),
+    ]);
+
+    let (title, text) = read_epub(&mut archive).unwrap();
+    assert_eq!(title.as_deref(), Some("A Short Book"));
+    assert_eq!(
+        text,
+        "# Chapter One\n\n## A Scene\n\nIt was a dark night.\n\nQuoted\n\nFirst\n\n# Chapter Two\n\nThe end.\n\n"
+    );
+    let headings = markdown_outline(&text)
+        .into_iter()
+        .map(|section| section.heading_path.join(" > "))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        headings,
+        ["Chapter One", "Chapter One > A Scene", "Chapter Two"]
+    );
+}
@@ -1,13 +1,17 @@
 use std::path::PathBuf;
 
+use scraper::{Html, Selector};
 use tokio::{fs::File, io::AsyncReadExt};
 
 use crate::context::{
     document::{Document, IntoDocument},
+    outline_from_headings,
     page::extract_article,
 };
 
 /// An html document that can be read from the file system.
+///
+/// The `h1` to `h6` headings of the page start new sections.
 #[derive(Debug, Clone)]
 pub struct HtmlDocument {
     path: PathBuf,
@@ -35,6 +39,65 @@ impl IntoDocument for HtmlDocument {
         tokio::io::BufReader::new(file)
             .read_to_string(&mut html)
             .await?;
-        extract_article(&html)
+        let mut document = extract_article(&html)?;
+        // Find each heading in the extracted text in order. Headings that were removed with the boilerplate are skipped
+        let sections = outline_from_headings(document.body(), html_headings(&html));
+        document.set_sections(sections);
+        Ok(document)
     }
 }
+
+/// Find the level and text of each `h1` to `h6` heading in an html document.
+fn html_headings(html: &str) -> Vec<(usize, String)> {
+    let html = Html::parse_document(html);
+    let selector = Selector::parse("h1, h2, h3, h4, h5, h6").unwrap();
+    html.select(&selector)
+        .filter_map(|heading| {
+            let level = heading.value().name()[1..].parse::<usize>().ok()?;
+            let text = heading.text().collect::<String>();
+            let text = text.trim();
+            (!text.is_empty()).then(|| (level, text.to_string()))
+        })
+        .collect()
+}
+
+#[test]
+fn html_headings_become_sections() {
+    let html = r#"<html><body>
+        <h1>Guide</h1>
+        <p>Welcome.</p>
+        <h2> Install <code>kalosm</code></h2>
+        <p>Run cargo add.</p>
+        <h3></h3>
+        <h2>Removed with the navigation</h2>
+        <h2>Usage</h2>
+        <p>Call the model.</p>
+    </body></html>"#;
+    let headings = html_headings(html);
+    assert_eq!(
+        headings,
+        [
+            (1, "Guide".to_string()),
+            (2, "Install kalosm".to_string()),
+            (2, "Removed with the navigation".to_string()),
+            (2, "Usage".to_string()),
+        ]
+    );
+
+    let body = "Guide\nWelcome.\nInstall kalosm\nRun cargo add.\nUsage\nCall the model.";
+    let sections = outline_from_headings(body, headings)
+        .into_iter()
+        .map(|section| (section.heading_path.join(" > "), &body[section.byte_range]))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        sections,
+        [
+            ("Guide".to_string(), body),
+            (
+                "Guide > Install kalosm".to_string(),
+                "Install kalosm\nRun cargo add.\n"
+            ),
+            ("Guide > Usage".to_string(), "Usage\nCall the model."),
+        ]
+    );
+}
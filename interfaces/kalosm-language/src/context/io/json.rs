@@ -0,0 +1,210 @@
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::context::document::{Document, IntoDocument};
+use crate::context::{DocumentSection, OutlineBuilder};
+
+use super::title_from_path;
+
+/// A json or json lines document that can be read from the file system.
+///
+/// Every record (the top level value, each item of a top level array, or each line of a json lines file) is written as a paragraph of `field: value` lines in its own `Record n` section.
+#[derive(Debug, Clone)]
+pub struct JsonDocument {
+    path: PathBuf,
+    lines: bool,
+    fields: Option<Vec<String>>,
+    title_field: Option<String>,
+}
+
+impl JsonDocument {
+    /// Only include these fields of each record in the document. Nested fields are separated with a `.`, like `author.name`. By default every field is included.
+    pub fn with_fields(mut self, fields: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.fields = Some(fields.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Use this field as the title of the document if the file contains a single record. Defaults to the name of the file.
+    pub fn with_title_field(mut self, field: impl Into<String>) -> Self {
+        self.title_field = Some(field.into());
+        self
+    }
+}
+
+impl TryFrom<PathBuf> for JsonDocument {
+    type Error = anyhow::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        if !path.is_file() {
+            return Err(anyhow::anyhow!("Path is not a file"));
+        }
+        let lines = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => false,
+            Some("jsonl" | "ndjson") => true,
+            _ => return Err(anyhow::anyhow!("Path is not a json or jsonl file")),
+        };
+        Ok(Self {
+            path,
+            lines,
+            fields: None,
+            title_field: None,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl IntoDocument for JsonDocument {
+    async fn into_document(self) -> anyhow::Result<Document> {
+        let source = tokio::fs::read_to_string(&self.path).await?;
+        let records = parse_records(&source, self.lines)?;
+
+        let title = match (&self.title_field, records.as_slice()) {
+            (Some(field), [record]) => field_value(record, field).and_then(value_text),
+            _ => None,
+        }
+        .unwrap_or_else(|| title_from_path(&self.path));
+
+        let (text, sections) = read_records(&records, self.fields.as_deref());
+        let mut document = Document::from_parts(title, text);
+        document.set_sections(sections);
+        Ok(document)
+    }
+}
+
+/// Parse the records of a json file or a json lines file.
+fn parse_records(source: &str, lines: bool) -> anyhow::Result<Vec<Value>> {
+    if lines {
+        Ok(source
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Value>, _>>()?)
+    } else {
+        Ok(match serde_json::from_str(source)? {
+            Value::Array(records) => records,
+            record => vec![record],
+        })
+    }
+}
+
+/// Write every record as a paragraph of `field: value` lines. Returns the text and a `Record n` section for each record that has any text.
+fn read_records(records: &[Value], fields: Option<&[String]>) -> (String, Vec<DocumentSection>) {
+    let mut text = String::new();
+    let mut outline = OutlineBuilder::default();
+    for (index, record) in records.iter().enumerate() {
+        let mut lines = Vec::new();
+        match fields {
+            Some(fields) => {
+                for field in fields {
+                    if let Some(value) = field_value(record, field) {
+                        flatten(field, value, &mut lines);
+                    }
+                }
+            }
+            None => flatten("", record, &mut lines),
+        }
+        if !lines.is_empty() {
+            outline.heading(1, format!("Record {}", index + 1), text.len(), None);
+            text += &lines.join("\n");
+            text += "\n\n";
+        }
+    }
+    let sections = outline.finish(text.len());
+    (text, sections)
+}
+
+/// Get a field from a record with a `.` separated path.
+fn field_value<'a>(record: &'a Value, field: &str) -> Option<&'a Value> {
+    field.split('.').try_fold(record, |value, key| match value {
+        Value::Object(object) => object.get(key),
+        Value::Array(array) => array.get(key.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+/// Get the text of a scalar value.
+fn value_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(string) => Some(string.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(bool) => Some(bool.to_string()),
+        Value::Null | Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+/// Write every scalar in the value as a `path: value` line.
+fn flatten(path: &str, value: &Value, lines: &mut Vec<String>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                flatten(&path, value, lines);
+            }
+        }
+        Value::Array(array) => {
+            for value in array {
+                flatten(path, value, lines);
+            }
+        }
+        value => {
+            if let Some(text) = value_text(value) {
+                if path.is_empty() {
+                    lines.push(text);
+                } else {
+                    lines.push(format!("{path}: {text}"));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn json_records_become_sections() {
+    let json = r#"[
+        {"author": {"born": 1920, "name": "Frank Herbert"}, "tags": ["sci-fi", "classic"], "title": "Dune"},
+        {"author": {"name": "Jane Austen"}, "notes": null, "read": true, "title": "Emma"},
+        {}
+    ]"#;
+    let records = parse_records(json, false).unwrap();
+    assert_eq!(records.len(), 3);
+    let (text, sections) = read_records(&records, None);
+    assert_eq!(
+        text,
+        "author.born: 1920\nauthor.name: Frank Herbert\ntags: sci-fi\ntags: classic\ntitle: Dune\n\nauthor.name: Jane Austen\nread: true\ntitle: Emma\n\n"
+    );
+    let emma = text.find("author.name: Jane").unwrap();
+    assert_eq!(
+        sections
+            .iter()
+            .map(|section| (section.heading().unwrap(), section.byte_range.clone()))
+            .collect::<Vec<_>>(),
+        [("Record 1", 0..emma), ("Record 2", emma..text.len())]
+    );
+
+    let fields = ["author.name".to_string(), "tags.1".to_string()];
+    assert_eq!(
+        read_records(&records, Some(&fields)).0,
+        "author.name: Frank Herbert\ntags.1: classic\n\nauthor.name: Jane Austen\n\n"
+    );
+
+    let lines = "{\"title\": \"Dune\"}\n\n{\"title\": \"Emma\"}\n";
+    let records = parse_records(lines, true).unwrap();
+    assert_eq!(
+        read_records(&records, None).0,
+        "title: Dune\n\ntitle: Emma\n\n"
+    );
+    assert!(parse_records("{\"title\": ", false).is_err());
+
+    let record = &parse_records(r#"{"meta": {"name": "Dune"}}"#, false).unwrap()[0];
+    assert_eq!(
+        field_value(record, "meta.name")
+            .and_then(value_text)
+            .as_deref(),
+        Some("Dune")
+    );
+}
@@ -4,6 +4,7 @@ use tokio::{fs::File, io::AsyncReadExt};
 
 use crate::context::{
     document::{Document, IntoDocument},
+    outline_from_headings,
     page::extract_article,
 };
 
@@ -35,10 +36,46 @@ impl IntoDocument for MdDocument {
         tokio::io::BufReader::new(file)
             .read_to_string(&mut md)
             .await?;
+        let headings = headings(&md);
         let parser = pulldown_cmark::Parser::new(&md);
 
         let mut html_output = String::new();
         pulldown_cmark::html::push_html(&mut html_output, parser);
-        extract_article(&html_output)
+        let mut document = extract_article(&html_output)?;
+
+        // Find each heading in the extracted text in order. Headings that were removed with the boilerplate are skipped
+        let sections = outline_from_headings(document.body(), headings);
+        document.set_sections(sections);
+        Ok(document)
+    }
+}
+
+/// Find the level and text of each heading in a markdown document.
+fn headings(md: &str) -> Vec<(usize, String)> {
+    use pulldown_cmark::{Event, Tag};
+
+    let mut headings = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    for event in pulldown_cmark::Parser::new(md) {
+        match event {
+            Event::Start(Tag::Heading(level, _, _)) => {
+                current = Some((level as usize, String::new()));
+            }
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, heading)) = &mut current {
+                    heading.push_str(&text);
+                }
+            }
+            Event::End(Tag::Heading(..)) => {
+                if let Some((level, heading)) = current.take() {
+                    let heading = heading.trim().to_string();
+                    if !heading.is_empty() {
+                        headings.push((level, heading));
+                    }
+                }
+            }
+            _ => {}
+        }
     }
+    headings
 }
@@ -1,18 +1,43 @@
 use crate::context::document::Document;
 use crate::context::document::IntoDocument;
 use crate::context::document::IntoDocuments;
-use std::path::PathBuf;
+use convert_case::{Case, Casing};
+use std::path::{Path, PathBuf};
 use tokio::task::JoinSet;
+mod archive;
+mod code;
+pub use code::*;
+mod csv;
+pub use self::csv::*;
 mod docx;
 pub use docx::*;
+mod epub;
+pub use epub::*;
 mod html;
 pub use html::*;
+mod json;
+pub use json::*;
 mod md;
 pub use md::*;
+mod odt;
+pub use odt::*;
 mod pdf;
 pub use self::pdf::*;
+mod pptx;
+pub use pptx::*;
+mod rtf;
+pub use rtf::*;
 mod txt;
 pub use txt::*;
+mod xlsx;
+pub use xlsx::*;
+
+/// Create a title from the name of a file.
+pub(crate) fn title_from_path(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_case(Case::Title))
+        .unwrap_or_default()
+}
 
 /// A document that can be read from the file system.
 ///
@@ -29,16 +54,32 @@ pub use txt::*;
 /// ```
 #[derive(Debug, Clone)]
 pub enum FsDocument {
+    /// A csv or tsv document.
+    Csv(CsvDocument),
     /// A docx document.
     Docx(DocxDocument),
+    /// An epub book.
+    Epub(EpubDocument),
     /// An html document.
     Html(HtmlDocument),
+    /// A json or json lines document.
+    Json(JsonDocument),
     /// A markdown document.
     Md(MdDocument),
+    /// An OpenDocument text document.
+    Odt(OdtDocument),
     /// A pdf document.
     Pdf(PdfDocument),
+    /// A PowerPoint presentation.
+    Pptx(PptxDocument),
+    /// A rich text document.
+    Rtf(RtfDocument),
+    /// A source code file.
+    SourceCode(SourceCodeDocument),
     /// A text document.
     Txt(TextDocument),
+    /// An Excel workbook.
+    Xlsx(XlsxDocument),
 }
 
 impl TryFrom<PathBuf> for FsDocument {
@@ -49,11 +90,21 @@ impl TryFrom<PathBuf> for FsDocument {
             return Err(anyhow::anyhow!("Path is not a file"));
         }
         match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv" | "tsv") => Ok(Self::Csv(CsvDocument::try_from(path)?)),
             Some("docx") => Ok(Self::Docx(DocxDocument::try_from(path)?)),
+            Some("epub") => Ok(Self::Epub(EpubDocument::try_from(path)?)),
             Some("html") => Ok(Self::Html(HtmlDocument::try_from(path)?)),
+            Some("json" | "jsonl" | "ndjson") => Ok(Self::Json(JsonDocument::try_from(path)?)),
             Some("md") => Ok(Self::Md(MdDocument::try_from(path)?)),
+            Some("odt") => Ok(Self::Odt(OdtDocument::try_from(path)?)),
             Some("pdf") => Ok(Self::Pdf(PdfDocument::try_from(path)?)),
+            Some("pptx") => Ok(Self::Pptx(PptxDocument::try_from(path)?)),
+            Some("rtf") => Ok(Self::Rtf(RtfDocument::try_from(path)?)),
             Some("txt") => Ok(Self::Txt(TextDocument::try_from(path)?)),
+            Some("xlsx") => Ok(Self::Xlsx(XlsxDocument::try_from(path)?)),
+            _ if detect_language(&path).is_some() => {
+                Ok(Self::SourceCode(SourceCodeDocument::try_from(path)?))
+            }
             _ => Err(anyhow::anyhow!("Path is not a supported file type")),
         }
     }
@@ -63,17 +114,27 @@ impl TryFrom<PathBuf> for FsDocument {
 impl IntoDocument for FsDocument {
     async fn into_document(self) -> anyhow::Result<Document> {
         match self {
+            Self::Csv(csv) => csv.into_document().await,
             Self::Docx(docx) => docx.into_document().await,
+            Self::Epub(epub) => epub.into_document().await,
             Self::Html(html) => html.into_document().await,
+            Self::Json(json) => json.into_document().await,
             Self::Md(md) => md.into_document().await,
+            Self::Odt(odt) => odt.into_document().await,
             Self::Pdf(pdf) => pdf.into_document().await,
+            Self::Pptx(pptx) => pptx.into_document().await,
+            Self::Rtf(rtf) => rtf.into_document().await,
+            Self::SourceCode(code) => code.into_document().await,
             Self::Txt(txt) => txt.into_document().await,
+            Self::Xlsx(xlsx) => xlsx.into_document().await,
         }
     }
 }
 
 /// A folder full of documents.
 ///
+/// Files that fail to load, like a binary file with a source code extension, are skipped with a warning so one bad file doesn't stop the rest of the folder from loading.
+///
 /// # Example
 /// ```rust, no_run
 /// # use kalosm::language::*;
@@ -115,7 +176,14 @@ impl IntoDocuments for DocumentFolder {
         self.start_into_documents(&mut set).await?;
         let mut documents = Vec::new();
         while let Some(join) = set.join_next().await {
-            documents.push(join??);
+            let (path, document) = join?;
+            match document {
+                Ok(document) => documents.push(document),
+                Err(err) => tracing::warn!(
+                    "Skipping {} because it couldn't be read: {err}",
+                    path.display()
+                ),
+            }
         }
         Ok(documents)
     }
@@ -134,9 +202,33 @@ impl DocumentFolder {
         Self::try_from(path.into())
     }
 
+    /// Get the path of the folder.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the paths of every supported document in the folder and its subfolders without reading them.
+    pub async fn document_paths(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        let mut folders = vec![self.path.clone()];
+        while let Some(folder) = folders.pop() {
+            let mut read_dir = tokio::fs::read_dir(&folder).await?;
+            while let Some(entry) = read_dir.next_entry().await? {
+                let path = entry.path();
+                if path.is_dir() {
+                    folders.push(path);
+                } else if FsDocument::try_from(path.clone()).is_ok() {
+                    paths.push(path);
+                }
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
     fn start_into_documents<'a>(
         &'a self,
-        set: &'a mut JoinSet<anyhow::Result<Document>>,
+        set: &'a mut JoinSet<(PathBuf, anyhow::Result<Document>)>,
     ) -> std::pin::Pin<Box<dyn std::future::Future<Output = anyhow::Result<()>> + Send + Sync + 'a>>
     {
         Box::pin(async move {
@@ -147,8 +239,8 @@ impl DocumentFolder {
                     if let Ok(folder) = DocumentFolder::try_from(path) {
                         folder.start_into_documents(set).await?;
                     }
-                } else if let Ok(document) = FsDocument::try_from(path) {
-                    set.spawn(document.into_document());
+                } else if let Ok(document) = FsDocument::try_from(path.clone()) {
+                    set.spawn(async move { (path, document.into_document().await) });
                 }
             }
             Ok(())
@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::path::PathBuf;
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use zip::ZipArchive;
+
+use crate::context::document::{Document, IntoDocument};
+use crate::context::markdown_outline;
+
+use super::archive::{paragraphs, read_entry};
+use super::title_from_path;
+
+/// An OpenDocument text document that can be read from the file system.
+///
+/// Headings are kept as markdown style `#` lines with the outline level of the heading.
+#[derive(Debug, Clone)]
+pub struct OdtDocument {
+    path: PathBuf,
+}
+
+impl TryFrom<PathBuf> for OdtDocument {
+    type Error = anyhow::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        if !path.is_file() {
+            return Err(anyhow::anyhow!("Path is not a file"));
+        }
+        if path.extension().unwrap() != "odt" {
+            return Err(anyhow::anyhow!("Path is not an odt file"));
+        }
+        Ok(Self { path })
+    }
+}
+
+#[async_trait::async_trait]
+impl IntoDocument for OdtDocument {
+    async fn into_document(self) -> anyhow::Result<Document> {
+        let mut archive = ZipArchive::new(File::open(&self.path)?)?;
+        let title = read_entry(&mut archive, "meta.xml")
+            .ok()
+            .and_then(|meta| paragraphs(&meta, b"title", b"title").ok())
+            .and_then(|title| title.into_iter().next())
+            .unwrap_or_else(|| title_from_path(&self.path));
+        let content = read_entry(&mut archive, "content.xml")?;
+
+        let text = read_content(&content)?;
+
+        let sections = markdown_outline(&text);
+        let mut document = Document::from_parts(title, text);
+        document.set_sections(sections);
+        Ok(document)
+    }
+}
+
+/// Read the paragraphs and headings of the `content.xml` file of an OpenDocument text document.
+fn read_content(content: &str) -> anyhow::Result<String> {
+    let mut reader = Reader::from_str(content);
+    let mut text = String::new();
+    // The paragraphs and headings that are currently open. Paragraphs can be nested inside list items, table cells and notes.
+    let mut blocks: Vec<String> = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => match element.local_name().as_ref() {
+                b"h" => {
+                    let level = element
+                        .try_get_attribute("text:outline-level")?
+                        .and_then(|level| level.unescape_value().ok()?.parse::<usize>().ok())
+                        .unwrap_or(1);
+                    blocks.push(format!("{} ", "#".repeat(level)));
+                }
+                b"p" => blocks.push(String::new()),
+                _ => {}
+            },
+            Event::Empty(element) => {
+                if let Some(block) = blocks.last_mut() {
+                    match element.local_name().as_ref() {
+                        b"s" => block.push(' '),
+                        b"tab" => block.push('\t'),
+                        b"line-break" => block.push('\n'),
+                        _ => {}
+                    }
+                }
+            }
+            Event::Text(contents) => {
+                if let Some(block) = blocks.last_mut() {
+                    block.push_str(&contents.unescape()?);
+                }
+            }
+            Event::End(element) => {
+                if matches!(element.local_name().as_ref(), b"h" | b"p") {
+                    if let Some(block) = blocks.pop() {
+                        let block = block.trim();
+                        if !block.is_empty() && !block.chars().all(|c| c == '#') {
+                            text += block;
+                            text += "\n\n";
+                        }
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(text)
+}
+
+#[test]
+fn odt_headings_keep_their_level() {
+    let content = r#"<office:document-content xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0" xmlns:text="urn:oasis:names:tc:opendocument:xmlns:text:1.0">
+        <office:body><office:text>
+            <text:h text:outline-level="1">Report</text:h>
+            <text:p>Hello<text:s/>world<text:tab/>again<text:line-break/>next line</text:p>
+            <text:h text:outline-level="2"><text:span>Items</text:span></text:h>
+            <text:list><text:list-item><text:p>First &amp; best</text:p></text:list-item></text:list>
+            <text:h text:outline-level="2"></text:h>
+            <text:p/>
+        </office:text></office:body>
+    </office:document-content>"#;
+    let text = read_content(content).unwrap();
+    assert_eq!(
+        text,
+        "# Report\n\nHello world\tagain\nnext line\n\n## Items\n\nFirst & best\n\n"
+    );
+    let headings = markdown_outline(&text)
+        .into_iter()
+        .map(|section| section.heading_path.join(" > "))
+        .collect::<Vec<_>>();
+    assert_eq!(headings, ["Report", "Report > Items"]);
+}
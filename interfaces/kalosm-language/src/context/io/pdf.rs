@@ -1,5 +1,6 @@
 use crate::context::document::Document;
 use crate::context::document::IntoDocument;
+use crate::context::OutlineBuilder;
 use itertools::Itertools;
 use std::fmt::Write;
 use std::path::PathBuf;
@@ -40,7 +41,12 @@ impl IntoDocument for PdfDocument {
             }
         }
 
-        for page in file.pages().flatten() {
+        let mut outline = OutlineBuilder::default();
+        for (index, page) in file.pages().enumerate() {
+            let Ok(page) = page else {
+                continue;
+            };
+            let start = text.len();
             if let Ok(flow) = pdf_text::run(&file, &page, &resolver) {
                 for run in flow.runs {
                     for line in run.lines {
@@ -52,8 +58,12 @@ impl IntoDocument for PdfDocument {
                     }
                 }
             }
+            outline.page(index + 1, start..text.len());
         }
 
-        Ok(Document::from_parts(title, text))
+        let sections = outline.finish(text.len());
+        let mut document = Document::from_parts(title, text);
+        document.set_sections(sections);
+        Ok(document)
     }
 }
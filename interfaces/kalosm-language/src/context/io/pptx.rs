@@ -0,0 +1,110 @@
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+
+use zip::ZipArchive;
+
+use crate::context::document::{Document, IntoDocument};
+use crate::context::markdown_outline;
+
+use super::archive::{core_title, paragraphs, read_entry};
+use super::title_from_path;
+
+/// A PowerPoint presentation that can be read from the file system.
+///
+/// Each slide starts a new `# Slide n` section.
+#[derive(Debug, Clone)]
+pub struct PptxDocument {
+    path: PathBuf,
+}
+
+impl TryFrom<PathBuf> for PptxDocument {
+    type Error = anyhow::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        if !path.is_file() {
+            return Err(anyhow::anyhow!("Path is not a file"));
+        }
+        if path.extension().unwrap() != "pptx" {
+            return Err(anyhow::anyhow!("Path is not a pptx file"));
+        }
+        Ok(Self { path })
+    }
+}
+
+#[async_trait::async_trait]
+impl IntoDocument for PptxDocument {
+    async fn into_document(self) -> anyhow::Result<Document> {
+        let mut archive = ZipArchive::new(File::open(&self.path)?)?;
+        let title = core_title(&mut archive).unwrap_or_else(|| title_from_path(&self.path));
+
+        let text = read_slides(&mut archive)?;
+
+        let sections = markdown_outline(&text);
+        let mut document = Document::from_parts(title, text);
+        document.set_sections(sections);
+        Ok(document)
+    }
+}
+
+/// Read the paragraphs of every slide in order, starting each slide with a `# Slide n` heading.
+fn read_slides<R: Read + Seek>(archive: &mut ZipArchive<R>) -> anyhow::Result<String> {
+    let mut slides = archive
+        .file_names()
+        .filter_map(|name| {
+            let number = name
+                .strip_prefix("ppt/slides/slide")?
+                .strip_suffix(".xml")?
+                .parse::<usize>()
+                .ok()?;
+            Some((number, name.to_string()))
+        })
+        .collect::<Vec<_>>();
+    slides.sort();
+
+    let mut text = String::new();
+    for (number, name) in slides {
+        let slide = read_entry(archive, &name)?;
+        let paragraphs = paragraphs(&slide, b"p", b"t")?;
+        text += &format!("# Slide {number}\n\n");
+        for paragraph in paragraphs {
+            text += &paragraph;
+            text += "\n\n";
+        }
+    }
+    Ok(text)
+}
+
+#[test]
+fn reads_slides_in_order() {
+    let slide = |text: &str| {
+        format!(
+            r#"<p:sld xmlns:a="http://schemas.openxmlformats.org/drawingml/2006/main" xmlns:p="http://schemas.openxmlformats.org/presentationml/2006/main"><p:cSld><p:spTree><p:sp><p:txBody>{text}</p:txBody></p:sp></p:spTree></p:cSld></p:sld>"#
+        )
+    };
+    let first = slide("<a:p><a:r><a:t>Kalosm</a:t></a:r></a:p><a:p><a:r><a:t>Local</a:t></a:r><a:br/><a:r><a:t>models</a:t></a:r><a:br><a:rPr lang=\"en-US\"/></a:br><a:r><a:t>in Rust</a:t></a:r></a:p>");
+    let tenth = slide(
+        "<a:p><a:r><a:t xml:space=\"preserve\">Fish &amp; chips </a:t></a:r></a:p><a:p></a:p>",
+    );
+    let mut archive = super::archive::zip_archive(&[
+        ("ppt/slides/slide10.xml", tenth.as_str()),
+        ("ppt/slides/slide2.xml", first.as_str()),
+        ("ppt/slides/_rels/slide2.xml.rels", "<Relationships/>"),
+        (
+            "docProps/core.xml",
+            r#"<cp:coreProperties xmlns:cp="http://schemas.openxmlformats.org/package/2006/metadata/core-properties" xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>Launch</dc:title></cp:coreProperties>"#,
+        ),
+    ]);
+
+    assert_eq!(core_title(&mut archive).as_deref(), Some("Launch"));
+    let text = read_slides(&mut archive).unwrap();
+    assert_eq!(
+        text,
+        "# Slide 2\n\nKalosm\n\nLocal\nmodels\nin Rust\n\n# Slide 10\n\nFish & chips\n\n"
+    );
+    let headings = markdown_outline(&text)
+        .into_iter()
+        .map(|section| section.heading_path.join(" > "))
+        .collect::<Vec<_>>();
+    assert_eq!(headings, ["Slide 2", "Slide 10"]);
+}
@@ -0,0 +1,242 @@
+use std::iter::Peekable;
+use std::path::PathBuf;
+use std::str::Chars;
+
+use crate::context::document::{Document, IntoDocument};
+
+use super::title_from_path;
+
+/// Groups that contain metadata or binary data instead of the text of the document.
+const IGNORED_DESTINATIONS: &[&str] = &[
+    "fonttbl",
+    "colortbl",
+    "stylesheet",
+    "info",
+    "pict",
+    "object",
+    "header",
+    "footer",
+    "listtable",
+    "listoverridetable",
+    "generator",
+];
+
+/// A rich text document that can be read from the file system.
+#[derive(Debug, Clone)]
+pub struct RtfDocument {
+    path: PathBuf,
+}
+
+impl TryFrom<PathBuf> for RtfDocument {
+    type Error = anyhow::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        if !path.is_file() {
+            return Err(anyhow::anyhow!("Path is not a file"));
+        }
+        if path.extension().unwrap() != "rtf" {
+            return Err(anyhow::anyhow!("Path is not a rtf file"));
+        }
+        Ok(Self { path })
+    }
+}
+
+#[async_trait::async_trait]
+impl IntoDocument for RtfDocument {
+    async fn into_document(self) -> anyhow::Result<Document> {
+        let bytes = tokio::fs::read(&self.path).await?;
+        let rtf = String::from_utf8_lossy(&bytes);
+        let (title, text) = rtf_to_text(&rtf);
+        let title = title.unwrap_or_else(|| title_from_path(&self.path));
+        Ok(Document::from_parts(title, text))
+    }
+}
+
+/// A `{}` group in a rich text document.
+struct Group {
+    ignored: bool,
+    title: bool,
+    // The number of fallback characters after each `\u` character, set with `\uc`
+    unicode_skip: usize,
+}
+
+/// Add a character to the title or text depending on the group it is in.
+fn push(groups: &[Group], c: char, text: &mut String, title: &mut Option<String>) {
+    let Some(group) = groups.last() else {
+        return;
+    };
+    if group.title {
+        title.get_or_insert_with(String::new).push(c);
+    } else if !group.ignored {
+        text.push(c);
+    }
+}
+
+/// Skip the fallback characters readers without unicode support show instead of a `\u` character. An escaped `\'hh` byte or a control word counts as one character, and the fallback never extends past the end of the group.
+fn skip_fallback(chars: &mut Peekable<Chars>, count: usize) {
+    for _ in 0..count {
+        match chars.peek() {
+            None | Some('{' | '}') => return,
+            Some('\\') => {
+                chars.next();
+                match chars.next() {
+                    Some('\'') => {
+                        chars.next();
+                        chars.next();
+                    }
+                    Some(c) if c.is_ascii_alphabetic() => {
+                        while chars.next_if(|c| c.is_ascii_alphabetic()).is_some() {}
+                        chars.next_if_eq(&'-');
+                        while chars.next_if(|c| c.is_ascii_digit()).is_some() {}
+                        chars.next_if_eq(&' ');
+                    }
+                    _ => {}
+                }
+            }
+            Some(_) => {
+                chars.next();
+            }
+        }
+    }
+}
+
+/// Strip the control words and groups from rich text. Returns the title from the info group (if there is one) and the text.
+fn rtf_to_text(rtf: &str) -> (Option<String>, String) {
+    let mut text = String::new();
+    let mut title: Option<String> = None;
+    let mut groups = vec![Group {
+        ignored: false,
+        title: false,
+        unicode_skip: 1,
+    }];
+    let mut chars = rtf.chars().peekable();
+    let mut group_start = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                let (ignored, unicode_skip) = groups
+                    .last()
+                    .map(|group| (group.ignored, group.unicode_skip))
+                    .unwrap_or((false, 1));
+                groups.push(Group {
+                    ignored,
+                    title: false,
+                    unicode_skip,
+                });
+                group_start = true;
+                continue;
+            }
+            '}' => {
+                groups.pop();
+            }
+            '\\' => match chars.peek().copied() {
+                Some('\\' | '{' | '}') => {
+                    let escaped = chars.next().unwrap();
+                    push(&groups, escaped, &mut text, &mut title);
+                }
+                Some('\'') => {
+                    chars.next();
+                    let hex = chars.by_ref().take(2).collect::<String>();
+                    if let Ok(byte) = u8::from_str_radix(&hex, 16) {
+                        // Code page characters are read as latin-1, which matches the default ansi code page for ascii and most accented letters
+                        push(&groups, byte as char, &mut text, &mut title);
+                    }
+                }
+                Some('*') => {
+                    chars.next();
+                    // Unknown destinations marked with \* should be skipped
+                    if let Some(group) = groups.last_mut() {
+                        group.ignored = true;
+                    }
+                }
+                Some('~') => {
+                    chars.next();
+                    push(&groups, '\u{a0}', &mut text, &mut title);
+                }
+                Some('-' | '_') => {
+                    chars.next();
+                }
+                Some(c) if c.is_ascii_alphabetic() => {
+                    let mut word = String::new();
+                    while let Some(c) = chars.peek().filter(|c| c.is_ascii_alphabetic()) {
+                        word.push(*c);
+                        chars.next();
+                    }
+                    let mut parameter = String::new();
+                    while let Some(c) = chars
+                        .peek()
+                        .filter(|c| c.is_ascii_digit() || (**c == '-' && parameter.is_empty()))
+                    {
+                        parameter.push(*c);
+                        chars.next();
+                    }
+                    // A single space after a control word is part of the control word
+                    if chars.peek() == Some(&' ') {
+                        chars.next();
+                    }
+
+                    if group_start && IGNORED_DESTINATIONS.contains(&word.as_str()) {
+                        if let Some(group) = groups.last_mut() {
+                            group.ignored = true;
+                        }
+                    }
+                    match word.as_str() {
+                        "title" if group_start => {
+                            if let Some(group) = groups.last_mut() {
+                                group.ignored = false;
+                                group.title = true;
+                            }
+                        }
+                        "par" | "line" | "sect" | "page" => {
+                            push(&groups, '\n', &mut text, &mut title)
+                        }
+                        "tab" => push(&groups, '\t', &mut text, &mut title),
+                        "uc" => {
+                            if let (Some(group), Ok(skip)) =
+                                (groups.last_mut(), parameter.parse::<usize>())
+                            {
+                                group.unicode_skip = skip;
+                            }
+                        }
+                        "u" => {
+                            if let Some(c) = parameter
+                                .parse::<i32>()
+                                .ok()
+                                // Negative values are used for code points above i16::MAX
+                                .map(|code| if code < 0 { code + 65536 } else { code })
+                                .and_then(|code| char::from_u32(code as u32))
+                            {
+                                push(&groups, c, &mut text, &mut title);
+                            }
+                            let skip = groups.last().map(|group| group.unicode_skip).unwrap_or(1);
+                            skip_fallback(&mut chars, skip);
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            },
+            '\r' | '\n' => {}
+            c => push(&groups, c, &mut text, &mut title),
+        }
+        group_start = false;
+    }
+
+    let title = title
+        .map(|title| title.trim().to_string())
+        .filter(|title| !title.is_empty());
+    (title, text.trim().to_string())
+}
+
+#[test]
+fn rtf_to_text_strips_control_words() {
+    let rtf = r"{\rtf1\ansi{\fonttbl{\f0 Times;}}{\info{\title Release notes}}{\*\generator Writer;}\f0 Caf\'e9 opens {\b today}.\par Tab\tab done\u8217?s\par}";
+    let (title, text) = rtf_to_text(rtf);
+    assert_eq!(title.as_deref(), Some("Release notes"));
+    assert_eq!(text, "Café opens today.\nTab\tdone’s");
+
+    // The fallback can be an escaped code page byte, and \uc changes how many fallback characters there are
+    let rtf = r"{\rtf1\ansi it\u8217\'92s {\uc2\u8364EUR}\uc0\u8364 5 {\uc3\u8212-}.}";
+    assert_eq!(rtf_to_text(rtf).1, "it’s €R€5 —.");
+}
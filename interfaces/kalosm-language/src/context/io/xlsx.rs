@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use zip::ZipArchive;
+
+use crate::context::document::{Document, IntoDocument};
+use crate::context::markdown_outline;
+
+use super::archive::{core_title, read_entry};
+use super::csv::format_row;
+use super::title_from_path;
+
+/// An Excel workbook that can be read from the file system.
+///
+/// Each sheet starts a new `# Sheet name` section. The first row of each sheet is used as the header, and every other row is written on its own line with the headers like a [`CsvDocument`](super::CsvDocument).
+#[derive(Debug, Clone)]
+pub struct XlsxDocument {
+    path: PathBuf,
+}
+
+impl TryFrom<PathBuf> for XlsxDocument {
+    type Error = anyhow::Error;
+
+    fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+        if !path.is_file() {
+            return Err(anyhow::anyhow!("Path is not a file"));
+        }
+        if path.extension().unwrap() != "xlsx" {
+            return Err(anyhow::anyhow!("Path is not an xlsx file"));
+        }
+        Ok(Self { path })
+    }
+}
+
+#[async_trait::async_trait]
+impl IntoDocument for XlsxDocument {
+    async fn into_document(self) -> anyhow::Result<Document> {
+        let mut archive = ZipArchive::new(File::open(&self.path)?)?;
+        let title = core_title(&mut archive).unwrap_or_else(|| title_from_path(&self.path));
+        let text = read_workbook(&mut archive)?;
+
+        let sections = markdown_outline(&text);
+        let mut document = Document::from_parts(title, text);
+        document.set_sections(sections);
+        Ok(document)
+    }
+}
+
+/// Write every sheet of a workbook as a `# Sheet name` section with one line per row.
+fn read_workbook<R: Read + Seek>(archive: &mut ZipArchive<R>) -> anyhow::Result<String> {
+    let shared_strings = match read_entry(archive, "xl/sharedStrings.xml") {
+        Ok(xml) => read_shared_strings(&xml)?,
+        Err(_) => Vec::new(),
+    };
+    let relationships = read_relationships(&read_entry(archive, "xl/_rels/workbook.xml.rels")?)?;
+    let sheets = read_sheets(&read_entry(archive, "xl/workbook.xml")?)?;
+
+    let mut text = String::new();
+    for (name, relationship) in sheets {
+        let Some(target) = relationships.get(&relationship) else {
+            continue;
+        };
+        let path = match target.strip_prefix('/') {
+            Some(absolute) => absolute.to_string(),
+            None => format!("xl/{target}"),
+        };
+        let rows = read_rows(&read_entry(archive, &path)?, &shared_strings)?;
+        let mut rows = rows.into_iter();
+        let Some(headers) = rows.next() else {
+            continue;
+        };
+
+        text += &format!("# {name}\n\n");
+        for row in rows {
+            let row = format_row(&headers, row.iter().map(|cell| cell.as_str()));
+            if !row.is_empty() {
+                text += &row;
+                text += "\n";
+            }
+        }
+        text += "\n";
+    }
+    Ok(text)
+}
+
+/// Read the shared string table of a workbook. Cells refer to shared strings by index, so there is exactly one string for every `<si>` element, even if it is empty. Rich text strings are split into `<r>` runs that are joined together, and phonetic hints in `<rPh>` are skipped.
+fn read_shared_strings(xml: &str) -> anyhow::Result<Vec<String>> {
+    let mut reader = Reader::from_str(xml);
+    let mut strings = Vec::new();
+    let mut current = None;
+    let mut in_text = false;
+    let mut in_phonetic = false;
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => match element.local_name().as_ref() {
+                b"si" => current = Some(String::new()),
+                b"t" => in_text = true,
+                b"rPh" => in_phonetic = true,
+                _ => {}
+            },
+            Event::Empty(element) if element.local_name().as_ref() == b"si" => {
+                strings.push(String::new())
+            }
+            Event::Text(contents) if in_text && !in_phonetic => {
+                if let Some(current) = &mut current {
+                    current.push_str(&contents.unescape()?);
+                }
+            }
+            Event::End(element) => match element.local_name().as_ref() {
+                b"si" => strings.push(current.take().unwrap_or_default()),
+                b"t" => in_text = false,
+                b"rPh" => in_phonetic = false,
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(strings)
+}
+
+/// Read the relationship ids and targets from a relationships file.
+fn read_relationships(xml: &str) -> anyhow::Result<HashMap<String, String>> {
+    let mut reader = Reader::from_str(xml);
+    let mut relationships = HashMap::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().as_ref() == b"Relationship" =>
+            {
+                let id = element.try_get_attribute("Id")?;
+                let target = element.try_get_attribute("Target")?;
+                if let (Some(id), Some(target)) = (id, target) {
+                    relationships.insert(
+                        id.unescape_value()?.to_string(),
+                        target.unescape_value()?.to_string(),
+                    );
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(relationships)
+}
+
+/// Read the names and relationship ids of the sheets in a workbook in order.
+fn read_sheets(xml: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut reader = Reader::from_str(xml);
+    let mut sheets = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().as_ref() == b"sheet" =>
+            {
+                let name = element.try_get_attribute("name")?;
+                let id = element.try_get_attribute("r:id")?;
+                if let (Some(name), Some(id)) = (name, id) {
+                    sheets.push((
+                        name.unescape_value()?.to_string(),
+                        id.unescape_value()?.to_string(),
+                    ));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(sheets)
+}
+
+/// Read the text of each cell in a sheet, row by row.
+fn read_rows(xml: &str, shared_strings: &[String]) -> anyhow::Result<Vec<Vec<String>>> {
+    let mut reader = Reader::from_str(xml);
+    let mut rows = Vec::new();
+    let mut row: Vec<String> = Vec::new();
+    let mut cell_type = None;
+    let mut column = 0;
+    let mut in_value = false;
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => match element.local_name().as_ref() {
+                b"row" => row = Vec::new(),
+                b"c" => {
+                    cell_type = element
+                        .try_get_attribute("t")?
+                        .map(|kind| kind.unescape_value().map(|kind| kind.to_string()))
+                        .transpose()?;
+                    if let Some(reference) = element.try_get_attribute("r")? {
+                        column = column_index(&reference.unescape_value()?).unwrap_or(row.len());
+                    } else {
+                        column = row.len();
+                    }
+                }
+                b"v" | b"t" => in_value = true,
+                _ => {}
+            },
+            Event::Text(contents) if in_value => {
+                let value = contents.unescape()?;
+                let value = match cell_type.as_deref() {
+                    Some("s") => value
+                        .trim()
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|index| shared_strings.get(index))
+                        .cloned()
+                        .unwrap_or_default(),
+                    Some("b") => (value.trim() == "1").to_string(),
+                    _ => value.to_string(),
+                };
+                if row.len() <= column {
+                    row.resize(column + 1, String::new());
+                }
+                row[column].push_str(&value);
+            }
+            Event::End(element) => match element.local_name().as_ref() {
+                b"row" => rows.push(std::mem::take(&mut row)),
+                b"v" | b"t" => in_value = false,
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(rows)
+}
+
+/// Get the zero based column index of a cell reference like `C12`.
+fn column_index(reference: &str) -> Option<usize> {
+    let letters = reference
+        .chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect::<Vec<_>>();
+    if letters.is_empty() {
+        return None;
+    }
+    let column = letters.iter().fold(0, |column, letter| {
+        column * 26 + (letter.to_ascii_uppercase() as usize - 'A' as usize + 1)
+    });
+    Some(column - 1)
+}
+
+#[test]
+fn shared_strings_keep_their_indices() {
+    let xml = r#"<sst xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main">
+        <si><t>Name</t></si>
+        <si><t></t></si>
+        <si/>
+        <si><t xml:space="preserve">  </t></si>
+        <si><r><t>Rich </t></r><r><rPr><b/></rPr><t>text</t></r><rPh><t>hint</t></rPh></si>
+        <si><t>Last</t></si>
+    </sst>"#;
+    assert_eq!(
+        read_shared_strings(xml).unwrap(),
+        ["Name", "", "", "  ", "Rich text", "Last"]
+    );
+}
+
+#[test]
+fn reads_sheets_from_a_workbook() {
+    let files = [
+        (
+            "xl/workbook.xml",
+            r#"<workbook xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships"><sheets>
+                <sheet name="People" sheetId="1" r:id="rId1"/>
+                <sheet name="Empty" sheetId="2" r:id="rId2"/>
+            </sheets></workbook>"#,
+        ),
+        (
+            "xl/_rels/workbook.xml.rels",
+            r#"<Relationships>
+                <Relationship Id="rId1" Target="worksheets/sheet1.xml"/>
+                <Relationship Id="rId2" Target="/xl/worksheets/sheet2.xml"/>
+            </Relationships>"#,
+        ),
+        (
+            "xl/sharedStrings.xml",
+            r#"<sst><si><t>Name</t></si><si/><si><t>Age</t></si><si><t>Ada</t></si><si><t>Grace</t></si></sst>"#,
+        ),
+        (
+            "xl/worksheets/sheet1.xml",
+            r#"<worksheet><sheetData>
+                <row r="1"><c r="A1" t="s"><v>0</v></c><c r="B1" t="s"><v>2</v></c><c r="C1" t="s"><v>1</v></c></row>
+                <row r="2"><c r="A2" t="s"><v>3</v></c><c r="B2"><v>36</v></c><c r="C2" t="b"><v>1</v></c></row>
+                <row r="3"><c r="A3" t="s"><v>4</v></c><c r="C3" t="inlineStr"><is><t>inline</t></is></c></row>
+            </sheetData></worksheet>"#,
+        ),
+        (
+            "xl/worksheets/sheet2.xml",
+            r#"<worksheet><sheetData/></worksheet>"#,
+        ),
+    ];
+    let mut archive = super::archive::zip_archive(&files);
+
+    assert_eq!(
+        read_workbook(&mut archive).unwrap(),
+        "# People\n\nName: Ada, Age: 36, true\nName: Grace, inline\n\n"
+    );
+}
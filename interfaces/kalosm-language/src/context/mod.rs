@@ -10,5 +10,7 @@ mod rss;
 pub use self::rss::*;
 mod search;
 pub use search::*;
+mod section;
+pub use section::*;
 
 pub use url::Url;
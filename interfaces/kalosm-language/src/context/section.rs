@@ -0,0 +1,196 @@
+use std::ops::Range;
+
+/// A section of a [`Document`](crate::context::Document), like the text under a heading or a page of a pdf.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DocumentSection {
+    /// The heading of the section and the headings of its parent sections, from the outermost to the innermost. Empty for sections that only mark a page.
+    pub heading_path: Vec<String>,
+    /// The position of the section in the outline of the document. Section 3.2 is `[3, 2]`. Empty for sections that only mark a page.
+    pub number: Vec<usize>,
+    /// The byte range of the section in the body of the document, including any subsections.
+    pub byte_range: Range<usize>,
+    /// The page the section starts on, if the document has pages.
+    pub page: Option<usize>,
+}
+
+impl DocumentSection {
+    /// Get the heading of the section.
+    pub fn heading(&self) -> Option<&str> {
+        self.heading_path.last().map(|heading| heading.as_str())
+    }
+
+    /// Get the number of the section formatted like `3.2`.
+    pub fn number_string(&self) -> Option<String> {
+        if self.number.is_empty() {
+            return None;
+        }
+        Some(
+            self.number
+                .iter()
+                .map(|number| number.to_string())
+                .collect::<Vec<_>>()
+                .join("."),
+        )
+    }
+}
+
+/// Builds the sections of a document from headings as the body is written.
+#[derive(Debug, Default)]
+pub(crate) struct OutlineBuilder {
+    sections: Vec<DocumentSection>,
+    // The level and index of the heading sections that have not ended yet
+    open: Vec<(usize, usize)>,
+    counters: Vec<usize>,
+}
+
+impl OutlineBuilder {
+    /// Start a new section with a heading at the given level (1 is the outermost level). Any open sections at the same or a deeper level end where this one starts.
+    pub(crate) fn heading(
+        &mut self,
+        level: usize,
+        heading: impl Into<String>,
+        start: usize,
+        page: Option<usize>,
+    ) {
+        while let Some((open_level, index)) = self.open.last().copied() {
+            if open_level < level {
+                break;
+            }
+            self.sections[index].byte_range.end = start;
+            self.open.pop();
+        }
+
+        // Number the section by its depth in the outline so skipped heading levels don't leave gaps
+        let depth = self.open.len();
+        self.counters.truncate(depth + 1);
+        if self.counters.len() == depth + 1 {
+            self.counters[depth] += 1;
+        } else {
+            self.counters.resize(depth, 1);
+            self.counters.push(1);
+        }
+
+        let mut heading_path = self
+            .open
+            .iter()
+            .map(|(_, index)| self.sections[*index].heading_path.last().cloned())
+            .collect::<Option<Vec<_>>>()
+            .unwrap_or_default();
+        heading_path.push(heading.into());
+
+        self.open.push((level, self.sections.len()));
+        self.sections.push(DocumentSection {
+            heading_path,
+            number: self.counters.clone(),
+            byte_range: start..start,
+            page,
+        });
+    }
+
+    /// Add a page that covers the given byte range.
+    pub(crate) fn page(&mut self, page: usize, byte_range: Range<usize>) {
+        self.sections.push(DocumentSection {
+            heading_path: Vec::new(),
+            number: Vec::new(),
+            byte_range,
+            page: Some(page),
+        });
+    }
+
+    /// End every open section at the end of the body and return the sections in the order they start.
+    pub(crate) fn finish(mut self, end: usize) -> Vec<DocumentSection> {
+        for (_, index) in self.open.drain(..) {
+            self.sections[index].byte_range.end = end;
+        }
+        self.sections
+            .sort_by_key(|section| (section.byte_range.start, section.number.len()));
+        self.sections
+    }
+}
+
+/// Find the sections of a body from the level and text of its headings. Each heading is searched for in order after the previous one, and headings that are not in the body are skipped.
+pub(crate) fn outline_from_headings(
+    body: &str,
+    headings: impl IntoIterator<Item = (usize, String)>,
+) -> Vec<DocumentSection> {
+    let mut outline = OutlineBuilder::default();
+    let mut offset = 0;
+    for (level, heading) in headings {
+        if let Some(position) = body[offset..].find(&heading) {
+            outline.heading(level, heading.as_str(), offset + position, None);
+            offset += position + heading.len();
+        }
+    }
+    outline.finish(body.len())
+}
+
+/// Find the sections of text that uses markdown style `#` heading lines.
+pub(crate) fn markdown_outline(text: &str) -> Vec<DocumentSection> {
+    let mut outline = OutlineBuilder::default();
+    let mut in_code_block = false;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let trimmed = line.trim_end();
+        if trimmed.starts_with("```") {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            continue;
+        }
+        let level = trimmed.chars().take_while(|c| *c == '#').count();
+        if (1..=6).contains(&level) {
+            if let Some(heading) = trimmed[level..].strip_prefix(' ') {
+                outline.heading(level, heading.trim(), start, None);
+            }
+        }
+    }
+    outline.finish(text.len())
+}
+
+#[test]
+fn markdown_outline_numbers_sections() {
+    use crate::context::Document;
+
+    let text = "# Intro\n\nHello\n\n# Methods\n\n## Data\n\nRows\n\n## Model\n\n```\n# not a heading\n```\n\nWeights\n";
+    let mut sections = markdown_outline(text);
+    sections.push(DocumentSection {
+        heading_path: Vec::new(),
+        number: Vec::new(),
+        byte_range: text.find("## Model").unwrap()..text.len(),
+        page: Some(2),
+    });
+    let mut document = Document::from_parts("Paper", text);
+    document.set_sections(sections);
+
+    let headings = document
+        .sections()
+        .iter()
+        .filter_map(|section| Some((section.number_string()?, section.heading_path.join(" > "))))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        headings,
+        [
+            ("1".to_string(), "Intro".to_string()),
+            ("2".to_string(), "Methods".to_string()),
+            ("2.1".to_string(), "Methods > Data".to_string()),
+            ("2.2".to_string(), "Methods > Model".to_string()),
+        ]
+    );
+
+    let weights = text.find("Weights").unwrap();
+    assert_eq!(
+        document.location(weights..weights + 7).as_deref(),
+        Some("page 2, §2.2")
+    );
+    let rows = text.find("Rows").unwrap();
+    assert_eq!(document.location(rows..rows + 4).as_deref(), Some("§2.1"));
+
+    let segments = document.segments();
+    assert_eq!(segments.len(), 4);
+    assert!(segments
+        .iter()
+        .all(|segment| document.body()[segment.clone()].starts_with('#')));
+}
@@ -9,6 +9,10 @@ pub mod task;
 pub mod tool;
 pub mod vector_db;
 
+#[cfg(test)]
+#[path = "../../language-model/src/test_util.rs"]
+mod test_util;
+
 pub use kalosm_language_model;
 pub use kalosm_llama;
 pub use kalosm_sample;
@@ -28,7 +32,9 @@ pub mod prelude {
     pub use kalosm_llama::{Llama, LlamaBuilder, LlamaSession, LlamaSource};
     pub use kalosm_sample::*;
     pub use kalosm_streams::text_stream::*;
-    pub use rbert::{Bert, BertBuilder, BertSource, BertSpace};
+    pub use rbert::{
+        Bert, BertBuilder, BertCrossEncoder, BertCrossEncoderBuilder, BertSource, BertSpace,
+    };
     pub use rphi::{Phi, PhiBuilder, PhiSource};
     pub use scraper::Html;
 }
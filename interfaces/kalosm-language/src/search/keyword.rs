@@ -0,0 +1,207 @@
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::hash::Hash;
+
+/// The terms in a piece of text that has been indexed for keyword search.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexedText {
+    terms: FxHashMap<String, u32>,
+    length: usize,
+}
+
+impl IndexedText {
+    /// Split text into lowercase terms for keyword search.
+    pub fn new(text: &str) -> Self {
+        let mut terms = FxHashMap::default();
+        let mut length = 0;
+        for term in keyword_terms(text) {
+            *terms.entry(term).or_insert(0) += 1;
+            length += 1;
+        }
+        Self { terms, length }
+    }
+
+    /// Get the number of terms in the text.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Check if the text has no terms.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Get the number of times a term appears in the text.
+    pub fn term_frequency(&self, term: &str) -> u32 {
+        self.terms.get(term).copied().unwrap_or_default()
+    }
+}
+
+/// Split text into the lowercase alphanumeric terms used by [`KeywordIndex`].
+pub fn keyword_terms(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| term.to_lowercase())
+}
+
+/// A keyword index that ranks text with [BM25](https://en.wikipedia.org/wiki/Okapi_BM25).
+///
+/// Keyword search complements vector search for exact matches like names, error codes, or product ids that embeddings tend to blur together.
+///
+/// # Example
+/// ```rust
+/// use kalosm_language::prelude::*;
+///
+/// let mut index = KeywordIndex::new();
+/// index.insert(0, "The build failed with error E0277");
+/// index.insert(1, "The build succeeded");
+///
+/// let results = index.search("E0277", 1);
+/// assert_eq!(results[0].0, 0);
+/// ```
+#[derive(Debug, Clone)]
+pub struct KeywordIndex<K> {
+    texts: FxHashMap<K, IndexedText>,
+    document_frequency: FxHashMap<String, usize>,
+    total_length: usize,
+    k1: f32,
+    b: f32,
+}
+
+impl<K: Hash + Eq + Clone> Default for KeywordIndex<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone> KeywordIndex<K> {
+    /// Create a new empty keyword index.
+    pub fn new() -> Self {
+        Self {
+            texts: FxHashMap::default(),
+            document_frequency: FxHashMap::default(),
+            total_length: 0,
+            k1: 1.2,
+            b: 0.75,
+        }
+    }
+
+    /// Set the BM25 parameters. `k1` controls how quickly repeated terms stop increasing the score (default 1.2) and `b` controls how much long texts are penalized (default 0.75).
+    pub fn with_parameters(mut self, k1: f32, b: f32) -> Self {
+        self.k1 = k1;
+        self.b = b;
+        self
+    }
+
+    /// Get the number of texts in the index.
+    pub fn len(&self) -> usize {
+        self.texts.len()
+    }
+
+    /// Check if the index is empty.
+    pub fn is_empty(&self) -> bool {
+        self.texts.is_empty()
+    }
+
+    /// Index a text under the given key. If the key was already in the index, the old text is replaced.
+    pub fn insert(&mut self, key: K, text: &str) {
+        self.insert_indexed(key, IndexedText::new(text));
+    }
+
+    /// Insert text that has already been split into terms under the given key. If the key was already in the index, the old text is replaced.
+    pub fn insert_indexed(&mut self, key: K, text: IndexedText) {
+        self.remove(&key);
+        for term in text.terms.keys() {
+            *self.document_frequency.entry(term.clone()).or_insert(0) += 1;
+        }
+        self.total_length += text.length;
+        self.texts.insert(key, text);
+    }
+
+    /// Remove the text with the given key from the index.
+    pub fn remove(&mut self, key: &K) -> Option<IndexedText> {
+        let text = self.texts.remove(key)?;
+        for term in text.terms.keys() {
+            if let Some(frequency) = self.document_frequency.get_mut(term) {
+                *frequency -= 1;
+                if *frequency == 0 {
+                    self.document_frequency.remove(term);
+                }
+            }
+        }
+        self.total_length -= text.length;
+        Some(text)
+    }
+
+    /// Remove every text from the index.
+    pub fn clear(&mut self) {
+        self.texts.clear();
+        self.document_frequency.clear();
+        self.total_length = 0;
+    }
+
+    /// Find the top k texts that best match the query. Returns the keys with their BM25 scores, sorted from best to worst match. Texts that share no terms with the query are not returned.
+    pub fn search(&self, query: &str, k: usize) -> Vec<(K, f32)> {
+        if self.texts.is_empty() {
+            return Vec::new();
+        }
+        let mut query_terms: Vec<String> = keyword_terms(query).collect();
+        query_terms.sort();
+        query_terms.dedup();
+
+        let text_count = self.texts.len() as f32;
+        let average_length = self.total_length as f32 / text_count;
+        let idfs: Vec<(&str, f32)> = query_terms
+            .iter()
+            .filter_map(|term| {
+                let frequency = *self.document_frequency.get(term)? as f32;
+                let idf = ((text_count - frequency + 0.5) / (frequency + 0.5) + 1.0).ln();
+                Some((term.as_str(), idf))
+            })
+            .collect();
+        if idfs.is_empty() {
+            return Vec::new();
+        }
+
+        let mut scores: Vec<(K, f32)> = self
+            .texts
+            .iter()
+            .filter_map(|(key, text)| {
+                let mut score = 0.0;
+                for (term, idf) in &idfs {
+                    let frequency = text.term_frequency(term) as f32;
+                    if frequency == 0.0 {
+                        continue;
+                    }
+                    let length_norm = if average_length > 0.0 {
+                        1.0 - self.b + self.b * text.length as f32 / average_length
+                    } else {
+                        1.0
+                    };
+                    score +=
+                        idf * frequency * (self.k1 + 1.0) / (frequency + self.k1 * length_norm);
+                }
+                (score > 0.0).then(|| (key.clone(), score))
+            })
+            .collect();
+        scores.sort_by(|(_, first), (_, second)| second.total_cmp(first));
+        scores.truncate(k);
+        scores
+    }
+}
+
+#[test]
+fn bm25_prefers_rare_exact_matches() {
+    let mut index = KeywordIndex::new();
+    index.insert(0, "The server returned error code E1042 after the update");
+    index.insert(1, "The server returned an error after the update");
+    index.insert(2, "The server is running");
+
+    let results = index.search("error E1042", 3);
+    assert_eq!(results[0].0, 0);
+    assert_eq!(results.len(), 2);
+
+    index.remove(&0);
+    let results = index.search("E1042", 3);
+    assert!(results.is_empty());
+}
@@ -0,0 +1,302 @@
+use std::future::Future;
+use std::ops::Range;
+
+use kalosm_language_model::{Model, SyncModel};
+use kalosm_sample::LiteralParser;
+
+use crate::context::Document;
+use crate::prelude::{IndexParser, SentenceChunker, StructuredRunner, Task};
+
+const TASK_DESCRIPTION: &str = "You decide if a sentence contains information that helps answer a search query. You answer Yes if the sentence is relevant to the query and No if it is not.";
+
+const EXAMPLES: [(&str, &str); 2] = [
+    (
+        "Query: When was the Eiffel Tower built?\nSentence: Construction of the Eiffel Tower finished in 1889.",
+        "Yes",
+    ),
+    (
+        "Query: When was the Eiffel Tower built?\nSentence: Paris is known for its cafes and museums.",
+        "No",
+    ),
+];
+
+/// A chunk of a document that context can be extracted from.
+pub trait ContextChunk {
+    /// Get the text of the whole document the chunk is from.
+    fn document_text(&self) -> &str;
+
+    /// Get the byte range of the chunk in the document.
+    fn chunk_range(&self) -> Range<usize>;
+}
+
+impl ContextChunk for Document {
+    fn document_text(&self) -> &str {
+        self.body()
+    }
+
+    fn chunk_range(&self) -> Range<usize> {
+        0..self.body().len()
+    }
+}
+
+/// Decides which sentences from retrieved chunks are worth keeping in the context of a model.
+pub trait SentenceFilter {
+    /// Return whether each sentence should be kept for the query.
+    fn keep(
+        &self,
+        query: &str,
+        sentences: &[&str],
+    ) -> impl Future<Output = anyhow::Result<Vec<bool>>> + Send;
+}
+
+/// A [`SentenceFilter`] that keeps sentences that mention any of a list of entities.
+#[derive(Debug, Clone)]
+pub struct EntitySentenceFilter {
+    entities: Vec<String>,
+    case_sensitive: bool,
+}
+
+impl EntitySentenceFilter {
+    /// Create a new filter that keeps sentences that mention any of the entities. Matching ignores case by default.
+    pub fn new(entities: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            entities: entities.into_iter().map(Into::into).collect(),
+            case_sensitive: false,
+        }
+    }
+
+    /// Set whether entities must match the case of the sentence.
+    pub fn with_case_sensitive(mut self, case_sensitive: bool) -> Self {
+        self.case_sensitive = case_sensitive;
+        self
+    }
+
+    fn mentions_entity(&self, sentence: &str) -> bool {
+        if self.case_sensitive {
+            self.entities
+                .iter()
+                .any(|entity| sentence.contains(entity.as_str()))
+        } else {
+            let sentence = sentence.to_lowercase();
+            self.entities
+                .iter()
+                .any(|entity| sentence.contains(&entity.to_lowercase()))
+        }
+    }
+}
+
+impl SentenceFilter for EntitySentenceFilter {
+    async fn keep(&self, _query: &str, sentences: &[&str]) -> anyhow::Result<Vec<bool>> {
+        Ok(sentences
+            .iter()
+            .map(|sentence| self.mentions_entity(sentence))
+            .collect())
+    }
+}
+
+/// A [`SentenceFilter`] that asks a language model if each sentence is relevant to the query.
+pub struct LlmSentenceFilter<'a, M: Model>
+where
+    <M::SyncModel as SyncModel>::Session: Sync + Send,
+{
+    model: &'a M,
+    task: Task<StructuredRunner<IndexParser<LiteralParser>>>,
+}
+
+impl<'a, M: Model> LlmSentenceFilter<'a, M>
+where
+    <M::SyncModel as SyncModel>::Session: Sync + Send,
+{
+    /// Create a new filter that checks sentences with the given model.
+    pub fn new(model: &'a M) -> Self {
+        let task = Task::builder(TASK_DESCRIPTION)
+            .with_constraints(IndexParser::new(vec![
+                LiteralParser::new("Yes"),
+                LiteralParser::new("No"),
+            ]))
+            .with_examples(EXAMPLES)
+            .build();
+        Self { model, task }
+    }
+}
+
+impl<M: Model> SentenceFilter for LlmSentenceFilter<'_, M>
+where
+    <M::SyncModel as SyncModel>::Session: Sync + Send,
+{
+    async fn keep(&self, query: &str, sentences: &[&str]) -> anyhow::Result<Vec<bool>> {
+        let mut keep = Vec::with_capacity(sentences.len());
+        for sentence in sentences {
+            let prompt = format!("Query: {query}\nSentence: {sentence}");
+            let (answer, _) = self.task.run(prompt, self.model).await?;
+            keep.push(answer == 0);
+        }
+        Ok(keep)
+    }
+}
+
+/// A sentence extracted from a chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtractedSentence {
+    /// The index of the chunk the sentence came from.
+    pub chunk_index: usize,
+    /// The byte range of the sentence in the document the chunk is from. This can be used to cite the source of the sentence.
+    pub byte_range: Range<usize>,
+    /// The text of the sentence.
+    pub text: String,
+}
+
+/// Context compressed down to the sentences a [`SentenceFilter`] kept.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtractedContext {
+    sentences: Vec<ExtractedSentence>,
+}
+
+impl ExtractedContext {
+    /// Get the sentences that were kept, in the order of the chunks they came from.
+    pub fn sentences(&self) -> &[ExtractedSentence] {
+        &self.sentences
+    }
+
+    /// Get the sentences that were kept.
+    pub fn into_sentences(self) -> Vec<ExtractedSentence> {
+        self.sentences
+    }
+
+    /// Check if no sentences were kept.
+    pub fn is_empty(&self) -> bool {
+        self.sentences.is_empty()
+    }
+
+    /// Get the compressed context as text. Sentences from the same chunk are joined with a space and chunks are separated by a blank line.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        let mut last_chunk = None;
+        for sentence in &self.sentences {
+            match last_chunk {
+                Some(chunk) if chunk == sentence.chunk_index => text.push(' '),
+                Some(_) => text.push_str("\n\n"),
+                None => {}
+            }
+            text.push_str(sentence.text.trim());
+            last_chunk = Some(sentence.chunk_index);
+        }
+        text
+    }
+}
+
+/// Extracts the relevant sentences from retrieved chunks to compress the context passed to a model.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm_language::prelude::*;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let llm = Llama::new_chat().await.unwrap();
+///     let documents = vec![Document::from_parts(
+///         "Eiffel Tower",
+///         "The Eiffel Tower is in Paris. It was finished in 1889. Paris has many cafes.",
+///     )];
+///
+///     let extractor = ContextExtractor::new(LlmSentenceFilter::new(&llm));
+///     let context = extractor
+///         .extract("When was the Eiffel Tower built?", &documents)
+///         .await
+///         .unwrap();
+///     println!("{}", context.text());
+/// }
+/// ```
+pub struct ContextExtractor<F> {
+    filter: F,
+}
+
+impl<F: SentenceFilter> ContextExtractor<F> {
+    /// Create a new context extractor that keeps the sentences the filter accepts.
+    pub fn new(filter: F) -> Self {
+        Self { filter }
+    }
+
+    /// Split each chunk into sentences and keep the sentences that are relevant to the query.
+    pub async fn extract<C: ContextChunk>(
+        &self,
+        query: &str,
+        chunks: &[C],
+    ) -> anyhow::Result<ExtractedContext> {
+        let candidates = split_chunks(chunks);
+
+        let texts = candidates
+            .iter()
+            .map(|sentence| sentence.text.as_str())
+            .collect::<Vec<_>>();
+        let keep = self.filter.keep(query, &texts).await?;
+        if keep.len() != candidates.len() {
+            anyhow::bail!(
+                "The sentence filter returned {} decisions for {} sentences",
+                keep.len(),
+                candidates.len()
+            );
+        }
+
+        Ok(ExtractedContext {
+            sentences: candidates
+                .into_iter()
+                .zip(keep)
+                .filter_map(|(sentence, keep)| keep.then_some(sentence))
+                .collect(),
+        })
+    }
+}
+
+/// Split chunks into sentences with byte ranges in the chunk's document.
+fn split_chunks<C: ContextChunk>(chunks: &[C]) -> Vec<ExtractedSentence> {
+    let chunker = SentenceChunker::default();
+    let mut sentences = Vec::new();
+    for (chunk_index, chunk) in chunks.iter().enumerate() {
+        let range = chunk.chunk_range();
+        let Some(text) = chunk.document_text().get(range.clone()) else {
+            continue;
+        };
+        for sentence in chunker.split_sentences(text) {
+            let sentence_text = &text[sentence.clone()];
+            if sentence_text.trim().is_empty() {
+                continue;
+            }
+            sentences.push(ExtractedSentence {
+                chunk_index,
+                byte_range: range.start + sentence.start..range.start + sentence.end,
+                text: sentence_text.to_string(),
+            });
+        }
+    }
+    sentences
+}
+
+#[tokio::test]
+async fn sentences_keep_document_byte_ranges() {
+    struct TestChunk(&'static str, Range<usize>);
+
+    impl ContextChunk for TestChunk {
+        fn document_text(&self) -> &str {
+            self.0
+        }
+
+        fn chunk_range(&self) -> Range<usize> {
+            self.1.clone()
+        }
+    }
+
+    let document = "Intro text. Kalosm runs models locally. It is written in Rust.";
+    let chunks = [TestChunk(document, 12..document.len())];
+
+    let extractor = ContextExtractor::new(EntitySentenceFilter::new(["kalosm"]));
+    let context = extractor.extract("What is Kalosm?", &chunks).await.unwrap();
+
+    assert_eq!(context.sentences().len(), 1);
+    let sentence = &context.sentences()[0];
+    assert_eq!(
+        document[sentence.byte_range.clone()].trim(),
+        "Kalosm runs models locally."
+    );
+    assert_eq!(context.text(), "Kalosm runs models locally.");
+}
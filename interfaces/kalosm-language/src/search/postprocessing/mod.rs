@@ -1,4 +1,4 @@
-// Context extraction strategies:
-// 1. Dump all sentences
-// 2. Dump all sentences that mention an entity
-// 3. Extract relevant sentences with an llm
+mod extract;
+pub use extract::*;
+mod rerank;
+pub use rerank::*;
@@ -0,0 +1,264 @@
+use std::borrow::Cow;
+use std::future::Future;
+
+use kalosm_language_model::{Model, SyncModel};
+use kalosm_sample::IntegerParser;
+use rbert::BertCrossEncoder;
+use tokenizers::Tokenizer;
+
+use crate::context::Document;
+use crate::prelude::{StructuredRunner, Task};
+
+const TASK_DESCRIPTION: &str = "You rate how relevant a passage is to a search query on a scale from 0 to 10. 0 means the passage has nothing to do with the query and 10 means the passage fully answers the query.";
+
+const EXAMPLES: [(&str, &str); 2] = [
+    (
+        "Query: How tall is the Eiffel Tower?\nPassage: The Eiffel Tower is 330 metres tall and is the tallest structure in Paris.",
+        "10",
+    ),
+    (
+        "Query: How tall is the Eiffel Tower?\nPassage: Paris is known for its cafes and museums.",
+        "1",
+    ),
+];
+
+/// Text that can be scored by a [`Reranker`].
+pub trait RerankText {
+    /// Get the text of the item that should be compared to the query.
+    fn rerank_text(&self) -> Cow<'_, str>;
+}
+
+impl RerankText for String {
+    fn rerank_text(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl RerankText for &str {
+    fn rerank_text(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl RerankText for Document {
+    fn rerank_text(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self.body())
+    }
+}
+
+impl<T: RerankText> RerankText for Reranked<T> {
+    fn rerank_text(&self) -> Cow<'_, str> {
+        self.item.rerank_text()
+    }
+}
+
+/// An item with the score a [`Reranker`] gave it.
+#[derive(Debug, Clone)]
+pub struct Reranked<T> {
+    /// How relevant the item is to the query. Higher is better. Scores are only comparable between items scored by the same reranker.
+    pub score: f32,
+    /// The item that was scored.
+    pub item: T,
+}
+
+/// A model that scores how relevant passages are to a query. Rerankers are slower than vector search, but much more precise, so they are used to reorder the top results of a search.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm_language::prelude::*;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let reranker = BertCrossEncoder::new().await.unwrap();
+///     let passages = vec![
+///         "Kalosm is a library for building local AI applications",
+///         "The quick brown fox jumps over the lazy dog",
+///     ];
+///     let reranked = reranker.rerank("What is Kalosm?", passages).await.unwrap();
+///     println!("{}", reranked[0].item);
+/// }
+/// ```
+pub trait Reranker {
+    /// Score how relevant each passage is to the query. Higher scores are more relevant.
+    fn score(
+        &self,
+        query: &str,
+        passages: &[&str],
+    ) -> impl Future<Output = anyhow::Result<Vec<f32>>> + Send;
+
+    /// Score every item with [`Reranker::score`] and sort them from most to least relevant.
+    fn rerank<T: RerankText + Send>(
+        &self,
+        query: &str,
+        items: Vec<T>,
+    ) -> impl Future<Output = anyhow::Result<Vec<Reranked<T>>>> + Send
+    where
+        Self: Sync,
+    {
+        async move {
+            let texts = items
+                .iter()
+                .map(|item| item.rerank_text().into_owned())
+                .collect::<Vec<_>>();
+            let passages = texts.iter().map(|text| text.as_str()).collect::<Vec<_>>();
+            let scores = self.score(query, &passages).await?;
+            if scores.len() != items.len() {
+                anyhow::bail!(
+                    "The reranker returned {} scores for {} items",
+                    scores.len(),
+                    items.len()
+                );
+            }
+
+            let mut reranked = items
+                .into_iter()
+                .zip(scores)
+                .map(|(item, score)| Reranked { score, item })
+                .collect::<Vec<_>>();
+            reranked.sort_by(|first, second| second.score.total_cmp(&first.score));
+            Ok(reranked)
+        }
+    }
+}
+
+impl Reranker for BertCrossEncoder {
+    async fn score(&self, query: &str, passages: &[&str]) -> anyhow::Result<Vec<f32>> {
+        let self_clone = self.clone();
+        let query = query.to_string();
+        let passages = passages
+            .iter()
+            .map(|passage| passage.to_string())
+            .collect::<Vec<_>>();
+        tokio::task::spawn_blocking(move || {
+            let passages = passages.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+            self_clone.score_batch(&query, &passages)
+        })
+        .await?
+    }
+}
+
+/// A [`Reranker`] that asks a language model to rate how relevant each passage is to the query.
+pub struct LlmReranker<'a, M: Model>
+where
+    <M::SyncModel as SyncModel>::Session: Sync + Send,
+{
+    model: &'a M,
+    task: Task<StructuredRunner<IntegerParser>>,
+}
+
+impl<'a, M: Model> LlmReranker<'a, M>
+where
+    <M::SyncModel as SyncModel>::Session: Sync + Send,
+{
+    /// Create a new reranker that scores passages with the given model.
+    pub fn new(model: &'a M) -> Self {
+        let task = Task::builder(TASK_DESCRIPTION)
+            .with_constraints(IntegerParser::new(0..=10))
+            .with_examples(EXAMPLES)
+            .build();
+        Self { model, task }
+    }
+}
+
+impl<M: Model> Reranker for LlmReranker<'_, M>
+where
+    <M::SyncModel as SyncModel>::Session: Sync + Send,
+{
+    async fn score(&self, query: &str, passages: &[&str]) -> anyhow::Result<Vec<f32>> {
+        let mut scores = Vec::with_capacity(passages.len());
+        for passage in passages {
+            let prompt = format!("Query: {query}\nPassage: {passage}");
+            let score = self.task.run(prompt, self.model).await?;
+            scores.push(score as f32);
+        }
+        Ok(scores)
+    }
+}
+
+/// Keep items from the start of the list until the total number of tokens in their text would exceed `max_tokens`. Use this after [`Reranker::rerank`] to fit the most relevant results into the context of a model.
+pub fn trim_to_token_budget<T: RerankText>(
+    items: impl IntoIterator<Item = T>,
+    tokenizer: &Tokenizer,
+    max_tokens: usize,
+) -> anyhow::Result<Vec<T>> {
+    let mut used_tokens = 0;
+    let mut trimmed = Vec::new();
+    for item in items {
+        let tokens = tokenizer
+            .encode(item.rerank_text().as_ref(), false)
+            .map_err(anyhow::Error::msg)?
+            .len();
+        if used_tokens + tokens > max_tokens {
+            break;
+        }
+        used_tokens += tokens;
+        trimmed.push(item);
+    }
+    Ok(trimmed)
+}
+
+#[cfg(test)]
+/// A reranker that scores passages by how many words they share with the query.
+struct WordOverlapReranker;
+
+#[cfg(test)]
+impl Reranker for WordOverlapReranker {
+    async fn score(&self, query: &str, passages: &[&str]) -> anyhow::Result<Vec<f32>> {
+        Ok(passages
+            .iter()
+            .map(|passage| {
+                passage
+                    .split_whitespace()
+                    .filter(|word| query.split_whitespace().any(|query| query == *word))
+                    .count() as f32
+            })
+            .collect())
+    }
+}
+
+#[tokio::test]
+async fn rerank_sorts_by_score() {
+    let passages = vec![
+        "the fox sleeps",
+        "a quick brown fox jumps",
+        "nothing to see here",
+        "the quick fox",
+    ];
+    let reranked = WordOverlapReranker
+        .rerank("the quick brown fox", passages)
+        .await
+        .unwrap();
+    let order: Vec<_> = reranked.iter().map(|reranked| reranked.item).collect();
+    assert_eq!(
+        order,
+        [
+            "a quick brown fox jumps",
+            "the quick fox",
+            "the fox sleeps",
+            "nothing to see here"
+        ]
+    );
+    let scores: Vec<_> = reranked.iter().map(|reranked| reranked.score).collect();
+    assert_eq!(scores, [3.0, 3.0, 2.0, 0.0]);
+}
+
+#[test]
+fn trim_keeps_items_that_fit_the_budget() {
+    // A tokenizer with one token for every word
+    let tokenizer = crate::test_util::word_level_tokenizer(&["[UNK]"]);
+
+    let items = ["one two three", "four five", "six seven eight", "nine"];
+    assert_eq!(
+        trim_to_token_budget(items, &tokenizer, 5).unwrap(),
+        ["one two three", "four five"]
+    );
+    // Trimming stops at the first item that doesn't fit, even if a later item would
+    assert_eq!(
+        trim_to_token_budget(items, &tokenizer, 7).unwrap(),
+        ["one two three", "four five"]
+    );
+    assert!(trim_to_token_budget(items, &tokenizer, 2)
+        .unwrap()
+        .is_empty());
+    assert_eq!(trim_to_token_budget(items, &tokenizer, 100).unwrap(), items);
+}
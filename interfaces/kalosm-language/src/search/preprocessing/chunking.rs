@@ -33,6 +33,20 @@ pub enum ChunkStrategy {
 }
 
 impl ChunkStrategy {
+    /// Chunk the body of a document into smaller ranges. Each section of the document is chunked separately so chunks never span two sections.
+    pub fn chunk_document(&self, document: &Document) -> Vec<Range<usize>> {
+        let body = document.body();
+        document
+            .segments()
+            .into_iter()
+            .flat_map(|segment| {
+                self.chunk_str(&body[segment.clone()])
+                    .into_iter()
+                    .map(move |range| range.start + segment.start..range.end + segment.start)
+            })
+            .collect()
+    }
+
     /// Chunk a string into smaller ranges.
     pub fn chunk_str(&self, string: &str) -> Vec<Range<usize>> {
         match self {
@@ -207,7 +221,7 @@ impl Chunker for ChunkStrategy {
         let mut chunks = Vec::new();
         let body = document.body();
         let mut documents = Vec::new();
-        let chunk_ranges = self.chunk_str(body);
+        let chunk_ranges = self.chunk_document(document);
         for byte_range in &chunk_ranges {
             documents.push(document.body()[byte_range.clone()].to_string());
         }
@@ -234,7 +248,7 @@ impl Chunker for ChunkStrategy {
         let mut chunk_strings = Vec::new();
         for document in documents {
             let body = document.body();
-            let chunk = self.chunk_str(body);
+            let chunk = self.chunk_document(document);
             for byte_range in &chunk {
                 chunk_strings.push(body[byte_range.clone()].to_string());
             }
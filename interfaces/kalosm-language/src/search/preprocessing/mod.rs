@@ -12,7 +12,8 @@
 // 2. Dump all sentences that mention an entity
 // 3. Extract relevant sentences with an llm
 
-use kalosm_language_model::Embedder;
+use futures_util::{StreamExt, TryStreamExt};
+use kalosm_language_model::{Embedder, MAX_CONCURRENT_EMBEDDINGS};
 
 use crate::context::Document;
 
@@ -28,6 +29,8 @@ mod sentence;
 pub use sentence::*;
 mod semantic;
 pub use semantic::*;
+mod recursive;
+pub use recursive::*;
 mod html;
 pub use html::*;
 
@@ -41,6 +44,8 @@ pub trait Chunker {
     ) -> impl std::future::Future<Output = anyhow::Result<Vec<Chunk<E::VectorSpace>>>> + Send;
 
     /// Chunk a batch of documents into embedded snippets.
+    ///
+    /// By default, up to [`MAX_CONCURRENT_EMBEDDINGS`] documents are chunked concurrently so embedders that batch concurrent requests (like [`kalosm_language_model::BatchedEmbedder`] or [`rbert::Bert`]) can embed chunks from different documents together.
     fn chunk_batch<'a, I, E: Embedder + Send>(
         &self,
         documents: I,
@@ -51,12 +56,91 @@ pub trait Chunker {
         I::IntoIter: Send,
         Self: Sync,
     {
-        async {
-            let mut chunks = Vec::new();
-            for document in documents {
-                chunks.push(self.chunk(document, embedder).await?);
-            }
-            Ok(chunks)
+        futures_util::stream::iter(documents)
+            .map(|document| self.chunk(document, embedder))
+            .buffered(MAX_CONCURRENT_EMBEDDINGS)
+            .try_collect()
+    }
+}
+
+#[cfg(test)]
+use kalosm_language_model::{Embedding, EmbeddingInput, UnknownVectorSpace};
+
+#[cfg(test)]
+/// An embedder that embeds every text at the same point, so the semantic chunker merges as much as it can.
+struct ConstantEmbedder;
+
+#[cfg(test)]
+impl Embedder for ConstantEmbedder {
+    type VectorSpace = UnknownVectorSpace;
+
+    fn embed_for(
+        &self,
+        _: EmbeddingInput,
+    ) -> futures_util::future::BoxFuture<'_, anyhow::Result<Embedding<Self::VectorSpace>>> {
+        Box::pin(async move { Ok(Embedding::from([1.0, 1.0])) })
+    }
+}
+
+#[tokio::test]
+async fn chunks_never_cross_sections() {
+    let text = "# Intro\n\nThe first section starts here. It has two sentences.\n\n# Details\n\nThe second section is separate. It also has two sentences.\n";
+    let mut document = Document::from_parts("Sections", text);
+    document.set_sections(crate::context::markdown_outline(text));
+    let segments = document.segments();
+    assert_eq!(segments.len(), 2);
+    let within_a_section = |range: &std::ops::Range<usize>| {
+        segments
+            .iter()
+            .any(|segment| segment.start <= range.start && range.end <= segment.end)
+    };
+
+    let strategies = [
+        ChunkStrategy::Paragraph {
+            paragraph_count: 3,
+            overlap: 1,
+        },
+        ChunkStrategy::Sentence {
+            sentence_count: 2,
+            overlap: 1,
+        },
+        ChunkStrategy::Words {
+            word_count: 4,
+            overlap: 1,
+        },
+    ];
+    for strategy in strategies {
+        let chunks = strategy.chunk_document(&document);
+        assert!(!chunks.is_empty());
+        for chunk in chunks {
+            assert!(within_a_section(&chunk), "{strategy:?}: {:?}", &text[chunk]);
         }
     }
+
+    let chunks = SentenceChunker::default()
+        .chunk(&document, &ConstantEmbedder)
+        .await
+        .unwrap();
+    assert!(chunks.len() >= 4);
+    for chunk in chunks {
+        assert!(
+            within_a_section(&chunk.byte_range),
+            "{:?}",
+            &text[chunk.byte_range]
+        );
+    }
+
+    // Every sentence has the same embedding, so only the section boundary keeps the chunks apart
+    let chunks = SemanticChunker::new()
+        .chunk(&document, &ConstantEmbedder)
+        .await
+        .unwrap();
+    assert_eq!(chunks.len(), 2);
+    for chunk in chunks {
+        assert!(
+            within_a_section(&chunk.byte_range),
+            "{:?}",
+            &text[chunk.byte_range]
+        );
+    }
 }
@@ -0,0 +1,404 @@
+use kalosm_language_model::Embedder;
+use std::future::Future;
+use std::ops::Range;
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+
+use super::{Chunker, SentenceChunker};
+use crate::{prelude::Document, search::Chunk};
+
+/// The places a [`RecursiveChunker`] can split text, from the coarsest to the finest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Boundary {
+    Heading,
+    Paragraph,
+    Line,
+    Sentence,
+    Word,
+}
+
+impl Boundary {
+    const ALL: [Self; 5] = [
+        Self::Heading,
+        Self::Paragraph,
+        Self::Line,
+        Self::Sentence,
+        Self::Word,
+    ];
+
+    /// Find the byte offsets in the text where a new piece starts. The start and end of the text are never included.
+    fn split_points(self, text: &str) -> Vec<usize> {
+        let bytes = text.as_bytes();
+        let points: Vec<usize> = match self {
+            Self::Heading => (1..bytes.len())
+                .filter(|&i| bytes[i - 1] == b'\n' && bytes[i] == b'#')
+                .collect(),
+            Self::Paragraph => (2..bytes.len())
+                .filter(|&i| bytes[i - 2] == b'\n' && bytes[i - 1] == b'\n' && bytes[i] != b'\n')
+                .collect(),
+            Self::Line => (1..bytes.len())
+                .filter(|&i| bytes[i - 1] == b'\n' && bytes[i] != b'\n')
+                .collect(),
+            Self::Sentence => SentenceChunker::default()
+                .split_sentences(text)
+                .into_iter()
+                .map(|range| range.start)
+                .collect(),
+            Self::Word => {
+                let mut after_whitespace = false;
+                text.char_indices()
+                    .filter_map(|(i, c)| {
+                        let point = after_whitespace && !c.is_whitespace();
+                        after_whitespace = c.is_whitespace();
+                        point.then_some(i)
+                    })
+                    .collect()
+            }
+        };
+        points
+            .into_iter()
+            .filter(|&point| point > 0 && point < text.len())
+            .collect()
+    }
+}
+
+/// A chunker that splits documents into chunks that fit in a token budget.
+///
+/// Text that is too long is split recursively at the coarsest boundary that exists: first at headings, then paragraphs, lines, sentences and finally words. The pieces are then merged back together into chunks as large as the budget allows. Tokens are counted with the tokenizer of the embedding model, so chunks are never silently truncated by the model.
+///
+/// Each section of the document is chunked separately, so chunks never span two sections.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm::language::*;
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let bert = Bert::new_for_search().await?;
+///     // Keep every chunk within the 512 tokens the model can embed at once
+///     let chunker = RecursiveChunker::new(bert.tokenizer(), 512).with_overlap(32);
+///     let document = Document::from_parts("Title", "A long document...");
+///     let chunks = chunker.chunk(&document, &bert).await?;
+///     println!("{:?}", chunks.len());
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct RecursiveChunker {
+    tokenizer: Arc<Tokenizer>,
+    max_tokens: usize,
+    overlap: usize,
+}
+
+impl RecursiveChunker {
+    /// Create a new chunker that creates chunks of at most `max_tokens` tokens measured with the tokenizer, including any special tokens the tokenizer adds.
+    pub fn new(tokenizer: impl Into<Arc<Tokenizer>>, max_tokens: usize) -> Self {
+        Self {
+            tokenizer: tokenizer.into(),
+            max_tokens,
+            overlap: 0,
+        }
+    }
+
+    /// Set the number of tokens at the end of each chunk that are repeated at the start of the next chunk. The overlap is made of whole pieces of text, so it may be shorter than this. (default: 0)
+    pub fn with_overlap(mut self, overlap: usize) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    /// Chunk the body of a document into ranges that fit in the token budget. Each section of the document is chunked separately.
+    pub fn chunk_document(&self, document: &Document) -> anyhow::Result<Vec<Range<usize>>> {
+        let body = document.body();
+        let mut chunks = Vec::new();
+        for segment in document.segments() {
+            for range in self.chunk_str(&body[segment.clone()])? {
+                chunks.push(range.start + segment.start..range.end + segment.start);
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Chunk a string into ranges that fit in the token budget.
+    pub fn chunk_str(&self, text: &str) -> anyhow::Result<Vec<Range<usize>>> {
+        // Leave room for the special tokens the tokenizer adds around the text
+        let special_tokens = self
+            .tokenizer
+            .encode("", true)
+            .map_err(anyhow::Error::msg)?
+            .len();
+        let budget = self.max_tokens.saturating_sub(special_tokens);
+        if budget == 0 {
+            anyhow::bail!(
+                "The token budget of {} is too small to fit any text",
+                self.max_tokens
+            );
+        }
+
+        let mut pieces = Vec::new();
+        self.split(text, 0..text.len(), &Boundary::ALL, budget, &mut pieces)?;
+        self.merge(text, &pieces, budget)
+    }
+
+    fn count_tokens(&self, text: &str) -> anyhow::Result<usize> {
+        Ok(self
+            .tokenizer
+            .encode(text, false)
+            .map_err(anyhow::Error::msg)?
+            .len())
+    }
+
+    /// Split a range of the text into pieces that each fit in the budget along with their token count.
+    fn split(
+        &self,
+        text: &str,
+        range: Range<usize>,
+        boundaries: &[Boundary],
+        budget: usize,
+        pieces: &mut Vec<(Range<usize>, usize)>,
+    ) -> anyhow::Result<()> {
+        let slice = &text[range.clone()];
+        if slice.trim().is_empty() {
+            return Ok(());
+        }
+        let tokens = self.count_tokens(slice)?;
+        if tokens <= budget {
+            pieces.push((range, tokens));
+            return Ok(());
+        }
+
+        let Some((boundary, finer)) = boundaries.split_first() else {
+            return self.split_tokens(text, range, budget, pieces);
+        };
+        let points = boundary.split_points(slice);
+        if points.is_empty() {
+            return self.split(text, range, finer, budget, pieces);
+        }
+        let mut start = range.start;
+        for point in points
+            .into_iter()
+            .map(|point| point + range.start)
+            .chain([range.end])
+        {
+            self.split(text, start..point, finer, budget, pieces)?;
+            start = point;
+        }
+        Ok(())
+    }
+
+    /// Split a range with no boundaries left (like a very long word) every `budget` tokens.
+    fn split_tokens(
+        &self,
+        text: &str,
+        range: Range<usize>,
+        budget: usize,
+        pieces: &mut Vec<(Range<usize>, usize)>,
+    ) -> anyhow::Result<()> {
+        let slice = &text[range.clone()];
+        let encoding = self
+            .tokenizer
+            .encode(slice, false)
+            .map_err(anyhow::Error::msg)?;
+        // The byte offsets where tokens start, along with the start and end of the slice
+        let mut points = encoding
+            .get_offsets()
+            .iter()
+            .map(|(offset, _)| *offset)
+            .filter(|&point| point < slice.len() && slice.is_char_boundary(point))
+            .chain([0, slice.len()])
+            .collect::<Vec<_>>();
+        points.sort_unstable();
+        points.dedup();
+
+        let mut first = 0;
+        while first + 1 < points.len() {
+            let mut last = (first + budget).min(points.len() - 1);
+            let mut tokens = self.count_tokens(&slice[points[first]..points[last]])?;
+            // Text can tokenize differently once it is cut, so move the end back until the piece fits
+            while tokens > budget && last > first + 1 {
+                last -= 1;
+                tokens = self.count_tokens(&slice[points[first]..points[last]])?;
+            }
+            pieces.push((
+                range.start + points[first]..range.start + points[last],
+                tokens,
+            ));
+            first = last;
+        }
+        Ok(())
+    }
+
+    /// Merge the pieces into chunks as large as the budget allows.
+    fn merge(
+        &self,
+        text: &str,
+        pieces: &[(Range<usize>, usize)],
+        budget: usize,
+    ) -> anyhow::Result<Vec<Range<usize>>> {
+        let mut chunks = Vec::new();
+        let mut first = 0;
+        while first < pieces.len() {
+            let start = pieces[first].0.start;
+            let mut last = first + 1;
+            let mut tokens = pieces[first].1;
+            // Chunks end before headings so each chunk stays in one section
+            while last < pieces.len()
+                && !starts_heading(text, pieces[last].0.start)
+                && tokens + pieces[last].1 <= budget
+            {
+                tokens += pieces[last].1;
+                last += 1;
+            }
+            // The pieces are counted separately, but tokens can merge or split where pieces meet. Check the whole chunk once and drop pieces from the end until it fits
+            while last > first + 1
+                && self.count_tokens(&text[start..pieces[last - 1].0.end])? > budget
+            {
+                last -= 1;
+            }
+            chunks.push(trim_range(text, start..pieces[last - 1].0.end));
+            if last == pieces.len() {
+                break;
+            }
+
+            // Start the next chunk with the pieces at the end of this chunk that fit in the overlap
+            let mut next = last;
+            let mut overlap_tokens = 0;
+            while next > first + 1 && !starts_heading(text, pieces[last].0.start) {
+                let tokens = overlap_tokens + pieces[next - 1].1;
+                if tokens > self.overlap || tokens + pieces[last].1 > budget {
+                    break;
+                }
+                overlap_tokens = tokens;
+                next -= 1;
+            }
+            first = next;
+        }
+        Ok(chunks)
+    }
+}
+
+/// Check if a markdown style `#` heading line starts at the byte offset.
+fn starts_heading(text: &str, offset: usize) -> bool {
+    text[offset..].starts_with('#') && (offset == 0 || text[..offset].ends_with('\n'))
+}
+
+/// Remove the whitespace at the start and end of a range.
+fn trim_range(text: &str, range: Range<usize>) -> Range<usize> {
+    let slice = &text[range.clone()];
+    let start = range.start + (slice.len() - slice.trim_start().len());
+    let end = range.end - (slice.len() - slice.trim_end().len());
+    start..end.max(start)
+}
+
+impl Chunker for RecursiveChunker {
+    fn chunk<E: Embedder + Send>(
+        &self,
+        document: &Document,
+        embedder: &E,
+    ) -> impl Future<Output = anyhow::Result<Vec<Chunk<E::VectorSpace>>>> + Send {
+        // Split the document before the future is created. The sentence rules are not Send
+        let ranges = self.chunk_document(document);
+        let body = document.body();
+
+        async move {
+            let ranges = ranges?;
+            let texts = ranges
+                .iter()
+                .map(|range| body[range.clone()].to_string())
+                .collect();
+            let embeddings = embedder.embed_vec(texts).await?;
+            Ok(ranges
+                .into_iter()
+                .zip(embeddings)
+                .map(|(byte_range, embedding)| Chunk {
+                    byte_range,
+                    embeddings: vec![embedding],
+                })
+                .collect())
+        }
+    }
+
+    fn chunk_batch<'a, I, E: Embedder + Send>(
+        &self,
+        documents: I,
+        embedder: &E,
+    ) -> impl Future<Output = anyhow::Result<Vec<Vec<Chunk<E::VectorSpace>>>>> + Send
+    where
+        I: IntoIterator<Item = &'a Document> + Send,
+        I::IntoIter: Send,
+        Self: Sync,
+    {
+        let ranges = documents
+            .into_iter()
+            .map(|document| Ok((document.body(), self.chunk_document(document)?)))
+            .collect::<anyhow::Result<Vec<_>>>();
+
+        async move {
+            let ranges = ranges?;
+            // Embed the chunks of every document in one batch
+            let texts = ranges
+                .iter()
+                .flat_map(|(body, ranges)| {
+                    ranges.iter().map(|range| body[range.clone()].to_string())
+                })
+                .collect();
+            let mut embeddings = embedder.embed_vec(texts).await?.into_iter();
+            Ok(ranges
+                .into_iter()
+                .map(|(_, ranges)| {
+                    ranges
+                        .into_iter()
+                        .zip(&mut embeddings)
+                        .map(|(byte_range, embedding)| Chunk {
+                            byte_range,
+                            embeddings: vec![embedding],
+                        })
+                        .collect()
+                })
+                .collect())
+        }
+    }
+}
+
+#[test]
+fn recursive_chunks_fit_the_token_budget() {
+    // A tokenizer with one token for every word or run of punctuation
+    let tokenizer = crate::test_util::word_level_tokenizer(&["[UNK]"]);
+
+    let text = "# Intro\n\nThe first paragraph is short.\n\n# Details\n\nThis paragraph is much longer than the budget. It has three sentences. Each one is short enough to fit on its own.\n\nThe last paragraph ends here.\n";
+    let chunker = RecursiveChunker::new(tokenizer.clone(), 10);
+    let chunks = chunker.chunk_str(text).unwrap();
+    for chunk in &chunks {
+        let chunk = &text[chunk.clone()];
+        assert!(
+            tokenizer.encode(chunk, false).unwrap().len() <= 10,
+            "{chunk}"
+        );
+        // Headings are the first boundary, so no chunk contains two headings
+        assert!(chunk.matches('#').count() <= 1, "{chunk}");
+    }
+    assert_eq!(
+        &text[chunks[0].clone()],
+        "# Intro\n\nThe first paragraph is short."
+    );
+    assert!(chunks
+        .iter()
+        .any(|chunk| text[chunk.clone()].starts_with("It has three sentences.")));
+
+    // With overlap, each chunk in the long paragraph starts before the previous one ends
+    let overlapping = RecursiveChunker::new(tokenizer.clone(), 10)
+        .with_overlap(5)
+        .chunk_str(text)
+        .unwrap();
+    assert!(overlapping
+        .windows(2)
+        .any(|pair| pair[1].start < pair[0].end));
+
+    // Text without any boundaries is split between tokens
+    let text = "a-b-c-d-e-f-g-h-i";
+    let chunks = RecursiveChunker::new(tokenizer, 4).chunk_str(text).unwrap();
+    let chunks = chunks
+        .iter()
+        .map(|chunk| &text[chunk.clone()])
+        .collect::<Vec<_>>();
+    assert_eq!(chunks, ["a-b-", "c-d-", "e-f-", "g-h-", "i"]);
+}
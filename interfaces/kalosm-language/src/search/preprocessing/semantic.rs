@@ -30,6 +30,8 @@ struct SemanticChunk<S: VectorSpace> {
 /// A chunker that tries to create chunks of wroughly the same size while grouping together chunks with a similar meaning.
 ///
 /// It starts by embedding the text and then merges chunks together while trying to create chunks with one coherent meaning without too many sentences.
+///
+/// Chunks are merged in rounds. Each round merges every pair of neighboring chunks that scores above the target score without sharing a chunk with a better pair, and embeds all of the merged chunks with one call to [`Embedder::embed_vec`].
 pub struct SemanticChunker {
     /// The score we are trying to achieve when merging chunks together. Once we reach this score, we stop merging chunks together.
     target_score: f32,
@@ -134,10 +136,19 @@ impl Chunker for SemanticChunker {
             overlap: 0,
         };
 
+        // Chunks in different sections of the document are never merged
+        let segments = document.segments();
+        let mut chunk_segments = Vec::new();
         let mut initial_chunks = Vec::new();
-        for chunk in chunker.chunk_str(text) {
+        for chunk in chunker.chunk_document(document) {
             let trimmed = text[chunk.clone()].trim();
             if !trimmed.is_empty() {
+                chunk_segments.push(
+                    segments
+                        .iter()
+                        .position(|segment| segment.contains(&chunk.start))
+                        .unwrap_or_default(),
+                );
                 current_chunks.push(chunk);
                 initial_chunks.push(trimmed.to_string());
             }
@@ -149,78 +160,78 @@ impl Chunker for SemanticChunker {
 
         // Find the chain of distances between sequential embeddings
         for (i, chunk) in current_chunks.iter().enumerate() {
-            if i == current_chunks.len() - 1 {
-                chunks.push(SemanticChunk {
-                    range: chunk.clone(),
-                    sentences: 1,
-                    embedding: embeddings[i].clone(),
-                    distance_to_next: None,
-                });
-                break;
-            }
             let first = &embeddings[i];
-            let second = &embeddings[i + 1];
-            let distance_to_next = first.cosine_similarity(second);
+            let distance_to_next = embeddings
+                .get(i + 1)
+                .filter(|_| chunk_segments[i] == chunk_segments[i + 1])
+                .map(|second| first.cosine_similarity(second));
             let chunk = SemanticChunk {
                 range: chunk.clone(),
                 sentences: 1,
                 embedding: first.clone(),
-                distance_to_next: Some(distance_to_next),
+                distance_to_next,
             };
             chunks.push(chunk);
         }
 
-        // Now loop until we have the right number of chunks merging the two closest chunks
-        // Find the lowest distance chunk
-        while let Some((index, first_chunk)) = chunks
-            .iter()
-            .enumerate()
-            .filter(|(_, c)| c.distance_to_next.is_some())
-            .max_by(|(index, c1), (index2, c2)| {
-                // Score higher if the similarity is high or if the text size of both is small
-                let c1_score = self.score_merge(c1, &chunks[index + 1]);
-                let c2_score = self.score_merge(c2, &chunks[index2 + 1]);
-
-                c1_score.partial_cmp(&c2_score).unwrap()
-            })
-        {
-            let second_chunk = &chunks[index + 1];
-
-            let highest_similarity = self.score_merge(first_chunk, second_chunk);
-            if highest_similarity < self.target_score {
+        // Merge chunks in rounds. Each round picks the pairs of neighboring chunks that score above the target score,
+        // starting with the highest score and skipping pairs that share a chunk with a pair that was already picked,
+        // and embeds all of the merged chunks together
+        loop {
+            let mut candidates = chunks
+                .iter()
+                .enumerate()
+                .filter(|(_, chunk)| chunk.distance_to_next.is_some())
+                .map(|(index, chunk)| (index, self.score_merge(chunk, &chunks[index + 1])))
+                .filter(|(_, score)| *score >= self.target_score)
+                .collect::<Vec<_>>();
+            if candidates.is_empty() {
                 break;
             }
-
-            // Merge the two chunks
-            let range = first_chunk.range.start..second_chunk.range.end;
-            let sentences = first_chunk.sentences + second_chunk.sentences;
-
-            let new_text = text[range.clone()].trim();
-            let embedding = embedder.embed(new_text).await?;
-
-            // Calculate the distance to the next chunk
-            let distance_to_next = chunks
-                .get(index + 2)
-                .map(|chunk_after_merge| embedding.cosine_similarity(&chunk_after_merge.embedding));
-
-            // Recalculate the distance to the previous chunk
-            if let Some(prev_chunk) = index.checked_sub(1).and_then(|index| chunks.get_mut(index)) {
-                let distance_to_prev = prev_chunk.embedding.cosine_similarity(&embedding);
-                prev_chunk.distance_to_next = Some(distance_to_prev);
+            candidates.sort_by(|(_, first), (_, second)| second.total_cmp(first));
+
+            let mut merging = vec![false; chunks.len()];
+            let mut merges = Vec::new();
+            for (index, _) in candidates {
+                if !merging[index] && !merging[index + 1] {
+                    merging[index] = true;
+                    merging[index + 1] = true;
+                    merges.push(index);
+                }
+            }
+            merges.sort_unstable();
+
+            let merged_text = merges
+                .iter()
+                .map(|&index| {
+                    let range = chunks[index].range.start..chunks[index + 1].range.end;
+                    text[range].trim().to_string()
+                })
+                .collect();
+            let embeddings = embedder.embed_vec(merged_text).await?;
+
+            // Merge from the back so the indexes of the earlier merges stay the same
+            for (index, embedding) in merges.into_iter().zip(embeddings).rev() {
+                let second_chunk = chunks.remove(index + 1);
+                let first_chunk = &mut chunks[index];
+                first_chunk.range.end = second_chunk.range.end;
+                first_chunk.sentences += second_chunk.sentences;
+                first_chunk.embedding = embedding;
+                first_chunk.distance_to_next = second_chunk.distance_to_next;
             }
 
-            let new_chunk = SemanticChunk {
-                range,
-                sentences,
-                embedding,
-                distance_to_next,
-            };
-
-            // Remove the last chunk
-            chunks.remove(index + 1);
-
-            // Add our merged chunk to the chunks
-            chunks[index] = new_chunk;
+            // Recalculate the distance to the next chunk if it is in the same section
+            let distances = chunks
+                .windows(2)
+                .map(|pair| {
+                    pair[0]
+                        .distance_to_next
+                        .map(|_| pair[0].embedding.cosine_similarity(&pair[1].embedding))
+                })
+                .collect::<Vec<_>>();
+            for (chunk, distance_to_next) in chunks.iter_mut().zip(distances) {
+                chunk.distance_to_next = distance_to_next;
+            }
         }
 
         let mut final_chunks = Vec::new();
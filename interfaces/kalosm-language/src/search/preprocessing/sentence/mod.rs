@@ -77,7 +77,16 @@ impl Chunker for SentenceChunker {
         // Split the document into sentences. We first just collect the sentences as strings and byte ranges
         let mut initial_chunks = Vec::new();
         let body = document.body();
-        let ranges = self.split_sentences(document.body());
+        let ranges = document
+            .segments()
+            .into_iter()
+            .flat_map(|segment| {
+                self.split_sentences(&body[segment.clone()])
+                    .into_iter()
+                    .map(move |range| range.start + segment.start..range.end + segment.start)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
         for chunk in &ranges {
             initial_chunks.push(body[chunk.clone()].to_string());
         }
@@ -6,8 +6,10 @@ use kalosm_language_model::ChatMarkers;
 use kalosm_language_model::Session;
 use kalosm_language_model::StructureParserResult;
 use kalosm_language_model::{GenerationParameters, Model, ModelExt, SyncModel, SyncModelExt};
+use kalosm_language_model::{SampleSelection, SelectedSample};
 use kalosm_sample::CreateParserState;
 use kalosm_sample::Parse;
+use kalosm_sample::ParseStatus;
 use kalosm_sample::Schema;
 use kalosm_sample::SendCreateParserState;
 use kalosm_streams::text_stream::ChannelTextStream;
@@ -211,6 +213,95 @@ impl<P: TaskBuilderReturn + Send + Sync + 'static> TaskBuilder<P> {
     }
 }
 
+impl<P: CreateParserState> TaskBuilder<P>
+where
+    P::Output: Parse,
+{
+    /// Add an example with a typed output to the task. The output is rendered with [`Parse::to_parse_string`] in the same format the constraints parse, so the example matches the text the model will generate.
+    ///
+    /// Returns an error if the output can't be rendered or the rendered output cannot be parsed by the constraints of the task.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// # use kalosm::language::*;
+    /// #[derive(Parse, Schema, Clone, Debug)]
+    /// struct Person {
+    ///     name: String,
+    ///     age: u32,
+    /// }
+    ///
+    /// # fn main() -> anyhow::Result<()> {
+    /// let task = Task::builder_for::<Person>("You extract people from text")
+    ///     .with_typed_example(
+    ///         "John is thirty years old.",
+    ///         Person {
+    ///             name: "John".to_string(),
+    ///             age: 30,
+    ///         },
+    ///     )?
+    ///     .build();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_typed_example(
+        mut self,
+        input: impl Into<String>,
+        output: P::Output,
+    ) -> Result<Self> {
+        let input = input.into();
+        let output = render_example_output(&self.constraints, &output)?;
+        self.examples.push(TaskExample { input, output });
+        Ok(self)
+    }
+
+    /// Add multiple examples with typed outputs to the task. See [`TaskBuilder::with_typed_example`] for more details.
+    pub fn with_typed_examples(
+        mut self,
+        examples: impl IntoIterator<Item = (impl Into<String>, P::Output)>,
+    ) -> Result<Self> {
+        for (input, output) in examples {
+            self = self.with_typed_example(input, output)?;
+        }
+        Ok(self)
+    }
+}
+
+/// Render the output of an example in the format the parser expects and check that the parser accepts it.
+fn render_example_output<P: CreateParserState>(parser: &P, output: &P::Output) -> Result<String>
+where
+    P::Output: Parse,
+{
+    let Some(rendered) = output.to_parse_string() else {
+        anyhow::bail!(
+            "The example output can't be rendered because {} does not implement `Parse::to_parse_string`",
+            std::any::type_name::<P::Output>()
+        );
+    };
+
+    let state = parser.create_parser_state();
+    match parser.parse(&state, rendered.as_bytes()) {
+        Ok(ParseStatus::Finished { remaining, .. }) if remaining.is_empty() => Ok(rendered),
+        Ok(ParseStatus::Finished { remaining, .. }) => anyhow::bail!(
+            "The example output {rendered:?} does not fit the constraints of the task: the constraints finished before {:?}",
+            String::from_utf8_lossy(remaining)
+        ),
+        // Some parsers (like numbers) can't know they are finished until they see the next character
+        Ok(ParseStatus::Incomplete { required_next, .. }) => {
+            let terminated = rendered.clone() + "\n";
+            match parser.parse(&state, terminated.as_bytes()) {
+                Ok(ParseStatus::Finished { remaining, .. }) if remaining == b"\n" => Ok(rendered),
+                _ => anyhow::bail!(
+                    "The example output {rendered:?} does not fit the constraints of the task: the constraints expect more text (next: {required_next:?})"
+                ),
+            }
+        }
+        Err(err) => anyhow::bail!(
+            "The example output {rendered:?} does not fit the constraints of the task: {}",
+            &*err
+        ),
+    }
+}
+
 /// A trait for returning the output of a [`TaskBuilder`].
 pub trait TaskBuilderReturn
 where
@@ -423,6 +514,74 @@ where
     }
 }
 
+impl<P> StructuredRunner<P>
+where
+    P: SendCreateParserState + Sync + 'static,
+{
+    fn run_samples<M: Model>(
+        &self,
+        input: String,
+        model: &M,
+        samples: usize,
+        selection: SampleSelection<P::Output>,
+    ) -> impl std::future::Future<Output = Result<SelectedSample<P::Output>>> + Send
+    where
+        <M::SyncModel as SyncModel>::Session: Send + Sync,
+    {
+        let (result_tx, result_rx) = oneshot::channel();
+        let arc_parser = self.parser.clone();
+        let sampler = self.sampler.clone();
+        let sessions = self.sessions.clone();
+        let chat_markers = model.chat_markers();
+
+        let started = model.run_sync(move |model| {
+            Box::pin(async move {
+                let mut sessions_write = sessions.sessions.write().unwrap();
+                let session_entry: &mut TaskSessionEntry<<M::SyncModel as SyncModel>::Session> = {
+                    sessions_write
+                        .entry(TypeId::of::<M>())
+                        .or_insert_with(|| {
+                            Box::new(
+                                TaskSessionEntry::<<M::SyncModel as SyncModel>::Session>::new(
+                                    chat_markers,
+                                    sessions.system_prompt.clone(),
+                                    &sessions.examples,
+                                ),
+                            )
+                        })
+                        .downcast_mut()
+                        .unwrap()
+                };
+
+                let result = session_entry.create_session(model).and_then(|mut session| {
+                    let state = arc_parser.create_parser_state();
+                    let prompt = session_entry.task_prompt(&input);
+                    let samples = model.generate_structured_samples(
+                        &mut session,
+                        &prompt,
+                        arc_parser,
+                        state,
+                        sampler,
+                        samples,
+                        Some(4),
+                    )?;
+                    selection
+                        .select(samples)
+                        .ok_or_else(|| anyhow::anyhow!("At least one sample is required"))
+                });
+                if result_tx.send(result).is_err() {
+                    tracing::error!("Failed to send sampled result");
+                }
+            })
+        });
+
+        async move {
+            started?;
+            result_rx.await?
+        }
+    }
+}
+
 // This is essentially a manual implementation of a closure so you can name the type
 /// Something that can run a task.
 pub trait TaskRunner {
@@ -501,3 +660,210 @@ impl<R: TaskRunner> Task<R> {
         self.runner.run(message, model)
     }
 }
+
+impl<P> Task<StructuredRunner<P>>
+where
+    P: SendCreateParserState + Sync + 'static,
+{
+    /// Run the task `samples` times and return the output the model thinks is most likely.
+    ///
+    /// The task prompt is only fed into the model once. The model's session must support [`Session::try_clone`].
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm_language::prelude::*;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let llm = Llama::new_chat().await.unwrap();
+    ///     let task = Task::builder("You classify the sentiment of a sentence.")
+    ///         .with_constraints(LiteralParser::new("positive").or(LiteralParser::new("negative")))
+    ///         .build();
+    ///
+    ///     let selected = task.run_best_of("I love this!", &llm, 5).await.unwrap();
+    ///     println!("{:?}", selected.winner());
+    /// }
+    /// ```
+    pub fn run_best_of<M>(
+        &self,
+        message: impl Into<String>,
+        model: &M,
+        samples: usize,
+    ) -> impl std::future::Future<Output = Result<SelectedSample<P::Output>>> + Send
+    where
+        M: Model,
+        <M::SyncModel as SyncModel>::Session: Send + Sync,
+    {
+        let message = message.into().trim().to_string();
+        self.runner
+            .run_samples(message, model, samples, SampleSelection::most_likely())
+    }
+
+    /// Run the task `samples` times and return the answer most samples agree on. `agree` returns true if two outputs are the same answer.
+    ///
+    /// The task prompt is only fed into the model once. The model's session must support [`Session::try_clone`].
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm_language::prelude::*;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let llm = Llama::new_chat().await.unwrap();
+    ///     let task = Task::builder_for::<u32>("You answer math questions with a single number.").build();
+    ///
+    ///     let selected = task
+    ///         .run_self_consistency("What is 12 * 12?", &llm, 5, |a, b| a == b)
+    ///         .await
+    ///         .unwrap();
+    ///     println!("{} ({}% agreement)", selected.winner(), selected.agreement() * 100.);
+    /// }
+    /// ```
+    pub fn run_self_consistency<M>(
+        &self,
+        message: impl Into<String>,
+        model: &M,
+        samples: usize,
+        agree: impl Fn(&P::Output, &P::Output) -> bool + Send + Sync + 'static,
+    ) -> impl std::future::Future<Output = Result<SelectedSample<P::Output>>> + Send
+    where
+        M: Model,
+        <M::SyncModel as SyncModel>::Session: Send + Sync,
+    {
+        let message = message.into().trim().to_string();
+        self.runner
+            .run_samples(message, model, samples, SampleSelection::vote(agree))
+    }
+}
+
+#[test]
+fn typed_examples_match_the_parser_format() {
+    use kalosm_sample::StringParser;
+
+    #[derive(Parse, Schema, Clone)]
+    struct Person {
+        name: String,
+        age: u32,
+        hobbies: Vec<String>,
+    }
+
+    let builder = Task::builder_for::<Person>("Extract the person from the text")
+        .with_typed_example(
+            "John is 30 and likes to read and swim",
+            Person {
+                name: "John".to_string(),
+                age: 30,
+                hobbies: vec!["reading".to_string(), "swimming".to_string()],
+            },
+        )
+        .unwrap();
+    assert_eq!(
+        builder.examples[0].output,
+        r#"{ "name": "John", "age": 30, "hobbies": ["reading", "swimming"] }"#
+    );
+
+    // Outputs that the constraints can't produce are rejected
+    #[derive(Parse, Schema, Clone)]
+    struct ShortName {
+        #[parse(with = StringParser::new(1..=3))]
+        name: String,
+    }
+
+    let builder = Task::builder_for::<ShortName>("Extract the name from the text");
+    assert!(builder
+        .with_typed_example(
+            "John is here",
+            ShortName {
+                name: "John".to_string()
+            }
+        )
+        .is_err());
+}
+
+#[test]
+fn typed_examples_use_parse_renames() {
+    #[derive(Parse, Schema, Clone)]
+    struct Person {
+        #[parse(rename = "full name")]
+        name: String,
+        #[parse(range = 0..=150)]
+        age: u32,
+    }
+
+    let builder = Task::builder_for::<Person>("Extract the person from the text")
+        .with_typed_example(
+            "John Smith is 30",
+            Person {
+                name: "John Smith".to_string(),
+                age: 30,
+            },
+        )
+        .unwrap();
+    assert_eq!(
+        builder.examples[0].output,
+        r#"{ "full name": "John Smith", "age": 30 }"#
+    );
+
+    #[derive(Parse, Schema, Clone)]
+    enum Color {
+        #[parse(rename = "red")]
+        Red,
+        Blue,
+    }
+
+    let builder = Task::builder_for::<Color>("Pick the color from the text")
+        .with_typed_examples([("The sky is blue", Color::Blue), ("A rose", Color::Red)])
+        .unwrap();
+    assert_eq!(builder.examples[0].output, r#""Blue""#);
+    assert_eq!(builder.examples[1].output, r#""red""#);
+}
+
+#[test]
+fn typed_examples_use_enum_tags() {
+    #[derive(Parse, Schema, Clone)]
+    enum Action {
+        Search {
+            #[parse(rename = "search query")]
+            query: String,
+        },
+        Open(String),
+        Quit,
+    }
+
+    let builder = Task::builder_for::<Action>("Pick the next action")
+        .with_typed_examples([
+            (
+                "Find the weather",
+                Action::Search {
+                    query: "weather".to_string(),
+                },
+            ),
+            ("Open the \"notes\" file", Action::Open("notes".to_string())),
+            ("Stop", Action::Quit),
+        ])
+        .unwrap();
+    assert_eq!(
+        builder.examples[0].output,
+        r#"{ "type": "Search", "data": { "search query": "weather" } }"#
+    );
+    assert_eq!(
+        builder.examples[1].output,
+        r#"{ "type": "Open", "data": "notes" }"#
+    );
+    assert_eq!(builder.examples[2].output, r#"{ "type": "Quit" }"#);
+
+    #[derive(Parse, Schema, Clone)]
+    #[parse(tag = "action", content = "arguments")]
+    enum Command {
+        #[parse(rename = "move")]
+        Move { steps: i32 },
+    }
+
+    let builder = Task::builder_for::<Command>("Pick the next command")
+        .with_typed_example("Go back two steps", Command::Move { steps: -2 })
+        .unwrap();
+    assert_eq!(
+        builder.examples[0].output,
+        r#"{ "action": "move", "arguments": { "steps": -2 } }"#
+    );
+}
@@ -225,6 +225,12 @@ impl Tool for CalculatorTool {
         format!("Evaluate a mathematical expression (made only of numbers and one of the prebuilt math functions). Available functions: sqrt, abs, exp, ln, sin, cos, tan, asin, acos, atan, atan2, sinh, cosh, tanh, asinh, acosh, atanh, floor, ceil, round, signum, pi, e\nUse tool with:\nAction: Calculator\nAction Input: the expression\nExample:\nQuestion: What is 2 + 2?\nThought: I should calculate 2 + 2.\nAction: Calculator\n{input_prompt}2 + 2\nObservation: 4\nThought: I now know that 2 + 2 is 4.\nFinal Answer: 4")
     }
 
+    fn validate(&self, expr: &Self::Input) -> Result<(), String> {
+        expr.parse::<meval::Expr>()
+            .map(|_| ())
+            .map_err(|e| format!("Input was invalid, try again making sure to only use numbers and one of the prebuilt math functions. {e}"))
+    }
+
     async fn run<'a>(&'a mut self, expr: &'a Self::Input) -> String {
         match meval::eval_str(expr){
                 Ok(result) => result.to_string(),
@@ -40,6 +40,11 @@ pub trait Tool {
     /// A description of the tool
     fn description(&self) -> String;
 
+    /// Check that the input is valid before the tool runs. If the input is invalid, the error is shown to the model as an observation and the model is asked to try again.
+    fn validate(&self, _args: &Self::Input) -> Result<(), String> {
+        Ok(())
+    }
+
     /// Run the tool with the given arguments
     fn run<'a>(&'a mut self, args: &'a Self::Input) -> impl Future<Output = String> + Send + 'a;
 }
@@ -72,6 +77,11 @@ impl<T: Tool + Send + Sync + 'static> DynToolExt for T {
                 let this: &T = tool.downcast_ref().unwrap();
                 this.description()
             },
+            validate: |tool, args| {
+                let this: &T = tool.downcast_ref().unwrap();
+                let args: &<Self as Tool>::Input = args.downcast_ref().unwrap();
+                this.validate(args)
+            },
             run: |tool, args| {
                 let this: &mut T = tool.downcast_mut().unwrap();
                 let args: &<Self as Tool>::Input = args.downcast_ref().unwrap();
@@ -88,6 +98,7 @@ pub struct BoxedTool {
     name: fn(&dyn Any) -> String,
     input_prompt: fn(&dyn Any) -> String,
     description: fn(&dyn Any) -> String,
+    validate: fn(&dyn Any, &Arc<dyn Any + Send + Sync>) -> Result<(), String>,
     run: for<'a> fn(
         &'a mut dyn Any,
         &'a Arc<dyn Any + Send + Sync>,
@@ -115,15 +126,27 @@ impl Tool for BoxedTool {
     fn description(&self) -> String {
         (self.description)(&self.tool)
     }
+    fn validate(&self, args: &Self::Input) -> Result<(), String> {
+        (self.validate)(&self.tool, args)
+    }
     fn run<'a>(&'a mut self, args: &'a Self::Input) -> impl Future<Output = String> + Send + 'a {
         (self.run)(&mut self.tool, args)
     }
 }
 
+type ToolValidator = Box<dyn Fn(&(dyn Any + Send + Sync)) -> Result<(), String> + Send + Sync>;
+
 /// A set of tools that can be used by a [`kalosm_language_model::Model`]
-#[derive(Default)]
 pub struct ToolManager {
     tools: Vec<BoxedTool>,
+    validators: Vec<Option<ToolValidator>>,
+    max_repair_attempts: usize,
+}
+
+impl Default for ToolManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl std::fmt::Debug for ToolManager {
@@ -154,7 +177,19 @@ pub(crate) enum Action {
 impl ToolManager {
     /// Create a new tool empty manager
     pub fn new() -> Self {
-        Self { tools: Vec::new() }
+        Self {
+            tools: Vec::new(),
+            validators: Vec::new(),
+            max_repair_attempts: 3,
+        }
+    }
+
+    /// Set the number of times the model can retry a step after it produces an action that cannot be parsed or a tool input that fails validation. Defaults to 3.
+    ///
+    /// Each failure is shown to the model as an observation before it retries.
+    pub fn with_max_repair_attempts(mut self, max_repair_attempts: usize) -> Self {
+        self.max_repair_attempts = max_repair_attempts;
+        self
     }
 
     /// Add a tool to the manager
@@ -172,6 +207,60 @@ impl ToolManager {
         T: Tool + Send + Sync + 'static,
     {
         self.tools.push(tool.boxed());
+        self.validators.push(None);
+    }
+
+    /// Add a tool to the manager with a custom validator for the tool's input. The validator runs after [`Tool::validate`].
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm_language::prelude::*;
+    ///
+    /// let tools = ToolManager::new().with_validated_tool(CalculatorTool, |expr: &String| {
+    ///     if expr.len() > 100 {
+    ///         Err("The expression must be shorter than 100 characters".to_string())
+    ///     } else {
+    ///         Ok(())
+    ///     }
+    /// });
+    /// ```
+    pub fn with_validated_tool<T>(
+        mut self,
+        tool: T,
+        validator: impl Fn(&T::Input) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self
+    where
+        T: Tool + Send + Sync + 'static,
+    {
+        self.add_validated_tool(tool, validator);
+        self
+    }
+
+    /// Add a tool to the manager with a custom validator for the tool's input. The validator runs after [`Tool::validate`].
+    pub fn add_validated_tool<T>(
+        &mut self,
+        tool: T,
+        validator: impl Fn(&T::Input) -> Result<(), String> + Send + Sync + 'static,
+    ) where
+        T: Tool + Send + Sync + 'static,
+    {
+        self.tools.push(tool.boxed());
+        self.validators.push(Some(Box::new(move |input| {
+            validator(input.downcast_ref().unwrap())
+        })));
+    }
+
+    /// Check an input for the tool at the given index with the tool's validators
+    fn validate_input(
+        &self,
+        index: usize,
+        input: &Arc<dyn Any + Send + Sync>,
+    ) -> Result<(), String> {
+        self.tools[index].validate(input)?;
+        if let Some(validator) = &self.validators[index] {
+            validator(&**input)?;
+        }
+        Ok(())
     }
 
     /// Get the tools in the manager
@@ -279,6 +368,8 @@ Question: {question}
     }
 
     /// Run one step of the tool manager
+    ///
+    /// If the model produces an action that cannot be parsed or a tool input that fails validation, the error is added to the session as an observation and the model retries the step up to [`ToolManager::with_max_repair_attempts`] times.
     pub async fn run_step<M: SyncModel>(
         &mut self,
         prompt: &str,
@@ -289,16 +380,46 @@ Question: {question}
         let mut new_text = String::new();
 
         let constraints = self.any_action_constraint();
-        let validator_state = constraints.create_parser_state();
-        let result = llm.generate_structured(
-            llm_session,
-            prompt,
-            constraints,
-            validator_state,
-            Arc::new(Mutex::new(GenerationParameters::default().sampler())),
-            &mut add_token,
-            Some(4),
-        )?;
+        let mut prompt = prompt.to_string();
+        let mut repair_attempts = 0;
+        let result = loop {
+            let mut stopped_by_callback = false;
+            let result = llm.generate_structured(
+                llm_session,
+                &prompt,
+                constraints.clone(),
+                constraints.create_parser_state(),
+                Arc::new(Mutex::new(GenerationParameters::default().sampler())),
+                |token| {
+                    let result = add_token(token);
+                    stopped_by_callback |= result.is_err();
+                    result
+                },
+                Some(4),
+            );
+
+            let error = match result {
+                Ok(Action::Tool { index, input }) => match self.validate_input(index, &input) {
+                    Ok(()) => break Action::Tool { index, input },
+                    Err(err) => format!("Invalid input for {}: {err}", self.tools[index].name()),
+                },
+                Ok(action) => break action,
+                Err(err) if stopped_by_callback => return Err(err),
+                Err(err) => format!("The action could not be parsed: {err}"),
+            };
+
+            if repair_attempts >= self.max_repair_attempts {
+                anyhow::bail!(
+                    "The model failed to produce a valid action after {} attempts: {error}",
+                    repair_attempts + 1
+                );
+            }
+            repair_attempts += 1;
+            tracing::warn!("Retrying tool step after invalid action: {error}");
+
+            prompt = format!("\nObservation: {error}\n");
+            add_token(prompt.clone())?;
+        };
 
         Ok(match result {
             Action::Thought(thought) => {
@@ -528,6 +649,26 @@ impl Parser for OneLine {
     }
 }
 
+#[test]
+fn validators_reject_invalid_tool_inputs() {
+    let tools = ToolManager::new().with_validated_tool(CalculatorTool, |expr: &String| {
+        if expr.contains("sqrt") {
+            Err("sqrt is not allowed".to_string())
+        } else {
+            Ok(())
+        }
+    });
+
+    let input = |expr: &str| Arc::new(expr.to_string()) as Arc<dyn Any + Send + Sync>;
+    assert!(tools.validate_input(0, &input("2 + 2")).is_ok());
+    assert_eq!(
+        tools.validate_input(0, &input("sqrt(4)")),
+        Err("sqrt is not allowed".to_string())
+    );
+    // The calculator's own validation runs before the custom validator
+    assert!(tools.validate_input(0, &input("2 +")).is_err());
+}
+
 macro_rules! impl_from_tool_tuple {
     ($($name:ident),*) => {
         #[allow(non_snake_case)]
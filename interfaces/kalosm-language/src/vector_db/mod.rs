@@ -0,0 +1,738 @@
+//! A vector database that can be used to store embeddings and search for similar embeddings.
+
+use std::fmt::Debug;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicUsize;
+use std::sync::Mutex;
+
+use arroy::distances::{Angular, DotProduct, Euclidean, Manhattan};
+use arroy::{Database as ArroyDatabase, Reader, Writer};
+use candle_core::Tensor;
+use heed::EnvOpenOptions;
+use kalosm_language_model::*;
+use kalosm_llama::accelerated_device_if_available;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+pub use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+
+mod quantized;
+pub use quantized::*;
+
+/// A vector database that can be used to store embeddings and search for similar embeddings.
+///
+/// It uses an in memory database with fast lookups for nearest neighbors and points within a certain distance.
+///
+/// # Example
+///
+/// ```rust, no_run
+/// # use kalosm_language::prelude::*;
+/// # use kalosm_language_model::*;
+/// # use rbert::*;
+/// # use std::collections::HashMap;
+/// # #[tokio::main]
+/// # async fn main() {
+/// // Create a good default Bert model for search
+/// let bert = Bert::new_for_search().await.unwrap();
+/// let sentences = [
+///     "Kalosm can be used to build local AI applications",
+///     "With private LLMs data never leaves your computer",
+///     "The quick brown fox jumps over the lazy dog",
+/// ];
+/// // Embed sentences into the vector space
+/// let embeddings = bert.embed_batch(sentences).await.unwrap();
+/// println!("embeddings {:?}", embeddings);
+///
+/// // Create a vector database from the embeddings along with a map between the embedding ids and the sentences
+/// let db = VectorDB::new().unwrap();
+/// let embeddings = db.add_embeddings(embeddings).unwrap();
+/// let embedding_id_to_sentence: HashMap<EmbeddingId, &str> =
+///     HashMap::from_iter(embeddings.into_iter().zip(sentences));
+///
+/// // Find the closest sentence to "What is Kalosm?"
+/// let query = "What is Kalosm?";
+/// // Embed the query into the vector space. We use `embed_query` instead of `embed` because some models embed queries differently than normal text.
+/// let embedding = bert.embed_query(query).await.unwrap();
+/// let closest = db.get_closest(embedding, 1).unwrap();
+/// if let [closest] = closest.as_slice() {
+///     let distance = closest.distance;
+///     let text = embedding_id_to_sentence.get(&closest.value).unwrap();
+///     println!("distance: {distance}");
+///     println!("closest:  {text}");
+/// }
+/// # }
+/// ```
+#[doc(alias = "VectorDatabase")]
+#[doc(alias = "Vector Database")]
+pub struct VectorDB<S = UnknownVectorSpace> {
+    database: RawVectorDatabase,
+    quantized: Option<QuantizedDatabase>,
+    settings: VectorDBSettings,
+    env: heed::Env,
+    max_id: Mutex<EmbeddingId>,
+    recycled_ids: Mutex<Vec<EmbeddingId>>,
+    dim: AtomicUsize,
+    _phantom: std::marker::PhantomData<S>,
+}
+
+/// The distance metric a [`VectorDB`] uses to compare embeddings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum VectorDistance {
+    /// The angle between the embeddings. This is the same as cosine distance, and works well for most embedding models.
+    #[default]
+    Angular,
+    /// The straight line distance between the embeddings.
+    Euclidean,
+    /// The negated dot product of the embeddings. Use this for embedding models that were trained for dot product similarity.
+    DotProduct,
+    /// The sum of the absolute differences between the embeddings.
+    Manhattan,
+}
+
+/// The underlying arroy database of a [`VectorDB`] for each [`VectorDistance`].
+#[derive(Debug, Clone, Copy)]
+pub enum RawVectorDatabase {
+    /// A database that uses [`VectorDistance::Angular`].
+    Angular(ArroyDatabase<Angular>),
+    /// A database that uses [`VectorDistance::Euclidean`].
+    Euclidean(ArroyDatabase<Euclidean>),
+    /// A database that uses [`VectorDistance::DotProduct`].
+    DotProduct(ArroyDatabase<DotProduct>),
+    /// A database that uses [`VectorDistance::Manhattan`].
+    Manhattan(ArroyDatabase<Manhattan>),
+}
+
+// Run the same code with the arroy database for whatever distance the vector database uses
+macro_rules! with_database {
+    ($database:expr, |$db:ident| $body:expr) => {
+        match $database {
+            RawVectorDatabase::Angular($db) => $body,
+            RawVectorDatabase::Euclidean($db) => $body,
+            RawVectorDatabase::DotProduct($db) => $body,
+            RawVectorDatabase::Manhattan($db) => $body,
+        }
+    };
+}
+
+/// The settings of a [`VectorDB`]. The settings are saved with the database, so reopening the database with [`VectorDB::new_at`] uses the same settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+struct VectorDBSettings {
+    distance: VectorDistance,
+    trees: Option<usize>,
+    search_k: Option<NonZeroUsize>,
+    #[serde(default)]
+    quantization: VectorQuantization,
+    rescore: Option<NonZeroUsize>,
+    #[serde(default)]
+    full_embeddings: bool,
+}
+
+impl VectorDBSettings {
+    const FILE_NAME: &'static str = "kalosm-vector-db.json";
+
+    fn load(path: &Path) -> heed::Result<Option<Self>> {
+        let path = path.join(Self::FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = std::fs::read(path)?;
+        let settings = serde_json::from_slice(&file)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        Ok(Some(settings))
+    }
+
+    fn save(&self, path: &Path) -> heed::Result<()> {
+        let file = serde_json::to_vec_pretty(self)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        std::fs::write(path.join(Self::FILE_NAME), file)?;
+        Ok(())
+    }
+}
+
+/// A builder for a [`VectorDB`] with a custom distance metric or index parameters.
+///
+/// # Example
+/// ```rust, no_run
+/// # use kalosm_language::prelude::*;
+/// let db: VectorDB = VectorDBBuilder::new()
+///     .with_distance(VectorDistance::DotProduct)
+///     .with_trees(16)
+///     .with_search_k(1000)
+///     .at("./embeddings.db")
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct VectorDBBuilder {
+    location: Option<PathBuf>,
+    distance: Option<VectorDistance>,
+    trees: Option<usize>,
+    search_k: Option<NonZeroUsize>,
+    quantization: Option<VectorQuantization>,
+    rescore: Option<usize>,
+}
+
+impl VectorDBBuilder {
+    /// Create a new vector database builder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the location of the vector database. If no location is set, the database is stored in a temporary directory.
+    pub fn at(mut self, location: impl AsRef<Path>) -> Self {
+        self.location = Some(location.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set the distance metric used to compare embeddings. Defaults to [`VectorDistance::Angular`].
+    ///
+    /// The distance can't be changed after the database is created. Opening an existing database with a different distance is an error.
+    pub fn with_distance(mut self, distance: VectorDistance) -> Self {
+        self.distance = Some(distance);
+        self
+    }
+
+    /// Set the number of trees in the index. More trees make searches more accurate, but make the index larger and slower to build. Defaults to a number of trees based on the size of the database.
+    pub fn with_trees(mut self, trees: usize) -> Self {
+        self.trees = Some(trees);
+        self
+    }
+
+    /// Set the number of nodes that are checked during a search. Larger values make searches more accurate but slower. Defaults to the number of results times the number of trees.
+    pub fn with_search_k(mut self, search_k: usize) -> Self {
+        self.search_k = NonZeroUsize::new(search_k);
+        self
+    }
+
+    /// Set how embeddings are stored. Defaults to [`VectorQuantization::None`].
+    ///
+    /// Quantized databases find candidates by comparing the query with every quantized embedding instead of using an approximate nearest neighbor index. The quantization can't be changed after the database is created. Opening an existing database with a different quantization is an error.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// # use kalosm_language::prelude::*;
+    /// // Store one bit per dimension, and rescore the closest 40 candidates for every 10 results
+    /// let db: VectorDB = VectorDBBuilder::new()
+    ///     .with_quantization(VectorQuantization::Binary)
+    ///     .with_rescoring(4)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn with_quantization(mut self, quantization: VectorQuantization) -> Self {
+        self.quantization = Some(quantization);
+        self
+    }
+
+    /// Set how many candidates a quantized database finds for each result. The candidates are rescored with the full embeddings to get exact distances. Defaults to 4.
+    ///
+    /// Setting this to 0 when the database is created disables rescoring and doesn't store the full embeddings at all, which saves the most memory. Rescoring can't be turned back on for a database that was created without the full embeddings. This has no effect on databases without quantization.
+    pub fn with_rescoring(mut self, oversampling: usize) -> Self {
+        self.rescore = Some(oversampling);
+        self
+    }
+
+    /// Build the vector database.
+    pub fn build<S: VectorSpace + Sync>(self) -> heed::Result<VectorDB<S>> {
+        match &self.location {
+            Some(location) => VectorDB::open(location, self),
+            None => {
+                let dir = tempfile::tempdir()?;
+                VectorDB::open(dir.path(), self)
+            }
+        }
+    }
+}
+
+impl<S: VectorSpace + Sync> Default for VectorDB<S> {
+    fn default() -> Self {
+        Self::new().unwrap()
+    }
+}
+
+impl<S: VectorSpace + Sync> VectorDB<S> {
+    fn set_dim(&self, dim: usize) {
+        if dim == 0 {
+            panic!("Dimension cannot be 0");
+        }
+        self.dim.store(dim, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn get_dim(&self) -> anyhow::Result<usize> {
+        let mut dims = self.dim.load(std::sync::atomic::Ordering::Relaxed);
+        if dims == 0 {
+            let rtxn = self.env.read_txn()?;
+            dims = with_database!(self.database, |database| {
+                Reader::open(&rtxn, 0, database)?.dimensions()
+            });
+            self.set_dim(dims);
+        }
+        Ok(dims)
+    }
+
+    /// Create a new temporary vector database.
+    #[tracing::instrument]
+    pub fn new() -> heed::Result<Self> {
+        Self::builder().build()
+    }
+
+    /// Create a new vector database at the given path. If a database already exists at the path, it is opened with the settings it was created with.
+    pub fn new_at(path: impl AsRef<std::path::Path>) -> heed::Result<Self> {
+        Self::builder().at(path).build()
+    }
+
+    /// Create a builder for a vector database with a custom distance metric or index parameters.
+    pub fn builder() -> VectorDBBuilder {
+        VectorDBBuilder::new()
+    }
+
+    fn open(path: &Path, builder: VectorDBBuilder) -> heed::Result<Self> {
+        const TWENTY_HUNDRED_MIB: usize = 2 * 1024 * 1024 * 1024;
+
+        std::fs::create_dir_all(path)?;
+
+        let mut settings = match VectorDBSettings::load(path)? {
+            Some(settings) => settings,
+            // Databases created before the settings file existed always use the angular distance without quantization
+            None if path.join("data.mdb").exists() => VectorDBSettings::default(),
+            None => {
+                let rescore = NonZeroUsize::new(builder.rescore.unwrap_or(4));
+                VectorDBSettings {
+                    distance: builder.distance.unwrap_or_default(),
+                    quantization: builder.quantization.unwrap_or_default(),
+                    rescore,
+                    full_embeddings: rescore.is_some(),
+                    ..Default::default()
+                }
+            }
+        };
+        if let Some(distance) = builder.distance {
+            if distance != settings.distance {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "The vector database at {} uses the {:?} distance, but {:?} was requested",
+                        path.display(),
+                        settings.distance,
+                        distance
+                    ),
+                )
+                .into());
+            }
+        }
+        if let Some(quantization) = builder.quantization {
+            if quantization != settings.quantization {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "The vector database at {} uses {:?} quantization, but {:?} was requested",
+                        path.display(),
+                        settings.quantization,
+                        quantization
+                    ),
+                )
+                .into());
+            }
+        }
+        if let Some(rescore) = builder.rescore {
+            if rescore > 0
+                && settings.quantization != VectorQuantization::None
+                && !settings.full_embeddings
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "The vector database at {} was created without the full embeddings, so it can't rescore results",
+                        path.display()
+                    ),
+                )
+                .into());
+            }
+            settings.rescore = NonZeroUsize::new(rescore);
+        }
+        if builder.trees.is_some() {
+            settings.trees = builder.trees;
+        }
+        if builder.search_k.is_some() {
+            settings.search_k = builder.search_k;
+        }
+
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(TWENTY_HUNDRED_MIB)
+                .max_dbs(2)
+                .open(path)
+        }?;
+
+        let mut wtxn = env.write_txn()?;
+        let database = match settings.distance {
+            VectorDistance::Angular => {
+                RawVectorDatabase::Angular(env.create_database(&mut wtxn, None)?)
+            }
+            VectorDistance::Euclidean => {
+                RawVectorDatabase::Euclidean(env.create_database(&mut wtxn, None)?)
+            }
+            VectorDistance::DotProduct => {
+                RawVectorDatabase::DotProduct(env.create_database(&mut wtxn, None)?)
+            }
+            VectorDistance::Manhattan => {
+                RawVectorDatabase::Manhattan(env.create_database(&mut wtxn, None)?)
+            }
+        };
+        let quantized = match settings.quantization {
+            VectorQuantization::None => None,
+            quantization => Some(QuantizedDatabase::create(
+                &env,
+                &mut wtxn,
+                quantization,
+                settings.full_embeddings,
+            )?),
+        };
+        wtxn.commit()?;
+        settings.save(path)?;
+
+        Ok(Self {
+            database,
+            quantized,
+            settings,
+            env,
+            max_id: Mutex::new(EmbeddingId(0)),
+            recycled_ids: Mutex::new(Vec::new()),
+            dim: AtomicUsize::new(0),
+            _phantom: std::marker::PhantomData,
+        })
+    }
+
+    fn take_id(&self) -> EmbeddingId {
+        self.recycled_ids.lock().unwrap().pop().unwrap_or_else(|| {
+            let mut locked = self.max_id.lock().unwrap();
+            let id = *locked;
+            locked.0 += 1;
+            id
+        })
+    }
+
+    fn recycle_id(&self, id: EmbeddingId) {
+        self.recycled_ids.lock().unwrap().push(id);
+    }
+
+    /// Get the underlying database.
+    pub fn raw(&self) -> (&RawVectorDatabase, &heed::Env) {
+        (&self.database, &self.env)
+    }
+
+    /// Get the distance metric the database uses to compare embeddings.
+    pub fn distance(&self) -> VectorDistance {
+        self.settings.distance
+    }
+
+    /// Get how the database stores embeddings.
+    pub fn quantization(&self) -> VectorQuantization {
+        self.settings.quantization
+    }
+
+    /// Clear the vector database.
+    pub async fn clear(&self) -> anyhow::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        match &self.quantized {
+            Some(quantized) => quantized.clear(&mut wtxn)?,
+            None => {
+                let dims = self.get_dim()?;
+                with_database!(self.database, |database| {
+                    Writer::new(database, 0, dims).clear(&mut wtxn)?
+                });
+            }
+        }
+        wtxn.commit()?;
+
+        // Reset the ids
+        self.max_id.lock().unwrap().0 = 0;
+        self.recycled_ids.lock().unwrap().clear();
+
+        Ok(())
+    }
+
+    /// Remove an embedding from the vector database.
+    pub fn remove_embedding(&self, embedding_id: EmbeddingId) -> anyhow::Result<()> {
+        if let Some(quantized) = &self.quantized {
+            let mut wtxn = self.env.write_txn()?;
+            quantized.delete(&mut wtxn, embedding_id.0)?;
+            self.recycle_id(embedding_id);
+            wtxn.commit()?;
+            return Ok(());
+        }
+
+        let dims = self.get_dim()?;
+
+        let mut wtxn = self.env.write_txn()?;
+
+        with_database!(self.database, |database| {
+            let writer = Writer::new(database, 0, dims);
+
+            writer.del_item(&mut wtxn, embedding_id.0)?;
+
+            let mut rng = StdRng::from_entropy();
+
+            writer.build(&mut wtxn, &mut rng, self.settings.trees)?;
+        });
+        self.recycle_id(embedding_id);
+
+        wtxn.commit()?;
+
+        Ok(())
+    }
+
+    /// Add a new embedding to the vector database.
+    ///
+    /// Note: Adding embeddings in a batch with [`VectorDB::add_embeddings`] will be faster.
+    pub fn add_embedding(&self, embedding: Embedding<S>) -> anyhow::Result<EmbeddingId> {
+        let embedding = embedding.vector().to_vec1()?;
+
+        self.set_dim(embedding.len());
+
+        let mut wtxn = self.env.write_txn()?;
+
+        let id = self.take_id();
+
+        if let Some(quantized) = &self.quantized {
+            quantized.put(&mut wtxn, id.0, &embedding)?;
+            wtxn.commit()?;
+            return Ok(id);
+        }
+
+        with_database!(self.database, |database| {
+            let writer = Writer::new(database, 0, embedding.len());
+
+            writer.add_item(&mut wtxn, id.0, &embedding)?;
+
+            let mut rng = StdRng::from_entropy();
+
+            writer.build(&mut wtxn, &mut rng, self.settings.trees)?;
+        });
+
+        wtxn.commit()?;
+
+        Ok(id)
+    }
+
+    /// Add a new batch of embeddings to the vector database.
+    pub fn add_embeddings(
+        &self,
+        embedding: impl IntoIterator<Item = Embedding<S>>,
+    ) -> anyhow::Result<Vec<EmbeddingId>> {
+        let mut embeddings = embedding.into_iter().map(|e| e.vector().to_vec1());
+        let first_embedding = match embeddings.next() {
+            Some(e) => e?,
+            None => return Ok(Vec::new()),
+        };
+        self.set_dim(first_embedding.len());
+
+        let mut wtxn = self.env.write_txn()?;
+
+        let mut ids: Vec<_> = Vec::with_capacity(embeddings.size_hint().0 + 1);
+
+        if let Some(quantized) = &self.quantized {
+            for embedding in std::iter::once(Ok(first_embedding)).chain(embeddings) {
+                let id = self.take_id();
+                quantized.put(&mut wtxn, id.0, &embedding?)?;
+                ids.push(id);
+            }
+            wtxn.commit()?;
+            return Ok(ids);
+        }
+
+        with_database!(self.database, |database| {
+            let writer = Writer::new(database, 0, first_embedding.len());
+
+            {
+                let first_id = self.take_id();
+                writer.add_item(&mut wtxn, first_id.0, &first_embedding)?;
+                ids.push(first_id);
+            }
+
+            for embedding in &mut embeddings {
+                let id = self.take_id();
+                writer.add_item(&mut wtxn, id.0, &embedding?)?;
+                ids.push(id);
+            }
+
+            let mut rng = StdRng::from_entropy();
+
+            writer.build(&mut wtxn, &mut rng, self.settings.trees)?;
+        });
+
+        wtxn.commit()?;
+
+        Ok(ids)
+    }
+
+    /// Get the embedding for an embedding id.
+    pub fn get_embedding(&self, embedding_id: EmbeddingId) -> anyhow::Result<Embedding<S>> {
+        let rtxn = self.env.read_txn()?;
+
+        let embedding = match &self.quantized {
+            Some(quantized) => quantized.get(&rtxn, embedding_id.0)?,
+            None => with_database!(self.database, |database| {
+                Reader::open(&rtxn, 0, database)?.item_vector(&rtxn, embedding_id.0)?
+            }),
+        }
+        .ok_or_else(|| anyhow::anyhow!("Embedding not found"))?;
+
+        let shape = (embedding.len(),);
+        Ok(Embedding::new(Tensor::from_vec(
+            embedding,
+            shape,
+            &accelerated_device_if_available()?,
+        )?))
+    }
+
+    /// Get the closest N embeddings to the given embedding.
+    pub fn get_closest(
+        &self,
+        embedding: Embedding<S>,
+        n: usize,
+    ) -> anyhow::Result<Vec<VectorDBSearchResult>> {
+        self.get_closest_inner(embedding, n, None)
+    }
+
+    /// Get the closest N embeddings to the given embedding, only considering the embedding ids in `candidates`.
+    ///
+    /// The candidates are passed into the nearest neighbor search, so you get up to N results from the candidates even if most of the database is filtered out.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// # use kalosm_language::prelude::*;
+    /// # use rbert::*;
+    /// # use std::collections::HashMap;
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let bert = Bert::new_for_search().await.unwrap();
+    /// let db = VectorDB::new().unwrap();
+    /// let tenants = ["acme", "globex", "acme"];
+    /// let embeddings = bert
+    ///     .embed_batch(["Acme invoice", "Globex invoice", "Acme receipt"])
+    ///     .await
+    ///     .unwrap();
+    /// let ids = db.add_embeddings(embeddings).unwrap();
+    /// let tenant_of: HashMap<EmbeddingId, &str> = HashMap::from_iter(ids.iter().copied().zip(tenants));
+    ///
+    /// // Only search the embeddings that belong to acme
+    /// let candidates: RoaringBitmap = ids
+    ///     .iter()
+    ///     .filter(|id| tenant_of[id] == "acme")
+    ///     .map(|id| id.0)
+    ///     .collect();
+    /// let embedding = bert.embed_query("invoice").await.unwrap();
+    /// let closest = db.get_closest_filtered(embedding, 1, &candidates).unwrap();
+    /// # }
+    /// ```
+    pub fn get_closest_filtered(
+        &self,
+        embedding: Embedding<S>,
+        n: usize,
+        candidates: &RoaringBitmap,
+    ) -> anyhow::Result<Vec<VectorDBSearchResult>> {
+        self.get_closest_inner(embedding, n, Some(candidates))
+    }
+
+    fn get_closest_inner(
+        &self,
+        embedding: Embedding<S>,
+        n: usize,
+        candidates: Option<&RoaringBitmap>,
+    ) -> anyhow::Result<Vec<VectorDBSearchResult>> {
+        let rtxn = self.env.read_txn()?;
+
+        let vector = embedding.vector().to_vec1()?;
+        let results = match &self.quantized {
+            Some(quantized) => quantized.search(
+                &rtxn,
+                &vector,
+                n,
+                candidates,
+                self.settings.distance,
+                self.settings.rescore,
+            )?,
+            None => with_database!(self.database, |database| {
+                Reader::open(&rtxn, 0, database)?.nns_by_vector(
+                    &rtxn,
+                    &vector,
+                    n,
+                    self.settings.search_k,
+                    candidates,
+                )?
+            }),
+        };
+
+        Ok(results
+            .into_iter()
+            .map(|(id, distance)| {
+                let value = EmbeddingId(id);
+                VectorDBSearchResult { distance, value }
+            })
+            .collect::<Vec<_>>())
+    }
+}
+
+/// A resulting point from a search.
+#[derive(Debug, Clone)]
+pub struct VectorDBSearchResult {
+    /// The distance from the searched point.
+    pub distance: f32,
+    /// The value of the point.
+    pub value: EmbeddingId,
+}
+
+/// A unique identifier for an embedding. If you delete an embedding, the id will be recycled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct EmbeddingId(pub u32);
+
+#[test]
+fn vector_db_keeps_its_settings() {
+    let dir = tempfile::tempdir().unwrap();
+    let open = |distance: Option<VectorDistance>| {
+        let mut builder = VectorDBBuilder::new().at(dir.path());
+        if let Some(distance) = distance {
+            builder = builder.with_distance(distance);
+        }
+        builder.build::<UnknownVectorSpace>()
+    };
+
+    let db = open(Some(VectorDistance::DotProduct)).unwrap();
+    db.add_embedding(Embedding::from([1.0, 0.0, 0.5])).unwrap();
+    drop(db);
+
+    // Reopening without a distance uses the saved distance
+    let db = open(None).unwrap();
+    assert_eq!(db.distance(), VectorDistance::DotProduct);
+    drop(db);
+
+    assert!(open(Some(VectorDistance::Euclidean)).is_err());
+}
+
+#[test]
+fn legacy_vector_db_uses_angular_distance() {
+    let dir = tempfile::tempdir().unwrap();
+    let open = |distance: VectorDistance| {
+        VectorDBBuilder::new()
+            .at(dir.path())
+            .with_distance(distance)
+            .build::<UnknownVectorSpace>()
+    };
+
+    let db = open(VectorDistance::Angular).unwrap();
+    db.add_embedding(Embedding::from([1.0, 0.0, 0.5])).unwrap();
+    drop(db);
+    // Databases from older versions have data but no settings file
+    std::fs::remove_file(dir.path().join(VectorDBSettings::FILE_NAME)).unwrap();
+
+    assert!(open(VectorDistance::DotProduct).is_err());
+    assert!(!dir.path().join(VectorDBSettings::FILE_NAME).exists());
+
+    let db = VectorDB::<UnknownVectorSpace>::new_at(dir.path()).unwrap();
+    assert_eq!(db.distance(), VectorDistance::Angular);
+    assert_eq!(
+        db.get_closest(Embedding::from([1.0, 0.0, 0.5]), 1).unwrap()[0].value,
+        EmbeddingId(0)
+    );
+}
@@ -0,0 +1,417 @@
+use std::num::NonZeroUsize;
+
+use heed::byteorder::BigEndian;
+use heed::types::{Bytes, U32};
+use heed::{Database, Env, RoTxn, RwTxn};
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
+
+use super::VectorDistance;
+
+/// How a [`VectorDB`](super::VectorDB) stores embeddings.
+///
+/// Quantized embeddings take up a fraction of the memory of full embeddings. Searches compare the query with every quantized embedding to find candidates, and then rescore the best candidates with the full embeddings. See [`VectorDBBuilder::with_rescoring`](super::VectorDBBuilder::with_rescoring).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum VectorQuantization {
+    /// Store the full 32 bit float embeddings in an approximate nearest neighbor index.
+    #[default]
+    None,
+    /// Store each dimension as an 8 bit integer, scaled by the largest dimension of the embedding. Uses 4x less memory than the full embeddings.
+    Int8,
+    /// Store the sign of each dimension as a single bit. Uses 32x less memory than the full embeddings. Candidates are compared with the Hamming distance, so this works best with large embeddings and rescoring.
+    ///
+    /// Without rescoring, the distances of results are estimated from the fraction of dimensions with a different sign. With [`VectorDistance::Angular`], that fraction is converted to an estimate of the angular distance. With other metrics, the distance is the fraction itself, between 0 and 1.
+    Binary,
+}
+
+impl VectorQuantization {
+    /// The number of bytes one embedding with the given number of dimensions takes up with this quantization. This does not include the full embedding that is kept for rescoring.
+    pub fn bytes_per_embedding(&self, dimensions: usize) -> usize {
+        match self {
+            Self::None => dimensions * 4,
+            Self::Int8 => 4 + dimensions,
+            Self::Binary => 4 + dimensions.div_ceil(8),
+        }
+    }
+
+    fn encode(self, vector: &[f32]) -> Vec<u8> {
+        let mut code = Vec::with_capacity(self.bytes_per_embedding(vector.len()));
+        match self {
+            Self::None => code.extend(vector.iter().flat_map(|x| x.to_le_bytes())),
+            Self::Int8 => {
+                let max = vector.iter().fold(0f32, |max, x| max.max(x.abs()));
+                let scale = if max > 0.0 { max / i8::MAX as f32 } else { 1.0 };
+                code.extend_from_slice(&scale.to_le_bytes());
+                code.extend(
+                    vector
+                        .iter()
+                        .map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8 as u8),
+                );
+            }
+            Self::Binary => {
+                code.extend_from_slice(&(vector.len() as u32).to_le_bytes());
+                code.extend(sign_bits(vector));
+            }
+        }
+        code
+    }
+
+    fn decode(self, code: &[u8]) -> Vec<f32> {
+        match self {
+            Self::None => code
+                .chunks_exact(4)
+                .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+                .collect(),
+            Self::Int8 => {
+                let (scale, values) = code.split_at(4);
+                let scale = f32::from_le_bytes([scale[0], scale[1], scale[2], scale[3]]);
+                values.iter().map(|&x| x as i8 as f32 * scale).collect()
+            }
+            Self::Binary => {
+                let (dims, bits) = code.split_at(4);
+                let dims = u32::from_le_bytes([dims[0], dims[1], dims[2], dims[3]]) as usize;
+                (0..dims)
+                    .map(|i| {
+                        if bits[i / 8] & (1 << (i % 8)) != 0 {
+                            1.0
+                        } else {
+                            -1.0
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+fn sign_bits(vector: &[f32]) -> Vec<u8> {
+    let mut bits = vec![0; vector.len().div_ceil(8)];
+    for (i, x) in vector.iter().enumerate() {
+        if *x > 0.0 {
+            bits[i / 8] |= 1 << (i % 8);
+        }
+    }
+    bits
+}
+
+fn hamming(a: &[u8], b: &[u8]) -> u32 {
+    a.iter().zip(b).map(|(a, b)| (a ^ b).count_ones()).sum()
+}
+
+/// Estimate the distance between two embeddings from the fraction of dimensions with a different sign. For random hyperplanes, that fraction is the angle between the embeddings divided by pi.
+fn binary_distance(distance: VectorDistance, query_bits: &[u8], code: &[u8]) -> f32 {
+    let (dims, bits) = code.split_at(4);
+    let dims = u32::from_le_bytes([dims[0], dims[1], dims[2], dims[3]]);
+    let different = hamming(query_bits, bits) as f32 / dims.max(1) as f32;
+    match distance {
+        VectorDistance::Angular => 1.0 - (different * std::f32::consts::PI).cos(),
+        _ => different,
+    }
+}
+
+/// The distance between the query and an int8 embedding, computed from the codes and scale without dequantizing the embedding.
+fn int8_distance(distance: VectorDistance, query: &[f32], query_norm: f32, code: &[u8]) -> f32 {
+    let (scale, values) = code.split_at(4);
+    let scale = f32::from_le_bytes([scale[0], scale[1], scale[2], scale[3]]);
+    let values = values.iter().map(|&x| x as i8 as f32);
+    match distance {
+        VectorDistance::Angular => {
+            let (dot, norm) = query
+                .iter()
+                .zip(values)
+                .fold((0.0, 0.0), |(dot, norm), (q, x)| {
+                    (dot + q * x, norm + x * x)
+                });
+            // The scale cancels out of the cosine similarity
+            let norms = query_norm * norm.sqrt();
+            if norms > 0.0 {
+                1.0 - dot / norms
+            } else {
+                1.0
+            }
+        }
+        VectorDistance::Euclidean => query
+            .iter()
+            .zip(values)
+            .map(|(q, x)| (q - x * scale) * (q - x * scale))
+            .sum::<f32>()
+            .sqrt(),
+        VectorDistance::DotProduct => {
+            -scale * query.iter().zip(values).map(|(q, x)| q * x).sum::<f32>()
+        }
+        VectorDistance::Manhattan => query
+            .iter()
+            .zip(values)
+            .map(|(q, x)| (q - x * scale).abs())
+            .sum(),
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+impl VectorDistance {
+    /// The exact distance between two embeddings with this metric.
+    pub(crate) fn between(self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Self::Angular => {
+                let norms = (dot(a, a) * dot(b, b)).sqrt();
+                if norms > 0.0 {
+                    1.0 - dot(a, b) / norms
+                } else {
+                    1.0
+                }
+            }
+            Self::Euclidean => a
+                .iter()
+                .zip(b)
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+                .sqrt(),
+            Self::DotProduct => -dot(a, b),
+            Self::Manhattan => a.iter().zip(b).map(|(a, b)| (a - b).abs()).sum(),
+        }
+    }
+}
+
+/// The storage for a quantized [`VectorDB`](super::VectorDB). The quantized embeddings are scanned to find candidates, and the optional full embeddings are used to rescore them.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct QuantizedDatabase {
+    quantization: VectorQuantization,
+    codes: Database<U32<BigEndian>, Bytes>,
+    full: Option<Database<U32<BigEndian>, Bytes>>,
+}
+
+impl QuantizedDatabase {
+    const CODES: &'static str = "kalosm-quantized";
+    const FULL: &'static str = "kalosm-full";
+
+    pub(crate) fn create(
+        env: &Env,
+        wtxn: &mut RwTxn,
+        quantization: VectorQuantization,
+        keep_full: bool,
+    ) -> heed::Result<Self> {
+        let codes = env.create_database(wtxn, Some(Self::CODES))?;
+        let full = if keep_full {
+            Some(env.create_database(wtxn, Some(Self::FULL))?)
+        } else {
+            None
+        };
+        Ok(Self {
+            quantization,
+            codes,
+            full,
+        })
+    }
+
+    pub(crate) fn put(&self, wtxn: &mut RwTxn, id: u32, vector: &[f32]) -> heed::Result<()> {
+        self.codes
+            .put(wtxn, &id, &self.quantization.encode(vector))?;
+        if let Some(full) = self.full {
+            full.put(wtxn, &id, &VectorQuantization::None.encode(vector))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn delete(&self, wtxn: &mut RwTxn, id: u32) -> heed::Result<()> {
+        self.codes.delete(wtxn, &id)?;
+        if let Some(full) = self.full {
+            full.delete(wtxn, &id)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn clear(&self, wtxn: &mut RwTxn) -> heed::Result<()> {
+        self.codes.clear(wtxn)?;
+        if let Some(full) = self.full {
+            full.clear(wtxn)?;
+        }
+        Ok(())
+    }
+
+    /// Get the full embedding if it is stored, or the dequantized embedding if it isn't.
+    pub(crate) fn get(&self, rtxn: &RoTxn, id: u32) -> heed::Result<Option<Vec<f32>>> {
+        if let Some(full) = self.full {
+            return Ok(full
+                .get(rtxn, &id)?
+                .map(|bytes| VectorQuantization::None.decode(bytes)));
+        }
+        Ok(self
+            .codes
+            .get(rtxn, &id)?
+            .map(|code| self.quantization.decode(code)))
+    }
+
+    /// Find the closest `n` embeddings to the query. If `rescore` is set and the full embeddings are stored, the closest `n * rescore` quantized embeddings are rescored with the full embeddings.
+    pub(crate) fn search(
+        &self,
+        rtxn: &RoTxn,
+        query: &[f32],
+        n: usize,
+        candidates: Option<&RoaringBitmap>,
+        distance: VectorDistance,
+        rescore: Option<NonZeroUsize>,
+    ) -> heed::Result<Vec<(u32, f32)>> {
+        let rescore = self.full.zip(rescore);
+        let keep = match rescore {
+            Some((_, oversampling)) => n.saturating_mul(oversampling.get()),
+            None => n,
+        };
+
+        let query_bits = sign_bits(query);
+        let query_norm = dot(query, query).sqrt();
+        let approximate_distance = |code: &[u8]| match self.quantization {
+            VectorQuantization::None => {
+                distance.between(query, &VectorQuantization::None.decode(code))
+            }
+            VectorQuantization::Int8 => int8_distance(distance, query, query_norm, code),
+            VectorQuantization::Binary => binary_distance(distance, &query_bits, code),
+        };
+
+        let mut scored = Vec::new();
+        match candidates {
+            Some(candidates) => {
+                for id in candidates {
+                    if let Some(code) = self.codes.get(rtxn, &id)? {
+                        scored.push((id, approximate_distance(code)));
+                    }
+                }
+            }
+            None => {
+                for item in self.codes.iter(rtxn)? {
+                    let (id, code) = item?;
+                    scored.push((id, approximate_distance(code)));
+                }
+            }
+        }
+        closest(&mut scored, keep);
+
+        if let Some((full, _)) = rescore {
+            for (id, score) in &mut scored {
+                if let Some(bytes) = full.get(rtxn, id)? {
+                    *score = distance.between(query, &VectorQuantization::None.decode(bytes));
+                }
+            }
+            closest(&mut scored, n);
+        }
+
+        Ok(scored)
+    }
+}
+
+// Keep the closest `n` scores sorted from closest to furthest
+fn closest(scored: &mut Vec<(u32, f32)>, n: usize) {
+    if n == 0 {
+        scored.clear();
+        return;
+    }
+    if scored.len() > n {
+        scored.select_nth_unstable_by(n - 1, |a, b| a.1.total_cmp(&b.1));
+        scored.truncate(n);
+    }
+    scored.sort_by(|a, b| a.1.total_cmp(&b.1));
+}
+
+#[test]
+fn quantized_search_finds_neighbors() {
+    let dir = tempfile::tempdir().unwrap();
+    let env = unsafe {
+        heed::EnvOpenOptions::new()
+            .max_dbs(2)
+            .open(dir.path())
+            .unwrap()
+    };
+    let vectors = [
+        [1.0, 0.9, -0.2, 0.1],
+        [-1.0, 0.2, 0.8, -0.3],
+        [0.9, 1.0, -0.1, 0.3],
+        [-0.5, -0.9, 0.4, 0.7],
+    ];
+    let query = [1.0, 1.0, -0.1, 0.2];
+
+    for quantization in [VectorQuantization::Int8, VectorQuantization::Binary] {
+        let mut wtxn = env.write_txn().unwrap();
+        let db = QuantizedDatabase::create(&env, &mut wtxn, quantization, true).unwrap();
+        db.clear(&mut wtxn).unwrap();
+        for (id, vector) in vectors.iter().enumerate() {
+            db.put(&mut wtxn, id as u32, vector).unwrap();
+        }
+        wtxn.commit().unwrap();
+
+        let rtxn = env.read_txn().unwrap();
+        let oversampling = NonZeroUsize::new(2);
+        let results = db
+            .search(
+                &rtxn,
+                &query,
+                2,
+                None,
+                VectorDistance::Angular,
+                oversampling,
+            )
+            .unwrap();
+        let ids: Vec<_> = results.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [2, 0]);
+        assert_eq!(
+            results[0].1,
+            VectorDistance::Angular.between(&query, &vectors[2])
+        );
+
+        let candidates = RoaringBitmap::from_iter([1, 3]);
+        let results = db
+            .search(
+                &rtxn,
+                &query,
+                1,
+                Some(&candidates),
+                VectorDistance::Angular,
+                oversampling,
+            )
+            .unwrap();
+        assert_eq!(results[0].0, 1);
+
+        assert_eq!(db.get(&rtxn, 1).unwrap().unwrap(), vectors[1]);
+    }
+
+    let int8 = VectorQuantization::Int8.decode(&VectorQuantization::Int8.encode(&vectors[0]));
+    assert!(int8
+        .iter()
+        .zip(vectors[0])
+        .all(|(a, b)| (a - b).abs() < 0.01));
+    let binary = VectorQuantization::Binary.decode(&VectorQuantization::Binary.encode(&vectors[1]));
+    assert_eq!(binary, [-1.0, 1.0, 1.0, -1.0]);
+}
+
+#[test]
+fn quantized_distances_match_the_decoded_embeddings() {
+    let vector = [0.3, -0.8, 0.5, 0.1];
+    let query = [0.2, -0.4, -0.9, -0.6];
+    let query_norm = dot(&query, &query).sqrt();
+
+    let code = VectorQuantization::Int8.encode(&vector);
+    let decoded = VectorQuantization::Int8.decode(&code);
+    for distance in [
+        VectorDistance::Angular,
+        VectorDistance::Euclidean,
+        VectorDistance::DotProduct,
+        VectorDistance::Manhattan,
+    ] {
+        let expected = distance.between(&query, &decoded);
+        assert!((int8_distance(distance, &query, query_norm, &code) - expected).abs() < 1e-5);
+    }
+
+    // The signs of the query and the embedding match in 2 out of 4 dimensions
+    let code = VectorQuantization::Binary.encode(&vector);
+    let query_bits = sign_bits(&query);
+    assert_eq!(
+        binary_distance(VectorDistance::Euclidean, &query_bits, &code),
+        0.5
+    );
+    assert!((binary_distance(VectorDistance::Angular, &query_bits, &code) - 1.0).abs() < 1e-6);
+    assert_eq!(
+        binary_distance(VectorDistance::Angular, &sign_bits(&vector), &code),
+        0.0
+    );
+}
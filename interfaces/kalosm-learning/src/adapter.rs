@@ -0,0 +1,415 @@
+use rand::prelude::SliceRandom;
+use rand::Rng;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use candle_core::{
+    safetensors::{self, Load},
+    DType, Device, Result, Tensor, Var,
+};
+use candle_nn::{Linear, Module, Optimizer, VarBuilder, VarMap};
+use kalosm_common::{maybe_autoreleasepool, BoxedFuture};
+use kalosm_language_model::{
+    Embedder, EmbedderExt, Embedding, EmbeddingInput, EmbeddingVariant, VectorSpace,
+};
+
+/// A dataset of pairs of embeddings of the same text in two vector spaces, used to train an [`EmbeddingAdapter`].
+pub struct EmbeddingAdapterDataset<S1: VectorSpace, S2: VectorSpace> {
+    train_inputs: Tensor,
+    train_targets: Tensor,
+    test_inputs: Tensor,
+    test_targets: Tensor,
+    phantom: PhantomData<(S1, S2)>,
+}
+
+impl<S1: VectorSpace, S2: VectorSpace> EmbeddingAdapterDataset<S1, S2> {
+    /// Create a builder for an embedding adapter dataset.
+    pub fn builder() -> EmbeddingAdapterDatasetBuilder<S1, S2> {
+        EmbeddingAdapterDatasetBuilder::default()
+    }
+
+    /// Save the dataset to the given path.
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        let safetensors = HashMap::from([
+            ("train_inputs".to_string(), self.train_inputs.clone()),
+            ("train_targets".to_string(), self.train_targets.clone()),
+            ("test_inputs".to_string(), self.test_inputs.clone()),
+            ("test_targets".to_string(), self.test_targets.clone()),
+        ]);
+
+        safetensors::save(&safetensors, path)?;
+        Ok(())
+    }
+
+    /// Load the dataset from the given path.
+    pub fn load<P: AsRef<std::path::Path>>(path: P, dev: &Device) -> Result<Self> {
+        let mut safetensors = safetensors::load(path, dev)?;
+        Ok(Self {
+            train_inputs: safetensors.remove("train_inputs").unwrap(),
+            train_targets: safetensors.remove("train_targets").unwrap(),
+            test_inputs: safetensors.remove("test_inputs").unwrap(),
+            test_targets: safetensors.remove("test_targets").unwrap(),
+            phantom: PhantomData,
+        })
+    }
+}
+
+/// A builder for [`EmbeddingAdapterDataset`].
+///
+/// # Example
+/// ```rust, no_run
+/// # use kalosm_learning::*;
+/// # use rbert::*;
+/// # #[tokio::main]
+/// # async fn main() -> anyhow::Result<()> {
+/// let old_model = Bert::new_for_search().await?;
+/// let new_model = Bert::new().await?;
+/// let mut dataset = EmbeddingAdapterDatasetBuilder::new();
+/// // Embed the same texts with both models
+/// dataset
+///     .extend_from(
+///         &new_model,
+///         &old_model,
+///         ["Cats are cool", "The geopolitical situation is dire"],
+///     )
+///     .await?;
+/// let dataset = dataset.build(&candle_core::Device::Cpu)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct EmbeddingAdapterDatasetBuilder<S1: VectorSpace, S2: VectorSpace> {
+    inputs: Vec<Vec<f32>>,
+    targets: Vec<Vec<f32>>,
+    phantom: PhantomData<(S1, S2)>,
+}
+
+impl<S1: VectorSpace, S2: VectorSpace> Default for EmbeddingAdapterDatasetBuilder<S1, S2> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S1: VectorSpace, S2: VectorSpace> EmbeddingAdapterDatasetBuilder<S1, S2> {
+    /// Create a new dataset builder.
+    pub fn new() -> Self {
+        Self {
+            inputs: Vec::new(),
+            targets: Vec::new(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Add a pair of embeddings of the same text to the dataset.
+    pub fn add(&mut self, input: &Embedding<S1>, target: &Embedding<S2>) {
+        let input = input.to_vec();
+        let target = target.to_vec();
+        if let (Some(first_input), Some(first_target)) = (self.inputs.first(), self.targets.first())
+        {
+            debug_assert_eq!(input.len(), first_input.len(), "input size mismatch");
+            debug_assert_eq!(target.len(), first_target.len(), "target size mismatch");
+        }
+        self.inputs.push(input);
+        self.targets.push(target);
+    }
+
+    /// Embed each text with both embedders and add the pairs to the dataset. The texts should look like the documents and queries the adapter will be used for.
+    pub async fn extend_from<E1, E2>(
+        &mut self,
+        input_embedder: &E1,
+        target_embedder: &E2,
+        texts: impl IntoIterator<Item = impl ToString>,
+    ) -> anyhow::Result<()>
+    where
+        E1: Embedder<VectorSpace = S1>,
+        E2: Embedder<VectorSpace = S2>,
+    {
+        let texts: Vec<_> = texts.into_iter().map(|text| text.to_string()).collect();
+        let inputs = input_embedder.embed_batch(&texts).await?;
+        let targets = target_embedder.embed_batch(&texts).await?;
+        for (input, target) in inputs.iter().zip(&targets) {
+            self.add(input, target);
+        }
+        Ok(())
+    }
+
+    /// Builds the dataset and copies the data to the device passed in. A quarter of the pairs are held out to test the adapter.
+    pub fn build(mut self, dev: &Device) -> Result<EmbeddingAdapterDataset<S1, S2>> {
+        let mut rng = rand::thread_rng();
+        let input_size = self.inputs.first().map(Vec::len).unwrap_or_default();
+        let target_size = self.targets.first().map(Vec::len).unwrap_or_default();
+
+        let test_len = self.inputs.len() / 4;
+        let mut test_inputs = Vec::with_capacity(test_len * input_size);
+        let mut test_targets = Vec::with_capacity(test_len * target_size);
+        for _ in 0..test_len {
+            let index = rng.gen_range(0..self.inputs.len());
+            test_inputs.append(&mut self.inputs.swap_remove(index));
+            test_targets.append(&mut self.targets.swap_remove(index));
+        }
+        let train_len = self.inputs.len();
+        let train_inputs: Vec<f32> = self.inputs.into_iter().flatten().collect();
+        let train_targets: Vec<f32> = self.targets.into_iter().flatten().collect();
+
+        Ok(EmbeddingAdapterDataset {
+            train_inputs: Tensor::from_vec(train_inputs, (train_len, input_size), dev)?,
+            train_targets: Tensor::from_vec(train_targets, (train_len, target_size), dev)?,
+            test_inputs: Tensor::from_vec(test_inputs, (test_len, input_size), dev)?,
+            test_targets: Tensor::from_vec(test_targets, (test_len, target_size), dev)?,
+            phantom: PhantomData,
+        })
+    }
+}
+
+/// A learned linear map from the vector space `S1` into the vector space `S2`.
+///
+/// Adapters let you keep using an index built with one embedding model after you switch to another. Train an adapter from the new model's space into the old model's space, and embed queries with [`EmbeddingAdapter::embedder`] to search the old index without re-embedding every document.
+///
+/// # Example
+/// ```rust, no_run
+/// # use kalosm_learning::*;
+/// # use kalosm_language_model::*;
+/// # use rbert::*;
+/// # #[tokio::main]
+/// # async fn main() -> anyhow::Result<()> {
+/// let dev = candle_core::Device::Cpu;
+/// let old_model = Bert::new_for_search().await?;
+/// let new_model = Bert::new().await?;
+///
+/// let mut dataset = EmbeddingAdapterDatasetBuilder::new();
+/// let texts = std::fs::read_to_string("documents.txt")?;
+/// dataset
+///     .extend_from(&new_model, &old_model, texts.lines())
+///     .await?;
+/// let dataset = dataset.build(&dev)?;
+///
+/// let mut adapter = EmbeddingAdapter::new(&dev);
+/// adapter.train(&dataset, 50, 0.01, 32)?;
+/// adapter.save("adapter.safetensors")?;
+///
+/// // Embed queries with the new model into the old model's vector space
+/// let embedder = adapter.embedder(new_model);
+/// let query = embedder.embed_query("What is Kalosm?").await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct EmbeddingAdapter<S1: VectorSpace, S2: VectorSpace> {
+    device: Device,
+    varmap: VarMap,
+    layer: Option<Linear>,
+    phantom: PhantomData<(S1, S2)>,
+}
+
+impl<S1: VectorSpace, S2: VectorSpace> EmbeddingAdapter<S1, S2> {
+    const LAYER: &'static str = "adapter";
+
+    /// Create a new untrained adapter. The size of the adapter is set by the dataset it is trained on.
+    pub fn new(dev: &Device) -> Self {
+        Self {
+            device: dev.clone(),
+            varmap: VarMap::new(),
+            layer: None,
+            phantom: PhantomData,
+        }
+    }
+
+    fn layer(&mut self, input_dim: usize, output_dim: usize) -> Result<&Linear> {
+        if self.layer.is_none() {
+            let vs = VarBuilder::from_varmap(&self.varmap, DType::F32, &self.device);
+            self.layer = Some(candle_nn::linear(
+                input_dim,
+                output_dim,
+                vs.pp(Self::LAYER),
+            )?);
+        }
+        Ok(self.layer.as_ref().unwrap())
+    }
+
+    fn trained_layer(&self) -> anyhow::Result<&Linear> {
+        self.layer
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("The embedding adapter has not been trained"))
+    }
+
+    /// Train the adapter on the given dataset. Returns the average cosine similarity between the adapted test inputs and the test targets.
+    pub fn train(
+        &mut self,
+        dataset: &EmbeddingAdapterDataset<S1, S2>,
+        epochs: usize,
+        learning_rate: f64,
+        batch_size: usize,
+    ) -> anyhow::Result<f32> {
+        let dev = self.device.clone();
+        let (train_len, input_dim) = dataset.train_inputs.dims2()?;
+        let output_dim = dataset.train_targets.dims2()?.1;
+        let layer = self.layer(input_dim, output_dim)?.clone();
+
+        let mut optimizer = candle_nn::AdamW::new_lr(self.varmap.all_vars(), learning_rate)?;
+        let train_inputs = dataset.train_inputs.to_device(&dev)?;
+        let train_targets = dataset.train_targets.to_device(&dev)?;
+        let test_inputs = dataset.test_inputs.to_device(&dev)?;
+        let test_targets = dataset.test_targets.to_device(&dev)?;
+        let mut final_similarity = 0.0;
+        let mut rng = rand::thread_rng();
+        for epoch in 1..epochs + 1 {
+            let mut indices = (0..train_len as u32).collect::<Vec<_>>();
+            indices.shuffle(&mut rng);
+            maybe_autoreleasepool(|| {
+                for indices in indices.chunks(batch_size.max(1)) {
+                    let indices = Tensor::new(indices, &dev)?;
+                    let inputs = train_inputs.index_select(&indices, 0)?;
+                    let targets = train_targets.index_select(&indices, 0)?;
+                    let similarity = cosine_similarity(&layer.forward(&inputs)?, &targets)?;
+                    let loss = similarity.affine(-1.0, 1.0)?;
+                    optimizer.backward_step(&loss)?;
+                }
+                if test_inputs.dims()[0] > 0 {
+                    final_similarity =
+                        cosine_similarity(&layer.forward(&test_inputs)?, &test_targets)?
+                            .to_scalar::<f32>()?;
+                    println!("Epoch: {epoch:5} Test similarity: {final_similarity:5.5}");
+                }
+                Ok::<_, anyhow::Error>(())
+            })?;
+        }
+        Ok(final_similarity)
+    }
+
+    /// Map an embedding from `S1` into `S2`.
+    pub fn adapt(&self, embedding: &Embedding<S1>) -> anyhow::Result<Embedding<S2>> {
+        let input = embedding.vector().to_device(&self.device)?.unsqueeze(0)?;
+        Ok(Embedding::new(self.trained_layer()?.forward(&input)?))
+    }
+
+    /// Map a batch of embeddings from `S1` into `S2`.
+    pub fn adapt_batch(&self, embeddings: &[Embedding<S1>]) -> anyhow::Result<Vec<Embedding<S2>>> {
+        if embeddings.is_empty() {
+            return Ok(Vec::new());
+        }
+        let inputs = embeddings
+            .iter()
+            .map(|embedding| embedding.vector().to_device(&self.device))
+            .collect::<Result<Vec<_>>>()?;
+        let outputs = self.trained_layer()?.forward(&Tensor::stack(&inputs, 0)?)?;
+        Ok((0..embeddings.len())
+            .map(|i| outputs.get(i).map(Embedding::new))
+            .collect::<Result<_>>()?)
+    }
+
+    /// Wrap an embedder for `S1` so it embeds text directly into `S2`.
+    pub fn embedder<E: Embedder<VectorSpace = S1>>(self, embedder: E) -> AdaptedEmbedder<E, S2> {
+        AdaptedEmbedder {
+            embedder,
+            adapter: self,
+        }
+    }
+
+    /// Save the adapter to a safetensors file at the given path.
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> Result<()> {
+        self.varmap.save(path)
+    }
+
+    /// Load the adapter from a safetensors file at the given path.
+    pub fn load(path: impl AsRef<std::path::Path>, dev: &Device) -> anyhow::Result<Self> {
+        let varmap = VarMap::new();
+        let (output_dim, input_dim) = {
+            let safetensors = unsafe { candle_core::safetensors::MmapedSafetensors::new(path) }?;
+            let mut tensor_data = varmap.data().lock().unwrap();
+            for (name, value) in safetensors.tensors() {
+                let tensor = value.load(dev)?;
+                tensor_data.insert(name.to_string(), Var::from_tensor(&tensor)?);
+            }
+            let weight = tensor_data
+                .get(&format!("{}.weight", Self::LAYER))
+                .ok_or_else(|| anyhow::anyhow!("The file is not an embedding adapter"))?;
+            weight.dims2()?
+        };
+        let mut adapter = Self {
+            device: dev.clone(),
+            varmap,
+            layer: None,
+            phantom: PhantomData,
+        };
+        adapter.layer(input_dim, output_dim)?;
+        Ok(adapter)
+    }
+}
+
+// The average cosine similarity between the rows of two matrices
+fn cosine_similarity(a: &Tensor, b: &Tensor) -> Result<Tensor> {
+    let dot = (a * b)?.sum(1)?;
+    let norms = (a.sqr()?.sum(1)? * b.sqr()?.sum(1)?)?.sqrt()?;
+    (dot / (norms + 1e-8)?)?.mean_all()
+}
+
+/// An embedder that maps the embeddings of another embedder into a different vector space with an [`EmbeddingAdapter`].
+pub struct AdaptedEmbedder<E: Embedder, S: VectorSpace> {
+    embedder: E,
+    adapter: EmbeddingAdapter<E::VectorSpace, S>,
+}
+
+impl<E: Embedder, S: VectorSpace> AdaptedEmbedder<E, S> {
+    /// Get a reference to the underlying embedder.
+    pub fn get_embedder(&self) -> &E {
+        &self.embedder
+    }
+
+    /// Get a reference to the adapter.
+    pub fn adapter(&self) -> &EmbeddingAdapter<E::VectorSpace, S> {
+        &self.adapter
+    }
+}
+
+impl<E: Embedder, S: VectorSpace> Embedder for AdaptedEmbedder<E, S> {
+    type VectorSpace = S;
+
+    fn embed_for(&self, input: EmbeddingInput) -> BoxedFuture<'_, anyhow::Result<Embedding<S>>> {
+        Box::pin(async move {
+            let embedding = self.embedder.embed_for(input).await?;
+            self.adapter.adapt(&embedding)
+        })
+    }
+
+    fn embed_vec(&self, inputs: Vec<String>) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<S>>>> {
+        self.embed_vec_for(
+            inputs
+                .into_iter()
+                .map(|text| EmbeddingInput::new(text, EmbeddingVariant::Document))
+                .collect(),
+        )
+    }
+
+    fn embed_vec_for(
+        &self,
+        inputs: Vec<EmbeddingInput>,
+    ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<S>>>> {
+        Box::pin(async move {
+            let embeddings = self.embedder.embed_vec_for(inputs).await?;
+            self.adapter.adapt_batch(&embeddings)
+        })
+    }
+}
+
+#[test]
+fn adapter_learns_a_permutation() {
+    use kalosm_language_model::UnknownVectorSpace;
+
+    let mut rng = rand::thread_rng();
+    let mut dataset =
+        EmbeddingAdapterDatasetBuilder::<UnknownVectorSpace, UnknownVectorSpace>::new();
+    for _ in 0..200 {
+        let input: Vec<f32> = (0..4).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let target = [input[1], -input[0], input[3], input[2]];
+        dataset.add(&Embedding::from(input), &Embedding::from(target));
+    }
+    let dataset = dataset.build(&Device::Cpu).unwrap();
+
+    let mut adapter = EmbeddingAdapter::new(&Device::Cpu);
+    let similarity = adapter.train(&dataset, 100, 0.05, 16).unwrap();
+    assert!(similarity > 0.95, "similarity {similarity}");
+
+    let adapted = adapter
+        .adapt(&Embedding::from([1.0, 0.0, 0.0, 0.0]))
+        .unwrap()
+        .to_vec();
+    assert!(adapted[1] < -0.5, "{adapted:?}");
+}
@@ -7,7 +7,10 @@
 //!
 //! Supported models:
 //! - [`Classifier`]
+//! - [`EmbeddingAdapter`]
 
+mod adapter;
+pub use adapter::*;
 mod classifier;
 pub use classifier::*;
 pub use kalosm_learning_macro::*;
@@ -307,12 +307,20 @@ impl StructParser {
         };
 
         let ty = &self.ty;
+        let render = self.fields.render(|field| {
+            let ident = field.field.ident.as_ref().unwrap();
+            quote! { &self.#ident }
+        });
 
         quote! {
             impl kalosm_sample::Parse for #ty {
                 fn new_parser() -> impl kalosm_sample::SendCreateParserState<Output = Self> {
                     #parser
                 }
+
+                fn to_parse_string(&self) -> Option<String> {
+                    #render
+                }
             }
         }
     }
@@ -368,12 +376,20 @@ fn quote_fields(fields: Fields) -> TokenStream2 {
 
 fn impl_unit_parser(attrs: &[syn::Attribute], ty: &Ident, construct: TokenStream2) -> TokenStream2 {
     let unit_parser = unit_parser(attrs, ty);
+    let ty_string = match unit_parse_literal(attrs, ty, false) {
+        Ok(ty_string) => ty_string,
+        Err(err) => return err.to_compile_error(),
+    };
     quote! {
         impl kalosm_sample::Parse for #ty {
             fn new_parser() -> impl kalosm_sample::SendCreateParserState<Output = Self> {
                 #unit_parser
                     .map_output(|_| #construct)
             }
+
+            fn to_parse_string(&self) -> Option<String> {
+                Some(#ty_string.to_string())
+            }
         }
     }
 }
@@ -506,6 +522,11 @@ impl EnumParser {
 
         let struct_start = format!("{{ \"{tag}\": \"");
 
+        let render_variants = self
+            .variants
+            .iter()
+            .map(|variant| variant.render(&struct_start, content));
+
         Ok(quote! {
             impl kalosm_sample::Parse for #ty {
                 fn new_parser() -> impl kalosm_sample::SendCreateParserState<Output = Self> {
@@ -513,6 +534,12 @@ impl EnumParser {
                         .ignore_output_then(#parser)
                         .then_literal(r#" }"#)
                 }
+
+                fn to_parse_string(&self) -> Option<String> {
+                    match self {
+                        #(#render_variants)*
+                    }
+                }
             }
         })
     }
@@ -619,6 +646,40 @@ impl EnumVariant {
         }
     }
 
+    /// Render a match arm that renders this variant in the format of the parser
+    fn render(&self, struct_start: &str, content_name: &str) -> TokenStream2 {
+        let pattern = self.construct_variant();
+        let variant_name = &self.name;
+        let render = match &self.ty {
+            EnumVariantType::Unit(_) => {
+                let rendered = format!("{struct_start}{variant_name}\" }}");
+                quote! { Some(#rendered.to_string()) }
+            }
+            EnumVariantType::Struct(parser) => {
+                let start = format!("{struct_start}{variant_name}\", \"{content_name}\": ");
+                let fields = parser.fields.render(|field| {
+                    let ident = field.field.ident.as_ref().unwrap();
+                    quote! { #ident }
+                });
+                quote! {
+                    {
+                        let fields = { #fields }?;
+                        Some(format!("{}{} }}", #start, fields))
+                    }
+                }
+            }
+            EnumVariantType::Tuple(_) => {
+                let start = format!("{struct_start}{variant_name}\", \"{content_name}\": ");
+                quote! {
+                    Some(format!("{}{} }}", #start, kalosm_sample::Parse::to_parse_string(data0)?))
+                }
+            }
+        };
+        quote! {
+            #pattern => #render,
+        }
+    }
+
     fn quote_schema(
         &self,
         tag: &str,
@@ -815,6 +876,7 @@ fn unit_enum_parser(attrs: Vec<syn::Attribute>, data: DataEnum, ty: Ident) -> To
     }
 
     let mut parse_construction_map = HashMap::new();
+    let mut render_variants = Vec::new();
     for variant in data.variants.iter() {
         let variant_name = &variant.ident;
         let fields = &variant.fields;
@@ -825,6 +887,9 @@ fn unit_enum_parser(attrs: Vec<syn::Attribute>, data: DataEnum, ty: Ident) -> To
             Ok(literal_string) => literal_string,
             Err(err) => return err.to_compile_error(),
         };
+        render_variants.push(quote! {
+            #construct_variant => Some(#literal_string.to_string()),
+        });
         parse_construction_map.insert(literal_string.as_bytes().to_vec(), construct_variant);
     }
 
@@ -978,6 +1043,12 @@ fn unit_enum_parser(attrs: Vec<syn::Attribute>, data: DataEnum, ty: Ident) -> To
 
                 #parser
             }
+
+            fn to_parse_string(&self) -> Option<String> {
+                match self {
+                    #(#render_variants)*
+                }
+            }
         }
     }
 }
@@ -1117,6 +1188,25 @@ impl FieldsParser {
         })
     }
 
+    /// Render an expression that renders the fields in the format of the parser. `value` returns a reference to the value of a field.
+    fn render(&self, value: impl Fn(&FieldParser) -> TokenStream2) -> TokenStream2 {
+        let fields = self.fields.iter().enumerate().map(|(i, field)| {
+            let separator = if i == 0 { "{ " } else { ", " };
+            let key = format!("{separator}\"{}\": ", field.name);
+            let render = field.parser.render(value(field));
+            quote! {
+                rendered.push_str(#key);
+                rendered.push_str(&#render);
+            }
+        });
+        quote! {
+            let mut rendered = String::new();
+            #(#fields)*
+            rendered.push_str(" }");
+            Some(rendered)
+        }
+    }
+
     fn quote_schema(&self) -> proc_macro2::TokenStream {
         let properties = self.fields.iter().map(|field| field.quote_schema());
         quote! {
@@ -1321,6 +1411,19 @@ impl Parser {
         attributes
     }
 
+    /// Render an expression that renders a reference to a value of this type. The expression returns early with `None` if the value can't be rendered.
+    fn render(&self, value: TokenStream2) -> TokenStream2 {
+        match &self.ty {
+            // Numbers and booleans are parsed in the format of their display implementation
+            ParserType::Number(_) | ParserType::Integer(_) | ParserType::Boolean(_) => {
+                quote! { ToString::to_string(#value) }
+            }
+            ParserType::String(_) | ParserType::Custom(_) => quote! {
+                kalosm_sample::Parse::to_parse_string(#value)?
+            },
+        }
+    }
+
     fn quote_schema(&self) -> proc_macro2::TokenStream {
         if let Some(schema) = &self.schema {
             return schema.clone();
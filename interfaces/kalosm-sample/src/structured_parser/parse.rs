@@ -36,7 +36,7 @@ use crate::{
 /// struct MyStruct(i64, String);
 ///
 /// impl Parse for MyStruct {
-///     // The only method on parse is new_parser, which returns a parser that outputs the current type
+///     // The only required method on parse is new_parser, which returns a parser that outputs the current type
 ///     fn new_parser() -> impl kalosm_sample::SendCreateParserState<Output = Self> {
 ///         let number_parser = i64::new_parser();
 ///         let string_parser = StringParser::new(0..=usize::MAX);
@@ -47,17 +47,33 @@ use crate::{
 ///             .then_literal(")")
 ///             .map_output(|(a, b)| Self(a, b))
 ///     }
+///
+///     // You can also render the type in the same format to use it in examples
+///     fn to_parse_string(&self) -> Option<String> {
+///         Some(format!("MyStruct({}, {})", self.0, self.1.to_parse_string()?))
+///     }
 /// }
 /// ```
 pub trait Parse: Clone + Send + Sync {
     /// Create a new parser that parses the current type and can be sent between threads.
     fn new_parser() -> impl SendCreateParserState<Output = Self>;
+
+    /// Render the value in the format the parser from [`Parse::new_parser`] parses. Returns `None` if the type doesn't support rendering.
+    ///
+    /// `#[derive(Parse)]` implements this method for you.
+    fn to_parse_string(&self) -> Option<String> {
+        None
+    }
 }
 
 impl<T: Parse> Parse for Box<T> {
     fn new_parser() -> impl SendCreateParserState<Output = Self> {
         T::new_parser().map_output(Box::new)
     }
+
+    fn to_parse_string(&self) -> Option<String> {
+        (**self).to_parse_string()
+    }
 }
 
 macro_rules! int_parser {
@@ -118,6 +134,10 @@ macro_rules! int_parser {
             fn new_parser() -> impl SendCreateParserState<Output = Self> {
                 $ty::default()
             }
+
+            fn to_parse_string(&self) -> Option<String> {
+                Some(self.to_string())
+            }
         }
 
         #[test]
@@ -156,6 +176,33 @@ impl Parse for String {
     fn new_parser() -> impl SendCreateParserState<Output = Self> {
         StringParser::new(0..=usize::MAX)
     }
+
+    fn to_parse_string(&self) -> Option<String> {
+        // The string parser reads the character after a backslash literally
+        let mut rendered = String::with_capacity(self.len() + 2);
+        rendered.push('"');
+        for char in self.chars() {
+            if matches!(char, '"' | '\\') {
+                rendered.push('\\');
+            }
+            rendered.push(char);
+        }
+        rendered.push('"');
+        Some(rendered)
+    }
+}
+
+/// Render a list of items in the format of the [`Vec`] and array parsers.
+fn render_list<'a, T: Parse + 'a>(items: impl IntoIterator<Item = &'a T>) -> Option<String> {
+    let mut rendered = String::from("[");
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            rendered.push_str(", ");
+        }
+        rendered.push_str(&item.to_parse_string()?);
+    }
+    rendered.push(']');
+    Some(rendered)
 }
 
 impl<T: Parse + Clone + Send + Sync> Parse for std::vec::Vec<T> {
@@ -169,6 +216,10 @@ impl<T: Parse + Clone + Send + Sync> Parse for std::vec::Vec<T> {
         )
         .map_output(|((), (outputs, ()))| outputs)
     }
+
+    fn to_parse_string(&self) -> Option<String> {
+        render_list(self)
+    }
 }
 
 impl<const N: usize, T: Parse + Clone + Send + Sync> Parse for [T; N] {
@@ -186,6 +237,10 @@ impl<const N: usize, T: Parse + Clone + Send + Sync> Parse for [T; N] {
                 .unwrap_or_else(|_| panic!("Array is not the correct size"))
         })
     }
+
+    fn to_parse_string(&self) -> Option<String> {
+        render_list(self)
+    }
 }
 
 impl<T: Parse> Parse for Option<T> {
@@ -195,4 +250,36 @@ impl<T: Parse> Parse for Option<T> {
             .map_output(|output| Some(output))
             .or(LiteralParser::new("null").map_output(|_| None))
     }
+
+    fn to_parse_string(&self) -> Option<String> {
+        match self {
+            Some(value) => value.to_parse_string(),
+            None => Some("null".to_string()),
+        }
+    }
+}
+
+#[test]
+fn render_parse_strings() {
+    assert_eq!(
+        "say \"hi\" \\ wave".to_string().to_parse_string().unwrap(),
+        r#""say \"hi\" \\ wave""#
+    );
+    assert_eq!(
+        vec![Some(1u8), None].to_parse_string().unwrap(),
+        "[1, null]"
+    );
+
+    // Rendered values parse back into the same value
+    let value = vec!["a \"quote\"".to_string(), String::new()];
+    let rendered = value.to_parse_string().unwrap();
+    let parser = Vec::<String>::new_parser();
+    let state = parser.create_parser_state();
+    assert_eq!(
+        parser
+            .parse(&state, rendered.as_bytes())
+            .unwrap()
+            .unwrap_finished(),
+        value
+    );
 }
@@ -101,4 +101,8 @@ impl<const MIN_LENGTH: usize, const MAX_LENGTH: usize> Parse for Sentence<MIN_LE
     fn new_parser() -> impl SendCreateParserState<Output = Self> {
         SentenceParser::default()
     }
+
+    fn to_parse_string(&self) -> Option<String> {
+        self.0.to_parse_string()
+    }
 }
@@ -98,4 +98,8 @@ impl<const MIN_LENGTH: usize, const MAX_LENGTH: usize> Parse for Word<MIN_LENGTH
     fn new_parser() -> impl SendCreateParserState<Output = Self> {
         WordParser::default()
     }
+
+    fn to_parse_string(&self) -> Option<String> {
+        self.0.to_parse_string()
+    }
 }
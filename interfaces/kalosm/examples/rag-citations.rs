@@ -0,0 +1,57 @@
+use kalosm::language::*;
+use surrealdb::{engine::local::RocksDb, Surreal};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let exists = std::path::Path::new("./db").exists();
+
+    // Create database connection
+    let db = Surreal::new::<RocksDb>("./db/temp.db").await?;
+
+    // Select a specific namespace / database
+    db.use_ns("test").use_db("test").await?;
+
+    // Create a table in the surreal database to store the embeddings
+    let document_table = db
+        .document_table_builder("documents")
+        .with_chunker(SemanticChunker::new())
+        .at("./db/embeddings.db")
+        .build::<Document>()
+        .await?;
+
+    // If the database is new, add documents to it
+    if !exists {
+        let context = [
+            "https://floneum.com/kalosm/docs",
+            "https://floneum.com/kalosm/docs/guides/retrieval_augmented_generation",
+        ]
+        .iter()
+        .map(|url| Url::parse(url).unwrap());
+
+        document_table.add_context(context).await?;
+    }
+
+    // Create a llama chat model
+    let model = Llama::new_chat().await?;
+
+    loop {
+        // Ask the user for a question
+        let user_question = prompt_input("\n> ")?;
+
+        // Answer the question with the nearest chunks and cite the chunks the answer uses
+        let answer = document_table
+            .answer_with_citations(&user_question, 5, &model)
+            .await?;
+
+        println!("Bot: {}", answer.answer);
+        for citation in answer.citations {
+            println!(
+                "[{}] {:?} bytes {:?}: {}",
+                citation.source + 1,
+                citation.record_id,
+                citation.byte_range,
+                citation.quote
+            );
+        }
+    }
+}
@@ -17,7 +17,9 @@ pub mod language {
     pub use kalosm_language::kalosm_llama::{Llama, LlamaBuilder, LlamaSession, LlamaSource};
     pub use kalosm_language::kalosm_sample::{self, *};
     pub use kalosm_language::prelude::Html;
-    pub use kalosm_language::rbert::{Bert, BertBuilder, BertSource, BertSpace};
+    pub use kalosm_language::rbert::{
+        Bert, BertBuilder, BertCrossEncoder, BertCrossEncoderBuilder, BertSource, BertSpace,
+    };
     pub use kalosm_language::rphi::{Phi, PhiBuilder, PhiSource};
     pub use kalosm_language::search::*;
     pub use kalosm_language::task::*;
@@ -25,8 +27,14 @@ pub mod language {
     pub use kalosm_language::vector_db::*;
     pub use kalosm_streams::text_stream::*;
 
+    #[cfg(feature = "surrealdb")]
+    pub use crate::surrealdb_integration::citations::{Citation, CitedAnswer};
     #[cfg(feature = "surrealdb")]
     pub use crate::surrealdb_integration::document_table::*;
+    #[cfg(feature = "surrealdb")]
+    pub use crate::surrealdb_integration::sync::FolderSyncReport;
+    #[cfg(feature = "surrealdb")]
+    pub use crate::surrealdb_integration::{FilterField, FilterOperator, RecordFilter};
 }
 #[cfg(feature = "sound")]
 pub mod sound {
@@ -0,0 +1,159 @@
+use std::ops::Range;
+
+use kalosm_language::prelude::*;
+use surrealdb::sql::Id;
+
+use super::EmbeddingIndexedTableSearchResult;
+
+pub(crate) const CITATION_TASK_DESCRIPTION: &str = "You answer questions using only the numbered sources the user gives you. After every claim, cite the sources that support it with their number in square brackets like [1]. Your answer may span several lines. You end your answer with an empty line.";
+
+/// A question answered from the chunks of a [`DocumentTable`](crate::language::DocumentTable) with citations back to the chunks.
+#[derive(Debug, Clone)]
+pub struct CitedAnswer<R> {
+    /// The answer with `[n]` citation markers.
+    pub answer: String,
+    /// The citations in the order their markers appear in the answer.
+    pub citations: Vec<Citation>,
+    /// The chunks the model was given. Source `[n]` is `sources[n - 1]`.
+    pub sources: Vec<EmbeddingIndexedTableSearchResult<R>>,
+}
+
+/// A `[n]` citation marker in an answer resolved to the chunk it refers to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Citation {
+    /// The index of the cited chunk in [`CitedAnswer::sources`].
+    pub source: usize,
+    /// The byte range of the marker in the answer.
+    pub marker_range: Range<usize>,
+    /// The id of the record the chunk is from.
+    pub record_id: Id,
+    /// The byte range of the chunk in the record.
+    pub byte_range: Range<usize>,
+    /// The text of the chunk.
+    pub quote: String,
+}
+
+/// Format the question with the sources numbered from 1.
+pub(crate) fn citation_prompt<R: AsRef<Document>>(
+    question: &str,
+    sources: &[EmbeddingIndexedTableSearchResult<R>],
+) -> String {
+    let mut prompt = String::new();
+    for (index, source) in sources.iter().enumerate() {
+        let document = source.record.as_ref();
+        prompt += &format!(
+            "[{}] Title: {}\n{}\n\n",
+            index + 1,
+            document.title(),
+            document.body()[source.byte_range.clone()].trim()
+        );
+    }
+    prompt += &format!("Question: {question}");
+    prompt
+}
+
+/// Create a parser for answers that only cite sources between 1 and `sources`. Every answer must cite at least one source. Answers may contain single new lines and end with an empty line.
+pub(crate) fn citation_parser(sources: usize) -> anyhow::Result<RegexParser> {
+    if sources == 0 {
+        anyhow::bail!("No sources to cite");
+    }
+    let markers = (1..=sources)
+        .map(|source| source.to_string())
+        .collect::<Vec<_>>()
+        .join("|");
+    // Any text without brackets or empty lines
+    let text = r"(?:[^\[\]\n]|\n[^\[\]\n])";
+    let regex = format!(r"(?:{text}+(?:\[(?:{markers})\])+)+{text}*\n\n");
+    Ok(RegexParser::new(&regex)?)
+}
+
+/// Find the `[n]` markers in the answer and resolve them to the sources they cite. Markers that don't refer to a source are ignored.
+pub(crate) fn resolve_citations<R: AsRef<Document>>(
+    answer: &str,
+    sources: &[EmbeddingIndexedTableSearchResult<R>],
+) -> Vec<Citation> {
+    let mut citations = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = answer[offset..].find('[').map(|start| start + offset) {
+        let Some(end) = answer[start..].find(']').map(|end| end + start + 1) else {
+            break;
+        };
+        offset = start + 1;
+        let Ok(number) = answer[start + 1..end - 1].parse::<usize>() else {
+            continue;
+        };
+        let Some(source) = number.checked_sub(1).and_then(|index| sources.get(index)) else {
+            continue;
+        };
+        citations.push(Citation {
+            source: number - 1,
+            marker_range: start..end,
+            record_id: source.record_id.clone(),
+            byte_range: source.byte_range.clone(),
+            quote: source.record.as_ref().body()[source.byte_range.clone()].to_string(),
+        });
+        offset = end;
+    }
+    citations
+}
+
+#[cfg(test)]
+fn test_sources() -> Vec<EmbeddingIndexedTableSearchResult<Document>> {
+    let document = Document::from_parts(
+        "Kalosm",
+        "Kalosm is a library for local AI. It runs models on your own hardware.",
+    );
+    [0..33, 34..70]
+        .into_iter()
+        .enumerate()
+        .map(|(index, byte_range)| EmbeddingIndexedTableSearchResult {
+            distance: 0.0,
+            id: EmbeddingId(index as u32),
+            record_id: Id::from("kalosm"),
+            byte_range,
+            record: document.clone(),
+        })
+        .collect()
+}
+
+#[test]
+fn resolves_citation_markers() {
+    let sources = test_sources();
+    let answer =
+        "Kalosm is a local AI library [1][2]. It has [3] sources, [x] and runs locally [2].";
+    let citations = resolve_citations(answer, &sources);
+
+    let markers: Vec<_> = citations
+        .iter()
+        .map(|citation| &answer[citation.marker_range.clone()])
+        .collect();
+    assert_eq!(markers, ["[1]", "[2]", "[2]"]);
+    let cited: Vec<_> = citations.iter().map(|citation| citation.source).collect();
+    assert_eq!(cited, [0, 1, 1]);
+    assert_eq!(citations[0].quote, "Kalosm is a library for local AI.");
+    assert_eq!(citations[1].quote, "It runs models on your own hardware.");
+    assert_eq!(citations[1].byte_range, 34..70);
+    assert_eq!(citations[1].record_id, Id::from("kalosm"));
+}
+
+#[test]
+fn citation_parser_accepts_cited_answers() {
+    let parser = citation_parser(2).unwrap();
+    let parse = |answer: &str| {
+        parser
+            .parse(&parser.create_parser_state(), answer.as_bytes())
+            .map(|status| match status {
+                ParseStatus::Finished { result, .. } => Some(result),
+                ParseStatus::Incomplete { .. } => None,
+            })
+    };
+
+    let answer = "Kalosm is a library [1].\nIt runs locally [1][2].\n\n";
+    assert_eq!(parse(answer).unwrap().as_deref(), Some(answer));
+    // A single new line doesn't end the answer
+    assert_eq!(parse("Kalosm is a library [1].\n").unwrap(), None);
+    // Sources that don't exist and answers without citations are rejected
+    assert!(parse("Kalosm is a library [3].").is_err());
+    assert!(parse("Kalosm is a library.\n\n").is_err());
+    assert!(citation_parser(0).is_err());
+}
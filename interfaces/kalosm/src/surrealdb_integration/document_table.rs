@@ -1,7 +1,13 @@
 use std::any::Any;
 use std::any::TypeId;
 
-use super::{EmbeddingIndexedTable, EmbeddingIndexedTableSearchResult};
+use super::citations::{
+    citation_parser, citation_prompt, resolve_citations, CitedAnswer, CITATION_TASK_DESCRIPTION,
+};
+use super::{
+    EmbeddingIndexedTable, EmbeddingIndexedTableSearchResult, HybridSearchConfig,
+    HybridSearchResult, RecordFilter,
+};
 use kalosm_language::prelude::*;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
@@ -106,6 +112,8 @@ impl<C: Connection, R, M: Embedder, K: Chunker> DocumentTable<C, R, M, K> {
     }
 
     /// Insert a new record into the table with pre-computed chunks.
+    ///
+    /// The chunks are not added to the keyword index. Use [`DocumentTable::insert_document_with_chunks`] to make the record searchable with [`DocumentTable::select_hybrid`].
     pub async fn insert_with_chunks(
         &self,
         value: R,
@@ -117,6 +125,19 @@ impl<C: Connection, R, M: Embedder, K: Chunker> DocumentTable<C, R, M, K> {
         self.table.insert(chunks, value).await
     }
 
+    /// Insert a new document into the table with pre-computed chunks. The text of each chunk is added to the keyword index.
+    pub async fn insert_document_with_chunks(
+        &self,
+        value: R,
+        chunks: impl IntoIterator<Item = Chunk<M::VectorSpace>>,
+    ) -> anyhow::Result<Id>
+    where
+        R: AsRef<Document> + Serialize + DeserializeOwned,
+    {
+        let text = value.as_ref().body().to_string();
+        self.table.insert_with_text(chunks, value, &text).await
+    }
+
     /// Insert a new record into the table and return the id of the record.
     pub async fn insert(&self, value: R) -> anyhow::Result<Id>
     where
@@ -126,7 +147,7 @@ impl<C: Connection, R, M: Embedder, K: Chunker> DocumentTable<C, R, M, K> {
             .chunker
             .chunk(value.as_ref(), &self.embedding_model)
             .await?;
-        self.insert_with_chunks(value, chunks).await
+        self.insert_document_with_chunks(value, chunks).await
     }
 
     /// Extend the table with a iterator of new records.
@@ -143,13 +164,15 @@ impl<C: Connection, R, M: Embedder, K: Chunker> DocumentTable<C, R, M, K> {
             .await?;
         let mut ids = Vec::new();
         for (value, embeddings) in entries.into_iter().zip(embeddings) {
-            let id = self.table.insert(embeddings, value).await?;
+            let id = self.insert_document_with_chunks(value, embeddings).await?;
             ids.push(id);
         }
         Ok(ids)
     }
 
     /// Update a record in the table with the given embedding id.
+    ///
+    /// The chunks of the record are not changed. Use [`DocumentTable::update_document`] to re-chunk the new value.
     pub async fn update(&self, id: Id, value: R) -> anyhow::Result<Option<R>>
     where
         R: Serialize + DeserializeOwned,
@@ -157,6 +180,21 @@ impl<C: Connection, R, M: Embedder, K: Chunker> DocumentTable<C, R, M, K> {
         self.table.update(id, value).await
     }
 
+    /// Update a document in the table with the given embedding id. The new value is re-chunked and the embeddings and keywords of the old chunks are replaced.
+    pub async fn update_document(&self, id: Id, value: R) -> anyhow::Result<Option<R>>
+    where
+        R: AsRef<Document> + Serialize + DeserializeOwned,
+    {
+        let chunks = self
+            .chunker
+            .chunk(value.as_ref(), &self.embedding_model)
+            .await?;
+        let text = value.as_ref().body().to_string();
+        self.table
+            .update_with_chunks(id, chunks, value, &text)
+            .await
+    }
+
     /// Select a record from the table with the given embedding id.
     pub async fn select(&self, id: Id) -> anyhow::Result<R>
     where
@@ -195,6 +233,246 @@ impl<C: Connection, R, M: Embedder, K: Chunker> DocumentTable<C, R, M, K> {
         let embedding = embedding.into_embedding(&self.embedding_model).await?;
         self.table.select_nearest(embedding, k).await
     }
+
+    /// Select the top k records nearest to the given item from the records that match a [`RecordFilter`]. The filter is applied before the nearest neighbor search, so filtering out most of the table still returns up to k results.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm::language::*;
+    /// use surrealdb::{engine::local::RocksDb, Surreal};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let db = Surreal::new::<RocksDb>("./db/temp.db").await.unwrap();
+    ///     db.use_ns("rag").use_db("rag").await.unwrap();
+    ///     let document_table = db
+    ///         .document_table_builder("documents")
+    ///         .at("./db/embeddings.db")
+    ///         .build::<Document>()
+    ///         .await
+    ///         .unwrap();
+    ///
+    ///     let faq = RecordFilter::field("title").contains("FAQ");
+    ///     let nearest = document_table
+    ///         .select_nearest_where("How do I reset my password?", 5, &faq)
+    ///         .await
+    ///         .unwrap();
+    ///     println!("{:?}", nearest);
+    /// }
+    /// ```
+    pub async fn select_nearest_where(
+        &self,
+        embedding: impl IntoEmbedding<M::VectorSpace>,
+        k: usize,
+        filter: &RecordFilter,
+    ) -> anyhow::Result<Vec<EmbeddingIndexedTableSearchResult<R>>>
+    where
+        R: DeserializeOwned,
+    {
+        let embedding = embedding.into_embedding(&self.embedding_model).await?;
+        self.table.select_nearest_where(embedding, k, filter).await
+    }
+
+    /// Select the `candidates` nearest chunks to the query, then rerank them with a [`Reranker`] and return the top k. Rerankers like [`BertCrossEncoder`] read the query and chunk together, so they are more precise than the distance between embeddings.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm::language::*;
+    /// use surrealdb::{engine::local::RocksDb, Surreal};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let db = Surreal::new::<RocksDb>("./db/temp.db").await.unwrap();
+    ///     db.use_ns("rag").use_db("rag").await.unwrap();
+    ///     let document_table = db
+    ///         .document_table_builder("documents")
+    ///         .at("./db/embeddings.db")
+    ///         .build::<Document>()
+    ///         .await
+    ///         .unwrap();
+    ///     let reranker = BertCrossEncoder::new().await.unwrap();
+    ///
+    ///     let results = document_table
+    ///         .select_reranked("What is Kalosm?", 5, 25, &reranker)
+    ///         .await
+    ///         .unwrap();
+    ///     for result in results {
+    ///         println!("{}: {}", result.score, result.item.text());
+    ///     }
+    /// }
+    /// ```
+    pub async fn select_reranked(
+        &self,
+        query: &str,
+        k: usize,
+        candidates: usize,
+        reranker: &(impl Reranker + Sync),
+    ) -> anyhow::Result<Vec<Reranked<EmbeddingIndexedTableSearchResult<R>>>>
+    where
+        R: AsRef<Document> + DeserializeOwned + Send,
+    {
+        let embedding = self.embedding_model.embed_query(query).await?;
+        let nearest = self
+            .table
+            .select_nearest(embedding, candidates.max(k))
+            .await?;
+        let mut reranked = reranker.rerank(query, nearest).await?;
+        reranked.truncate(k);
+        Ok(reranked)
+    }
+
+    /// Select the k nearest chunks to the query, then compress them down to the sentences the [`ContextExtractor`] keeps. The [`ExtractedSentence::chunk_index`] of each sentence is the index of the search result it came from, so the record id and byte range can be used to cite the source.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm::language::*;
+    /// use surrealdb::{engine::local::RocksDb, Surreal};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let db = Surreal::new::<RocksDb>("./db/temp.db").await.unwrap();
+    ///     db.use_ns("rag").use_db("rag").await.unwrap();
+    ///     let document_table = db
+    ///         .document_table_builder("documents")
+    ///         .at("./db/embeddings.db")
+    ///         .build::<Document>()
+    ///         .await
+    ///         .unwrap();
+    ///     let llm = Llama::new_chat().await.unwrap();
+    ///     let extractor = ContextExtractor::new(LlmSentenceFilter::new(&llm));
+    ///
+    ///     let (results, context) = document_table
+    ///         .select_context("What is Kalosm?", 5, &extractor)
+    ///         .await
+    ///         .unwrap();
+    ///     for sentence in context.sentences() {
+    ///         let source = &results[sentence.chunk_index];
+    ///         println!("{} ({:?} {:?})", sentence.text, source.record_id, sentence.byte_range);
+    ///     }
+    /// }
+    /// ```
+    pub async fn select_context<F: SentenceFilter + Sync>(
+        &self,
+        query: &str,
+        k: usize,
+        extractor: &ContextExtractor<F>,
+    ) -> anyhow::Result<(Vec<EmbeddingIndexedTableSearchResult<R>>, ExtractedContext)>
+    where
+        R: AsRef<Document> + DeserializeOwned + Send + Sync,
+    {
+        let embedding = self.embedding_model.embed_query(query).await?;
+        let nearest = self.table.select_nearest(embedding, k).await?;
+        let context = extractor.extract(query, &nearest).await?;
+        Ok((nearest, context))
+    }
+
+    /// Answer a question with the k nearest chunks and cite the chunks the answer is based on. The chunks are numbered in the prompt and the model is constrained to cite them with `[n]` markers. The markers are resolved to the record id, byte range and text of the chunk they cite.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm::language::*;
+    /// use surrealdb::{engine::local::RocksDb, Surreal};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let db = Surreal::new::<RocksDb>("./db/temp.db").await.unwrap();
+    ///     db.use_ns("rag").use_db("rag").await.unwrap();
+    ///     let document_table = db
+    ///         .document_table_builder("documents")
+    ///         .at("./db/embeddings.db")
+    ///         .build::<Document>()
+    ///         .await
+    ///         .unwrap();
+    ///     let llm = Llama::new_chat().await.unwrap();
+    ///
+    ///     let answer = document_table
+    ///         .answer_with_citations("What is Kalosm?", 5, &llm)
+    ///         .await
+    ///         .unwrap();
+    ///     println!("{}", answer.answer);
+    ///     for citation in answer.citations {
+    ///         println!(
+    ///             "[{}] {:?} {:?}: {}",
+    ///             citation.source + 1,
+    ///             citation.record_id,
+    ///             citation.byte_range,
+    ///             citation.quote
+    ///         );
+    ///     }
+    /// }
+    /// ```
+    pub async fn answer_with_citations<L: Model>(
+        &self,
+        question: &str,
+        k: usize,
+        model: &L,
+    ) -> anyhow::Result<CitedAnswer<R>>
+    where
+        R: AsRef<Document> + DeserializeOwned + Send,
+        <L::SyncModel as SyncModel>::Session: Send + Sync,
+    {
+        let embedding = self.embedding_model.embed_query(question).await?;
+        let sources = self.table.select_nearest(embedding, k).await?;
+        if sources.is_empty() {
+            anyhow::bail!("No chunks found to answer the question with");
+        }
+
+        let task = Task::builder(CITATION_TASK_DESCRIPTION)
+            .with_constraints(citation_parser(sources.len())?)
+            .build();
+        let answer = task
+            .run(citation_prompt(question, &sources), model)
+            .await?
+            .trim_end()
+            .to_string();
+        let citations = resolve_citations(&answer, &sources);
+
+        Ok(CitedAnswer {
+            answer,
+            citations,
+            sources,
+        })
+    }
+
+    /// Select the top k chunks that best match the query, combining vector search with keyword search. Keyword search helps with exact matches like names, error codes, or product ids that embeddings tend to miss.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm::{language::*, HybridSearchConfig};
+    /// use surrealdb::{engine::local::RocksDb, Surreal};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let db = Surreal::new::<RocksDb>("./db/temp.db").await.unwrap();
+    ///     db.use_ns("rag").use_db("rag").await.unwrap();
+    ///     let document_table = db
+    ///         .document_table_builder("documents")
+    ///         .at("./db/embeddings.db")
+    ///         .build::<Document>()
+    ///         .await
+    ///         .unwrap();
+    ///
+    ///     let results = document_table
+    ///         .select_hybrid("error E1042", 5, HybridSearchConfig::new().with_keyword_weight(2.0))
+    ///         .await
+    ///         .unwrap();
+    ///     for result in results {
+    ///         println!("{}: {}", result.score, result.text());
+    ///     }
+    /// }
+    /// ```
+    pub async fn select_hybrid(
+        &self,
+        query: &str,
+        k: usize,
+        config: HybridSearchConfig,
+    ) -> anyhow::Result<Vec<HybridSearchResult<R>>>
+    where
+        R: DeserializeOwned,
+    {
+        let embedding = self.embedding_model.embed_query(query).await?;
+        self.table.select_hybrid(embedding, query, k, config).await
+    }
 }
 
 impl<C: Connection, R, M: Embedder, K: Chunker> DocumentTable<C, R, M, K> {
@@ -271,12 +549,7 @@ impl<C: Connection, E, K: Chunker> DocumentTableBuilder<C, E, K> {
         } else {
             VectorDB::new()?
         };
-        let table = EmbeddingIndexedTable {
-            table: self.table.to_string(),
-            db: self.db,
-            vector_db,
-            phantom: std::marker::PhantomData,
-        };
+        let table = EmbeddingIndexedTable::new(self.table.to_string(), self.db, vector_db);
         let embedding_model = match self.embedding_model {
             Some(embedding_model) => embedding_model,
             None => {
@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use surrealdb::sql::Value;
+
+/// A filter on the fields of the records in an [`EmbeddingIndexedTable`](crate::EmbeddingIndexedTable).
+///
+/// Values in the filter are always sent to the database as bound query parameters, so they are never parsed as SurrealQL. Field names are checked when the query is built and must be identifiers optionally separated by dots, like `title` or `metadata.author`.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm::RecordFilter;
+///
+/// let filter = RecordFilter::field("title")
+///     .contains("FAQ")
+///     .and(RecordFilter::field("year").gte(2020));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordFilter {
+    /// Compare a field of the record with a value
+    Compare {
+        /// The path of the field
+        field: String,
+        /// The comparison to use
+        operator: FilterOperator,
+        /// The value to compare the field with
+        value: Value,
+    },
+    /// Match records that match both filters
+    And(Box<RecordFilter>, Box<RecordFilter>),
+    /// Match records that match either filter
+    Or(Box<RecordFilter>, Box<RecordFilter>),
+    /// Match records that don't match the filter
+    Not(Box<RecordFilter>),
+}
+
+/// A comparison between a field and a value in a [`RecordFilter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterOperator {
+    /// The field is equal to the value
+    Equal,
+    /// The field is not equal to the value
+    NotEqual,
+    /// The field is less than the value
+    LessThan,
+    /// The field is less than or equal to the value
+    LessThanOrEqual,
+    /// The field is greater than the value
+    GreaterThan,
+    /// The field is greater than or equal to the value
+    GreaterThanOrEqual,
+    /// The field (a string or array) contains the value
+    Contains,
+    /// The field is one of the items in the value (an array)
+    Inside,
+}
+
+impl FilterOperator {
+    fn as_surql(&self) -> &'static str {
+        match self {
+            Self::Equal => "=",
+            Self::NotEqual => "!=",
+            Self::LessThan => "<",
+            Self::LessThanOrEqual => "<=",
+            Self::GreaterThan => ">",
+            Self::GreaterThanOrEqual => ">=",
+            Self::Contains => "CONTAINS",
+            Self::Inside => "INSIDE",
+        }
+    }
+}
+
+/// A field of a record used to build a [`RecordFilter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterField {
+    field: String,
+}
+
+impl FilterField {
+    fn compare(self, operator: FilterOperator, value: impl Into<Value>) -> RecordFilter {
+        RecordFilter::Compare {
+            field: self.field,
+            operator,
+            value: value.into(),
+        }
+    }
+
+    /// Match records where the field is equal to the value.
+    pub fn eq(self, value: impl Into<Value>) -> RecordFilter {
+        self.compare(FilterOperator::Equal, value)
+    }
+
+    /// Match records where the field is not equal to the value.
+    pub fn ne(self, value: impl Into<Value>) -> RecordFilter {
+        self.compare(FilterOperator::NotEqual, value)
+    }
+
+    /// Match records where the field is less than the value.
+    pub fn lt(self, value: impl Into<Value>) -> RecordFilter {
+        self.compare(FilterOperator::LessThan, value)
+    }
+
+    /// Match records where the field is less than or equal to the value.
+    pub fn lte(self, value: impl Into<Value>) -> RecordFilter {
+        self.compare(FilterOperator::LessThanOrEqual, value)
+    }
+
+    /// Match records where the field is greater than the value.
+    pub fn gt(self, value: impl Into<Value>) -> RecordFilter {
+        self.compare(FilterOperator::GreaterThan, value)
+    }
+
+    /// Match records where the field is greater than or equal to the value.
+    pub fn gte(self, value: impl Into<Value>) -> RecordFilter {
+        self.compare(FilterOperator::GreaterThanOrEqual, value)
+    }
+
+    /// Match records where the field (a string or array) contains the value.
+    pub fn contains(self, value: impl Into<Value>) -> RecordFilter {
+        self.compare(FilterOperator::Contains, value)
+    }
+
+    /// Match records where the field is one of the values.
+    pub fn inside(self, values: impl Into<Value>) -> RecordFilter {
+        self.compare(FilterOperator::Inside, values)
+    }
+}
+
+impl RecordFilter {
+    /// Start a filter on a field of the record.
+    pub fn field(field: impl Into<String>) -> FilterField {
+        FilterField {
+            field: field.into(),
+        }
+    }
+
+    /// Match records that match both this filter and the other filter.
+    pub fn and(self, other: RecordFilter) -> Self {
+        Self::And(Box::new(self), Box::new(other))
+    }
+
+    /// Match records that match either this filter or the other filter.
+    pub fn or(self, other: RecordFilter) -> Self {
+        Self::Or(Box::new(self), Box::new(other))
+    }
+
+    /// Match records that don't match this filter.
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Self {
+        Self::Not(Box::new(self))
+    }
+
+    /// Build the SurrealQL condition for this filter. Every value is replaced with a parameter that is added to `params`.
+    pub(crate) fn to_condition(
+        &self,
+        params: &mut BTreeMap<String, Value>,
+    ) -> anyhow::Result<String> {
+        let mut condition = String::new();
+        self.write_condition(&mut condition, params)?;
+        Ok(condition)
+    }
+
+    fn write_condition(
+        &self,
+        condition: &mut String,
+        params: &mut BTreeMap<String, Value>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::Compare {
+                field,
+                operator,
+                value,
+            } => {
+                validate_field(field)?;
+                let param = format!("filter_{}", params.len());
+                write!(condition, "{field} {} ${param}", operator.as_surql())?;
+                params.insert(param, value.clone());
+            }
+            Self::And(first, second) | Self::Or(first, second) => {
+                let joiner = if matches!(self, Self::And(..)) {
+                    "AND"
+                } else {
+                    "OR"
+                };
+                condition.push('(');
+                first.write_condition(condition, params)?;
+                write!(condition, " {joiner} ")?;
+                second.write_condition(condition, params)?;
+                condition.push(')');
+            }
+            Self::Not(filter) => {
+                condition.push_str("!(");
+                filter.write_condition(condition, params)?;
+                condition.push(')');
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check that a field path only contains identifiers separated by dots so it can be placed in a query.
+fn validate_field(field: &str) -> anyhow::Result<()> {
+    let valid = field.split('.').all(|part| {
+        let mut chars = part.chars();
+        chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    });
+    if !valid {
+        anyhow::bail!("{field:?} is not a valid field name for a record filter");
+    }
+    Ok(())
+}
+
+#[test]
+fn filter_values_are_bound_as_parameters() {
+    let filter = RecordFilter::field("title")
+        .contains("FAQ'; DELETE documents; --")
+        .and(RecordFilter::field("metadata.year").gte(2020).not());
+    let mut params = BTreeMap::new();
+    let condition = filter.to_condition(&mut params).unwrap();
+    assert_eq!(
+        condition,
+        "(title CONTAINS $filter_0 AND !(metadata.year >= $filter_1))"
+    );
+    assert_eq!(
+        params["filter_0"],
+        Value::from("FAQ'; DELETE documents; --")
+    );
+    assert_eq!(params["filter_1"], Value::from(2020));
+}
+
+#[test]
+fn filter_rejects_invalid_field_names() {
+    for field in [
+        "title; DELETE documents",
+        "",
+        "a..b",
+        "1st",
+        "title = 'x' OR 1",
+    ] {
+        let filter = RecordFilter::field(field).eq("value");
+        assert!(filter.to_condition(&mut BTreeMap::new()).is_err());
+    }
+}
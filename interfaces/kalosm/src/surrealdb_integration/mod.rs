@@ -1,12 +1,21 @@
 use kalosm_language::prelude::*;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::ops::Range;
+use std::sync::RwLock;
 use surrealdb::sql::{Id, Thing};
 use surrealdb::{Connection, Surreal};
 
+#[cfg(feature = "language")]
+pub(crate) mod citations;
 #[cfg(feature = "language")]
 pub(crate) mod document_table;
+#[cfg(feature = "language")]
+pub(crate) mod sync;
+
+mod filter;
+pub use filter::*;
 
 /// A link between a document and an embedding.
 ///
@@ -27,15 +36,37 @@ pub struct ObjectWithEmbeddingIds<T> {
     chunks: Vec<(Range<usize>, Vec<EmbeddingId>)>,
 }
 
+/// The keyword terms of a chunk.
+///
+/// This type is stored in the [`EmbeddingIndexedTable::table_keywords`] table.
+#[derive(Serialize, Deserialize)]
+pub struct ChunkKeywords {
+    embedding_id: EmbeddingId,
+    text: IndexedText,
+}
+
 /// A table in a surreal database with a primary key tied to an embedding in a vector database.
+///
+/// Text indexed with [`EmbeddingIndexedTable::insert_with_text`] is also added to a keyword index which can be combined with the vector database with [`EmbeddingIndexedTable::select_hybrid`].
 pub struct EmbeddingIndexedTable<C: Connection, R, S = UnknownVectorSpace> {
     table: String,
     db: Surreal<C>,
     vector_db: VectorDB<S>,
+    keywords: tokio::sync::OnceCell<RwLock<KeywordIndex<EmbeddingId>>>,
     phantom: std::marker::PhantomData<R>,
 }
 
 impl<C: Connection, R, S: VectorSpace> EmbeddingIndexedTable<C, R, S> {
+    pub(crate) fn new(table: String, db: Surreal<C>, vector_db: VectorDB<S>) -> Self {
+        Self {
+            table,
+            db,
+            vector_db,
+            keywords: tokio::sync::OnceCell::new(),
+            phantom: std::marker::PhantomData,
+        }
+    }
+
     /// Get the name of the table.
     pub fn table(&self) -> &str {
         &self.table
@@ -46,6 +77,67 @@ impl<C: Connection, R, S: VectorSpace> EmbeddingIndexedTable<C, R, S> {
         format!("{}-links", &self.table)
     }
 
+    /// Get the name of the table that stores the keyword terms of each chunk.
+    pub fn table_keywords(&self) -> String {
+        format!("{}-keywords", &self.table)
+    }
+
+    /// Get the name of the table that keeps track of the files synced into the table with [`DocumentTable::sync_folder`](crate::language::DocumentTable::sync_folder).
+    pub fn table_manifest(&self) -> String {
+        format!("{}-manifest", &self.table)
+    }
+
+    /// Get the keyword index, loading it from the database the first time it is used.
+    async fn keyword_index(&self) -> anyhow::Result<&RwLock<KeywordIndex<EmbeddingId>>> {
+        self.keywords
+            .get_or_try_init(|| async {
+                let entries: Vec<ChunkKeywords> = self.db.select(self.table_keywords()).await?;
+                let mut index = KeywordIndex::new();
+                for entry in entries {
+                    index.insert_indexed(entry.embedding_id, entry.text);
+                }
+                Ok::<_, anyhow::Error>(RwLock::new(index))
+            })
+            .await
+    }
+
+    /// Add the text of a chunk to the keyword index.
+    async fn index_keywords(&self, embedding_id: EmbeddingId, text: &str) -> anyhow::Result<()> {
+        let text = IndexedText::new(text);
+        let entry = Thing {
+            tb: self.table_keywords(),
+            id: Id::Number(embedding_id.0 as i64),
+        };
+        self.db
+            .update::<Option<ChunkKeywords>>(entry)
+            .content(ChunkKeywords {
+                embedding_id,
+                text: text.clone(),
+            })
+            .await?;
+        self.keyword_index()
+            .await?
+            .write()
+            .unwrap()
+            .insert_indexed(embedding_id, text);
+        Ok(())
+    }
+
+    /// Remove the text of a chunk from the keyword index.
+    async fn remove_keywords(&self, embedding_id: EmbeddingId) -> anyhow::Result<()> {
+        let entry = Thing {
+            tb: self.table_keywords(),
+            id: Id::Number(embedding_id.0 as i64),
+        };
+        self.db.delete::<Option<ChunkKeywords>>(entry).await?;
+        self.keyword_index()
+            .await?
+            .write()
+            .unwrap()
+            .remove(&embedding_id);
+        Ok(())
+    }
+
     /// Get the raw vector database.
     pub fn vector_db(&self) -> &VectorDB<S> {
         &self.vector_db
@@ -62,6 +154,11 @@ impl<C: Connection, R, S: VectorSpace> EmbeddingIndexedTable<C, R, S> {
         R: DeserializeOwned,
     {
         let _: Vec<DocumentLink> = self.db.delete(self.table_links()).await?;
+        let _: Vec<ChunkKeywords> = self.db.delete(self.table_keywords()).await?;
+        self.db
+            .query("DELETE type::table($table)")
+            .bind(("table", self.table_manifest()))
+            .await?;
         let embeddings: Vec<ObjectWithEmbeddingIds<R>> = self.db.delete(&self.table).await?;
 
         let mut documents = Vec::with_capacity(embeddings.len());
@@ -87,21 +184,68 @@ impl<C: Connection, R, S: VectorSpace> EmbeddingIndexedTable<C, R, S> {
     }
 
     /// Insert a new record into the table with the given embedding.
+    ///
+    /// The chunks are not added to the keyword index. Use [`EmbeddingIndexedTable::insert_with_text`] to make the record searchable with [`EmbeddingIndexedTable::select_hybrid`].
     pub async fn insert(
         &self,
         chunks: impl IntoIterator<Item = Chunk<S>>,
         value: R,
     ) -> anyhow::Result<Id>
+    where
+        R: Serialize + DeserializeOwned,
+    {
+        self.insert_inner(chunks, value, None).await
+    }
+
+    /// Insert a new record into the table with the given embedding. The text each chunk's byte range points to is added to the keyword index.
+    pub async fn insert_with_text(
+        &self,
+        chunks: impl IntoIterator<Item = Chunk<S>>,
+        value: R,
+        text: &str,
+    ) -> anyhow::Result<Id>
+    where
+        R: Serialize + DeserializeOwned,
+    {
+        self.insert_inner(chunks, value, Some(text)).await
+    }
+
+    async fn insert_inner(
+        &self,
+        chunks: impl IntoIterator<Item = Chunk<S>>,
+        value: R,
+        text: Option<&str>,
+    ) -> anyhow::Result<Id>
     where
         R: Serialize + DeserializeOwned,
     {
         let id = Id::uuid();
 
-        let mut embedding_ids = Vec::new();
         let thing = Thing {
             tb: self.table.clone(),
             id: id.clone(),
         };
+        let embedding_ids = self.add_chunks(&id, chunks, text).await?;
+
+        self.db
+            .create::<Option<ObjectWithEmbeddingIds<R>>>(thing)
+            .content(ObjectWithEmbeddingIds {
+                object: value,
+                chunks: embedding_ids,
+            })
+            .await?;
+
+        Ok(id)
+    }
+
+    /// Add the embeddings of each chunk to the vector database and link them to the record with the given id.
+    async fn add_chunks(
+        &self,
+        id: &Id,
+        chunks: impl IntoIterator<Item = Chunk<S>>,
+        text: Option<&str>,
+    ) -> anyhow::Result<Vec<(Range<usize>, Vec<EmbeddingId>)>> {
+        let mut embedding_ids = Vec::new();
 
         for chunk in chunks {
             let chunk_embedding_ids = self.vector_db.add_embeddings(chunk.embeddings)?;
@@ -120,18 +264,34 @@ impl<C: Connection, R, S: VectorSpace> EmbeddingIndexedTable<C, R, S> {
                     })
                     .await?;
             }
+            // Every embedding of a chunk points to the same text, so the keywords are only indexed once per chunk
+            if let (Some(text), Some(embedding_id)) = (text, chunk_embedding_ids.first()) {
+                if let Some(chunk_text) = text.get(chunk.byte_range.clone()) {
+                    self.index_keywords(*embedding_id, chunk_text).await?;
+                }
+            }
             embedding_ids.push((chunk.byte_range.clone(), chunk_embedding_ids));
         }
 
-        self.db
-            .create::<Option<ObjectWithEmbeddingIds<R>>>(thing)
-            .content(ObjectWithEmbeddingIds {
-                object: value,
-                chunks: embedding_ids,
-            })
-            .await?;
+        Ok(embedding_ids)
+    }
 
-        Ok(id)
+    /// Remove the links, keywords and embeddings of chunks that were added with [`Self::add_chunks`].
+    async fn remove_chunks(
+        &self,
+        chunks: &[(Range<usize>, Vec<EmbeddingId>)],
+    ) -> anyhow::Result<()> {
+        for id in chunks.iter().flat_map(|(_, ids)| ids.iter()).copied() {
+            let link = Thing {
+                tb: self.table_links(),
+                id: Id::Number(id.0 as i64),
+            };
+            self.db.delete::<Option<DocumentLink>>(link).await?;
+            self.remove_keywords(id).await?;
+            // Then delete the embedding from the vector db
+            self.vector_db.remove_embedding(id)?;
+        }
+        Ok(())
     }
 
     /// Update a record in the table with the given embedding id.
@@ -148,6 +308,70 @@ impl<C: Connection, R, S: VectorSpace> EmbeddingIndexedTable<C, R, S> {
         Ok(old)
     }
 
+    /// Update a record in the table with the given embedding id and re-index the keywords of its chunks with the new text.
+    pub async fn update_with_text(&self, id: Id, value: R, text: &str) -> anyhow::Result<Option<R>>
+    where
+        R: Serialize + DeserializeOwned,
+    {
+        let old = self.update(id.clone(), value).await?;
+
+        let thing = Thing {
+            tb: self.table.clone(),
+            id,
+        };
+        let record = self
+            .db
+            .select::<Option<ObjectWithEmbeddingIds<R>>>(thing)
+            .await?;
+        if let Some(record) = record {
+            for (byte_range, embedding_ids) in record.chunks {
+                if let Some(embedding_id) = embedding_ids.first() {
+                    match text.get(byte_range) {
+                        Some(chunk_text) => self.index_keywords(*embedding_id, chunk_text).await?,
+                        None => self.remove_keywords(*embedding_id).await?,
+                    }
+                }
+            }
+        }
+
+        Ok(old)
+    }
+
+    /// Replace a record in the table and its chunks. The embeddings of the old chunks are removed from the vector database and the text each new chunk's byte range points to is added to the keyword index.
+    pub async fn update_with_chunks(
+        &self,
+        id: Id,
+        chunks: impl IntoIterator<Item = Chunk<S>>,
+        value: R,
+        text: &str,
+    ) -> anyhow::Result<Option<R>>
+    where
+        R: Serialize + DeserializeOwned,
+    {
+        let thing = Thing {
+            tb: self.table.clone(),
+            id: id.clone(),
+        };
+        let old = self
+            .db
+            .select::<Option<ObjectWithEmbeddingIds<R>>>(thing.clone())
+            .await?;
+        if let Some(old) = &old {
+            self.remove_chunks(&old.chunks).await?;
+        }
+
+        let embedding_ids = self.add_chunks(&id, chunks, Some(text)).await?;
+        self.db
+            .update::<Option<ObjectWithEmbeddingIds<R>>>(thing)
+            .content(ObjectWithEmbeddingIds {
+                object: value,
+                chunks: embedding_ids,
+            })
+            .await?;
+
+        Ok(old.map(|old| old.object))
+    }
+
     /// Select a record from the table with the given embedding id.
     pub async fn select(&self, id: Id) -> anyhow::Result<R>
     where
@@ -184,20 +408,8 @@ impl<C: Connection, R, S: VectorSpace> EmbeddingIndexedTable<C, R, S> {
                 object,
                 chunks: embedding_ids,
             } = old;
-            // Then delete the links from the links table
-            for id in embedding_ids
-                .iter()
-                .flat_map(|(_, ids)| ids.iter())
-                .copied()
-            {
-                let link = Thing {
-                    tb: self.table_links(),
-                    id: Id::Number(id.0 as i64),
-                };
-                self.db.delete::<Option<DocumentLink>>(link).await?;
-                // Then delete the embedding from the vector db
-                self.vector_db.remove_embedding(id)?;
-            }
+            // Then delete the links, keywords and embeddings of the chunks
+            self.remove_chunks(&embedding_ids).await?;
 
             Ok(Some(object))
         } else {
@@ -224,16 +436,66 @@ impl<C: Connection, R, S: VectorSpace> EmbeddingIndexedTable<C, R, S> {
         R: DeserializeOwned,
     {
         let ids = self.vector_db.get_closest(embedding, k)?;
+        self.search_results(ids).await
+    }
+
+    /// Select the top k records nearest to the given embedding from the records that match a [`RecordFilter`].
+    ///
+    /// The matching records are found first and only their embeddings are searched, so you get up to k results even if the filter removes most of the table.
+    pub async fn select_nearest_where(
+        &self,
+        embedding: Embedding<S>,
+        k: usize,
+        filter: &RecordFilter,
+    ) -> anyhow::Result<Vec<EmbeddingIndexedTableSearchResult<R>>>
+    where
+        R: DeserializeOwned,
+    {
+        let candidates = self.candidates_where(filter).await?;
+        let ids = self
+            .vector_db
+            .get_closest_filtered(embedding, k, &candidates)?;
+        self.search_results(ids).await
+    }
+
+    /// Get the embedding ids of every chunk in the records that match a [`RecordFilter`].
+    pub async fn candidates_where(&self, filter: &RecordFilter) -> anyhow::Result<RoaringBitmap> {
+        #[derive(Deserialize)]
+        struct RecordChunks {
+            chunks: Vec<(Range<usize>, Vec<EmbeddingId>)>,
+        }
+
+        let mut params = std::collections::BTreeMap::new();
+        let condition = filter.to_condition(&mut params)?;
+        let mut response = self
+            .db
+            .query(format!(
+                "SELECT chunks FROM type::table($table) WHERE {condition}"
+            ))
+            .bind(params)
+            .bind(("table", self.table.clone()))
+            .await?;
+        let records: Vec<RecordChunks> = response.take(0)?;
+
+        Ok(records
+            .iter()
+            .flat_map(|record| record.chunks.iter())
+            .flat_map(|(_, ids)| ids.iter())
+            .map(|id| id.0)
+            .collect())
+    }
+
+    /// Look up the records for the results of a vector search.
+    async fn search_results(
+        &self,
+        ids: Vec<VectorDBSearchResult>,
+    ) -> anyhow::Result<Vec<EmbeddingIndexedTableSearchResult<R>>>
+    where
+        R: DeserializeOwned,
+    {
         let mut records = Vec::new();
         for id in ids {
-            let main_table_id = self
-                .db
-                .select::<Option<DocumentLink>>(Thing {
-                    tb: self.table_links(),
-                    id: Id::Number(id.value.0 as i64),
-                })
-                .await?
-                .ok_or_else(|| anyhow::anyhow!("Record not found"))?;
+            let main_table_id = self.select_link(id.value).await?;
             let record = self.select(main_table_id.document_id.clone()).await?;
             records.push(EmbeddingIndexedTableSearchResult {
                 distance: id.distance,
@@ -245,6 +507,194 @@ impl<C: Connection, R, S: VectorSpace> EmbeddingIndexedTable<C, R, S> {
         }
         Ok(records)
     }
+
+    /// Select the top k chunks that best match both the embedding and the keywords in the query text.
+    ///
+    /// The nearest chunks in the vector database and the best keyword matches are fused with [reciprocal-rank fusion](https://plg.uwaterloo.ca/~gvcormac/cormacksigir09-rrf.pdf). Only chunks inserted with text are part of the keyword ranking.
+    pub async fn select_hybrid(
+        &self,
+        embedding: Embedding<S>,
+        query: &str,
+        k: usize,
+        config: HybridSearchConfig,
+    ) -> anyhow::Result<Vec<HybridSearchResult<R>>>
+    where
+        R: DeserializeOwned,
+    {
+        let candidates = config.candidates.unwrap_or(k * 4).max(k);
+        let vector_ids = self.vector_db.get_closest(embedding, candidates)?;
+        let keyword_ids = self
+            .keyword_index()
+            .await?
+            .read()
+            .unwrap()
+            .search(query, candidates);
+
+        let mut results: Vec<HybridSearchResult<R>> = Vec::new();
+        let mut chunk_indexes: HashMap<(Id, Range<usize>), usize> = HashMap::new();
+        for (rank, id) in vector_ids.into_iter().enumerate() {
+            let index = self
+                .hybrid_result(id.value, &mut results, &mut chunk_indexes)
+                .await?;
+            let result = &mut results[index];
+            if result.vector_rank.is_none() {
+                result.vector_rank = Some(rank + 1);
+                result.distance = Some(id.distance);
+                result.score += config.vector_weight / (config.rrf_k + (rank + 1) as f32);
+            }
+        }
+        for (rank, (id, keyword_score)) in keyword_ids.into_iter().enumerate() {
+            let index = self
+                .hybrid_result(id, &mut results, &mut chunk_indexes)
+                .await?;
+            let result = &mut results[index];
+            if result.keyword_rank.is_none() {
+                result.keyword_rank = Some(rank + 1);
+                result.keyword_score = Some(keyword_score);
+                result.score += config.keyword_weight / (config.rrf_k + (rank + 1) as f32);
+            }
+        }
+
+        results.sort_by(|first, second| second.score.total_cmp(&first.score));
+        results.truncate(k);
+        Ok(results)
+    }
+
+    /// Find or create the hybrid search result for the chunk an embedding belongs to.
+    async fn hybrid_result(
+        &self,
+        id: EmbeddingId,
+        results: &mut Vec<HybridSearchResult<R>>,
+        chunk_indexes: &mut HashMap<(Id, Range<usize>), usize>,
+    ) -> anyhow::Result<usize>
+    where
+        R: DeserializeOwned,
+    {
+        let link = self.select_link(id).await?;
+        let key = (link.document_id.clone(), link.byte_range.clone());
+        if let Some(index) = chunk_indexes.get(&key) {
+            return Ok(*index);
+        }
+        let record = self.select(link.document_id.clone()).await?;
+        results.push(HybridSearchResult {
+            score: 0.0,
+            vector_rank: None,
+            keyword_rank: None,
+            distance: None,
+            keyword_score: None,
+            id,
+            record_id: link.document_id,
+            byte_range: link.byte_range,
+            record,
+        });
+        chunk_indexes.insert(key, results.len() - 1);
+        Ok(results.len() - 1)
+    }
+
+    /// Select the link between an embedding and the document it belongs to.
+    async fn select_link(&self, id: EmbeddingId) -> anyhow::Result<DocumentLink> {
+        self.db
+            .select::<Option<DocumentLink>>(Thing {
+                tb: self.table_links(),
+                id: Id::Number(id.0 as i64),
+            })
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Record not found"))
+    }
+}
+
+/// The weights used to fuse vector and keyword rankings in [`EmbeddingIndexedTable::select_hybrid`].
+///
+/// Each result is scored with `weight / (rrf_k + rank)` for each ranking it appears in.
+#[derive(Debug, Clone, Copy)]
+pub struct HybridSearchConfig {
+    vector_weight: f32,
+    keyword_weight: f32,
+    rrf_k: f32,
+    candidates: Option<usize>,
+}
+
+impl Default for HybridSearchConfig {
+    fn default() -> Self {
+        Self {
+            vector_weight: 1.0,
+            keyword_weight: 1.0,
+            rrf_k: 60.0,
+            candidates: None,
+        }
+    }
+}
+
+impl HybridSearchConfig {
+    /// Create a new hybrid search config that weights vector and keyword results equally.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the weight of the vector ranking. Defaults to 1.0.
+    pub fn with_vector_weight(mut self, weight: f32) -> Self {
+        self.vector_weight = weight;
+        self
+    }
+
+    /// Set the weight of the keyword ranking. Defaults to 1.0.
+    pub fn with_keyword_weight(mut self, weight: f32) -> Self {
+        self.keyword_weight = weight;
+        self
+    }
+
+    /// Set the rank offset used in reciprocal-rank fusion. Higher values make lower ranked results matter more. Defaults to 60.
+    pub fn with_rrf_k(mut self, rrf_k: f32) -> Self {
+        self.rrf_k = rrf_k;
+        self
+    }
+
+    /// Set the number of candidates taken from each ranking before they are fused. Defaults to four times the number of requested results.
+    pub fn with_candidates(mut self, candidates: usize) -> Self {
+        self.candidates = Some(candidates);
+        self
+    }
+}
+
+/// The result of a hybrid search in an embedding indexed table.
+#[derive(Debug, Clone)]
+pub struct HybridSearchResult<R> {
+    /// The fused reciprocal-rank score. Higher is better.
+    pub score: f32,
+    /// The rank of the chunk in the vector search starting at 1, if it was found by the vector search.
+    pub vector_rank: Option<usize>,
+    /// The rank of the chunk in the keyword search starting at 1, if it was found by the keyword search.
+    pub keyword_rank: Option<usize>,
+    /// The distance from the searched point, if the chunk was found by the vector search.
+    pub distance: Option<f32>,
+    /// The BM25 score of the chunk, if it was found by the keyword search.
+    pub keyword_score: Option<f32>,
+    /// The embedding id of the record.
+    pub id: EmbeddingId,
+    /// The record id.
+    pub record_id: Id,
+    /// The byte range of the record.
+    pub byte_range: std::ops::Range<usize>,
+    /// The record.
+    pub record: R,
+}
+
+impl<R> HybridSearchResult<R> {
+    /// Get the text of the search result.
+    pub fn text(&self) -> String
+    where
+        R: AsRef<Document>,
+    {
+        self.record.as_ref().body()[self.byte_range.clone()].to_string()
+    }
+
+    /// Describe where the search result is in its document, like `page 12, §3.2`.
+    pub fn location(&self) -> Option<String>
+    where
+        R: AsRef<Document>,
+    {
+        self.record.as_ref().location(self.byte_range.clone())
+    }
 }
 
 /// The result of a search in an embedding indexed table.
@@ -273,6 +723,46 @@ where
     {
         self.record.as_ref().body()[self.byte_range.clone()].to_string()
     }
+
+    /// Describe where the search result is in its document, like `page 12, §3.2`.
+    pub fn location(&self) -> Option<String>
+    where
+        R: AsRef<Document>,
+    {
+        self.record.as_ref().location(self.byte_range.clone())
+    }
+}
+
+impl<R: AsRef<Document>> RerankText for EmbeddingIndexedTableSearchResult<R> {
+    fn rerank_text(&self) -> std::borrow::Cow<'_, str> {
+        std::borrow::Cow::Borrowed(&self.record.as_ref().body()[self.byte_range.clone()])
+    }
+}
+
+impl<R: AsRef<Document>> RerankText for HybridSearchResult<R> {
+    fn rerank_text(&self) -> std::borrow::Cow<'_, str> {
+        std::borrow::Cow::Borrowed(&self.record.as_ref().body()[self.byte_range.clone()])
+    }
+}
+
+impl<R: AsRef<Document>> ContextChunk for EmbeddingIndexedTableSearchResult<R> {
+    fn document_text(&self) -> &str {
+        self.record.as_ref().body()
+    }
+
+    fn chunk_range(&self) -> Range<usize> {
+        self.byte_range.clone()
+    }
+}
+
+impl<R: AsRef<Document>> ContextChunk for HybridSearchResult<R> {
+    fn document_text(&self) -> &str {
+        self.record.as_ref().body()
+    }
+
+    fn chunk_range(&self) -> Range<usize> {
+        self.byte_range.clone()
+    }
 }
 
 /// A builder for creating a new document table.
@@ -307,12 +797,11 @@ impl<C: Connection> EmbeddingIndexedTableBuilder<C> {
         } else {
             VectorDB::new()?
         };
-        Ok(EmbeddingIndexedTable {
-            table: self.table.to_string(),
-            db: self.db,
+        Ok(EmbeddingIndexedTable::new(
+            self.table.to_string(),
+            self.db,
             vector_db,
-            phantom: std::marker::PhantomData,
-        })
+        ))
     }
 }
 
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use kalosm_language::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use surrealdb::sql::{Id, Thing};
+use surrealdb::Connection;
+
+use super::document_table::DocumentTable;
+
+/// A file that was synced into a document table.
+///
+/// This type is stored in the [`EmbeddingIndexedTable::table_manifest`](super::EmbeddingIndexedTable::table_manifest) table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ManifestEntry {
+    path: String,
+    hash: String,
+    modified: i64,
+    record_id: Id,
+}
+
+/// The changes made to a document table by [`DocumentTable::sync_folder`].
+#[derive(Debug, Clone, Default)]
+pub struct FolderSyncReport {
+    /// Files that were not in the table before and were inserted.
+    pub inserted: Vec<PathBuf>,
+    /// Files whose contents changed and were re-chunked.
+    pub updated: Vec<PathBuf>,
+    /// Files that were removed from the folder and deleted from the table.
+    pub removed: Vec<PathBuf>,
+    /// The number of files that did not change.
+    pub unchanged: usize,
+    /// Files that couldn't be synced with the error for each file. They are retried the next time the folder is synced.
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl<C: Connection, R, M: Embedder, K: Chunker> DocumentTable<C, R, M, K> {
+    /// Sync the table with the documents in a folder. New files are inserted, files with new contents are re-chunked with [`DocumentTable::update_document`] and files that were removed from the folder are deleted from the table.
+    ///
+    /// The path, modification time and content hash of every file is kept in a manifest table, so files that did not change are not read again. Run this as often as you like to keep the table up to date with the folder.
+    ///
+    /// A file that can't be read or embedded doesn't stop the sync. It is added to [`FolderSyncReport::failed`] and retried on the next sync.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm::language::*;
+    /// use surrealdb::{engine::local::RocksDb, Surreal};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let db = Surreal::new::<RocksDb>("./db/temp.db").await.unwrap();
+    ///     db.use_ns("rag").use_db("rag").await.unwrap();
+    ///     let document_table = db
+    ///         .document_table_builder("documents")
+    ///         .at("./db/embeddings.db")
+    ///         .build::<Document>()
+    ///         .await
+    ///         .unwrap();
+    ///
+    ///     let folder = DocumentFolder::new("./documents").unwrap();
+    ///     let report = document_table.sync_folder(&folder).await.unwrap();
+    ///     println!(
+    ///         "inserted {}, updated {}, removed {}",
+    ///         report.inserted.len(),
+    ///         report.updated.len(),
+    ///         report.removed.len()
+    ///     );
+    /// }
+    /// ```
+    pub async fn sync_folder(&self, folder: &DocumentFolder) -> anyhow::Result<FolderSyncReport>
+    where
+        R: From<Document> + AsRef<Document> + Serialize + DeserializeOwned,
+    {
+        let db = self.table().db();
+        let manifest_table = self.table().table_manifest();
+        let entries: Vec<ManifestEntry> = db.select(manifest_table.clone()).await?;
+        // Only files inside the folder belong to this sync. Other folders may be synced into the same table.
+        let mut previous: HashMap<String, ManifestEntry> = entries
+            .into_iter()
+            .filter(|entry| Path::new(&entry.path).starts_with(folder.path()))
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+
+        let mut report = FolderSyncReport::default();
+        for path in folder.document_paths().await? {
+            let key = path.to_string_lossy().to_string();
+            let entry = previous.remove(&key);
+            if let Err(err) = self
+                .sync_file(&path, key, entry, &manifest_table, &mut report)
+                .await
+            {
+                report.failed.push((path, err.to_string()));
+            }
+        }
+
+        // Anything left in the manifest was removed from the folder
+        for (key, entry) in previous {
+            let result: anyhow::Result<()> = async {
+                self.delete(entry.record_id).await?;
+                db.delete::<Option<ManifestEntry>>(Thing {
+                    tb: manifest_table.clone(),
+                    id: Id::String(key.clone()),
+                })
+                .await?;
+                Ok(())
+            }
+            .await;
+            match result {
+                Ok(()) => report.removed.push(PathBuf::from(key)),
+                Err(err) => report.failed.push((PathBuf::from(key), err.to_string())),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Sync a single file into the table and record it in the manifest.
+    async fn sync_file(
+        &self,
+        path: &Path,
+        key: String,
+        entry: Option<ManifestEntry>,
+        manifest_table: &str,
+        report: &mut FolderSyncReport,
+    ) -> anyhow::Result<()>
+    where
+        R: From<Document> + AsRef<Document> + Serialize + DeserializeOwned,
+    {
+        let modified = tokio::fs::metadata(path)
+            .await?
+            .modified()?
+            .duration_since(UNIX_EPOCH)?
+            .as_millis() as i64;
+        if let Some(entry) = &entry {
+            if entry.modified == modified {
+                report.unchanged += 1;
+                return Ok(());
+            }
+        }
+
+        let hash = format!("{:x}", Sha256::digest(tokio::fs::read(path).await?));
+        let record_id = match entry {
+            // The file was touched, but the contents are the same
+            Some(entry) if entry.hash == hash => {
+                report.unchanged += 1;
+                entry.record_id
+            }
+            Some(entry) => {
+                let document = FsDocument::try_from(path.to_path_buf())?
+                    .into_document()
+                    .await?;
+                self.update_document(entry.record_id.clone(), document.into())
+                    .await?;
+                report.updated.push(path.to_path_buf());
+                entry.record_id
+            }
+            None => {
+                // New files are stored under an id derived from their path. If a previous sync inserted the file but failed to write the manifest, this replaces that record instead of adding a duplicate.
+                let record_id = Id::String(key.clone());
+                let document = FsDocument::try_from(path.to_path_buf())?
+                    .into_document()
+                    .await?;
+                self.update_document(record_id.clone(), document.into())
+                    .await?;
+                report.inserted.push(path.to_path_buf());
+                record_id
+            }
+        };
+
+        self.table()
+            .db()
+            .update::<Option<ManifestEntry>>(Thing {
+                tb: manifest_table.to_string(),
+                id: Id::String(key.clone()),
+            })
+            .content(ManifestEntry {
+                path: key,
+                hash,
+                modified,
+                record_id,
+            })
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+/// An embedder that embeds text by its length, so tests don't need a model.
+struct LengthEmbedder;
+
+#[cfg(test)]
+impl Embedder for LengthEmbedder {
+    type VectorSpace = UnknownVectorSpace;
+
+    fn embed_for(
+        &self,
+        input: EmbeddingInput,
+    ) -> kalosm_common::BoxedFuture<'_, anyhow::Result<Embedding<UnknownVectorSpace>>> {
+        Box::pin(async move { Ok(Embedding::from([input.text.len() as f32, 1.0])) })
+    }
+}
+
+#[tokio::test]
+async fn sync_folder_tracks_changes() {
+    use std::fs::File;
+    use std::time::Duration;
+    use surrealdb::engine::local::RocksDb;
+    use surrealdb::Surreal;
+
+    let dir = std::env::temp_dir().join(format!("kalosm-sync-folder-{}", std::process::id()));
+    let documents = dir.join("documents");
+    std::fs::create_dir_all(&documents).unwrap();
+    let write = |name: &str, contents: &[u8], modified: u64| {
+        let path = documents.join(name);
+        std::fs::write(&path, contents).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(modified))
+            .unwrap();
+        path
+    };
+
+    let db = Surreal::new::<RocksDb>(dir.join("db")).await.unwrap();
+    db.use_ns("test").use_db("test").await.unwrap();
+    let table: DocumentTable<_, Document, _, _> = DocumentTable::new(
+        LengthEmbedder,
+        super::EmbeddingIndexedTable::new(
+            "documents".to_string(),
+            db.clone(),
+            VectorDB::new().unwrap(),
+        ),
+        ChunkStrategy::Paragraph {
+            paragraph_count: 1,
+            overlap: 0,
+        },
+    );
+    let folder = DocumentFolder::new(&documents).unwrap();
+
+    let first = write("first.txt", b"The first document.", 1);
+    // Invalid UTF-8 can't be read as a text document
+    let broken = write("second.txt", &[0xff, 0xfe, 0xfd], 1);
+    let third = write("third.txt", b"The third document.", 1);
+    let report = table.sync_folder(&folder).await.unwrap();
+    assert_eq!(report.inserted, [first.clone(), third.clone()]);
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, broken);
+    assert_eq!(table.select_all().await.unwrap().len(), 2);
+
+    // Fix the broken file, modify the first file and delete the third file
+    write("second.txt", b"The second document.", 2);
+    write("first.txt", b"The first document, with more text.", 2);
+    std::fs::remove_file(&third).unwrap();
+    let report = table.sync_folder(&folder).await.unwrap();
+    assert_eq!(report.inserted, [broken.clone()]);
+    assert_eq!(report.updated, [first.clone()]);
+    assert_eq!(report.removed, [third]);
+    assert!(report.failed.is_empty());
+    let mut bodies: Vec<_> = table
+        .select_all()
+        .await
+        .unwrap()
+        .iter()
+        .map(|document| document.body().to_string())
+        .collect();
+    bodies.sort();
+    assert_eq!(
+        bodies,
+        [
+            "The first document, with more text.",
+            "The second document."
+        ]
+    );
+
+    // If the manifest entry of a file is lost after it was inserted, syncing again replaces the record instead of adding a duplicate
+    let manifest_table = table.table().table_manifest();
+    db.delete::<Option<ManifestEntry>>(Thing {
+        tb: manifest_table,
+        id: Id::String(broken.to_string_lossy().to_string()),
+    })
+    .await
+    .unwrap();
+    let report = table.sync_folder(&folder).await.unwrap();
+    assert_eq!(report.inserted, [broken]);
+    assert_eq!(report.unchanged, 1);
+    assert_eq!(table.select_all().await.unwrap().len(), 2);
+
+    drop(table);
+    drop(db);
+    std::fs::remove_dir_all(dir).unwrap();
+}
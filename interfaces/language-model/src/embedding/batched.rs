@@ -0,0 +1,472 @@
+use futures_util::future::BoxFuture;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{Embedder, Embedding, EmbeddingInput, EmbeddingVariant, VectorSpace};
+
+/// Settings for how an [`EmbeddingScheduler`] groups inputs into batches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchingConfig {
+    max_batch_tokens: usize,
+    max_batch_size: usize,
+    max_wait: Duration,
+}
+
+impl Default for BatchingConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_tokens: 16384,
+            max_batch_size: 64,
+            max_wait: Duration::from_millis(5),
+        }
+    }
+}
+
+impl BatchingConfig {
+    /// Set the maximum number of tokens in a batch after every input is padded to the length of the longest input. (default: 16384)
+    pub fn with_max_batch_tokens(mut self, max_batch_tokens: usize) -> Self {
+        self.max_batch_tokens = max_batch_tokens;
+        self
+    }
+
+    /// Set the maximum number of inputs in a batch. (default: 64)
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size.max(1);
+        self
+    }
+
+    /// Set how long the scheduler waits for more inputs after the first input of a batch arrives. (default: 5ms)
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+}
+
+type EmbedBatch<S> = Arc<
+    dyn Fn(Vec<EmbeddingInput>) -> BoxFuture<'static, anyhow::Result<Vec<Embedding<S>>>>
+        + Send
+        + Sync,
+>;
+
+type CountTokens = Arc<dyn Fn(&str) -> usize + Send + Sync>;
+
+struct Job<S: VectorSpace> {
+    input: EmbeddingInput,
+    tokens: usize,
+    sender: oneshot::Sender<anyhow::Result<Embedding<S>>>,
+}
+
+/// A scheduler that collects embedding requests from concurrent callers and embeds them together in batches.
+///
+/// Requests that arrive within a short window are sorted by length and split into batches that fit in a token budget, so inputs with a similar length are padded together.
+///
+/// The scheduler starts a background task on the current tokio runtime the first time it is used. The task stops when every clone of the scheduler is dropped.
+pub struct EmbeddingScheduler<S: VectorSpace> {
+    embed_batch: EmbedBatch<S>,
+    count_tokens: CountTokens,
+    config: BatchingConfig,
+    queue: Arc<Mutex<Option<mpsc::UnboundedSender<Job<S>>>>>,
+}
+
+impl<S: VectorSpace> Clone for EmbeddingScheduler<S> {
+    fn clone(&self) -> Self {
+        Self {
+            embed_batch: self.embed_batch.clone(),
+            count_tokens: self.count_tokens.clone(),
+            config: self.config,
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<S: VectorSpace> EmbeddingScheduler<S> {
+    /// Create a new scheduler that embeds each batch with the given function.
+    pub fn new<F, Fut>(config: BatchingConfig, embed_batch: F) -> Self
+    where
+        F: Fn(Vec<EmbeddingInput>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<Vec<Embedding<S>>>> + Send + 'static,
+    {
+        Self {
+            embed_batch: Arc::new(move |inputs| Box::pin(embed_batch(inputs))),
+            count_tokens: Arc::new(estimate_tokens),
+            config,
+            queue: Default::default(),
+        }
+    }
+
+    /// Set the function used to count the tokens in each input. Defaults to an estimate based on the length of the text.
+    pub fn with_token_counter(
+        mut self,
+        count_tokens: impl Fn(&str) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.count_tokens = Arc::new(count_tokens);
+        self
+    }
+
+    /// Get the batching settings of the scheduler.
+    pub fn config(&self) -> &BatchingConfig {
+        &self.config
+    }
+
+    /// Embed one input along with any other inputs that are waiting.
+    pub async fn embed(&self, input: EmbeddingInput) -> anyhow::Result<Embedding<S>> {
+        self.embed_vec(vec![input])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("The embedding scheduler returned no embedding"))
+    }
+
+    /// Embed a list of inputs along with any other inputs that are waiting. Returns the embeddings in the same order as the inputs.
+    pub async fn embed_vec(
+        &self,
+        inputs: Vec<EmbeddingInput>,
+    ) -> anyhow::Result<Vec<Embedding<S>>> {
+        let sender = self.sender();
+        let mut receivers = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            let (job_sender, receiver) = oneshot::channel();
+            let tokens = (self.count_tokens)(&input.text);
+            sender
+                .send(Job {
+                    input,
+                    tokens,
+                    sender: job_sender,
+                })
+                .map_err(|_| anyhow::anyhow!("The embedding scheduler stopped"))?;
+            receivers.push(receiver);
+        }
+
+        let mut embeddings = Vec::with_capacity(receivers.len());
+        for receiver in receivers {
+            let embedding = receiver.await.map_err(|_| {
+                anyhow::anyhow!("The embedding scheduler stopped before the input was embedded")
+            })??;
+            embeddings.push(embedding);
+        }
+        Ok(embeddings)
+    }
+
+    /// Get the sender for the queue of the background task, starting the task if it is not running.
+    fn sender(&self) -> mpsc::UnboundedSender<Job<S>> {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(sender) = queue.as_ref().filter(|sender| !sender.is_closed()) {
+            return sender.clone();
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_scheduler(
+            self.embed_batch.clone(),
+            self.config,
+            receiver,
+        ));
+        *queue = Some(sender.clone());
+        sender
+    }
+}
+
+async fn run_scheduler<S: VectorSpace>(
+    embed_batch: EmbedBatch<S>,
+    config: BatchingConfig,
+    mut receiver: mpsc::UnboundedReceiver<Job<S>>,
+) {
+    while let Some(first) = receiver.recv().await {
+        let mut tokens = first.tokens;
+        let mut jobs = vec![first];
+
+        // Wait a short time for other callers to add their inputs to the batch
+        let deadline = tokio::time::Instant::now() + config.max_wait;
+        while tokens < config.max_batch_tokens {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(job)) => {
+                    tokens += job.tokens;
+                    jobs.push(job);
+                }
+                _ => break,
+            }
+        }
+
+        // Skip inputs from callers that stopped waiting
+        jobs.retain(|job| !job.sender.is_closed());
+
+        for batch in bucket_by_tokens(jobs, |job| job.tokens, &config) {
+            let (inputs, senders): (Vec<_>, Vec<_>) =
+                batch.into_iter().map(|job| (job.input, job.sender)).unzip();
+            // Keep a copy of the inputs so they can be retried one at a time if the batch fails
+            let retry_inputs = (inputs.len() > 1).then(|| inputs.clone());
+            match embed_batch(inputs).await {
+                Ok(embeddings) if embeddings.len() == senders.len() => {
+                    for (sender, embedding) in senders.into_iter().zip(embeddings) {
+                        _ = sender.send(Ok(embedding));
+                    }
+                }
+                Ok(embeddings) => {
+                    let message = format!(
+                        "The embedder returned {} embeddings for {} inputs",
+                        embeddings.len(),
+                        senders.len()
+                    );
+                    for sender in senders {
+                        _ = sender.send(Err(anyhow::anyhow!("{message}")));
+                    }
+                }
+                Err(err) => match retry_inputs {
+                    // One bad input shouldn't fail every caller in the batch, so each input is retried alone and gets its own result
+                    Some(inputs) => {
+                        for (input, sender) in inputs.into_iter().zip(senders) {
+                            let result = embed_batch(vec![input]).await.and_then(|embeddings| {
+                                embeddings.into_iter().next().ok_or_else(|| {
+                                    anyhow::anyhow!("The embedder returned no embedding")
+                                })
+                            });
+                            _ = sender.send(result);
+                        }
+                    }
+                    None => {
+                        for sender in senders {
+                            _ = sender.send(Err(anyhow::anyhow!("{err}")));
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Estimate the number of tokens in a text. English text has roughly four bytes per token.
+fn estimate_tokens(text: &str) -> usize {
+    text.len() / 4 + 1
+}
+
+/// Sort the jobs by length and split them into batches that fit in the token budget once every input is padded to the longest input in its batch.
+fn bucket_by_tokens<T>(
+    mut jobs: Vec<T>,
+    tokens: impl Fn(&T) -> usize,
+    config: &BatchingConfig,
+) -> Vec<Vec<T>> {
+    jobs.sort_by_key(&tokens);
+    let mut batches = Vec::new();
+    let mut batch: Vec<T> = Vec::new();
+    for job in jobs {
+        // The jobs are sorted, so this job is the longest in the batch
+        let padded_tokens = (batch.len() + 1) * tokens(&job).max(1);
+        if !batch.is_empty()
+            && (padded_tokens > config.max_batch_tokens || batch.len() >= config.max_batch_size)
+        {
+            batches.push(std::mem::take(&mut batch));
+        }
+        batch.push(job);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Embed the inputs in the same length bucketed batches an [`EmbeddingScheduler`] with the default [`BatchingConfig`] creates, embedding the inputs of each batch concurrently. Returns the embeddings in the same order as the inputs.
+///
+/// The default [`Embedder::embed_vec`] and [`Embedder::embed_vec_for`] use this instead of a scheduler because the scheduler task can't hold on to a borrowed embedder.
+pub(crate) async fn embed_in_batches<S, F, Fut>(
+    inputs: Vec<EmbeddingInput>,
+    embed: F,
+) -> anyhow::Result<Vec<Embedding<S>>>
+where
+    S: VectorSpace,
+    F: Fn(EmbeddingInput) -> Fut,
+    Fut: Future<Output = anyhow::Result<Embedding<S>>>,
+{
+    let config = BatchingConfig::default();
+    let mut embeddings = inputs.iter().map(|_| None).collect::<Vec<_>>();
+    let jobs = inputs
+        .into_iter()
+        .enumerate()
+        .map(|(index, input)| (index, estimate_tokens(&input.text), input))
+        .collect();
+    for batch in bucket_by_tokens(jobs, |(_, tokens, _)| *tokens, &config) {
+        let (indices, inputs): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .map(|(index, _, input)| (index, input))
+            .unzip();
+        let batch_embeddings =
+            futures_util::future::try_join_all(inputs.into_iter().map(&embed)).await?;
+        for (index, embedding) in indices.into_iter().zip(batch_embeddings) {
+            embeddings[index] = Some(embedding);
+        }
+    }
+    Ok(embeddings.into_iter().flatten().collect())
+}
+
+/// An embedder that collects concurrent embedding requests into batches with an [`EmbeddingScheduler`] before passing them to the underlying embedder.
+///
+/// This is useful for embedders that are much faster with batches, when the inputs come from many tasks at once.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm::language::*;
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let embedder = Bert::new().await?.batched();
+///     // These calls are embedded together in one batch
+///     let (cats, dogs) = tokio::join!(
+///         embedder.embed("Cats are cool"),
+///         embedder.embed("Dogs are cool")
+///     );
+///     println!("{}", cats?.cosine_similarity(&dogs?));
+///     Ok(())
+/// }
+/// ```
+pub struct BatchedEmbedder<M: Embedder> {
+    model: Arc<M>,
+    scheduler: EmbeddingScheduler<M::VectorSpace>,
+}
+
+impl<M: Embedder> BatchedEmbedder<M> {
+    /// Create a new batched embedder with the default [`BatchingConfig`].
+    pub fn new(model: M) -> Self {
+        Self::new_with_config(model, BatchingConfig::default())
+    }
+
+    /// Create a new batched embedder with the given batching settings.
+    pub fn new_with_config(model: M, config: BatchingConfig) -> Self {
+        let model = Arc::new(model);
+        let scheduler = EmbeddingScheduler::new(config, {
+            let model = model.clone();
+            move |inputs| {
+                let model = model.clone();
+                async move { model.embed_vec_for(inputs).await }
+            }
+        });
+        Self { model, scheduler }
+    }
+
+    /// Set the function used to count the tokens in each input. Defaults to an estimate based on the length of the text.
+    pub fn with_token_counter(
+        mut self,
+        count_tokens: impl Fn(&str) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.scheduler = self.scheduler.with_token_counter(count_tokens);
+        self
+    }
+
+    /// Get a reference to the underlying embedder.
+    pub fn get_embedder(&self) -> &M {
+        &self.model
+    }
+}
+
+impl<M: Embedder> Embedder for BatchedEmbedder<M> {
+    type VectorSpace = M::VectorSpace;
+
+    fn embed_for(
+        &self,
+        input: EmbeddingInput,
+    ) -> BoxFuture<'_, anyhow::Result<Embedding<Self::VectorSpace>>> {
+        Box::pin(self.scheduler.embed(input))
+    }
+
+    fn embed_vec(
+        &self,
+        inputs: Vec<String>,
+    ) -> BoxFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        self.embed_vec_for(
+            inputs
+                .into_iter()
+                .map(|text| EmbeddingInput::new(text, EmbeddingVariant::Document))
+                .collect(),
+        )
+    }
+
+    fn embed_vec_for(
+        &self,
+        inputs: Vec<EmbeddingInput>,
+    ) -> BoxFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        Box::pin(self.scheduler.embed_vec(inputs))
+    }
+}
+
+#[tokio::test]
+async fn concurrent_embeds_share_a_batch() {
+    use crate::UnknownVectorSpace;
+
+    let batch_sizes = Arc::new(Mutex::new(Vec::new()));
+    let scheduler = EmbeddingScheduler::<UnknownVectorSpace>::new(
+        BatchingConfig::default().with_max_wait(Duration::from_millis(50)),
+        {
+            let batch_sizes = batch_sizes.clone();
+            move |inputs: Vec<EmbeddingInput>| {
+                batch_sizes.lock().unwrap().push(inputs.len());
+                async move {
+                    Ok(inputs
+                        .iter()
+                        .map(|input| Embedding::from([input.text.len() as f32]))
+                        .collect())
+                }
+            }
+        },
+    );
+
+    let texts = ["a", "bbb", "cc", "dddd"];
+    let embeddings =
+        futures_util::future::try_join_all(texts.iter().map(|text| {
+            scheduler.embed(EmbeddingInput::new(text, crate::EmbeddingVariant::Document))
+        }))
+        .await
+        .unwrap();
+
+    // Every input is embedded in one batch, and the results are returned to the right caller
+    assert_eq!(*batch_sizes.lock().unwrap(), [4]);
+    for (text, embedding) in texts.iter().zip(embeddings) {
+        assert_eq!(embedding.to_vec(), [text.len() as f32]);
+    }
+}
+
+#[test]
+fn long_inputs_are_batched_separately() {
+    use crate::UnknownVectorSpace;
+
+    let config = BatchingConfig::default().with_max_batch_tokens(8);
+    let jobs = [1, 6, 2, 1]
+        .into_iter()
+        .map(|tokens| Job::<UnknownVectorSpace> {
+            input: EmbeddingInput::new("", crate::EmbeddingVariant::Document),
+            tokens,
+            sender: oneshot::channel().0,
+        })
+        .collect();
+    let batches = bucket_by_tokens(jobs, |job| job.tokens, &config)
+        .into_iter()
+        .map(|batch| batch.iter().map(|job| job.tokens).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(batches, [vec![1, 1, 2], vec![6]]);
+}
+
+#[tokio::test]
+async fn failed_inputs_only_fail_their_caller() {
+    use crate::UnknownVectorSpace;
+
+    let scheduler = EmbeddingScheduler::<UnknownVectorSpace>::new(
+        BatchingConfig::default().with_max_wait(Duration::from_millis(50)),
+        |inputs: Vec<EmbeddingInput>| async move {
+            if inputs.iter().any(|input| input.text == "bad") {
+                anyhow::bail!("Can't embed bad input");
+            }
+            Ok(inputs
+                .iter()
+                .map(|input| Embedding::from([input.text.len() as f32]))
+                .collect())
+        },
+    );
+
+    let texts = ["good", "bad", "fine"];
+    let results = futures_util::future::join_all(
+        texts
+            .iter()
+            .map(|text| scheduler.embed(EmbeddingInput::new(text, EmbeddingVariant::Document))),
+    )
+    .await;
+
+    assert_eq!(results[0].as_ref().unwrap().to_vec(), [4.0]);
+    assert!(results[1].is_err());
+    assert_eq!(results[2].as_ref().unwrap().to_vec(), [4.0]);
+}
@@ -276,6 +276,19 @@ pub trait EmbedderCacheExt: Embedder {
     {
         CachedEmbeddingModel::new(self, cache_size)
     }
+
+    /// Wrap the embedder with a cache that stores embeddings on disk in the directory at `path`. See [`DiskCachedEmbedder`] for more details.
+    #[cfg(feature = "disk-cache")]
+    fn cached_on_disk(
+        self,
+        path: impl AsRef<Path>,
+        model_id: impl ToString,
+    ) -> anyhow::Result<crate::DiskCachedEmbedder<Self>>
+    where
+        Self: Sized,
+    {
+        crate::DiskCachedEmbedder::builder(path, model_id).build(self)
+    }
 }
 
 impl<M: Embedder> EmbedderCacheExt for M {}
@@ -0,0 +1,465 @@
+use futures_util::future::BoxFuture;
+use heed::byteorder::BigEndian;
+use heed::types::{Bytes, U64};
+use heed::{Database, EnvOpenOptions};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::{Embedder, Embedding, EmbeddingInput, EmbeddingVariant};
+
+type Key = [u8; 32];
+
+/// The number of cache hits that are kept in memory before their recency is written to disk
+const RECENCY_BATCH_SIZE: usize = 256;
+
+/// A builder for a [`DiskCachedEmbedder`].
+#[derive(Debug, Clone)]
+pub struct DiskCacheBuilder {
+    path: PathBuf,
+    model_id: String,
+    max_entries: u64,
+    map_size: usize,
+}
+
+impl DiskCacheBuilder {
+    /// Create a new builder for a cache stored in the directory at `path`.
+    ///
+    /// The `model_id` is part of the key of every embedding. It should change whenever the embeddings the model produces change (for example the model name and revision), so one cache directory can be shared between models without mixing up their embeddings.
+    pub fn new(path: impl AsRef<Path>, model_id: impl ToString) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            model_id: model_id.to_string(),
+            max_entries: 1_000_000,
+            map_size: (10u64 << 30).try_into().unwrap_or(usize::MAX),
+        }
+    }
+
+    /// Set the maximum number of embeddings in the cache. When the cache is full, the least recently used embeddings are evicted. (default: 1,000,000)
+    pub fn with_max_entries(mut self, max_entries: u64) -> Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    /// Set the maximum size of the cache file in bytes. This should be large enough to hold `max_entries` embeddings. (default: 10 GiB)
+    pub fn with_map_size(mut self, map_size: usize) -> Self {
+        self.map_size = map_size;
+        self
+    }
+
+    /// Build the cache around an embedding model.
+    pub fn build<M: Embedder>(self, model: M) -> anyhow::Result<DiskCachedEmbedder<M>> {
+        std::fs::create_dir_all(&self.path)?;
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(self.map_size)
+                .max_dbs(2)
+                .open(&self.path)
+        }?;
+        let mut wtxn = env.write_txn()?;
+        let entries: Database<Bytes, Bytes> = env.create_database(&mut wtxn, Some("embeddings"))?;
+        let order: Database<U64<BigEndian>, Bytes> =
+            env.create_database(&mut wtxn, Some("order"))?;
+        wtxn.commit()?;
+
+        Ok(DiskCachedEmbedder {
+            model,
+            model_id: self.model_id,
+            store: DiskStore {
+                env,
+                entries,
+                order,
+                max_entries: self.max_entries,
+            },
+            pending_hits: Mutex::new(HashSet::new()),
+        })
+    }
+}
+
+/// An embedder that caches embeddings in an on disk database, so embeddings survive restarts.
+///
+/// Embeddings are keyed by a hash of the model id, the [`EmbeddingVariant`] and the text. They are read from disk only when they are requested and written to disk as soon as they are embedded.
+///
+/// Reading an embedding from the cache doesn't write to disk. Cache hits are collected in memory and marked as recently used the next time new embeddings are written, after enough hits, or when the embedder is dropped.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm::language::*;
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let bert = Bert::new_for_search()
+///         .await?
+///         .cached_on_disk("./embedding-cache", "bge-small-en-v1.5")?;
+///
+///     // The first run embeds the sentences. Every run after that reads them from the cache.
+///     let embeddings = bert.embed_batch(["Cats are cool", "Pets are great"]).await?;
+///     println!("{:?}", embeddings);
+///     Ok(())
+/// }
+/// ```
+pub struct DiskCachedEmbedder<M: Embedder> {
+    model: M,
+    model_id: String,
+    store: DiskStore,
+    pending_hits: Mutex<HashSet<Key>>,
+}
+
+// Heed transactions block the thread they run on, so the embedder moves a clone of the store to a blocking thread while it embeds
+#[derive(Clone)]
+struct DiskStore {
+    env: heed::Env,
+    entries: Database<Bytes, Bytes>,
+    order: Database<U64<BigEndian>, Bytes>,
+    max_entries: u64,
+}
+
+// Each entry is the tick it was last used at, followed by the embedding as little endian floats
+fn encode_entry(tick: u64, vector: &[f32]) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(8 + vector.len() * 4);
+    entry.extend_from_slice(&tick.to_be_bytes());
+    entry.extend(vector.iter().flat_map(|x| x.to_le_bytes()));
+    entry
+}
+
+fn decode_entry(entry: &[u8]) -> (u64, Vec<f32>) {
+    let (tick, vector) = entry.split_at(8);
+    let tick = u64::from_be_bytes(tick.try_into().unwrap());
+    let vector = vector
+        .chunks_exact(4)
+        .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect();
+    (tick, vector)
+}
+
+impl<M: Embedder> DiskCachedEmbedder<M> {
+    /// Create a builder for a cache stored in the directory at `path`. See [`DiskCacheBuilder::new`].
+    pub fn builder(path: impl AsRef<Path>, model_id: impl ToString) -> DiskCacheBuilder {
+        DiskCacheBuilder::new(path, model_id)
+    }
+
+    /// Get a reference to the underlying embedder.
+    pub fn get_embedder(&self) -> &M {
+        &self.model
+    }
+
+    /// Get the number of embeddings in the cache.
+    pub fn len(&self) -> anyhow::Result<u64> {
+        self.store.len()
+    }
+
+    /// Check if the cache is empty.
+    pub fn is_empty(&self) -> anyhow::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Remove every embedding from the cache.
+    pub fn clear(&self) -> anyhow::Result<()> {
+        self.pending_hits.lock().unwrap().clear();
+        self.store.clear()
+    }
+
+    fn key(&self, input: &EmbeddingInput) -> Key {
+        let variant: &[u8] = match input.variant {
+            EmbeddingVariant::Query => b"query",
+            EmbeddingVariant::Document => b"document",
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(self.model_id.as_bytes());
+        hasher.update([0]);
+        hasher.update(variant);
+        hasher.update([0]);
+        hasher.update(input.text.as_bytes());
+        hasher.finalize().into()
+    }
+
+    /// Record cache hits, and take every pending hit if they should be written to disk now.
+    fn take_hits(&self, hits: Vec<Key>, force: bool) -> Vec<Key> {
+        let mut pending = self.pending_hits.lock().unwrap();
+        pending.extend(hits);
+        if force || pending.len() >= RECENCY_BATCH_SIZE {
+            pending.drain().collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Run a function with the store on a blocking thread.
+    async fn with_store<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&DiskStore) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || f(&store)).await?
+    }
+}
+
+impl DiskStore {
+    fn len(&self) -> anyhow::Result<u64> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.entries.len(&rtxn)?)
+    }
+
+    fn clear(&self) -> anyhow::Result<()> {
+        let mut wtxn = self.env.write_txn()?;
+        self.entries.clear(&mut wtxn)?;
+        self.order.clear(&mut wtxn)?;
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    fn read(&self, keys: &[Key]) -> anyhow::Result<Vec<Option<Vec<f32>>>> {
+        let rtxn = self.env.read_txn()?;
+        keys.iter()
+            .map(|key| {
+                let entry = self.entries.get(&rtxn, key)?;
+                Ok(entry.map(|entry| decode_entry(entry).1))
+            })
+            .collect()
+    }
+
+    // Mark the hits as recently used, insert the new embeddings, and evict the least recently used embeddings if the cache is full
+    fn write(&self, hits: &[Key], inserts: &[(Key, Vec<f32>)]) -> anyhow::Result<()> {
+        if hits.is_empty() && inserts.is_empty() {
+            return Ok(());
+        }
+        let mut wtxn = self.env.write_txn()?;
+        // Write transactions are serialized, so the next tick read inside the transaction is unique even if other processes share the cache
+        let mut next_tick = self
+            .order
+            .last(&wtxn)?
+            .map(|(tick, _)| tick + 1)
+            .unwrap_or(0);
+        let mut take_tick = || {
+            next_tick += 1;
+            next_tick - 1
+        };
+        for key in hits {
+            let Some(mut entry) = self.entries.get(&wtxn, key)?.map(<[u8]>::to_vec) else {
+                continue;
+            };
+            let old_tick = u64::from_be_bytes(entry[..8].try_into().unwrap());
+            let tick = take_tick();
+            entry[..8].copy_from_slice(&tick.to_be_bytes());
+            self.order.delete(&mut wtxn, &old_tick)?;
+            self.order.put(&mut wtxn, &tick, key)?;
+            self.entries.put(&mut wtxn, key, &entry)?;
+        }
+        for (key, vector) in inserts {
+            if let Some((old_tick, _)) = self.entries.get(&wtxn, key)?.map(decode_entry) {
+                self.order.delete(&mut wtxn, &old_tick)?;
+            }
+            let tick = take_tick();
+            self.order.put(&mut wtxn, &tick, key)?;
+            self.entries
+                .put(&mut wtxn, key, &encode_entry(tick, vector))?;
+        }
+        while self.entries.len(&wtxn)? > self.max_entries {
+            let Some((tick, key)) = self.order.first(&wtxn)? else {
+                break;
+            };
+            let key = key.to_vec();
+            self.order.delete(&mut wtxn, &tick)?;
+            self.entries.delete(&mut wtxn, &key)?;
+        }
+        wtxn.commit()?;
+        Ok(())
+    }
+}
+
+impl<M: Embedder> Drop for DiskCachedEmbedder<M> {
+    fn drop(&mut self) {
+        let hits = self.take_hits(Vec::new(), true);
+        if let Err(err) = self.store.write(&hits, &[]) {
+            log::warn!("Failed to write to the embedding cache: {err}");
+        }
+    }
+}
+
+impl<M: Embedder> Embedder for DiskCachedEmbedder<M> {
+    type VectorSpace = M::VectorSpace;
+
+    fn embed_for(
+        &self,
+        input: EmbeddingInput,
+    ) -> BoxFuture<'_, anyhow::Result<Embedding<Self::VectorSpace>>> {
+        Box::pin(async move {
+            let mut embeddings = self.embed_vec_for(vec![input]).await?;
+            Ok(embeddings.remove(0))
+        })
+    }
+
+    fn embed_vec(
+        &self,
+        inputs: Vec<String>,
+    ) -> BoxFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        self.embed_vec_for(
+            inputs
+                .into_iter()
+                .map(|text| EmbeddingInput::new(text, EmbeddingVariant::Document))
+                .collect(),
+        )
+    }
+
+    fn embed_vec_for(
+        &self,
+        inputs: Vec<EmbeddingInput>,
+    ) -> BoxFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        Box::pin(async move {
+            let keys: Vec<_> = inputs.iter().map(|input| self.key(input)).collect();
+            let cached = {
+                let keys = keys.clone();
+                self.with_store(move |store| store.read(&keys)).await?
+            };
+
+            let mut embeddings = Vec::with_capacity(inputs.len());
+            let mut hits = Vec::new();
+            let mut missing = Vec::new();
+            let mut missing_indices = Vec::new();
+            for (i, ((input, key), cached)) in inputs.into_iter().zip(&keys).zip(cached).enumerate()
+            {
+                match cached {
+                    Some(vector) => {
+                        hits.push(*key);
+                        embeddings.push(Some(Embedding::from(vector)));
+                    }
+                    None => {
+                        missing.push(input);
+                        missing_indices.push(i);
+                        embeddings.push(None);
+                    }
+                }
+            }
+
+            let mut inserts = Vec::with_capacity(missing.len());
+            if !missing.is_empty() {
+                let new_embeddings = self.model.embed_vec_for(missing).await?;
+                for (i, embedding) in missing_indices.into_iter().zip(new_embeddings) {
+                    inserts.push((keys[i], embedding.to_vec()));
+                    embeddings[i] = Some(embedding);
+                }
+            }
+
+            // Hits are only written to disk in batches, or along with new embeddings so they aren't evicted to make room for them
+            let hits = self.take_hits(hits, !inserts.is_empty());
+            // Failing to update the cache shouldn't fail the embedding
+            if let Err(err) = self
+                .with_store(move |store| store.write(&hits, &inserts))
+                .await
+            {
+                log::warn!("Failed to write to the embedding cache: {err}");
+            }
+
+            embeddings
+                .into_iter()
+                .map(|embedding| {
+                    embedding.ok_or_else(|| {
+                        anyhow::anyhow!("The embedding model returned too few embeddings")
+                    })
+                })
+                .collect()
+        })
+    }
+}
+
+#[tokio::test]
+async fn disk_cache_survives_reopening() {
+    use crate::{EmbedderExt, UnknownVectorSpace};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct CountingEmbedder(Arc<AtomicUsize>);
+
+    impl Embedder for CountingEmbedder {
+        type VectorSpace = UnknownVectorSpace;
+
+        fn embed_for(
+            &self,
+            input: EmbeddingInput,
+        ) -> BoxFuture<'_, anyhow::Result<Embedding<UnknownVectorSpace>>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            let variant = (input.variant == EmbeddingVariant::Query) as u8 as f32;
+            Box::pin(async move { Ok(Embedding::from([input.text.len() as f32, variant])) })
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let open = || {
+        DiskCacheBuilder::new(dir.path(), "counting")
+            .with_max_entries(3)
+            .build(CountingEmbedder(calls.clone()))
+            .unwrap()
+    };
+
+    {
+        let cache = open();
+        let embeddings = cache.embed_batch(["a", "bb"]).await.unwrap();
+        assert_eq!(embeddings[1].to_vec(), [2.0, 0.0]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    let cache = open();
+    let embeddings = cache.embed_batch(["a", "ccc"]).await.unwrap();
+    assert_eq!(embeddings[0].to_vec(), [1.0, 0.0]);
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // Queries and documents are cached separately
+    let query = cache.embed_query("a").await.unwrap();
+    assert_eq!(query.to_vec(), [1.0, 1.0]);
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+    // "bb" was used least recently, so it was evicted
+    assert_eq!(cache.len().unwrap(), 3);
+    cache.embed_batch(["a", "ccc", "bb"]).await.unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 5);
+}
+
+#[tokio::test]
+async fn disk_cache_writes_hits_in_batches() {
+    use crate::{EmbedderExt, UnknownVectorSpace};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct CountingEmbedder(Arc<AtomicUsize>);
+
+    impl Embedder for CountingEmbedder {
+        type VectorSpace = UnknownVectorSpace;
+
+        fn embed_for(
+            &self,
+            input: EmbeddingInput,
+        ) -> BoxFuture<'_, anyhow::Result<Embedding<UnknownVectorSpace>>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(Embedding::from([input.text.len() as f32])) })
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let open = || {
+        DiskCacheBuilder::new(dir.path(), "counting")
+            .with_max_entries(2)
+            .build(CountingEmbedder(calls.clone()))
+            .unwrap()
+    };
+
+    {
+        let cache = open();
+        cache.embed_batch(["a", "bb"]).await.unwrap();
+        // Reading "a" only marks it as recently used in memory
+        cache.embed("a").await.unwrap();
+        assert_eq!(cache.pending_hits.lock().unwrap().len(), 1);
+    }
+
+    // The hit was written when the cache was dropped, so "bb" is evicted instead of "a"
+    let cache = open();
+    cache.embed("ccc").await.unwrap();
+    assert!(cache.pending_hits.lock().unwrap().is_empty());
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    cache.embed_batch(["a", "ccc"]).await.unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    cache.embed("bb").await.unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+}
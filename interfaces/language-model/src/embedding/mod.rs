@@ -11,8 +11,16 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 mod cache;
 #[cfg(feature = "cache")]
 pub use cache::*;
+#[cfg(feature = "disk-cache")]
+mod disk_cache;
+#[cfg(feature = "disk-cache")]
+pub use disk_cache::*;
 mod model;
 pub use model::*;
+mod batched;
+pub use batched::*;
+mod truncated;
+pub use truncated::*;
 mod into_embedding;
 pub use into_embedding::*;
 
@@ -1,8 +1,12 @@
 use kalosm_common::BoxedFuture;
 
-use crate::embedding::{Embedding, VectorSpace};
+use crate::embedding::batched::embed_in_batches;
+use crate::embedding::{BatchedEmbedder, Embedding, TruncatedEmbedder, VectorSpace};
 use crate::UnknownVectorSpace;
 
+/// The number of inputs the default [`Embedder::embed_vec`] and [`Embedder::embed_vec_for`] embed at the same time. This matches the default batch size of [`BatchedEmbedder`].
+pub const MAX_CONCURRENT_EMBEDDINGS: usize = 64;
+
 /// A model that can be used to embed text. This trait is generic over the vector space that the model uses to help keep track of what embeddings came from which model.
 ///
 /// # Example
@@ -43,17 +47,19 @@ pub trait Embedder: Send + Sync + 'static {
     }
 
     /// Embed a [`Vec<String>`] into a vector space. Returns a list of embeddings in the same order as the inputs.
+    ///
+    /// By default, the inputs are grouped by length into batches like an [`EmbeddingScheduler`](crate::EmbeddingScheduler) groups them, and the up to [`MAX_CONCURRENT_EMBEDDINGS`] inputs in each batch are embedded concurrently with [`Embedder::embed_string`].
     fn embed_vec(
         &self,
         inputs: Vec<String>,
     ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
-        Box::pin(async move {
-            let mut embeddings = Vec::with_capacity(inputs.len());
-            for input in inputs {
-                embeddings.push(self.embed_string(input).await?);
-            }
-            Ok(embeddings)
-        })
+        let inputs = inputs
+            .into_iter()
+            .map(|text| EmbeddingInput::new(text, EmbeddingVariant::Document))
+            .collect();
+        Box::pin(embed_in_batches(inputs, |input| {
+            self.embed_string(input.text)
+        }))
     }
 
     /// Embed a [`EmbeddingInput`] into a vector space
@@ -63,17 +69,13 @@ pub trait Embedder: Send + Sync + 'static {
     ) -> BoxedFuture<'_, anyhow::Result<Embedding<Self::VectorSpace>>>;
 
     /// Embed a [`Vec<String>`] into a vector space. Returns a list of embeddings in the same order as the inputs.
+    ///
+    /// By default, the inputs are grouped by length into batches like an [`EmbeddingScheduler`](crate::EmbeddingScheduler) groups them, and the up to [`MAX_CONCURRENT_EMBEDDINGS`] inputs in each batch are embedded concurrently with [`Embedder::embed_for`], so embedders that batch concurrent requests (like [`BatchedEmbedder`]) embed inputs with a similar length together.
     fn embed_vec_for(
         &self,
         inputs: Vec<EmbeddingInput>,
     ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
-        Box::pin(async move {
-            let mut embeddings = Vec::with_capacity(inputs.len());
-            for input in inputs {
-                embeddings.push(self.embed_for(input).await?);
-            }
-            Ok(embeddings)
-        })
+        Box::pin(embed_in_batches(inputs, |input| self.embed_for(input)))
     }
 }
 
@@ -122,6 +124,22 @@ pub trait EmbedderExt: Embedder {
         Box::new(AnyEmbedder::<Self>(self))
     }
 
+    /// Wrap the embedder in a [`BatchedEmbedder`] that collects concurrent embedding requests into batches.
+    fn batched(self) -> BatchedEmbedder<Self>
+    where
+        Self: Sized,
+    {
+        BatchedEmbedder::new(self)
+    }
+
+    /// Wrap the embedder in a [`TruncatedEmbedder`] that truncates every embedding to the first `DIMENSIONS` dimensions. This only works well for models trained with Matryoshka representation learning.
+    fn truncated<const DIMENSIONS: usize>(self) -> TruncatedEmbedder<Self, DIMENSIONS>
+    where
+        Self: Sized,
+    {
+        TruncatedEmbedder::new(self)
+    }
+
     /// Embed some text into a vector space
     fn embed(
         &self,
@@ -212,3 +230,63 @@ impl<E: Embedder + Send + Sync + 'static> Embedder for AnyEmbedder<E> {
         })
     }
 }
+
+#[tokio::test]
+async fn default_embed_vec_does_not_recurse() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// An embedder that implements the variant methods with the plain methods, like many embedders do
+    struct PlainEmbedder {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl Embedder for PlainEmbedder {
+        type VectorSpace = UnknownVectorSpace;
+
+        fn embed_string(
+            &self,
+            input: String,
+        ) -> BoxedFuture<'_, anyhow::Result<Embedding<UnknownVectorSpace>>> {
+            Box::pin(async move {
+                let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(Embedding::from([input.len() as f32]))
+            })
+        }
+
+        fn embed_for(
+            &self,
+            input: EmbeddingInput,
+        ) -> BoxedFuture<'_, anyhow::Result<Embedding<UnknownVectorSpace>>> {
+            self.embed_string(input.text)
+        }
+
+        fn embed_vec_for(
+            &self,
+            inputs: Vec<EmbeddingInput>,
+        ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<UnknownVectorSpace>>>> {
+            self.embed_vec(inputs.into_iter().map(|input| input.text).collect())
+        }
+    }
+
+    let embedder = PlainEmbedder {
+        in_flight: AtomicUsize::new(0),
+        max_in_flight: AtomicUsize::new(0),
+    };
+    let texts: Vec<_> = (0..200).map(|i| "a".repeat(i)).collect();
+    let embeddings = embedder
+        .embed_batch_for(
+            texts
+                .iter()
+                .map(|text| EmbeddingInput::new(text, EmbeddingVariant::Query)),
+        )
+        .await
+        .unwrap();
+    for (i, embedding) in embeddings.iter().enumerate() {
+        assert_eq!(embedding.to_vec(), [i as f32]);
+    }
+    assert!(embedder.max_in_flight.load(Ordering::SeqCst) <= MAX_CONCURRENT_EMBEDDINGS);
+}
@@ -0,0 +1,129 @@
+use futures_util::future::BoxFuture;
+use std::marker::PhantomData;
+
+use crate::{Embedder, Embedding, EmbeddingInput, EmbeddingVariant, VectorSpace};
+
+/// The vector space of embeddings from `S` that were truncated to their first `DIMENSIONS` dimensions with [`Embedding::truncate`].
+///
+/// Truncated embeddings can't be compared with the full embeddings they came from, so they get their own vector space.
+pub struct Truncated<S: VectorSpace, const DIMENSIONS: usize>(PhantomData<S>);
+
+impl<S: VectorSpace, const DIMENSIONS: usize> VectorSpace for Truncated<S, DIMENSIONS> {}
+
+impl<S: VectorSpace> Embedding<S> {
+    /// Truncate this embedding to its first `DIMENSIONS` dimensions and normalize the result. If the first `DIMENSIONS` dimensions are all zero, they are returned as they are.
+    ///
+    /// Models trained with Matryoshka representation learning (like Snowflake Arctic Embed and newer BGE models) put the most important information in the first dimensions, so truncated embeddings keep most of their quality while taking up a fraction of the space. For other models, truncation will make search results much worse.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kalosm_language_model::*;
+    /// let embedding = Embedding::<UnknownVectorSpace>::from([3.0, 4.0, 1.0, 2.0]);
+    /// let truncated = embedding.truncate::<2>().unwrap();
+    /// assert_eq!(truncated.to_vec(), [0.6, 0.8]);
+    /// ```
+    pub fn truncate<const DIMENSIONS: usize>(
+        &self,
+    ) -> anyhow::Result<Embedding<Truncated<S, DIMENSIONS>>> {
+        let dimensions = self.embedding.elem_count();
+        if DIMENSIONS == 0 || DIMENSIONS > dimensions {
+            anyhow::bail!(
+                "Can't truncate an embedding with {dimensions} dimensions to {DIMENSIONS} dimensions"
+            );
+        }
+        let truncated = self.embedding.flatten_all()?.narrow(0, 0, DIMENSIONS)?;
+        let norm = truncated.sqr()?.sum_all()?.sqrt()?;
+        // A prefix of all zeros can't be normalized. Dividing by its norm would fill it with NaN
+        if norm.to_dtype(candle_core::DType::F32)?.to_scalar::<f32>()? == 0.0 {
+            return Ok(Embedding::new(truncated));
+        }
+        let truncated = truncated.broadcast_div(&norm)?;
+        Ok(Embedding::new(truncated))
+    }
+}
+
+/// An embedder that truncates the embeddings from another embedder to their first `DIMENSIONS` dimensions. See [`Embedding::truncate`].
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm::language::*;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     // Only keep the first 256 dimensions of each embedding
+///     let bert = Bert::new_for_search().await.unwrap().truncated::<256>();
+///     let embedding = bert.embed("Cats are cool").await.unwrap();
+///     assert_eq!(embedding.to_vec().len(), 256);
+/// }
+/// ```
+pub struct TruncatedEmbedder<M: Embedder, const DIMENSIONS: usize> {
+    model: M,
+}
+
+impl<M: Embedder, const DIMENSIONS: usize> TruncatedEmbedder<M, DIMENSIONS> {
+    /// Create a new truncated embedder.
+    pub fn new(model: M) -> Self {
+        Self { model }
+    }
+
+    /// Get a reference to the underlying embedder.
+    pub fn get_embedder(&self) -> &M {
+        &self.model
+    }
+}
+
+impl<M: Embedder, const DIMENSIONS: usize> Embedder for TruncatedEmbedder<M, DIMENSIONS> {
+    type VectorSpace = Truncated<M::VectorSpace, DIMENSIONS>;
+
+    fn embed_for(
+        &self,
+        input: EmbeddingInput,
+    ) -> BoxFuture<'_, anyhow::Result<Embedding<Self::VectorSpace>>> {
+        Box::pin(async move { self.model.embed_for(input).await?.truncate() })
+    }
+
+    fn embed_vec(
+        &self,
+        inputs: Vec<String>,
+    ) -> BoxFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        self.embed_vec_for(
+            inputs
+                .into_iter()
+                .map(|text| EmbeddingInput::new(text, EmbeddingVariant::Document))
+                .collect(),
+        )
+    }
+
+    fn embed_vec_for(
+        &self,
+        inputs: Vec<EmbeddingInput>,
+    ) -> BoxFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        Box::pin(async move {
+            self.model
+                .embed_vec_for(inputs)
+                .await?
+                .iter()
+                .map(Embedding::truncate)
+                .collect()
+        })
+    }
+}
+
+#[test]
+fn truncation_renormalizes() {
+    use crate::UnknownVectorSpace;
+
+    let embedding = Embedding::<UnknownVectorSpace>::from([0.0, 2.0, 0.0, 5.0]);
+    let truncated = embedding.truncate::<3>().unwrap();
+    assert_eq!(truncated.to_vec(), [0.0, 1.0, 0.0]);
+    assert!(embedding.truncate::<5>().is_err());
+}
+
+#[test]
+fn truncating_a_zero_prefix_does_not_produce_nan() {
+    use crate::UnknownVectorSpace;
+
+    let embedding = Embedding::<UnknownVectorSpace>::from([0.0, 0.0, 3.0, 4.0]);
+    let truncated = embedding.truncate::<2>().unwrap();
+    assert_eq!(truncated.to_vec(), [0.0, 0.0]);
+}
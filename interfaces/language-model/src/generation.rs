@@ -0,0 +1,232 @@
+use std::sync::{Arc, Mutex};
+
+use llm_samplers::types::{Logits, Sampler};
+use tokenizers::tokenizer::Tokenizer;
+
+use crate::{ModelFeedback, TokenOutputStream};
+
+/// The state of a text generation that is stepped one token at a time.
+///
+/// [`SyncModelExt::stream_text_with_sampler`](crate::SyncModelExt::stream_text_with_sampler) drives a `TextGeneration` for a single session. Models that decode several sessions at once can keep one `TextGeneration` per session and step each of them with the logits from a shared forward pass.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm::language::*;
+/// use kalosm_language_model::TextGeneration;
+/// use std::sync::{Arc, Mutex};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let model = Llama::new().await.unwrap();
+///     model
+///         .run_sync(|model| {
+///             Box::pin(async move {
+///                 let mut session = model.new_session().unwrap();
+///                 let prompt = model.tokenizer().encode("The capital of France is", false).unwrap();
+///                 let sampler = Arc::new(Mutex::new(GenerationParameters::default().sampler()));
+///                 let mut generation = TextGeneration::new(
+///                     model.tokenizer(),
+///                     prompt.get_ids(),
+///                     model.stop_token().unwrap(),
+///                     Some(20),
+///                     None,
+///                     sampler,
+///                 )
+///                 .unwrap();
+///                 let mut on_token = |text: String| {
+///                     print!("{text}");
+///                     Ok(ModelFeedback::Continue)
+///                 };
+///
+///                 let mut logits = Vec::new();
+///                 model.feed_tokens(&mut session, prompt.get_ids(), &mut logits).unwrap();
+///                 while let Some(token) = generation.next_token(&logits, &mut on_token).unwrap() {
+///                     model.feed_tokens(&mut session, &[token], &mut logits).unwrap();
+///                 }
+///                 generation.finish(&mut on_token).unwrap();
+///             })
+///         })
+///         .unwrap();
+/// }
+/// ```
+pub struct TextGeneration {
+    text_stream: TokenOutputStream,
+    // Generations driven by speculative decoding choose their tokens outside of the generation
+    sampler: Option<Arc<Mutex<dyn Sampler>>>,
+    stop_on: Option<String>,
+    stop_on_lowercase: Option<String>,
+    // This stores a buffer of text that has been generated to check against the stop_on string. It should never be longer than the stop_on string.
+    queued_text_matching_stop_on: String,
+    stop_token: u32,
+    max_tokens: Option<u32>,
+    tokens_generated: u32,
+}
+
+impl TextGeneration {
+    /// Start generating text after the given prompt tokens.
+    pub fn new(
+        tokenizer: Arc<Tokenizer>,
+        prompt: &[u32],
+        stop_token: u32,
+        max_tokens: Option<u32>,
+        stop_on: Option<&str>,
+        sampler: Arc<Mutex<dyn Sampler>>,
+    ) -> anyhow::Result<Self> {
+        Self::start(
+            tokenizer,
+            prompt,
+            stop_token,
+            max_tokens,
+            stop_on,
+            Some(sampler),
+        )
+    }
+
+    /// Start a generation that is only advanced with [`TextGeneration::add_token`].
+    pub(crate) fn without_sampler(
+        tokenizer: Arc<Tokenizer>,
+        prompt: &[u32],
+        stop_token: u32,
+        max_tokens: Option<u32>,
+        stop_on: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        Self::start(tokenizer, prompt, stop_token, max_tokens, stop_on, None)
+    }
+
+    fn start(
+        tokenizer: Arc<Tokenizer>,
+        prompt: &[u32],
+        stop_token: u32,
+        max_tokens: Option<u32>,
+        stop_on: Option<&str>,
+        sampler: Option<Arc<Mutex<dyn Sampler>>>,
+    ) -> anyhow::Result<Self> {
+        let mut text_stream = TokenOutputStream::new(tokenizer);
+        for &token in prompt {
+            text_stream.next_token(token)?;
+        }
+        Ok(Self {
+            text_stream,
+            sampler,
+            stop_on: stop_on.map(ToString::to_string),
+            stop_on_lowercase: stop_on.map(|s| s.to_lowercase()),
+            queued_text_matching_stop_on: String::new(),
+            stop_token,
+            max_tokens,
+            tokens_generated: 0,
+        })
+    }
+
+    /// Sample the next token from the logits of the last token fed into the model, and pass any new text to `on_token`.
+    ///
+    /// Returns the token to feed into the model next, or `None` if the generation is finished. Once the generation is finished, call [`TextGeneration::finish`].
+    pub fn next_token(
+        &mut self,
+        logits: &[f32],
+        on_token: impl FnMut(String) -> anyhow::Result<ModelFeedback>,
+    ) -> anyhow::Result<Option<u32>> {
+        let Some(sampler) = &mut self.sampler else {
+            anyhow::bail!("This generation has no sampler. Add tokens with add_token instead");
+        };
+        let logits = Logits::try_from_iter_top_k(logits.iter().copied(), 512)?;
+        let new_token = self
+            .text_stream
+            .sample_token(sampler, logits, self.stop_on.as_deref())?;
+        Ok(self.add_token(new_token, on_token)?.then_some(new_token))
+    }
+
+    /// The prompt and generated tokens so far.
+    pub(crate) fn tokens(&self) -> &[u32] {
+        self.text_stream.tokens()
+    }
+
+    /// The token that ends the generation.
+    pub(crate) fn stop_token(&self) -> u32 {
+        self.stop_token
+    }
+
+    /// Add a token to the generation, and pass any new text to `on_token`. Returns `false` if the generation is finished.
+    pub(crate) fn add_token(
+        &mut self,
+        new_token: u32,
+        mut on_token: impl FnMut(String) -> anyhow::Result<ModelFeedback>,
+    ) -> anyhow::Result<bool> {
+        if new_token == self.stop_token {
+            tracing::trace!("Stopping on stop token");
+            return Ok(false);
+        }
+        if let Some(mut new_text) = self.text_stream.next_token(new_token)? {
+            if let Some(stop_on) = self.stop_on_lowercase.as_deref() {
+                let lowercase = new_text.to_lowercase();
+
+                // Check if the string ends with the start of the stop_on string
+                let mut before_stop_on = None;
+                let remaining_stop_on = stop_on
+                    .strip_prefix(&self.queued_text_matching_stop_on)
+                    .unwrap_or(stop_on);
+
+                // If the remaining stop_on string is empty, we have found a match
+                if remaining_stop_on.is_empty() {
+                    return Ok(false);
+                }
+
+                for (i, _) in lowercase.char_indices() {
+                    let end_of_new_text = &lowercase[i..];
+                    if end_of_new_text.is_empty() {
+                        break;
+                    }
+
+                    // Check if we have matched all of the stop_on string
+                    if end_of_new_text.starts_with(remaining_stop_on) {
+                        self.queued_text_matching_stop_on += end_of_new_text;
+                        return Ok(false);
+                    }
+
+                    // Check if the string ends with the start of the stop_on string
+                    if remaining_stop_on.starts_with(end_of_new_text) {
+                        before_stop_on = Some(lowercase[..i].to_string());
+                        self.queued_text_matching_stop_on += end_of_new_text;
+                        break;
+                    }
+                }
+
+                match before_stop_on {
+                    Some(before_stop_on) => {
+                        if let ModelFeedback::Stop = on_token(before_stop_on)? {
+                            return Ok(false);
+                        }
+                    }
+                    None => {
+                        new_text =
+                            std::mem::take(&mut self.queued_text_matching_stop_on) + &new_text;
+                        if let ModelFeedback::Stop = on_token(new_text)? {
+                            return Ok(false);
+                        }
+                    }
+                }
+            } else if let ModelFeedback::Stop = on_token(new_text)? {
+                return Ok(false);
+            }
+        }
+        self.tokens_generated += 1;
+        if let Some(max_tokens) = self.max_tokens {
+            if self.tokens_generated >= max_tokens {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Finish the generation and pass any text that was held back while checking for the stop_on string to `on_token`.
+    pub fn finish(
+        self,
+        mut on_token: impl FnMut(String) -> anyhow::Result<ModelFeedback>,
+    ) -> anyhow::Result<()> {
+        if let Some(stop_string) = &self.stop_on_lowercase {
+            if !self.queued_text_matching_stop_on.starts_with(stop_string) {
+                on_token(self.queued_text_matching_stop_on)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -30,11 +30,20 @@ mod remote;
 #[cfg(feature = "remote")]
 pub use remote::*;
 
+mod sampling;
+pub use sampling::*;
+mod speculative;
+pub(crate) use speculative::TokenDistribution;
+pub use speculative::{SpeculativeDraft, SpeculativeGeneration, SpeculativeSampling};
 mod structured;
 mod token_stream;
 pub use token_stream::*;
 
 mod embedding;
 pub use embedding::*;
+mod generation;
+pub use generation::*;
 mod model;
 pub use model::*;
+#[cfg(test)]
+mod test_util;
@@ -1,5 +1,9 @@
-use crate::structured::generate_structured;
-use crate::TokenOutputStream;
+use crate::speculative::stream_text_with_draft;
+use crate::structured::{
+    generate_structured, generate_structured_samples, generate_structured_with_draft,
+};
+use crate::{SampleSelection, SelectedSample, StructuredSample};
+use crate::{SpeculativeDraft, SpeculativeSampling, TextGeneration};
 use futures_util::{Future, FutureExt};
 use futures_util::{Stream, StreamExt};
 use kalosm_common::*;
@@ -480,6 +484,130 @@ pub trait ModelExt: Model + Send + Sync + 'static {
         StructureParserResult::new(Self::TextStream::from(receiver), result_receiver)
     }
 
+    /// Generate a type that implements [`Parse`] multiple times and choose the output the model thinks is most likely.
+    ///
+    /// The prompt is only processed once and shared between the samples.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm::language::*;
+    ///
+    /// #[derive(Parse, Clone, Debug)]
+    /// enum Sentiment {
+    ///     Positive,
+    ///     Negative,
+    /// }
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> anyhow::Result<()> {
+    /// let llm = Llama::new().await?;
+    /// let prompt = "The review 'I loved this movie' has the sentiment: ";
+    ///
+    /// let sentiment = llm.generate_parsed_best_of::<Sentiment>(prompt, 5).await?;
+    /// println!("{:?} ({}% of samples agree)", sentiment.winner(), sentiment.agreement() * 100.);
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn generate_parsed_best_of<P: Parse + 'static>(
+        &self,
+        prompt: &str,
+        samples: usize,
+    ) -> impl Future<Output = anyhow::Result<SelectedSample<P>>> + Send {
+        self.sample_structured_text(
+            prompt,
+            P::new_parser(),
+            samples,
+            SampleSelection::MostLikely,
+        )
+    }
+
+    /// Generate a type that implements [`Parse`] multiple times and choose the answer most samples agree on (self-consistency).
+    /// `agree` returns true if two outputs are the same answer.
+    ///
+    /// The prompt is only processed once and shared between the samples.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm::language::*;
+    ///
+    /// #[derive(Parse, Clone, Debug)]
+    /// struct Answer {
+    ///     reasoning: String,
+    ///     result: i64,
+    /// }
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> anyhow::Result<()> {
+    /// let llm = Llama::new().await?;
+    /// let prompt = "What is 17 * 3? Answer in JSON: ";
+    ///
+    /// // Samples agree if they have the same result, even if the reasoning is different
+    /// let answer = llm
+    ///     .generate_parsed_self_consistency::<Answer>(prompt, 8, |a, b| a.result == b.result)
+    ///     .await?;
+    /// println!("{} ({} of 8 samples agree)", answer.winner().result, answer.agreeing_samples());
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn generate_parsed_self_consistency<P: Parse + 'static>(
+        &self,
+        prompt: &str,
+        samples: usize,
+        agree: impl Fn(&P, &P) -> bool + Send + Sync + 'static,
+    ) -> impl Future<Output = anyhow::Result<SelectedSample<P>>> + Send {
+        self.sample_structured_text(
+            prompt,
+            P::new_parser(),
+            samples,
+            SampleSelection::vote(agree),
+        )
+    }
+
+    /// Generate structured text with the given prompt and constraints multiple times, and choose one output with the [`SampleSelection`].
+    /// See [`ModelExt::generate_parsed_best_of`] and [`ModelExt::generate_parsed_self_consistency`] for more information.
+    fn sample_structured_text<P>(
+        &self,
+        prompt: &str,
+        parser: P,
+        samples: usize,
+        selection: SampleSelection<P::Output>,
+    ) -> impl Future<Output = anyhow::Result<SelectedSample<P::Output>>> + Send
+    where
+        P: CreateParserState<PartialState: Send, Output: Send> + Send + 'static,
+    {
+        let (result_sender, result_receiver) = tokio::sync::oneshot::channel();
+
+        let prompt = prompt.to_string();
+        let sampler = Arc::new(Mutex::new(GenerationParameters::default().sampler()));
+        let result = self.run_sync(move |llm: &mut Self::SyncModel| {
+            Box::pin(async move {
+                let result = llm.new_session().and_then(|mut session| {
+                    let parser_state = parser.create_parser_state();
+                    let samples = llm.generate_structured_samples(
+                        &mut session,
+                        prompt,
+                        parser,
+                        parser_state,
+                        sampler,
+                        samples,
+                        Some(64),
+                    )?;
+                    selection
+                        .select(samples)
+                        .ok_or_else(|| anyhow::anyhow!("No samples were generated"))
+                });
+                _ = result_sender.send(result);
+            })
+        });
+
+        async move {
+            result?;
+            result_receiver
+                .await
+                .map_err(|_| anyhow::anyhow!("Model stopped"))?
+        }
+    }
+
     /// Get the default constraints for an assistant response. It parses any text until the end of the assistant's response.
     fn default_assistant_constraints(&self) -> Option<StopOn> {
         let end_assistant_marker = self.chat_markers()?.end_assistant_marker;
@@ -607,11 +735,34 @@ pub trait SyncModel {
         into: &mut Vec<f32>,
     ) -> anyhow::Result<()>;
 
+    /// Run the model with a pre-tokenized input and write the logits after each token into `into`. This lets a model check several tokens at once, like the tokens proposed by a [`SpeculativeDraft`].
+    ///
+    /// The default implementation feeds the tokens one at a time.
+    fn feed_tokens_all_logits(
+        &self,
+        session: &mut Self::Session,
+        tokens: &[u32],
+        into: &mut Vec<Vec<f32>>,
+    ) -> anyhow::Result<()> {
+        into.clear();
+        for token in tokens {
+            let mut logits = Vec::new();
+            self.feed_tokens(session, &[*token], &mut logits)?;
+            into.push(logits);
+        }
+        Ok(())
+    }
+
     /// Get the token ID that represents the end of a sequence.
     fn stop_token(&self) -> anyhow::Result<u32>;
 
     /// Return the tokenizer associated with this model.
     fn tokenizer(&self) -> Arc<Tokenizer>;
+
+    /// The maximum number of tokens the model can attend to in a single session, if known.
+    fn context_length(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// A session for a model.
@@ -641,6 +792,11 @@ pub trait Session {
     {
         Err(anyhow::Error::msg("Not implemented"))
     }
+
+    /// Remove the last `tokens` tokens from the session.
+    fn rollback(&mut self, _tokens: usize) -> anyhow::Result<()> {
+        Err(anyhow::Error::msg("Not implemented"))
+    }
 }
 
 impl Session for () {
@@ -679,6 +835,81 @@ pub trait SyncModelExt: SyncModel {
         )
     }
 
+    /// Generate multiple outputs for the same prompt that conform to the given parser. The prompt is fed into the session once, and the session is forked for each sample.
+    ///
+    /// The session must support [`Session::try_clone`].
+    #[allow(clippy::too_many_arguments)]
+    fn generate_structured_samples<P: Parser>(
+        &self,
+        session: &mut Self::Session,
+        prompt: impl Display,
+        parser: P,
+        parser_state: P::PartialState,
+        sampler: Arc<Mutex<dyn Sampler>>,
+        samples: usize,
+        top_k: Option<usize>,
+    ) -> anyhow::Result<Vec<StructuredSample<P::Output>>> {
+        generate_structured_samples(
+            prompt,
+            self,
+            session,
+            parser,
+            parser_state,
+            sampler,
+            samples,
+            top_k,
+        )
+    }
+
+    /// Generate new text with the given prompt that conforms to the given parser, with tokens proposed by a draft model. Draft tokens that the parser rejects are never accepted, so the output follows the same distribution as sampling the valid tokens from `sampling` without a draft.
+    ///
+    /// Both sessions must support [`Session::rollback`].
+    #[allow(clippy::too_many_arguments)]
+    fn generate_structured_with_draft<D: SyncModel + ?Sized, P: Parser>(
+        &self,
+        session: &mut Self::Session,
+        draft: SpeculativeDraft<'_, D>,
+        prompt: impl Display,
+        parser: P,
+        parser_state: P::PartialState,
+        sampling: SpeculativeSampling,
+        on_token: impl FnMut(String) -> anyhow::Result<()>,
+        top_k: Option<usize>,
+    ) -> anyhow::Result<P::Output> {
+        generate_structured_with_draft(
+            prompt,
+            self,
+            session,
+            draft,
+            parser,
+            parser_state,
+            sampling,
+            on_token,
+            top_k,
+        )
+    }
+
+    /// Stream text like [`SyncModelExt::stream_text_with_sampler`], with tokens proposed by a draft model. The draft model's tokens are verified in one forward pass of this model, and rejected tokens are removed from both sessions.
+    ///
+    /// Tokens are sampled from `sampling` instead of a [`Sampler`] because the probability of each draft token under both models must be known exactly. The output follows the same distribution as sampling from `sampling` without a draft.
+    ///
+    /// Both sessions must support [`Session::rollback`].
+    #[allow(clippy::too_many_arguments)]
+    fn stream_text_with_draft<D: SyncModel + ?Sized>(
+        &self,
+        session: &mut Self::Session,
+        draft: SpeculativeDraft<'_, D>,
+        prompt: &str,
+        max_tokens: Option<u32>,
+        stop_on: Option<&str>,
+        sampling: SpeculativeSampling,
+        on_token: impl FnMut(String) -> anyhow::Result<ModelFeedback>,
+    ) -> anyhow::Result<()> {
+        stream_text_with_draft(
+            self, session, draft, prompt, max_tokens, stop_on, sampling, on_token,
+        )
+    }
+
     #[allow(clippy::too_many_arguments)]
     /// Stream text, calling the on_token callback every time a new token is generated. For some models, this could be used to implement [`Model::stream_text_with_sampler`].
     fn stream_text_with_sampler(
@@ -687,7 +918,7 @@ pub trait SyncModelExt: SyncModel {
         prompt: &str,
         max_tokens: Option<u32>,
         stop_on: Option<&str>,
-        mut sampler: Arc<Mutex<dyn Sampler>>,
+        sampler: Arc<Mutex<dyn Sampler>>,
         mut on_token: impl FnMut(String) -> anyhow::Result<ModelFeedback>,
     ) -> anyhow::Result<()> {
         let tokens = self
@@ -695,99 +926,22 @@ pub trait SyncModelExt: SyncModel {
             .encode(prompt, false)
             .map_err(|e| anyhow::anyhow!(e))?;
         let tokens = tokens.get_ids();
-        let mut text_stream = TokenOutputStream::new(self.tokenizer());
-        for &token in tokens {
-            text_stream.next_token(token)?;
-        }
+        let mut generation = TextGeneration::new(
+            self.tokenizer(),
+            tokens,
+            self.stop_token()?,
+            max_tokens,
+            stop_on,
+            sampler,
+        )?;
 
         let mut logit_probs = Vec::new();
         self.feed_tokens(session, tokens, &mut logit_probs)?;
-        let mut logits = Logits::try_from_iter_top_k(logit_probs, 512)?;
-        let mut tokens_generated = 0;
-        // This stores a buffer of text that has been generated to check against the stop_on string. It should never be longer than the stop_on string.
-        let mut queued_text_matching_stop_on = String::new();
-        let stop_on_lowercase = stop_on.map(|s| s.to_lowercase());
-        let stop_on_lowercase = stop_on_lowercase.as_deref();
-        let stop_token = self.stop_token()?;
-        let mut logit_probs = Vec::new();
-
-        'generate: loop {
-            let new_token = text_stream.sample_token(&mut sampler, logits, stop_on)?;
-            if new_token == stop_token {
-                tracing::trace!("Stopping on stop token");
-                break;
-            }
-            if let Some(mut new_text) = text_stream.next_token(new_token)? {
-                if let Some(stop_on) = stop_on_lowercase {
-                    let lowercase = new_text.to_lowercase();
-
-                    // Check if the string ends with the start of the stop_on string
-                    let mut before_stop_on = None;
-                    let remaining_stop_on = stop_on
-                        .strip_prefix(&queued_text_matching_stop_on)
-                        .unwrap_or(stop_on);
-
-                    // If the remaining stop_on string is empty, we have found a match
-                    if remaining_stop_on.is_empty() {
-                        break;
-                    }
-
-                    for (i, _) in lowercase.char_indices() {
-                        let end_of_new_text = &lowercase[i..];
-                        if end_of_new_text.is_empty() {
-                            break;
-                        }
-
-                        // Check if we have matched all of the stop_on string
-                        if end_of_new_text.starts_with(remaining_stop_on) {
-                            queued_text_matching_stop_on += end_of_new_text;
-                            break 'generate;
-                        }
-
-                        // Check if the string ends with the start of the stop_on string
-                        if remaining_stop_on.starts_with(end_of_new_text) {
-                            before_stop_on = Some(lowercase[..i].to_string());
-                            queued_text_matching_stop_on += end_of_new_text;
-                            break;
-                        }
-                    }
-
-                    match before_stop_on {
-                        Some(before_stop_on) => {
-                            if let ModelFeedback::Stop = on_token(before_stop_on)? {
-                                break;
-                            }
-                        }
-                        None => {
-                            new_text =
-                                std::mem::take(&mut queued_text_matching_stop_on) + &new_text;
-                            if let ModelFeedback::Stop = on_token(new_text)? {
-                                break;
-                            }
-                        }
-                    }
-                } else if let ModelFeedback::Stop = on_token(new_text)? {
-                    break;
-                }
-            }
-            tokens_generated += 1;
-            if let Some(max_tokens) = max_tokens {
-                if tokens_generated >= max_tokens {
-                    break;
-                }
-            }
+        while let Some(new_token) = generation.next_token(&logit_probs, &mut on_token)? {
             self.feed_tokens(session, &[new_token], &mut logit_probs)?;
-            logits = Logits::try_from_iter_top_k(logit_probs.iter().copied(), 512)?;
-        }
-
-        // Flush the queued text
-        if let Some(stop_string) = stop_on_lowercase {
-            if !queued_text_matching_stop_on.starts_with(stop_string) {
-                on_token(queued_text_matching_stop_on)?;
-            }
         }
 
-        Ok(())
+        generation.finish(on_token)
     }
 }
 
@@ -1043,6 +1197,11 @@ impl SyncModel for BoxedSyncModel {
         let self_ref: &(dyn SyncModel<Session = AnySession>) = self.as_ref();
         self_ref.tokenizer()
     }
+
+    fn context_length(&self) -> Option<usize> {
+        let self_ref: &(dyn SyncModel<Session = AnySession>) = self.as_ref();
+        self_ref.context_length()
+    }
 }
 
 struct AnyModel<M>(M);
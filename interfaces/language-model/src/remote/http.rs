@@ -0,0 +1,521 @@
+use kalosm_common::*;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::Value;
+
+use crate::{Embedder, Embedding, EmbeddingInput, EmbeddingVariant, ModelBuilder, VectorSpace};
+
+/// The text remote embedders add to the start of queries and documents. Many embedding models are trained with a prefix like `"search_query: "` for queries.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EmbeddingPrefixes {
+    pub(crate) query: Option<String>,
+    pub(crate) document: Option<String>,
+}
+
+impl EmbeddingPrefixes {
+    pub(crate) fn apply(&self, input: EmbeddingInput) -> String {
+        let prefix = match input.variant {
+            EmbeddingVariant::Query => &self.query,
+            EmbeddingVariant::Document => &self.document,
+        };
+        match prefix {
+            Some(prefix) => format!("{prefix}{}", input.text),
+            None => input.text,
+        }
+    }
+}
+
+/// Send a JSON request to an embedding server and return the JSON response.
+pub(crate) async fn post_json(
+    client: &reqwest::Client,
+    url: &str,
+    headers: &HeaderMap,
+    body: &Value,
+) -> anyhow::Result<Value> {
+    let response = client
+        .post(url)
+        .headers(headers.clone())
+        .json(body)
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        let message = response.text().await.unwrap_or_default();
+        anyhow::bail!("The embedding server at {url} returned {status}: {message}");
+    }
+    Ok(response.json().await?)
+}
+
+/// Read a list of embeddings from a JSON value.
+pub(crate) fn parse_embeddings<S: VectorSpace>(
+    values: &[Value],
+) -> anyhow::Result<Vec<Embedding<S>>> {
+    values
+        .iter()
+        .map(|value| {
+            let vector = value
+                .as_array()
+                .ok_or_else(|| anyhow::anyhow!("Expected an embedding array, found {value}"))?;
+            vector
+                .iter()
+                .map(|x| {
+                    x.as_f64()
+                        .map(|x| x as f32)
+                        .ok_or_else(|| anyhow::anyhow!("Expected a number, found {x}"))
+                })
+                .collect::<anyhow::Result<Vec<f32>>>()
+                .map(Embedding::from)
+        })
+        .collect()
+}
+
+/// Embed the inputs in batches of at most `max_batch_size`, and check that the server returned one embedding per input.
+pub(crate) async fn embed_in_batches<S, F, Fut>(
+    inputs: Vec<String>,
+    max_batch_size: usize,
+    mut embed_batch: F,
+) -> anyhow::Result<Vec<Embedding<S>>>
+where
+    S: VectorSpace,
+    F: FnMut(Vec<String>) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<Vec<Embedding<S>>>>,
+{
+    let mut embeddings = Vec::with_capacity(inputs.len());
+    let mut inputs = inputs.into_iter().peekable();
+    while inputs.peek().is_some() {
+        let batch: Vec<_> = inputs.by_ref().take(max_batch_size.max(1)).collect();
+        let len = batch.len();
+        let batch_embeddings = embed_batch(batch).await?;
+        if batch_embeddings.len() != len {
+            anyhow::bail!(
+                "The embedding server returned {} embeddings for {len} inputs",
+                batch_embeddings.len()
+            );
+        }
+        embeddings.extend(batch_embeddings);
+    }
+    Ok(embeddings)
+}
+
+/// The vector space for embeddings from a [`HttpEmbedder`].
+pub struct HttpEmbedderSpace;
+
+impl VectorSpace for HttpEmbedderSpace {}
+
+/// An embedder for any HTTP embedding API that accepts a batch of texts in a JSON request and returns a JSON response with one embedding per text.
+///
+/// The request is built from a JSON body template, with the inputs inserted at the request path. The embeddings are read from the response path. Paths are dot separated object keys, and `*` in the response path matches every item of an array.
+///
+/// By default the embedder sends OpenAI style requests (`{"input": [...]}`) and reads OpenAI style responses (`{"data": [{"embedding": [...]}]}`).
+///
+/// Queries and documents can be sent with different fields in the body with [`HttpEmbedderBuilder::with_query_body`] and [`HttpEmbedderBuilder::with_document_body`]. Queries and documents are sent in separate requests, so every request only contains one [`EmbeddingVariant`].
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm_language_model::*;
+/// use serde_json::json;
+///
+/// # #[tokio::main]
+/// # async fn main() -> anyhow::Result<()> {
+/// // A Cohere style API
+/// let embedder = HttpEmbedder::builder("https://api.cohere.com/v1/embed")
+///     .with_bearer_token("my-api-key")
+///     .with_body(json!({ "model": "embed-english-v3.0" }))
+///     .with_query_body(json!({ "input_type": "search_query" }))
+///     .with_document_body(json!({ "input_type": "search_document" }))
+///     .with_request_path("texts")
+///     .with_response_path("embeddings")
+///     .build()?;
+/// let document = embedder.embed("Kalosm is a library for local AI").await?;
+/// let query = embedder.embed_query("What is Kalosm?").await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct HttpEmbedder {
+    client: reqwest::Client,
+    url: String,
+    headers: HeaderMap,
+    query_body: Value,
+    document_body: Value,
+    request_path: Vec<String>,
+    response_path: Vec<String>,
+    prefixes: EmbeddingPrefixes,
+    max_batch_size: usize,
+}
+
+/// A builder for a [`HttpEmbedder`].
+#[derive(Debug, Clone)]
+pub struct HttpEmbedderBuilder {
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Value,
+    query_body: Option<Value>,
+    document_body: Option<Value>,
+    request_path: String,
+    response_path: String,
+    prefixes: EmbeddingPrefixes,
+    max_batch_size: usize,
+}
+
+impl HttpEmbedderBuilder {
+    /// Create a new builder for an embedder that sends requests to the given URL.
+    pub fn new(url: impl ToString) -> Self {
+        Self {
+            url: url.to_string(),
+            headers: Vec::new(),
+            body: Value::Object(Default::default()),
+            query_body: None,
+            document_body: None,
+            request_path: "input".to_string(),
+            response_path: "data.*.embedding".to_string(),
+            prefixes: EmbeddingPrefixes::default(),
+            max_batch_size: usize::MAX,
+        }
+    }
+
+    /// Add a header to every request.
+    pub fn with_header(mut self, name: impl ToString, value: impl ToString) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Send the token in a bearer authorization header with every request.
+    pub fn with_bearer_token(self, token: &str) -> Self {
+        self.with_header("authorization", format!("Bearer {token}"))
+    }
+
+    /// Set the JSON body every request starts from. This is where you set extra fields like the model name. (default: `{}`)
+    pub fn with_body(mut self, body: Value) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Set the fields added to the body of requests with queries. Objects are merged into the body from [`Self::with_body`], and other values replace the value in the body. Use this for APIs that take the kind of input in the request, like `"input_type": "search_query"`.
+    pub fn with_query_body(mut self, body: Value) -> Self {
+        self.query_body = Some(body);
+        self
+    }
+
+    /// Set the fields added to the body of requests with documents. Objects are merged into the body from [`Self::with_body`], and other values replace the value in the body.
+    pub fn with_document_body(mut self, body: Value) -> Self {
+        self.document_body = Some(body);
+        self
+    }
+
+    /// Set the path in the request body the list of inputs is inserted at. (default: `"input"`)
+    pub fn with_request_path(mut self, path: impl ToString) -> Self {
+        self.request_path = path.to_string();
+        self
+    }
+
+    /// Set the path in the response the embeddings are read from. If the path doesn't contain a `*`, it should point to a list of embeddings. (default: `"data.*.embedding"`)
+    pub fn with_response_path(mut self, path: impl ToString) -> Self {
+        self.response_path = path.to_string();
+        self
+    }
+
+    /// Set the text added to the start of queries.
+    pub fn with_query_prefix(mut self, prefix: impl ToString) -> Self {
+        self.prefixes.query = Some(prefix.to_string());
+        self
+    }
+
+    /// Set the text added to the start of documents.
+    pub fn with_document_prefix(mut self, prefix: impl ToString) -> Self {
+        self.prefixes.document = Some(prefix.to_string());
+        self
+    }
+
+    /// Set the maximum number of inputs sent in one request. (default: unlimited)
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Build the embedder.
+    pub fn build(self) -> anyhow::Result<HttpEmbedder> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers {
+            headers.insert(HeaderName::try_from(name)?, HeaderValue::try_from(value)?);
+        }
+        let split = |path: &str| {
+            path.split('.')
+                .filter(|key| !key.is_empty())
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+        let request_path = split(&self.request_path);
+        if request_path.is_empty() {
+            anyhow::bail!("The request path can't be empty");
+        }
+        if !self.body.is_object() {
+            anyhow::bail!("The request body must be a JSON object");
+        }
+        let variant_body = |body: Option<Value>| {
+            let mut merged = self.body.clone();
+            if let Some(body) = body {
+                if !body.is_object() {
+                    anyhow::bail!("The query and document bodies must be JSON objects");
+                }
+                merge_json(&mut merged, body);
+            }
+            Ok(merged)
+        };
+        let query_body = variant_body(self.query_body)?;
+        let document_body = variant_body(self.document_body)?;
+        Ok(HttpEmbedder {
+            client: reqwest::Client::new(),
+            url: self.url,
+            headers,
+            query_body,
+            document_body,
+            request_path,
+            response_path: split(&self.response_path),
+            prefixes: self.prefixes,
+            max_batch_size: self.max_batch_size,
+        })
+    }
+}
+
+/// Merge the fields of one JSON value into another. Objects are merged key by key, and any other value replaces the old value.
+fn merge_json(base: &mut Value, value: Value) {
+    match (base, value) {
+        (Value::Object(base), Value::Object(value)) => {
+            for (key, value) in value {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, value) => *base = value,
+    }
+}
+
+#[async_trait::async_trait]
+impl ModelBuilder for HttpEmbedderBuilder {
+    type Model = HttpEmbedder;
+
+    async fn start_with_loading_handler(
+        self,
+        _: impl FnMut(ModelLoadingProgress) + Send + Sync + 'static,
+    ) -> anyhow::Result<HttpEmbedder> {
+        self.build()
+    }
+
+    fn requires_download(&self) -> bool {
+        false
+    }
+}
+
+impl HttpEmbedder {
+    /// Create a new builder for an embedder that sends requests to the given URL.
+    pub fn builder(url: impl ToString) -> HttpEmbedderBuilder {
+        HttpEmbedderBuilder::new(url)
+    }
+
+    fn request(&self, variant: EmbeddingVariant, inputs: Vec<String>) -> Value {
+        let mut body = match variant {
+            EmbeddingVariant::Query => self.query_body.clone(),
+            EmbeddingVariant::Document => self.document_body.clone(),
+        };
+        let (last, parents) = self.request_path.split_last().unwrap();
+        let mut object = &mut body;
+        for key in parents {
+            object = object
+                .as_object_mut()
+                .unwrap()
+                .entry(key.clone())
+                .or_insert_with(|| Value::Object(Default::default()));
+            if !object.is_object() {
+                *object = Value::Object(Default::default());
+            }
+        }
+        object.as_object_mut().unwrap().insert(
+            last.clone(),
+            Value::Array(inputs.into_iter().map(Value::String).collect()),
+        );
+        body
+    }
+
+    fn embeddings(&self, response: &Value) -> anyhow::Result<Vec<Value>> {
+        let mut selected: Vec<&Value> = vec![response];
+        for key in &self.response_path {
+            selected = selected
+                .into_iter()
+                .map(|value| match (key.as_str(), value) {
+                    ("*", Value::Array(items)) => Ok(items.iter().collect()),
+                    (key, Value::Object(object)) => object
+                        .get(key)
+                        .map(|value| vec![value])
+                        .ok_or_else(|| anyhow::anyhow!("The response is missing the key {key}")),
+                    (key, value) => Err(anyhow::anyhow!("Can't read {key} from {value}")),
+                })
+                .collect::<anyhow::Result<Vec<Vec<_>>>>()?
+                .into_iter()
+                .flatten()
+                .collect();
+        }
+
+        if self.response_path.iter().any(|key| key == "*") {
+            Ok(selected.into_iter().cloned().collect())
+        } else {
+            match selected.first() {
+                Some(Value::Array(embeddings)) => Ok(embeddings.clone()),
+                _ => anyhow::bail!("The response path doesn't point to a list of embeddings"),
+            }
+        }
+    }
+
+    async fn embed_request(
+        &self,
+        variant: EmbeddingVariant,
+        inputs: Vec<String>,
+    ) -> anyhow::Result<Vec<Embedding<HttpEmbedderSpace>>> {
+        let response = post_json(
+            &self.client,
+            &self.url,
+            &self.headers,
+            &self.request(variant, inputs),
+        )
+        .await?;
+        parse_embeddings(&self.embeddings(&response)?)
+    }
+}
+
+impl Embedder for HttpEmbedder {
+    type VectorSpace = HttpEmbedderSpace;
+
+    fn embed_for(
+        &self,
+        input: EmbeddingInput,
+    ) -> BoxedFuture<'_, anyhow::Result<Embedding<Self::VectorSpace>>> {
+        Box::pin(async move {
+            let mut embeddings = self.embed_vec_for(vec![input]).await?;
+            Ok(embeddings.remove(0))
+        })
+    }
+
+    fn embed_vec(
+        &self,
+        inputs: Vec<String>,
+    ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        self.embed_vec_for(
+            inputs
+                .into_iter()
+                .map(|text| EmbeddingInput::new(text, EmbeddingVariant::Document))
+                .collect(),
+        )
+    }
+
+    fn embed_vec_for(
+        &self,
+        inputs: Vec<EmbeddingInput>,
+    ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        Box::pin(async move {
+            // Each request has the body of one variant, so queries and documents are embedded separately and put back in the order of the inputs
+            let mut embeddings: Vec<Option<Embedding<Self::VectorSpace>>> =
+                inputs.iter().map(|_| None).collect();
+            for variant in [EmbeddingVariant::Query, EmbeddingVariant::Document] {
+                let (indices, texts): (Vec<_>, Vec<_>) = inputs
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, input)| input.variant == variant)
+                    .map(|(index, input)| (index, self.prefixes.apply(input.clone())))
+                    .unzip();
+                if texts.is_empty() {
+                    continue;
+                }
+                let variant_embeddings = embed_in_batches(texts, self.max_batch_size, |batch| {
+                    self.embed_request(variant, batch)
+                })
+                .await?;
+                for (index, embedding) in indices.into_iter().zip(variant_embeddings) {
+                    embeddings[index] = Some(embedding);
+                }
+            }
+            Ok(embeddings.into_iter().flatten().collect())
+        })
+    }
+}
+
+#[tokio::test]
+async fn http_embedder_reads_nested_paths() {
+    use crate::EmbedderExt;
+    use serde_json::json;
+    use wiremock::matchers::{body_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let server = MockServer::start().await;
+    // Queries and documents are sent in separate requests with their own body
+    Mock::given(method("POST"))
+        .and(path("/v1/embed"))
+        .and(header("authorization", "Bearer secret"))
+        .and(body_json(json!({
+            "model": "test",
+            "input_type": "search_query",
+            "request": { "texts": ["query: cats"] }
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "result": { "data": [{ "values": [1.0, 0.0] }] }
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/v1/embed"))
+        .and(header("authorization", "Bearer secret"))
+        .and(body_json(json!({
+            "model": "test",
+            "input_type": "search_document",
+            "request": { "texts": ["dogs", "birds"] }
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "result": { "data": [{ "values": [0.0, 1.0] }, { "values": [0.5, 0.5] }] }
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let embedder = HttpEmbedder::builder(format!("{}/v1/embed", server.uri()))
+        .with_bearer_token("secret")
+        .with_body(json!({ "model": "test" }))
+        .with_query_body(json!({ "input_type": "search_query" }))
+        .with_document_body(json!({ "input_type": "search_document" }))
+        .with_request_path("request.texts")
+        .with_response_path("result.data.*.values")
+        .with_query_prefix("query: ")
+        .build()
+        .unwrap();
+    let embeddings = embedder
+        .embed_batch_for([
+            EmbeddingInput::new("dogs", EmbeddingVariant::Document),
+            EmbeddingInput::new("cats", EmbeddingVariant::Query),
+            EmbeddingInput::new("birds", EmbeddingVariant::Document),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(embeddings[0].to_vec(), [0.0, 1.0]);
+    assert_eq!(embeddings[1].to_vec(), [1.0, 0.0]);
+    assert_eq!(embeddings[2].to_vec(), [0.5, 0.5]);
+}
+
+#[test]
+fn variant_bodies_are_merged_into_the_body() {
+    use serde_json::json;
+
+    let mut body = json!({ "model": "test", "options": { "truncate": true, "dims": 256 } });
+    merge_json(
+        &mut body,
+        json!({ "input_type": "search_query", "options": { "dims": 512 } }),
+    );
+    assert_eq!(
+        body,
+        json!({
+            "model": "test",
+            "input_type": "search_query",
+            "options": { "truncate": true, "dims": 512 }
+        })
+    );
+}
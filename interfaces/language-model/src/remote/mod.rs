@@ -1,2 +1,8 @@
+mod http;
+pub use http::*;
+mod ollama;
+pub use ollama::*;
 mod open_ai;
 pub use open_ai::*;
+mod tei;
+pub use tei::*;
@@ -0,0 +1,203 @@
+use kalosm_common::*;
+use reqwest::header::HeaderMap;
+use serde_json::{json, Value};
+
+use super::http::{embed_in_batches, parse_embeddings, post_json, EmbeddingPrefixes};
+use crate::{Embedder, Embedding, EmbeddingInput, EmbeddingVariant, ModelBuilder, VectorSpace};
+
+/// The vector space for embeddings from an [`OllamaEmbedder`].
+pub struct OllamaSpace;
+
+impl VectorSpace for OllamaSpace {}
+
+/// An embedder that uses the embeddings API of an [Ollama](https://ollama.com) server.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm_language_model::*;
+///
+/// # #[tokio::main]
+/// # async fn main() -> anyhow::Result<()> {
+/// let embedder = OllamaEmbedder::builder("nomic-embed-text")
+///     .with_query_prefix("search_query: ")
+///     .with_document_prefix("search_document: ")
+///     .build();
+/// let embeddings = embedder.embed_batch(["Cats are cool", "Dogs are cool"]).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct OllamaEmbedder {
+    client: reqwest::Client,
+    model: String,
+    url: String,
+    prefixes: EmbeddingPrefixes,
+    max_batch_size: usize,
+}
+
+/// A builder for an [`OllamaEmbedder`].
+#[derive(Debug, Clone)]
+pub struct OllamaEmbedderBuilder {
+    model: String,
+    base_url: String,
+    prefixes: EmbeddingPrefixes,
+    max_batch_size: usize,
+}
+
+impl OllamaEmbedderBuilder {
+    /// Create a new builder for the given Ollama model.
+    pub fn new(model: impl ToString) -> Self {
+        Self {
+            model: model.to_string(),
+            base_url: "http://localhost:11434".to_string(),
+            prefixes: EmbeddingPrefixes::default(),
+            max_batch_size: usize::MAX,
+        }
+    }
+
+    /// Set the base URL of the Ollama server. (default: `http://localhost:11434`)
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Set the text added to the start of queries.
+    pub fn with_query_prefix(mut self, prefix: impl ToString) -> Self {
+        self.prefixes.query = Some(prefix.to_string());
+        self
+    }
+
+    /// Set the text added to the start of documents.
+    pub fn with_document_prefix(mut self, prefix: impl ToString) -> Self {
+        self.prefixes.document = Some(prefix.to_string());
+        self
+    }
+
+    /// Set the maximum number of inputs sent in one request. (default: unlimited)
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Build the embedder.
+    pub fn build(self) -> OllamaEmbedder {
+        OllamaEmbedder {
+            client: reqwest::Client::new(),
+            model: self.model,
+            url: format!("{}/api/embed", self.base_url),
+            prefixes: self.prefixes,
+            max_batch_size: self.max_batch_size,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ModelBuilder for OllamaEmbedderBuilder {
+    type Model = OllamaEmbedder;
+
+    async fn start_with_loading_handler(
+        self,
+        _: impl FnMut(ModelLoadingProgress) + Send + Sync + 'static,
+    ) -> anyhow::Result<OllamaEmbedder> {
+        Ok(self.build())
+    }
+
+    fn requires_download(&self) -> bool {
+        false
+    }
+}
+
+impl OllamaEmbedder {
+    /// Create a new builder for the given Ollama model.
+    pub fn builder(model: impl ToString) -> OllamaEmbedderBuilder {
+        OllamaEmbedderBuilder::new(model)
+    }
+
+    async fn embed_request(
+        &self,
+        inputs: Vec<String>,
+    ) -> anyhow::Result<Vec<Embedding<OllamaSpace>>> {
+        let request = json!({ "model": self.model, "input": inputs });
+        let response = post_json(&self.client, &self.url, &HeaderMap::new(), &request).await?;
+        match response.get("embeddings") {
+            Some(Value::Array(embeddings)) => parse_embeddings(embeddings),
+            _ => anyhow::bail!("The Ollama response is missing embeddings: {response}"),
+        }
+    }
+}
+
+impl Embedder for OllamaEmbedder {
+    type VectorSpace = OllamaSpace;
+
+    fn embed_for(
+        &self,
+        input: EmbeddingInput,
+    ) -> BoxedFuture<'_, anyhow::Result<Embedding<Self::VectorSpace>>> {
+        Box::pin(async move {
+            let mut embeddings = self.embed_vec_for(vec![input]).await?;
+            Ok(embeddings.remove(0))
+        })
+    }
+
+    fn embed_vec(
+        &self,
+        inputs: Vec<String>,
+    ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        self.embed_vec_for(
+            inputs
+                .into_iter()
+                .map(|text| EmbeddingInput::new(text, EmbeddingVariant::Document))
+                .collect(),
+        )
+    }
+
+    fn embed_vec_for(
+        &self,
+        inputs: Vec<EmbeddingInput>,
+    ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        let inputs = inputs
+            .into_iter()
+            .map(|input| self.prefixes.apply(input))
+            .collect();
+        Box::pin(embed_in_batches(inputs, self.max_batch_size, |batch| {
+            self.embed_request(batch)
+        }))
+    }
+}
+
+#[tokio::test]
+async fn ollama_embeds_a_batch_in_one_request() {
+    use crate::EmbedderExt;
+    use wiremock::matchers::{body_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/api/embed"))
+        .and(body_json(json!({
+            "model": "nomic-embed-text",
+            "input": ["search_query: cats", "search_document: dogs", "search_document: fish"]
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "model": "nomic-embed-text",
+            "embeddings": [[1.0, 0.0], [0.0, 1.0], [0.5, 0.5]]
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let embedder = OllamaEmbedder::builder("nomic-embed-text")
+        .with_base_url(&server.uri())
+        .with_query_prefix("search_query: ")
+        .with_document_prefix("search_document: ")
+        .build();
+    let embeddings = embedder
+        .embed_batch_for([
+            EmbeddingInput::new("cats", EmbeddingVariant::Query),
+            EmbeddingInput::new("dogs", EmbeddingVariant::Document),
+            EmbeddingInput::new("fish", EmbeddingVariant::Document),
+        ])
+        .await
+        .unwrap();
+    let embeddings: Vec<_> = embeddings.iter().map(|e| e.to_vec()).collect();
+    assert_eq!(embeddings, [[1.0, 0.0], [0.0, 1.0], [0.5, 0.5]]);
+}
@@ -0,0 +1,254 @@
+use kalosm_common::*;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde_json::{json, Value};
+
+use super::http::{embed_in_batches, parse_embeddings, post_json, EmbeddingPrefixes};
+use crate::{Embedder, Embedding, EmbeddingInput, EmbeddingVariant, ModelBuilder, VectorSpace};
+
+/// The vector space for embeddings from a [`TeiEmbedder`].
+pub struct TeiSpace;
+
+impl VectorSpace for TeiSpace {}
+
+/// An embedder that uses the `/embed` route of a [text-embeddings-inference](https://github.com/huggingface/text-embeddings-inference) server.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm_language_model::*;
+///
+/// # #[tokio::main]
+/// # async fn main() -> anyhow::Result<()> {
+/// let embedder = TeiEmbedder::builder()
+///     .with_base_url("http://localhost:8080")
+///     .with_query_prefix("Represent this sentence for searching relevant passages: ")
+///     .build()?;
+/// let embedding = embedder.embed_query("What is Kalosm?").await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TeiEmbedder {
+    client: reqwest::Client,
+    url: String,
+    headers: HeaderMap,
+    normalize: bool,
+    truncate: bool,
+    prefixes: EmbeddingPrefixes,
+    max_batch_size: usize,
+}
+
+/// A builder for a [`TeiEmbedder`].
+#[derive(Debug, Clone)]
+pub struct TeiEmbedderBuilder {
+    base_url: String,
+    api_key: Option<String>,
+    normalize: bool,
+    truncate: bool,
+    prefixes: EmbeddingPrefixes,
+    max_batch_size: usize,
+}
+
+impl Default for TeiEmbedderBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TeiEmbedderBuilder {
+    /// Create a new builder.
+    pub fn new() -> Self {
+        Self {
+            base_url: "http://localhost:8080".to_string(),
+            api_key: None,
+            normalize: true,
+            truncate: false,
+            prefixes: EmbeddingPrefixes::default(),
+            max_batch_size: 32,
+        }
+    }
+
+    /// Set the base URL of the server. (default: `http://localhost:8080`)
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Set the API key sent as a bearer token. This is required for Hugging Face inference endpoints.
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    /// Set whether the server should normalize the embeddings. (default: true)
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Set whether the server should truncate inputs that are longer than the model's maximum length instead of returning an error. (default: false)
+    pub fn with_truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Set the text added to the start of queries.
+    pub fn with_query_prefix(mut self, prefix: impl ToString) -> Self {
+        self.prefixes.query = Some(prefix.to_string());
+        self
+    }
+
+    /// Set the text added to the start of documents.
+    pub fn with_document_prefix(mut self, prefix: impl ToString) -> Self {
+        self.prefixes.document = Some(prefix.to_string());
+        self
+    }
+
+    /// Set the maximum number of inputs sent in one request. This should be at most the `--max-client-batch-size` of the server. (default: 32)
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Build the embedder.
+    pub fn build(self) -> anyhow::Result<TeiEmbedder> {
+        let mut headers = HeaderMap::new();
+        if let Some(api_key) = &self.api_key {
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::try_from(format!("Bearer {api_key}"))?,
+            );
+        }
+        Ok(TeiEmbedder {
+            client: reqwest::Client::new(),
+            url: format!("{}/embed", self.base_url),
+            headers,
+            normalize: self.normalize,
+            truncate: self.truncate,
+            prefixes: self.prefixes,
+            max_batch_size: self.max_batch_size,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl ModelBuilder for TeiEmbedderBuilder {
+    type Model = TeiEmbedder;
+
+    async fn start_with_loading_handler(
+        self,
+        _: impl FnMut(ModelLoadingProgress) + Send + Sync + 'static,
+    ) -> anyhow::Result<TeiEmbedder> {
+        self.build()
+    }
+
+    fn requires_download(&self) -> bool {
+        false
+    }
+}
+
+impl TeiEmbedder {
+    /// Create a new builder.
+    pub fn builder() -> TeiEmbedderBuilder {
+        TeiEmbedderBuilder::new()
+    }
+
+    async fn embed_request(&self, inputs: Vec<String>) -> anyhow::Result<Vec<Embedding<TeiSpace>>> {
+        let request = json!({
+            "inputs": inputs,
+            "normalize": self.normalize,
+            "truncate": self.truncate,
+        });
+        let response = post_json(&self.client, &self.url, &self.headers, &request).await?;
+        match response {
+            Value::Array(embeddings) => parse_embeddings(&embeddings),
+            _ => anyhow::bail!("Expected a list of embeddings from the server, found {response}"),
+        }
+    }
+}
+
+impl Embedder for TeiEmbedder {
+    type VectorSpace = TeiSpace;
+
+    fn embed_for(
+        &self,
+        input: EmbeddingInput,
+    ) -> BoxedFuture<'_, anyhow::Result<Embedding<Self::VectorSpace>>> {
+        Box::pin(async move {
+            let mut embeddings = self.embed_vec_for(vec![input]).await?;
+            Ok(embeddings.remove(0))
+        })
+    }
+
+    fn embed_vec(
+        &self,
+        inputs: Vec<String>,
+    ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        self.embed_vec_for(
+            inputs
+                .into_iter()
+                .map(|text| EmbeddingInput::new(text, EmbeddingVariant::Document))
+                .collect(),
+        )
+    }
+
+    fn embed_vec_for(
+        &self,
+        inputs: Vec<EmbeddingInput>,
+    ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        let inputs = inputs
+            .into_iter()
+            .map(|input| self.prefixes.apply(input))
+            .collect();
+        Box::pin(embed_in_batches(inputs, self.max_batch_size, |batch| {
+            self.embed_request(batch)
+        }))
+    }
+}
+
+#[tokio::test]
+async fn tei_splits_large_batches() {
+    use crate::EmbedderExt;
+    use wiremock::matchers::{body_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/embed"))
+        .and(body_json(json!({
+            "inputs": ["passage: a", "passage: b"],
+            "normalize": true,
+            "truncate": false,
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([[1.0], [2.0]])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/embed"))
+        .and(body_json(json!({
+            "inputs": ["passage: c"],
+            "normalize": true,
+            "truncate": false,
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([[3.0]])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let embedder = TeiEmbedder::builder()
+        .with_base_url(&server.uri())
+        .with_document_prefix("passage: ")
+        .with_max_batch_size(2)
+        .build()
+        .unwrap();
+    let embeddings = embedder.embed_batch(["a", "b", "c"]).await.unwrap();
+    let embeddings: Vec<_> = embeddings.iter().map(|e| e.to_vec()).collect();
+    assert_eq!(embeddings, [[1.0], [2.0], [3.0]]);
+
+    let error = TeiEmbedder::builder()
+        .with_base_url("http://localhost:1")
+        .build()
+        .unwrap()
+        .embed("a")
+        .await;
+    assert!(error.is_err());
+}
@@ -0,0 +1,194 @@
+use std::sync::Arc;
+
+/// A structured output sampled from a model.
+#[derive(Debug, Clone)]
+pub struct StructuredSample<O> {
+    output: O,
+    text: String,
+    log_probability: f64,
+}
+
+impl<O> StructuredSample<O> {
+    pub(crate) fn new(output: O, text: String, log_probability: f64) -> Self {
+        Self {
+            output,
+            text,
+            log_probability,
+        }
+    }
+
+    /// Get the parsed output of the sample.
+    pub fn output(&self) -> &O {
+        &self.output
+    }
+
+    /// Get the parsed output of the sample.
+    pub fn into_output(self) -> O {
+        self.output
+    }
+
+    /// Get the text the model generated for the sample.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Get the log probability of the tokens the model sampled for this output.
+    pub fn log_probability(&self) -> f64 {
+        self.log_probability
+    }
+}
+
+/// How to choose the final output from multiple samples of the same prompt.
+pub enum SampleSelection<O> {
+    /// Choose the sample the model thinks is most likely. Samples agree if they generated the same text.
+    MostLikely,
+    /// Group the samples that agree with each other and choose the largest group. Ties are broken by the likelihood of the samples.
+    /// Within the winning group, the most likely sample is chosen.
+    Vote(Arc<dyn Fn(&O, &O) -> bool + Send + Sync>),
+}
+
+impl<O> Clone for SampleSelection<O> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::MostLikely => Self::MostLikely,
+            Self::Vote(agree) => Self::Vote(agree.clone()),
+        }
+    }
+}
+
+impl<O> SampleSelection<O> {
+    /// Choose the sample the model thinks is most likely.
+    pub fn most_likely() -> Self {
+        Self::MostLikely
+    }
+
+    /// Choose the answer most samples agree on. `agree` returns true if two outputs are the same answer.
+    pub fn vote(agree: impl Fn(&O, &O) -> bool + Send + Sync + 'static) -> Self {
+        Self::Vote(Arc::new(agree))
+    }
+
+    /// Choose the answer most samples agree on, where samples agree if they are equal.
+    pub fn majority() -> Self
+    where
+        O: PartialEq,
+    {
+        Self::vote(|first, second| first == second)
+    }
+
+    /// Choose the final output from a list of samples. Returns `None` if there are no samples.
+    pub fn select(&self, samples: Vec<StructuredSample<O>>) -> Option<SelectedSample<O>>
+    where
+        O: Clone,
+    {
+        // Group the samples into answers that agree with each other
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        for (index, sample) in samples.iter().enumerate() {
+            let group = groups.iter_mut().find(|group| {
+                let representative = &samples[group[0]];
+                match self {
+                    Self::MostLikely => representative.text == sample.text,
+                    Self::Vote(agree) => agree(&representative.output, &sample.output),
+                }
+            });
+            match group {
+                Some(group) => group.push(index),
+                None => groups.push(vec![index]),
+            }
+        }
+
+        let most_likely_in = |group: &Vec<usize>| {
+            group.iter().copied().max_by(|&first, &second| {
+                samples[first]
+                    .log_probability
+                    .total_cmp(&samples[second].log_probability)
+            })
+        };
+        let winning_group = match self {
+            Self::MostLikely => groups.iter().max_by(|first, second| {
+                let first = &samples[most_likely_in(first).unwrap()];
+                let second = &samples[most_likely_in(second).unwrap()];
+                first.log_probability.total_cmp(&second.log_probability)
+            })?,
+            Self::Vote(_) => groups.iter().max_by(|first, second| {
+                first.len().cmp(&second.len()).then_with(|| {
+                    let first = &samples[most_likely_in(first).unwrap()];
+                    let second = &samples[most_likely_in(second).unwrap()];
+                    first.log_probability.total_cmp(&second.log_probability)
+                })
+            })?,
+        };
+        let winner = samples[most_likely_in(winning_group)?].clone();
+
+        Some(SelectedSample {
+            winner,
+            agreeing_samples: winning_group.len(),
+            distinct_answers: groups.len(),
+            samples,
+        })
+    }
+}
+
+/// The output chosen from multiple samples of the same prompt, along with statistics about how much the samples agreed.
+#[derive(Debug, Clone)]
+pub struct SelectedSample<O> {
+    winner: StructuredSample<O>,
+    agreeing_samples: usize,
+    distinct_answers: usize,
+    samples: Vec<StructuredSample<O>>,
+}
+
+impl<O> SelectedSample<O> {
+    /// Get the output that was chosen.
+    pub fn winner(&self) -> &O {
+        &self.winner.output
+    }
+
+    /// Get the output that was chosen.
+    pub fn into_winner(self) -> O {
+        self.winner.output
+    }
+
+    /// Get the sample that was chosen.
+    pub fn winning_sample(&self) -> &StructuredSample<O> {
+        &self.winner
+    }
+
+    /// Get the number of samples that agree with the chosen output (including the chosen sample).
+    pub fn agreeing_samples(&self) -> usize {
+        self.agreeing_samples
+    }
+
+    /// Get the number of different answers in the samples.
+    pub fn distinct_answers(&self) -> usize {
+        self.distinct_answers
+    }
+
+    /// Get the fraction of samples that agree with the chosen output.
+    pub fn agreement(&self) -> f32 {
+        self.agreeing_samples as f32 / self.samples.len() as f32
+    }
+
+    /// Get all of the samples the output was chosen from.
+    pub fn samples(&self) -> &[StructuredSample<O>] {
+        &self.samples
+    }
+}
+
+#[test]
+fn vote_selects_the_majority() {
+    let samples = vec![
+        StructuredSample::new(1, "1".to_string(), -0.5),
+        StructuredSample::new(2, "2".to_string(), -0.1),
+        StructuredSample::new(1, "1".to_string(), -0.7),
+    ];
+
+    let selected = SampleSelection::majority().select(samples.clone()).unwrap();
+    assert_eq!(*selected.winner(), 1);
+    assert_eq!(selected.winning_sample().log_probability(), -0.5);
+    assert_eq!(selected.agreeing_samples(), 2);
+    assert_eq!(selected.distinct_answers(), 2);
+
+    let selected = SampleSelection::most_likely().select(samples).unwrap();
+    assert_eq!(*selected.winner(), 2);
+    assert_eq!(selected.agreeing_samples(), 1);
+}
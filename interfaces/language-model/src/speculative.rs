@@ -0,0 +1,762 @@
+use rand::Rng;
+use tokenizers::tokenizer::Tokenizer;
+
+use crate::{ModelFeedback, Session, SyncModel, TextGeneration};
+
+/// A small draft model that proposes tokens for a larger model to verify. See [`SyncModelExt::stream_text_with_draft`](crate::SyncModelExt::stream_text_with_draft).
+///
+/// Speculative decoding lets the draft model generate a few tokens, then checks all of them with a single forward pass of the larger model. Tokens are accepted with the same probability the larger model would have generated them with, so the output follows the same distribution as generating with the larger model alone.
+///
+/// The draft model must use the same tokenizer as the larger model, and both sessions must support [`Session::rollback`].
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm_language_model::*;
+///
+/// fn generate<M: SyncModel, D: SyncModel>(model: &M, draft: &D) -> anyhow::Result<()> {
+///     let mut session = model.new_session()?;
+///     let mut draft_session = draft.new_session()?;
+///     model.stream_text_with_draft(
+///         &mut session,
+///         // Let the draft model propose 6 tokens at a time
+///         SpeculativeDraft::new(draft, &mut draft_session).with_draft_tokens(6),
+///         "The capital of France is",
+///         Some(100),
+///         None,
+///         SpeculativeSampling::new().with_top_p(0.9),
+///         |token| {
+///             print!("{token}");
+///             Ok(ModelFeedback::Continue)
+///         },
+///     )
+/// }
+/// ```
+pub struct SpeculativeDraft<'a, M: SyncModel + ?Sized> {
+    pub(crate) model: &'a M,
+    pub(crate) session: &'a mut M::Session,
+    pub(crate) draft_tokens: usize,
+}
+
+impl<'a, M: SyncModel + ?Sized> SpeculativeDraft<'a, M> {
+    /// Create a new draft from a model and a session of that model.
+    pub fn new(model: &'a M, session: &'a mut M::Session) -> Self {
+        Self {
+            model,
+            session,
+            draft_tokens: 4,
+        }
+    }
+
+    /// Set the number of tokens the draft model proposes before the larger model verifies them. (Defaults to 4)
+    pub fn with_draft_tokens(mut self, draft_tokens: usize) -> Self {
+        self.draft_tokens = draft_tokens.max(1);
+        self
+    }
+
+    /// Make sure the draft model's tokens mean the same thing as the larger model's tokens.
+    pub(crate) fn check_tokenizer(&self, tokenizer: &Tokenizer) -> anyhow::Result<()> {
+        let draft_vocab = self.model.tokenizer().get_vocab(true);
+        let vocab = tokenizer.get_vocab(true);
+        if let Some((token, id)) = vocab
+            .iter()
+            .find(|(token, id)| draft_vocab.get(*token) != Some(id))
+        {
+            match draft_vocab.get(token) {
+                Some(draft_id) => anyhow::bail!(
+                    "The model has the token {token:?} at id {id}, but the draft model has it at id {draft_id}. Speculative decoding requires both models to use the same tokenizer"
+                ),
+                None => anyhow::bail!(
+                    "The model has the token {token:?}, but the draft model doesn't. Speculative decoding requires both models to use the same tokenizer"
+                ),
+            }
+        }
+        if draft_vocab.len() != vocab.len() {
+            anyhow::bail!(
+                "The draft model has a vocabulary of {} tokens, but the model has {} tokens. Speculative decoding requires both models to use the same tokenizer",
+                draft_vocab.len(),
+                vocab.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Feed tokens into the draft session.
+    pub(crate) fn feed(&mut self, tokens: &[u32], into: &mut Vec<f32>) -> anyhow::Result<()> {
+        self.model.feed_tokens(self.session, tokens, into)
+    }
+
+    /// Remove the last `tokens` tokens from the draft session.
+    pub(crate) fn rollback(&mut self, tokens: usize) -> anyhow::Result<()> {
+        if tokens > 0 {
+            self.session.rollback(tokens)?;
+        }
+        Ok(())
+    }
+
+    /// Sample a proposal from the draft model's logits.
+    pub(crate) fn propose(
+        &self,
+        logits: &[f32],
+        sampling: &SpeculativeSampling,
+    ) -> anyhow::Result<TokenDistribution> {
+        sampling.sample(candidates(logits), &mut rand::thread_rng())
+    }
+}
+
+/// The distribution tokens are sampled from during speculative decoding.
+///
+/// Accepting draft tokens without changing the output requires the exact probability of every token under both models. Stateful samplers like mirostat or repetition penalties don't have a fixed distribution, so speculative decoding samples from the softmax of the logits at a temperature, optionally limited to the most likely tokens.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm_language_model::SpeculativeSampling;
+///
+/// // Sample from the 40 most likely tokens at a temperature of 0.7
+/// let sampling = SpeculativeSampling::new()
+///     .with_temperature(0.7)
+///     .with_top_k(40);
+/// // Always pick the most likely token
+/// let greedy = SpeculativeSampling::greedy();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpeculativeSampling {
+    temperature: f32,
+    top_k: Option<usize>,
+    top_p: Option<f32>,
+}
+
+impl Default for SpeculativeSampling {
+    fn default() -> Self {
+        Self {
+            temperature: 0.8,
+            top_k: None,
+            top_p: None,
+        }
+    }
+}
+
+impl SpeculativeSampling {
+    /// Create a new distribution that samples from every token at the default temperature.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a distribution that always picks the most likely token. Draft tokens are only accepted if the model would pick the same token.
+    pub fn greedy() -> Self {
+        Self::default().with_temperature(0.)
+    }
+
+    /// Set the temperature the logits are divided by before the softmax. A temperature of 0 always picks the most likely token. (Defaults to 0.8)
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    /// Only sample from the `top_k` most likely tokens. (Defaults to every token)
+    pub fn with_top_k(mut self, top_k: usize) -> Self {
+        self.top_k = Some(top_k.max(1));
+        self
+    }
+
+    /// Only sample from the most likely tokens with a total probability of at least `top_p`. (Defaults to every token)
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    /// Get the temperature.
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    /// Get the number of most likely tokens that are sampled from, if limited.
+    pub fn top_k(&self) -> Option<usize> {
+        self.top_k
+    }
+
+    /// Get the probability mass of the most likely tokens that are sampled from, if limited.
+    pub fn top_p(&self) -> Option<f32> {
+        self.top_p
+    }
+
+    /// Sample a token from the logits of each candidate token and return the distribution it was sampled from.
+    pub(crate) fn sample(
+        &self,
+        logits: impl IntoIterator<Item = (u32, f32)>,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<TokenDistribution> {
+        let mut candidates: Vec<_> = logits
+            .into_iter()
+            .filter(|(_, logit)| logit.is_finite())
+            .collect();
+        if candidates.is_empty() {
+            anyhow::bail!("No token sampled");
+        }
+        // Ties are broken by the token id so the draft and the model rank tokens the same way
+        let more_likely = |a: &(u32, f32), b: &(u32, f32)| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0));
+        let greedy = self.temperature <= 0.;
+        let keep = if greedy {
+            1
+        } else {
+            self.top_k.unwrap_or(usize::MAX)
+        };
+        if keep < candidates.len() {
+            candidates.select_nth_unstable_by(keep - 1, more_likely);
+            candidates.truncate(keep);
+        }
+
+        let max = candidates
+            .iter()
+            .map(|(_, logit)| *logit)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let temperature = if greedy { 1. } else { self.temperature };
+        let mut probs: Vec<_> = candidates
+            .into_iter()
+            .map(|(token, logit)| (token, ((logit - max) / temperature).exp()))
+            .collect();
+        normalize(&mut probs);
+        if let Some(top_p) = self.top_p {
+            probs.sort_unstable_by(more_likely);
+            let mut total = 0.;
+            let len = probs
+                .iter()
+                .position(|(_, prob)| {
+                    total += prob;
+                    total >= top_p
+                })
+                .map_or(probs.len(), |index| index + 1);
+            probs.truncate(len);
+            normalize(&mut probs);
+        }
+        probs.sort_unstable_by_key(|(token, _)| *token);
+
+        let sampled = sample_weighted(&probs, rng).unwrap_or(probs[0].0);
+        Ok(TokenDistribution { sampled, probs })
+    }
+}
+
+/// A sampled token and the distribution it was sampled from.
+pub(crate) struct TokenDistribution {
+    pub(crate) sampled: u32,
+    // The probability of each candidate token sorted by token id
+    probs: Vec<(u32, f32)>,
+}
+
+impl TokenDistribution {
+    /// The probability of a token.
+    pub(crate) fn prob(&self, token: u32) -> f32 {
+        match self.probs.binary_search_by_key(&token, |(token, _)| *token) {
+            Ok(index) => self.probs[index].1,
+            Err(_) => 0.,
+        }
+    }
+
+    /// Decide if the token the draft proposed is accepted. The token is accepted with probability `min(1, p(token) / q(token))` where `p` is this distribution and `q` is the draft distribution.
+    pub(crate) fn accept(&self, draft: &TokenDistribution, rng: &mut impl Rng) -> bool {
+        let p = self.prob(draft.sampled);
+        let q = draft.prob(draft.sampled);
+        p >= q || rng.gen::<f32>() < p / q
+    }
+
+    /// Sample a replacement for a rejected draft token from the normalized residual distribution `max(0, p - q)`.
+    pub(crate) fn residual(&self, draft: &TokenDistribution, rng: &mut impl Rng) -> u32 {
+        let residual: Vec<_> = self
+            .probs
+            .iter()
+            .map(|&(token, prob)| (token, (prob - draft.prob(token)).max(0.)))
+            .collect();
+        sample_weighted(&residual, rng).unwrap_or(self.sampled)
+    }
+}
+
+/// The logits of a model paired with their token ids.
+fn candidates(logits: &[f32]) -> impl Iterator<Item = (u32, f32)> + '_ {
+    logits
+        .iter()
+        .enumerate()
+        .map(|(token, logit)| (token as u32, *logit))
+}
+
+/// Scale the weights so they sum to one.
+fn normalize(probs: &mut [(u32, f32)]) {
+    let total: f32 = probs.iter().map(|(_, prob)| prob).sum();
+    for (_, prob) in probs {
+        *prob /= total;
+    }
+}
+
+/// Sample a token with a probability proportional to its weight. Returns `None` if every weight is zero.
+fn sample_weighted(weights: &[(u32, f32)], rng: &mut impl Rng) -> Option<u32> {
+    let total: f32 = weights.iter().map(|(_, weight)| weight).sum();
+    if total.is_nan() || total <= 0. {
+        return None;
+    }
+    let mut remaining = rng.gen::<f32>() * total;
+    let mut last = None;
+    for &(token, weight) in weights {
+        if weight <= 0. {
+            continue;
+        }
+        if remaining < weight {
+            return Some(token);
+        }
+        remaining -= weight;
+        last = Some(token);
+    }
+    last
+}
+
+/// Stream text from the model with tokens proposed by a draft model.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn stream_text_with_draft<M: SyncModel + ?Sized, D: SyncModel + ?Sized>(
+    llm: &M,
+    session: &mut M::Session,
+    mut draft: SpeculativeDraft<'_, D>,
+    prompt: &str,
+    max_tokens: Option<u32>,
+    stop_on: Option<&str>,
+    sampling: SpeculativeSampling,
+    mut on_token: impl FnMut(String) -> anyhow::Result<ModelFeedback>,
+) -> anyhow::Result<()> {
+    let mut generation =
+        SpeculativeGeneration::new(llm, &draft, prompt, max_tokens, stop_on, sampling)?;
+    while generation.step(llm, session, &mut draft, &mut on_token)? {}
+    generation.finish(on_token)
+}
+
+/// A speculative decoding request that runs one round of proposals and verification at a time.
+///
+/// [`SyncModelExt::stream_text_with_draft`](crate::SyncModelExt::stream_text_with_draft) runs a whole request at once. A model that serves several requests from one thread can call [`SpeculativeGeneration::step`] between its other work instead.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm_language_model::*;
+///
+/// fn generate<M: SyncModel, D: SyncModel>(model: &M, draft: &D) -> anyhow::Result<()> {
+///     let mut session = model.new_session()?;
+///     let mut draft_session = draft.new_session()?;
+///     let mut on_token = |token: String| -> anyhow::Result<ModelFeedback> {
+///         print!("{token}");
+///         Ok(ModelFeedback::Continue)
+///     };
+///     let mut generation = SpeculativeGeneration::new(
+///         model,
+///         &SpeculativeDraft::new(draft, &mut draft_session),
+///         "The capital of France is",
+///         Some(100),
+///         None,
+///         SpeculativeSampling::new(),
+///     )?;
+///     // The draft only borrows the draft session, so it can be recreated before each step
+///     while generation.step(
+///         model,
+///         &mut session,
+///         &mut SpeculativeDraft::new(draft, &mut draft_session),
+///         &mut on_token,
+///     )? {}
+///     generation.finish(on_token)
+/// }
+/// ```
+pub struct SpeculativeGeneration {
+    generation: TextGeneration,
+    sampling: SpeculativeSampling,
+    // The number of tokens of the generation that each session has processed
+    processed: usize,
+    draft_processed: usize,
+    draft_logits: Vec<f32>,
+    logits: Vec<Vec<f32>>,
+}
+
+impl SpeculativeGeneration {
+    /// Start generating text after the prompt. This checks that the draft model uses the same tokenizer as the model, but doesn't run either model.
+    pub fn new<M: SyncModel + ?Sized, D: SyncModel + ?Sized>(
+        llm: &M,
+        draft: &SpeculativeDraft<'_, D>,
+        prompt: &str,
+        max_tokens: Option<u32>,
+        stop_on: Option<&str>,
+        sampling: SpeculativeSampling,
+    ) -> anyhow::Result<Self> {
+        let tokenizer = llm.tokenizer();
+        draft.check_tokenizer(&tokenizer)?;
+        let tokens = tokenizer
+            .encode(prompt, false)
+            .map_err(|e| anyhow::anyhow!(e))?;
+        let tokens = tokens.get_ids();
+        if tokens.is_empty() {
+            anyhow::bail!("The prompt must not be empty for speculative decoding");
+        }
+        let generation = TextGeneration::without_sampler(
+            tokenizer,
+            tokens,
+            llm.stop_token()?,
+            max_tokens,
+            stop_on,
+        )?;
+        Ok(Self {
+            generation,
+            sampling,
+            processed: 0,
+            draft_processed: 0,
+            draft_logits: Vec::new(),
+            logits: Vec::new(),
+        })
+    }
+
+    /// Let the draft model propose tokens, verify them with one forward pass of the model and pass the accepted text to `on_token`.
+    ///
+    /// Returns `false` once the generation is finished. Then call [`SpeculativeGeneration::finish`]. Every step must use the same sessions.
+    pub fn step<M: SyncModel + ?Sized, D: SyncModel + ?Sized>(
+        &mut self,
+        llm: &M,
+        session: &mut M::Session,
+        draft: &mut SpeculativeDraft<'_, D>,
+        mut on_token: impl FnMut(String) -> anyhow::Result<ModelFeedback>,
+    ) -> anyhow::Result<bool> {
+        let mut rng = rand::thread_rng();
+        let sampling = &self.sampling;
+        let tokens = self.generation.tokens().to_vec();
+
+        // Let the draft model propose tokens. The last proposal is never fed into the draft session because it may be rejected
+        draft.feed(&tokens[self.draft_processed..], &mut self.draft_logits)?;
+        let mut draft_fed = 0;
+        let mut proposals = Vec::with_capacity(draft.draft_tokens);
+        loop {
+            let proposal = draft.propose(&self.draft_logits, sampling)?;
+            let token = proposal.sampled;
+            proposals.push(proposal);
+            if token == self.generation.stop_token() || proposals.len() == draft.draft_tokens {
+                break;
+            }
+            draft.feed(&[token], &mut self.draft_logits)?;
+            draft_fed += 1;
+        }
+
+        // Verify every proposal with one forward pass of the model
+        let mut verify = tokens[self.processed..].to_vec();
+        verify.extend(proposals.iter().map(|proposal| proposal.sampled));
+        llm.feed_tokens_all_logits(session, &verify, &mut self.logits)?;
+        let logits = &self.logits;
+        let offset = tokens.len() - self.processed - 1;
+
+        let mut accepted = 0;
+        let mut finished = false;
+        let mut next_token = None;
+        for proposal in &proposals {
+            let distribution = sampling.sample(candidates(&logits[offset + accepted]), &mut rng)?;
+            if !distribution.accept(proposal, &mut rng) {
+                next_token = Some(distribution.residual(proposal, &mut rng));
+                break;
+            }
+            accepted += 1;
+            if !self.generation.add_token(proposal.sampled, &mut on_token)? {
+                finished = true;
+                break;
+            }
+        }
+        // If every proposal was accepted, the model's logits after the last proposal give one more token for free
+        if !finished && accepted == proposals.len() {
+            next_token = Some(
+                sampling
+                    .sample(candidates(&logits[offset + accepted]), &mut rng)?
+                    .sampled,
+            );
+        }
+        tracing::trace!("Accepted {accepted} of {} draft tokens", proposals.len());
+
+        // Remove the rejected proposals from both sessions
+        if accepted < proposals.len() {
+            session.rollback(proposals.len() - accepted)?;
+        }
+        self.processed = tokens.len() + accepted;
+        let draft_kept = draft_fed.min(accepted);
+        draft.rollback(draft_fed - draft_kept)?;
+        self.draft_processed = tokens.len() + draft_kept;
+
+        if finished {
+            return Ok(false);
+        }
+        match next_token {
+            Some(token) => self.generation.add_token(token, &mut on_token),
+            None => Ok(true),
+        }
+    }
+
+    /// Finish the generation and pass any text that was held back while checking for the stop_on string to `on_token`.
+    pub fn finish(
+        self,
+        on_token: impl FnMut(String) -> anyhow::Result<ModelFeedback>,
+    ) -> anyhow::Result<()> {
+        self.generation.finish(on_token)
+    }
+}
+
+#[test]
+fn speculative_acceptance_matches_target() {
+    let distribution = |sampled, probs: &[(u32, f32)]| {
+        let mut probs = probs.to_vec();
+        probs.sort_unstable_by_key(|(token, _)| *token);
+        TokenDistribution { sampled, probs }
+    };
+    let mut rng = rand::thread_rng();
+
+    // A draft that matches the target is always accepted
+    let target = distribution(1, &[(0, 0.25), (1, 0.75)]);
+    let draft = distribution(1, &[(1, 0.75), (0, 0.25)]);
+    assert!((target.prob(1) - 0.75).abs() < 1e-6);
+    assert!((0..100).all(|_| target.accept(&draft, &mut rng)));
+
+    // A draft token the target never samples is always rejected, and the replacement only comes from where the target is more likely than the draft
+    let draft = distribution(2, &[(1, 0.5), (2, 0.5)]);
+    assert!((0..100).all(|_| !target.accept(&draft, &mut rng)));
+    assert!((0..100).all(|_| target.residual(&draft, &mut rng) != 2));
+    let draft = distribution(1, &[(1, 1.)]);
+    assert!((0..100).all(|_| target.residual(&draft, &mut rng) == 0));
+}
+
+#[test]
+fn speculative_sampling_uses_the_full_distribution() {
+    let mut rng = rand::thread_rng();
+    let logits = [(0, 1.), (1, 3.), (2, 2.), (3, f32::NEG_INFINITY)];
+
+    // Greedy sampling puts all of the probability on the most likely token, so a draft of any other token is always rejected
+    let greedy = SpeculativeSampling::greedy()
+        .sample(logits, &mut rng)
+        .unwrap();
+    assert_eq!(greedy.sampled, 1);
+    assert_eq!(greedy.prob(1), 1.);
+    let draft = SpeculativeSampling::new().sample(logits, &mut rng).unwrap();
+    assert!(draft.prob(2) > 0.);
+    let draft = TokenDistribution {
+        sampled: 2,
+        probs: draft.probs,
+    };
+    assert!((0..100).all(|_| !greedy.accept(&draft, &mut rng)));
+    assert!((0..100).all(|_| greedy.residual(&draft, &mut rng) == 1));
+
+    // Without a limit every token is weighted by the softmax of its logit at the temperature
+    let softmax = SpeculativeSampling::new()
+        .with_temperature(2.)
+        .sample(logits, &mut rng)
+        .unwrap();
+    let total = 0.5f32.exp() + 1.5f32.exp() + 1f32.exp();
+    assert!((softmax.prob(0) - 0.5f32.exp() / total).abs() < 1e-6);
+    assert!((softmax.prob(1) - 1.5f32.exp() / total).abs() < 1e-6);
+    assert_eq!(softmax.prob(3), 0.);
+
+    // Top k and top p remove the unlikely tokens and renormalize the rest
+    let top_k = SpeculativeSampling::new()
+        .with_temperature(1.)
+        .with_top_k(2)
+        .sample(logits, &mut rng)
+        .unwrap();
+    assert_eq!(top_k.prob(0), 0.);
+    assert!((top_k.prob(1) - 1. / (1. + (-1f32).exp())).abs() < 1e-6);
+    let top_p = SpeculativeSampling::new()
+        .with_temperature(1.)
+        .with_top_p(0.5)
+        .sample(logits, &mut rng)
+        .unwrap();
+    assert_eq!(top_p.prob(1), 1.);
+}
+
+/// A model over the tokens "a" to "f" that always predicts one token after the last token it was fed. It counts how many times it runs.
+#[cfg(test)]
+struct SuccessorModel {
+    tokenizer: std::sync::Arc<Tokenizer>,
+    successor: fn(u32) -> u32,
+    runs: std::cell::Cell<usize>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct SuccessorSession {
+    tokens: Vec<u32>,
+}
+
+#[cfg(test)]
+impl Session for SuccessorSession {
+    fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    fn rollback(&mut self, tokens: usize) -> anyhow::Result<()> {
+        let len = self.tokens.len();
+        if tokens > len {
+            anyhow::bail!("Cannot roll back {tokens} tokens from a session with {len} tokens");
+        }
+        self.tokens.truncate(len - tokens);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl SuccessorModel {
+    const STOP: u32 = 6;
+
+    /// Create a model that predicts the next letter after "a" to "e" and the stop token after "f".
+    fn new() -> Self {
+        Self::with_successor(|token| token + 1)
+    }
+
+    fn with_successor(successor: fn(u32) -> u32) -> Self {
+        // The fuse decoder joins the tokens without spaces
+        let mut tokenizer = crate::test_util::word_level_tokenizer(&[
+            "a", "b", "c", "d", "e", "f", "EOS", "x", "[UNK]",
+        ]);
+        tokenizer.with_decoder(tokenizers::decoders::fuse::Fuse::new());
+        Self {
+            tokenizer: std::sync::Arc::new(tokenizer),
+            successor,
+            runs: Default::default(),
+        }
+    }
+
+    fn logits(&self, token: u32) -> Vec<f32> {
+        let mut logits = vec![0.; 9];
+        logits[(self.successor)(token) as usize] = 10.;
+        logits
+    }
+}
+
+#[cfg(test)]
+impl SyncModel for SuccessorModel {
+    type Session = SuccessorSession;
+
+    fn new_session(&self) -> anyhow::Result<Self::Session> {
+        Ok(SuccessorSession::default())
+    }
+
+    fn feed_text(
+        &self,
+        session: &mut Self::Session,
+        prompt: &str,
+        into: &mut Vec<f32>,
+    ) -> anyhow::Result<()> {
+        let tokens = self
+            .tokenizer
+            .encode(prompt, false)
+            .map_err(|e| anyhow::anyhow!(e))?;
+        self.feed_tokens(session, tokens.get_ids(), into)
+    }
+
+    fn feed_tokens(
+        &self,
+        session: &mut Self::Session,
+        tokens: &[u32],
+        into: &mut Vec<f32>,
+    ) -> anyhow::Result<()> {
+        self.runs.set(self.runs.get() + 1);
+        session.tokens.extend_from_slice(tokens);
+        *into = self.logits(*tokens.last().unwrap());
+        Ok(())
+    }
+
+    fn feed_tokens_all_logits(
+        &self,
+        session: &mut Self::Session,
+        tokens: &[u32],
+        into: &mut Vec<Vec<f32>>,
+    ) -> anyhow::Result<()> {
+        self.runs.set(self.runs.get() + 1);
+        session.tokens.extend_from_slice(tokens);
+        *into = tokens.iter().map(|&token| self.logits(token)).collect();
+        Ok(())
+    }
+
+    fn stop_token(&self) -> anyhow::Result<u32> {
+        Ok(Self::STOP)
+    }
+
+    fn tokenizer(&self) -> std::sync::Arc<Tokenizer> {
+        self.tokenizer.clone()
+    }
+}
+
+#[test]
+fn speculative_decoding_matches_the_model() {
+    use crate::SyncModelExt;
+
+    let generate = |model: &SuccessorModel, draft: &SuccessorModel, draft_tokens| {
+        let mut session = model.new_session().unwrap();
+        let mut draft_session = draft.new_session().unwrap();
+        let mut text = String::new();
+        model
+            .stream_text_with_draft(
+                &mut session,
+                SpeculativeDraft::new(draft, &mut draft_session).with_draft_tokens(draft_tokens),
+                "a",
+                None,
+                None,
+                SpeculativeSampling::greedy(),
+                |token| {
+                    text += &token;
+                    Ok(ModelFeedback::Continue)
+                },
+            )
+            .unwrap();
+        (text, session.tokens, draft_session.tokens)
+    };
+
+    // When every proposal is accepted, the logits after the last proposal give one more token. Two proposals and the extra token are generated with each run of the model
+    let model = SuccessorModel::new();
+    let (text, tokens, _) = generate(&model, &SuccessorModel::new(), 2);
+    assert_eq!(text, "bcdef");
+    assert_eq!(tokens, [0, 1, 2, 3, 4, 5]);
+    assert_eq!(model.runs.get(), 2);
+
+    // A draft that proposes "a" after "c" is rejected there. The model replaces the proposal with its own token and both sessions drop the rejected tokens
+    let model = SuccessorModel::new();
+    let draft = SuccessorModel::with_successor(|token| if token == 2 { 0 } else { token + 1 });
+    let (text, tokens, draft_tokens) = generate(&model, &draft, 4);
+    assert_eq!(text, "bcdef");
+    assert_eq!(tokens, [0, 1, 2, 3, 4, 5, SuccessorModel::STOP]);
+    assert_eq!(draft_tokens, [0, 1, 2, 3, 4, 5]);
+    assert_eq!(model.runs.get(), 2);
+
+    // Both models must share a tokenizer
+    let mut session = model.new_session().unwrap();
+    let mut other_session = draft.new_session().unwrap();
+    let mut other = SuccessorModel::new();
+    other.tokenizer = std::sync::Arc::new(crate::test_util::word_level_tokenizer(&["b", "a"]));
+    assert!(model
+        .stream_text_with_draft(
+            &mut session,
+            SpeculativeDraft::new(&other, &mut other_session),
+            "a",
+            None,
+            None,
+            SpeculativeSampling::greedy(),
+            |_| Ok(ModelFeedback::Continue),
+        )
+        .is_err());
+}
+
+#[test]
+fn speculative_structured_generation_rejects_invalid_drafts() {
+    use crate::SyncModelExt;
+    use kalosm_sample::{CreateParserState, RegexParser};
+
+    // The draft proposes "f" after "c", which the parser never accepts
+    let model = SuccessorModel::new();
+    let draft = SuccessorModel::with_successor(|token| if token == 2 { 5 } else { token + 1 });
+    let mut session = model.new_session().unwrap();
+    let mut draft_session = draft.new_session().unwrap();
+    let parser = RegexParser::new("[cx][dx][ex]").unwrap();
+    let state = parser.create_parser_state();
+    let output = model
+        .generate_structured_with_draft(
+            &mut session,
+            SpeculativeDraft::new(&draft, &mut draft_session),
+            "a b",
+            parser,
+            state,
+            SpeculativeSampling::greedy(),
+            |_| Ok(()),
+            None,
+        )
+        .unwrap();
+    assert_eq!(output, "cde");
+    assert_eq!(session.tokens, [0, 1, 2, 3, 4]);
+    assert_eq!(draft_session.tokens, [0, 1, 2, 3]);
+}
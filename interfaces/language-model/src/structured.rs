@@ -3,8 +3,10 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+use crate::StructuredSample;
 use crate::SyncModel;
 use crate::TokenOutputStream;
+use crate::{Session, SpeculativeDraft, SpeculativeSampling, TokenDistribution};
 use kalosm_sample::CreateParserState;
 use kalosm_sample::{LiteralParser, ParseStatus, Parser, ParserExt};
 use llm_samplers::prelude::{Logit, Logits};
@@ -12,6 +14,85 @@ use llm_samplers::types::{HasSamplerResources, Sampler, SamplerError};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use tokenizers::tokenizer::Tokenizer;
 
+/// A prompt that has been tokenized for structured generation.
+///
+/// The last token of the prompt is removed and added to the constraints instead (prompt healing). That lets the model choose a tokenization of the end of the prompt that fits the constraints.
+#[derive(Clone)]
+struct HealedPrompt {
+    token_stream: TokenOutputStream,
+    remaining_prompt_text: String,
+}
+
+impl HealedPrompt {
+    fn new<M: ?Sized + SyncModel>(llm: &M, prompt: impl Display) -> anyhow::Result<Self> {
+        let tokenizer = llm.tokenizer();
+
+        let prompt_text = prompt.to_string();
+        let prompt_tokens = tokenizer
+            .encode(prompt_text, false)
+            .map_err(|e| anyhow::anyhow!(e))?;
+        let mut prompt_tokens = prompt_tokens.get_ids();
+
+        // Prompt healing
+        // Trim the last token and add what it would decode to into the constraints
+        let last_token = if let Some((last, tokens)) = prompt_tokens.split_last() {
+            prompt_tokens = tokens;
+            Some(*last)
+        } else {
+            None
+        };
+
+        let mut token_stream = TokenOutputStream::new(tokenizer);
+        for token in prompt_tokens {
+            token_stream.next_token(*token)?;
+        }
+
+        let remaining_prompt_text = last_token
+            .map(|token| token_stream.peek_token(token))
+            .transpose()?
+            .flatten()
+            .unwrap_or_default();
+
+        Ok(Self {
+            token_stream,
+            remaining_prompt_text,
+        })
+    }
+
+    /// The tokens of the prompt that need to be fed into the model before sampling.
+    fn tokens(&self) -> &[u32] {
+        self.token_stream.tokens()
+    }
+
+    /// Start a structured generation after the prompt. The text of the trimmed last token must be parsed before `parser`.
+    fn generate<P: Parser, F: FnMut(String) -> anyhow::Result<()>>(
+        self,
+        tokenizer: Arc<Tokenizer>,
+        parser: P,
+        parser_state: P::PartialState,
+        on_token: F,
+    ) -> StructuredGeneration<impl CreateParserState<Output = P::Output>, F> {
+        let Self {
+            token_stream,
+            remaining_prompt_text,
+        } = self;
+
+        let parser = LiteralParser::new(remaining_prompt_text.clone())
+            .ignore_output_then(parser.with_initial_state(move || parser_state.clone()));
+        let parser_state = parser.create_parser_state();
+
+        StructuredGeneration {
+            parser,
+            parser_state,
+            token_stream,
+            tokenizer,
+            remaining_prompt_text,
+            strip_required_next: true,
+            on_token,
+        }
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn generate_structured<M: ?Sized + SyncModel, P: Parser>(
     prompt: impl Display,
@@ -19,62 +100,347 @@ pub(crate) fn generate_structured<M: ?Sized + SyncModel, P: Parser>(
     session: &mut M::Session,
     parser: P,
     parser_state: P::PartialState,
-    mut sampler: Arc<Mutex<dyn Sampler>>,
-    mut on_token: impl FnMut(String) -> anyhow::Result<()>,
+    sampler: Arc<Mutex<dyn Sampler>>,
+    on_token: impl FnMut(String) -> anyhow::Result<()>,
     top_k: Option<usize>,
 ) -> anyhow::Result<P::Output> {
-    let tokenizer = llm.tokenizer();
+    let prompt = HealedPrompt::new(llm, prompt)?;
+    let unprocessed_token_count = prompt.tokens().len();
+    let (output, _) = sample_structured(
+        llm,
+        session,
+        prompt,
+        parser,
+        parser_state,
+        sampler,
+        on_token,
+        top_k,
+        unprocessed_token_count,
+        None,
+    )?;
+    Ok(output)
+}
 
-    let prompt_text = prompt.to_string();
-    let prompt_tokens = tokenizer
-        .encode(prompt_text, false)
-        .map_err(|e| anyhow::anyhow!(e))?;
-    let mut prompt_tokens = prompt_tokens.get_ids();
-
-    // Prompt healing
-    // Trim the last token and add what it would decode to into the constraints
-    let last_token = if let Some((last, tokens)) = prompt_tokens.split_last() {
-        prompt_tokens = tokens;
-        Some(*last)
-    } else {
-        None
-    };
-
-    let mut unprocessed_token_count = prompt_tokens.len();
-    let mut token_stream = TokenOutputStream::new(tokenizer.clone());
-    for token in prompt_tokens {
-        token_stream.next_token(*token)?;
+/// Generate multiple structured outputs from the same prompt. The prompt is only fed into the model once and the session is forked for each sample.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn generate_structured_samples<M: ?Sized + SyncModel, P: Parser>(
+    prompt: impl Display,
+    llm: &M,
+    session: &mut M::Session,
+    parser: P,
+    parser_state: P::PartialState,
+    sampler: Arc<Mutex<dyn Sampler>>,
+    samples: usize,
+    top_k: Option<usize>,
+) -> anyhow::Result<Vec<StructuredSample<P::Output>>> {
+    let prompt = HealedPrompt::new(llm, prompt)?;
+    if prompt.tokens().is_empty() {
+        anyhow::bail!("The prompt must be at least two tokens long to share it between samples");
+    }
+    let mut prompt_logits = Vec::new();
+    llm.feed_tokens(session, prompt.tokens(), &mut prompt_logits)?;
+
+    let parser = Arc::new(parser);
+    let mut outputs = Vec::with_capacity(samples);
+    for _ in 0..samples {
+        let mut fork = session.try_clone()?;
+        let mut text = String::new();
+        let (output, log_probability) = sample_structured(
+            llm,
+            &mut fork,
+            prompt.clone(),
+            parser.clone(),
+            parser_state.clone(),
+            sampler.clone(),
+            |token| {
+                text += &token;
+                Ok(())
+            },
+            top_k,
+            0,
+            Some(prompt_logits.clone()),
+        )?;
+        outputs.push(StructuredSample::new(output, text, log_probability));
     }
 
-    let remaining_prompt_text = last_token
-        .map(|token| token_stream.peek_token(token))
-        .transpose()?
-        .flatten()
-        .unwrap_or_default();
+    Ok(outputs)
+}
 
-    let parser = LiteralParser::new(remaining_prompt_text.clone())
-        .ignore_output_then(parser.with_initial_state(move || parser_state.clone()));
-    let mut parser_state = parser.create_parser_state();
-    let mut strip_required_next = true;
+/// Generate a structured output with tokens proposed by a draft model. Draft tokens are checked against the parser while drafting, and tokens the parser rejects are never accepted by the model.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn generate_structured_with_draft<
+    M: ?Sized + SyncModel,
+    D: ?Sized + SyncModel,
+    P: Parser,
+>(
+    prompt: impl Display,
+    llm: &M,
+    session: &mut M::Session,
+    mut draft: SpeculativeDraft<'_, D>,
+    parser: P,
+    parser_state: P::PartialState,
+    sampling: SpeculativeSampling,
+    on_token: impl FnMut(String) -> anyhow::Result<()>,
+    top_k: Option<usize>,
+) -> anyhow::Result<P::Output> {
+    let tokenizer = llm.tokenizer();
+    draft.check_tokenizer(&tokenizer)?;
+    let prompt = HealedPrompt::new(llm, prompt)?;
+    if prompt.tokens().is_empty() {
+        anyhow::bail!("The prompt must be at least two tokens long for speculative decoding");
+    }
+    let mut generation = prompt.generate(tokenizer, parser, parser_state, on_token);
 
     let mut rng = rand::thread_rng();
-    let mut state_map = vec![];
-    let mut logits_indexed = Vec::new();
-    let mut token_cache = DetokenizationCache::new();
-    let mut logits = Logits::default();
+    let mut constraints = TokenConstraints::new();
+    // The number of tokens of the generation that each session has processed
+    let mut processed = 0;
+    let mut draft_processed = 0;
+    let mut draft_logits = Vec::new();
+    let mut logits = Vec::new();
+    // Tokens the parser requires are fed in with the next round, so this count is not needed
+    let mut unprocessed_token_count = 0;
+
+    loop {
+        let tokens = generation.token_stream.tokens().to_vec();
+
+        // Let the draft model propose tokens until it proposes a token the parser rejects, the parser finishes, or the parser requires text that will be added without sampling
+        draft.feed(&tokens[draft_processed..], &mut draft_logits)?;
+        let mut draft_fed = 0;
+        let mut draft_stream = generation.token_stream.clone();
+        let mut draft_state = generation.parser_state.clone();
+        let mut proposals = Vec::with_capacity(draft.draft_tokens);
+        loop {
+            let proposal = draft.propose(&draft_logits, &sampling)?;
+            let token = proposal.sampled;
+            // Invalid tokens are still proposed so the model rejects them and samples a replacement. Skipping them would change the output distribution
+            proposals.push(proposal);
+            let Some(text) = draft_stream.peek_token(token)? else {
+                break;
+            };
+            let Ok(ParseStatus::Incomplete {
+                new_state,
+                required_next,
+            }) = generation.parser.parse(&draft_state, text.as_bytes())
+            else {
+                break;
+            };
+            if !required_next.is_empty() || proposals.len() == draft.draft_tokens {
+                break;
+            }
+            draft_state = new_state;
+            draft_stream.next_token(token)?;
+            draft.feed(&[token], &mut draft_logits)?;
+            draft_fed += 1;
+        }
+
+        // Verify every proposal with one forward pass of the model
+        let mut verify = tokens[processed..].to_vec();
+        verify.extend(proposals.iter().map(|proposal| proposal.sampled));
+        llm.feed_tokens_all_logits(session, &verify, &mut logits)?;
+        let offset = tokens.len() - processed - 1;
+
+        let mut accepted = 0;
+        let mut output = None;
+        let mut next_token = None;
+        for proposal in &proposals {
+            constraints.update(
+                &generation.parser,
+                &generation.parser_state,
+                &generation.token_stream,
+                &logits[offset + accepted],
+                top_k,
+            )?;
+            let distribution = constraints.sample(&sampling, &mut rng)?;
+            if !distribution.accept(proposal, &mut rng) {
+                next_token = Some(distribution.residual(proposal, &mut rng));
+                break;
+            }
+            accepted += 1;
+            let parsed = constraints.take(proposal.sampled)?;
+            output =
+                generation.add_token(proposal.sampled, parsed, &mut unprocessed_token_count)?;
+            if output.is_some() {
+                break;
+            }
+        }
+        // If every proposal was accepted and the parser didn't add any required tokens, the model's logits after the last proposal give one more token for free
+        if output.is_none()
+            && next_token.is_none()
+            && generation.token_stream.tokens().len() == tokens.len() + accepted
+        {
+            constraints.update(
+                &generation.parser,
+                &generation.parser_state,
+                &generation.token_stream,
+                &logits[offset + accepted],
+                top_k,
+            )?;
+            let distribution = constraints.sample(&sampling, &mut rng)?;
+            next_token = Some(distribution.sampled);
+        }
+        if let Some(token) = next_token {
+            let parsed = constraints.take(token)?;
+            output = generation.add_token(token, parsed, &mut unprocessed_token_count)?;
+        }
+        tracing::trace!("Accepted {accepted} of {} draft tokens", proposals.len());
+
+        // Remove the rejected proposals from both sessions
+        if accepted < proposals.len() {
+            session.rollback(proposals.len() - accepted)?;
+        }
+        processed = tokens.len() + accepted;
+        let draft_kept = draft_fed.min(accepted);
+        draft.rollback(draft_fed - draft_kept)?;
+        draft_processed = tokens.len() + draft_kept;
+
+        if let Some(output) = output {
+            return Ok(output);
+        }
+    }
+}
+
+/// Sample tokens that fit the parser until the parser is finished. If `initial_logits` is set, the prompt has already been fed into the session and those logits are used for the first token.
+#[allow(clippy::too_many_arguments)]
+fn sample_structured<M: ?Sized + SyncModel, P: Parser>(
+    llm: &M,
+    session: &mut M::Session,
+    prompt: HealedPrompt,
+    parser: P,
+    parser_state: P::PartialState,
+    mut sampler: Arc<Mutex<dyn Sampler>>,
+    on_token: impl FnMut(String) -> anyhow::Result<()>,
+    top_k: Option<usize>,
+    mut unprocessed_token_count: usize,
+    mut initial_logits: Option<Vec<f32>>,
+) -> anyhow::Result<(P::Output, f64)> {
+    let mut generation = prompt.generate(llm.tokenizer(), parser, parser_state, on_token);
+    let mut log_probability = 0.;
+
+    let mut rng = rand::thread_rng();
+    let mut constraints = TokenConstraints::new();
     let mut logit_probs = Vec::new();
 
     loop {
-        let tokens = token_stream.tokens();
-        llm.feed_tokens(
-            session,
-            &tokens[tokens.len() - unprocessed_token_count..],
-            &mut logit_probs,
+        let tokens = generation.token_stream.tokens();
+        match initial_logits.take() {
+            Some(initial_logits) => logit_probs = initial_logits,
+            None => llm.feed_tokens(
+                session,
+                &tokens[tokens.len() - unprocessed_token_count..],
+                &mut logit_probs,
+            )?,
+        }
+        constraints.update(
+            &generation.parser,
+            &generation.parser_state,
+            &generation.token_stream,
+            &logit_probs,
+            top_k,
         )?;
+
         let resources = &mut SamplerResources {
-            previous_tokens: tokens,
+            previous_tokens: generation.token_stream.tokens(),
             rng: &mut rng,
         };
+        let token_id = sampler
+            .sample_token(resources, &mut constraints.logits)?
+            .ok_or(anyhow::anyhow!("Failed to sample constrained tokens"))?;
+        log_probability += log_softmax(&logit_probs, token_id);
+
+        unprocessed_token_count = 1;
+        let parsed = constraints.take(token_id)?;
+        if let Some(result) =
+            generation.add_token(token_id, parsed, &mut unprocessed_token_count)?
+        {
+            return Ok((result, log_probability));
+        }
+    }
+}
+
+/// The parse result for a token and the number of bytes of the token the parser consumed.
+type ParsedToken<P> = (
+    ParseStatus<'static, <P as Parser>::PartialState, <P as Parser>::Output>,
+    usize,
+);
+
+/// The state of a structured generation: the parser, the tokens generated so far, and the callback for new text.
+struct StructuredGeneration<P: Parser, F> {
+    parser: P,
+    parser_state: P::PartialState,
+    token_stream: TokenOutputStream,
+    tokenizer: Arc<Tokenizer>,
+    remaining_prompt_text: String,
+    strip_required_next: bool,
+    on_token: F,
+}
+
+impl<P: Parser, F: FnMut(String) -> anyhow::Result<()>> StructuredGeneration<P, F> {
+    /// Add a sampled token to the output along with any tokens the parser requires next. Returns the output of the parser once it is finished.
+    fn add_token(
+        &mut self,
+        token_id: u32,
+        (result, parsed_bytes): ParsedToken<P>,
+        unprocessed_token_count: &mut usize,
+    ) -> anyhow::Result<Option<P::Output>> {
+        let mut token = self.token_stream.next_token(token_id)?.unwrap();
+        token.truncate(parsed_bytes);
+        tracing::trace!("Adding token {} to parser", token);
+        // If we are still loading the initial prompt, don't send that part of the text
+        if self.strip_required_next {
+            if let Some(stripped) = token.strip_prefix(&self.remaining_prompt_text) {
+                token = stripped.to_string();
+            }
+            self.strip_required_next = false;
+        }
+        (self.on_token)(token)?;
+
+        update_state(
+            &self.parser,
+            &mut self.parser_state,
+            result,
+            &self.tokenizer,
+            &mut self.token_stream,
+            &mut self.on_token,
+            unprocessed_token_count,
+        )
+    }
+}
+
+/// Buffers for finding the tokens that are valid for the current parser state.
+struct TokenConstraints<P: Parser> {
+    state_map: Vec<Option<ParsedToken<P>>>,
+    logits_indexed: Vec<Logit>,
+    token_cache: DetokenizationCache,
+    /// The valid tokens from the last call to [`TokenConstraints::update`]
+    logits: Logits,
+}
+
+impl<P: Parser> TokenConstraints<P> {
+    fn new() -> Self {
+        Self {
+            state_map: Vec::new(),
+            logits_indexed: Vec::new(),
+            token_cache: DetokenizationCache::new(),
+            logits: Logits::default(),
+        }
+    }
+
+    /// Find the tokens that are valid for the parser state and store their parse results. If `top_k` is set, only the `top_k` most likely valid tokens are kept.
+    fn update(
+        &mut self,
+        parser: &P,
+        parser_state: &P::PartialState,
+        token_stream: &TokenOutputStream,
+        logit_probs: &[f32],
+        top_k: Option<usize>,
+    ) -> anyhow::Result<()> {
+        let Self {
+            state_map,
+            logits_indexed,
+            token_cache,
+            logits,
+        } = self;
 
         // fill the state map with None for each token
         token_cache.clear(logit_probs.len());
@@ -96,7 +462,7 @@ pub(crate) fn generate_structured<M: ?Sized + SyncModel, P: Parser>(
         if top_k.is_none() {
             token_cache.expand(
                 &(0..logit_probs.len() as u32).collect::<Vec<_>>(),
-                &token_stream,
+                token_stream,
             )?;
         }
 
@@ -128,7 +494,7 @@ pub(crate) fn generate_structured<M: ?Sized + SyncModel, P: Parser>(
                     partitioned_logits_index = Some(new_partitioned_index);
                     token_cache.expand_with_logits(
                         &logits_indexed[i..=new_partitioned_index],
-                        &token_stream,
+                        token_stream,
                     )?;
 
                     // Double the batch size for next time
@@ -142,7 +508,7 @@ pub(crate) fn generate_structured<M: ?Sized + SyncModel, P: Parser>(
             let Some(text) = token_cache.get(token_id as usize) else {
                 continue;
             };
-            if let Ok(result) = parser.parse(&parser_state, text.as_bytes()) {
+            if let Ok(result) = parser.parse(parser_state, text.as_bytes()) {
                 let parsed_bytes = match result {
                     ParseStatus::Finished { remaining, .. } => text.len() - remaining.len(),
                     ParseStatus::Incomplete { .. } => text.len(),
@@ -168,40 +534,38 @@ pub(crate) fn generate_structured<M: ?Sized + SyncModel, P: Parser>(
         if !valid_tokens {
             return Err(anyhow::anyhow!("No valid tokens found"));
         }
-        let token_id = sampler
-            .sample_token(resources, &mut logits)?
-            .ok_or(anyhow::anyhow!("Failed to sample constrained tokens"))?;
 
-        unprocessed_token_count = 1;
-        let (result, parsed_bytes) = state_map
-            .get_mut(token_id as usize)
-            .unwrap()
-            .take()
-            .ok_or(anyhow::anyhow!("Token {} not found in state map", token_id))?;
-        let mut token = token_stream.next_token(token_id)?.unwrap();
-        token.truncate(parsed_bytes);
-        tracing::trace!("Adding token {} to parser", token);
-        // If we are still loading the initial prompt, don't send that part of the text
-        if strip_required_next {
-            if let Some(stripped) = token.strip_prefix(&remaining_prompt_text) {
-                token = stripped.to_string();
-            }
-            strip_required_next = false;
-        }
-        on_token(token)?;
+        Ok(())
+    }
 
-        if let Some(result) = update_state(
-            &parser,
-            &mut parser_state,
-            result,
-            &tokenizer,
-            &mut token_stream,
-            &mut on_token,
-            &mut unprocessed_token_count,
-        )? {
-            return Ok(result);
-        }
+    /// Sample one of the valid tokens from the last call to [`TokenConstraints::update`] and return the distribution it was sampled from.
+    fn sample(
+        &self,
+        sampling: &SpeculativeSampling,
+        rng: &mut impl rand::Rng,
+    ) -> anyhow::Result<TokenDistribution> {
+        sampling.sample(
+            self.logits
+                .iter()
+                .map(|logit| (logit.token_id, logit.logit)),
+            rng,
+        )
     }
+
+    /// Take the parse result for a valid token.
+    fn take(&mut self, token_id: u32) -> anyhow::Result<ParsedToken<P>> {
+        self.state_map
+            .get_mut(token_id as usize)
+            .and_then(Option::take)
+            .ok_or(anyhow::anyhow!("Token {} not found in state map", token_id))
+    }
+}
+
+/// The log probability of a token given the raw logits of the model.
+fn log_softmax(logits: &[f32], token_id: u32) -> f64 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max) as f64;
+    let sum: f64 = logits.iter().map(|&logit| (logit as f64 - max).exp()).sum();
+    logits[token_id as usize] as f64 - max - sum.ln()
 }
 
 fn cmp_logits(a: &Logit, b: &Logit) -> std::cmp::Ordering {
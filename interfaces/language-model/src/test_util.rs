@@ -0,0 +1,25 @@
+//! Helpers shared by the tests of kalosm-language-model and the crates built on top of it.
+//!
+//! `cfg(test)` items are not visible to other crates, so those crates include this file with a `#[path]` attribute.
+
+use std::collections::HashMap;
+use tokenizers::models::wordlevel::WordLevel;
+use tokenizers::pre_tokenizers::whitespace::Whitespace;
+use tokenizers::Tokenizer;
+
+/// Create a tokenizer with one token for every word or run of punctuation. Each word in the vocabulary gets its index as its id, and every other word becomes `[UNK]`.
+pub(crate) fn word_level_tokenizer(vocab: &[&str]) -> Tokenizer {
+    let vocab = vocab
+        .iter()
+        .enumerate()
+        .map(|(id, word)| (word.to_string(), id as u32))
+        .collect::<HashMap<_, _>>();
+    let model = WordLevel::builder()
+        .vocab(vocab)
+        .unk_token("[UNK]".into())
+        .build()
+        .unwrap();
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer.with_pre_tokenizer(Whitespace {});
+    tokenizer
+}
@@ -9,6 +9,7 @@ use tokenizers::tokenizer::Tokenizer;
 
 /// This is a wrapper around a tokenizer to ensure that tokens can be returned to the user in a
 /// streaming way rather than having to wait for the full decoding.
+#[derive(Clone)]
 pub struct TokenOutputStream {
     tokenizer: Arc<Tokenizer>,
     tokens: Vec<u32>,
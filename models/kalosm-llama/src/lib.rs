@@ -34,12 +34,15 @@ extern crate accelerate_src;
 
 mod language_model;
 mod model;
+mod prefix_cache;
 mod raw;
 mod session;
 mod source;
 
 pub use crate::model::LlamaModel;
+pub use crate::prefix_cache::PrefixCacheConfig;
 pub use crate::raw::cache::*;
+use crate::raw::lora::LoraAdapter;
 use crate::raw::Model;
 pub use crate::session::LlamaSession;
 use candle_core::{
@@ -47,9 +50,13 @@ use candle_core::{
     Device,
 };
 pub use kalosm_common::*;
-use kalosm_language_model::ChatMarkers;
+use kalosm_language_model::{ChatMarkers, SpeculativeSampling};
+use kalosm_streams::text_stream::ChannelTextStream;
 use llm_samplers::types::Sampler;
 pub use source::*;
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use tokenizers::Tokenizer;
 
@@ -67,6 +74,11 @@ enum Task {
         sender: tokio::sync::mpsc::UnboundedSender<String>,
         sampler: Arc<Mutex<dyn Sampler>>,
     },
+    InferWithDraft {
+        settings: InferenceSettings,
+        sender: tokio::sync::mpsc::UnboundedSender<String>,
+        sampling: SpeculativeSampling,
+    },
     RunSync {
         callback: SyncCallback,
     },
@@ -85,6 +97,7 @@ pub struct Llama {
     task_sender: tokio::sync::mpsc::UnboundedSender<Task>,
     tokenizer: Arc<Tokenizer>,
     chat_markers: Arc<Option<ChatMarkers>>,
+    has_draft_model: bool,
 }
 
 impl Drop for Llama {
@@ -131,36 +144,84 @@ impl Llama {
         tokenizer: Tokenizer,
         device: Device,
         cache: LlamaCache,
+        prefix_cache: Option<PrefixCacheConfig>,
+        max_batch_size: usize,
         chat_markers: Option<ChatMarkers>,
+        draft: Option<LlamaModel>,
+        draft_tokens: Option<usize>,
     ) -> Self {
         let (task_sender, mut task_receiver) = tokio::sync::mpsc::unbounded_channel();
         let arc_tokenizer = Arc::new(tokenizer);
+        let has_draft_model = draft.is_some();
 
         std::thread::spawn({
             let arc_tokenizer = arc_tokenizer.clone();
             move || {
-                let mut inner = LlamaModel::new(model, arc_tokenizer, device, cache);
+                let mut inner = LlamaModel::new(model, arc_tokenizer, device, cache, prefix_cache)
+                    .with_draft(draft, draft_tokens);
                 tokio::runtime::Builder::new_current_thread()
                     .enable_all()
                     .build()
                     .unwrap()
                     .block_on(async move {
-                        while let Some(task) = task_receiver.recv().await {
-                            match task {
-                                Task::Kill => break,
-                                Task::Infer {
-                                    settings,
-                                    sender,
-                                    sampler,
-                                } => {
-                                    if let Err(err) = inner._infer(settings, sampler, sender) {
-                                        eprintln!("Error: {}", err);
+                        let mut batch = Vec::new();
+                        let mut drafts = Vec::new();
+                        let mut waiting = VecDeque::new();
+                        'run: loop {
+                            let mut tasks = Vec::new();
+                            // Only wait for new tasks if there is nothing left to generate
+                            if batch.is_empty() && drafts.is_empty() && waiting.is_empty() {
+                                match task_receiver.recv().await {
+                                    Some(task) => tasks.push(task),
+                                    None => break,
+                                }
+                            }
+                            while let Ok(task) = task_receiver.try_recv() {
+                                tasks.push(task);
+                            }
+                            for task in tasks {
+                                match task {
+                                    Task::Kill => break 'run,
+                                    Task::Infer {
+                                        settings,
+                                        sender,
+                                        sampler,
+                                    } => waiting.push_back((settings, sampler, sender)),
+                                    // Speculative requests decode several tokens per round with their own forward passes, so each request runs one round between steps of the batch
+                                    Task::InferWithDraft {
+                                        settings,
+                                        sender,
+                                        sampling,
+                                    } => match inner
+                                        .start_draft_generation(settings, sampling, sender)
+                                    {
+                                        Ok(generation) => drafts.push(generation),
+                                        Err(err) => eprintln!("Error: {}", err),
+                                    },
+                                    Task::RunSync { callback } => {
+                                        callback(&mut inner).await;
                                     }
                                 }
-                                Task::RunSync { callback } => {
-                                    callback(&mut inner).await;
+                            }
+
+                            // New requests join the batch between decoding steps
+                            while batch.len() < max_batch_size {
+                                let Some((settings, sampler, sender)) = waiting.pop_front() else {
+                                    break;
+                                };
+                                match inner.start_generation(settings, sampler, sender) {
+                                    Ok(Some(generation)) => batch.push(generation),
+                                    Ok(None) => {}
+                                    Err(err) => eprintln!("Error: {}", err),
                                 }
                             }
+
+                            if !batch.is_empty() {
+                                inner.step_batch(&mut batch);
+                            }
+                            if !drafts.is_empty() {
+                                inner.step_drafts(&mut drafts);
+                            }
                         }
                     })
             }
@@ -169,6 +230,7 @@ impl Llama {
             task_sender,
             tokenizer: arc_tokenizer,
             chat_markers: chat_markers.into(),
+            has_draft_model,
         }
     }
 
@@ -192,6 +254,78 @@ impl Llama {
             .unwrap();
         Ok(receiver)
     }
+
+    /// Stream text with tokens proposed by the draft model set with [`LlamaBuilder::with_draft_model`]. The draft model proposes a few tokens at a time and the model checks all of them in one forward pass, so the output follows the same distribution as sampling from `sampling` with this model alone.
+    ///
+    /// # Example
+    /// ```rust, no_run
+    /// use kalosm_llama::prelude::*;
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let model = Llama::builder()
+    ///         .with_source(LlamaSource::qwen_2_5_7b_instruct())
+    ///         // The draft model must use the same tokenizer as the model
+    ///         .with_draft_model(LlamaSource::qwen_2_5_0_5b_instruct())
+    ///         .build()
+    ///         .await?;
+    ///     let mut stream = model.stream_text_with_draft(
+    ///         "The capital of France is",
+    ///         Some(100),
+    ///         None,
+    ///         SpeculativeSampling::new().with_top_p(0.9),
+    ///     )?;
+    ///     while let Some(token) = stream.next().await {
+    ///         print!("{token}");
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn stream_text_with_draft(
+        &self,
+        prompt: &str,
+        max_tokens: Option<u32>,
+        stop_on: Option<&str>,
+        sampling: SpeculativeSampling,
+    ) -> anyhow::Result<ChannelTextStream> {
+        if !self.has_draft_model {
+            anyhow::bail!(
+                "The model was built without a draft model. Set one with LlamaBuilder::with_draft_model"
+            );
+        }
+        let max_length = max_tokens.unwrap_or(64);
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.task_sender
+            .send(Task::InferWithDraft {
+                settings: InferenceSettings::new(prompt)
+                    .with_sample_len(max_length as usize)
+                    .with_stop_on(stop_on.map(|s| s.to_string())),
+                sender,
+                sampling,
+            })
+            .unwrap();
+        Ok(receiver.into())
+    }
+}
+
+/// Read the weights of a model from a gguf or ggml file.
+pub(crate) fn read_model(
+    filename: &Path,
+    group_query_attention: u8,
+    device: &Device,
+) -> anyhow::Result<Model> {
+    let mut file = std::fs::File::open(filename)?;
+    let model = match filename.extension().and_then(|v| v.to_str()) {
+        Some("gguf") => {
+            let model = gguf_file::Content::read(&mut file)?;
+            Model::from_gguf(model, &mut file, device)?
+        }
+        Some("ggml" | "bin") | Some(_) | None => {
+            let model = ggml_file::Content::read(&mut file, device)?;
+            Model::from_ggml(model, group_query_attention as usize, device)?
+        }
+    };
+    Ok(model)
 }
 
 /// A builder with configuration for a Llama model.
@@ -200,6 +334,11 @@ pub struct LlamaBuilder {
     source: source::LlamaSource,
     device: Option<Device>,
     flash_attn: bool,
+    prefix_cache: Option<PrefixCacheConfig>,
+    max_batch_size: Option<NonZeroUsize>,
+    lora_adapters: Vec<LoraSource>,
+    draft: Option<source::LlamaSource>,
+    draft_tokens: Option<usize>,
 }
 
 impl LlamaBuilder {
@@ -215,6 +354,42 @@ impl LlamaBuilder {
         self
     }
 
+    /// Cache the attention for prompts and reuse it for later prompts that start with the same tokens. (Defaults to disabled)
+    ///
+    /// This makes prompts that share a long prefix, like a system prompt or few-shot examples, much faster to process at the cost of keeping the cached attention in memory. See [`PrefixCacheConfig`] for the memory limits.
+    pub fn with_prefix_cache(mut self, config: PrefixCacheConfig) -> Self {
+        self.prefix_cache = Some(config);
+        self
+    }
+
+    /// Set the maximum number of generation requests that are decoded together. (Defaults to 8)
+    ///
+    /// Requests that arrive while the model is generating join the batch between decoding steps, and every request in the batch is decoded in a single forward pass. Requests beyond the limit wait until another request finishes. Set this to 1 to generate one request at a time.
+    pub fn with_max_batch_size(mut self, max_batch_size: NonZeroUsize) -> Self {
+        self.max_batch_size = Some(max_batch_size);
+        self
+    }
+
+    /// Load a LoRA adapter on top of the base weights of the model. Call this multiple times to load several adapters. (Defaults to no adapters)
+    ///
+    /// New sessions use every loaded adapter at the scale set in its [`LoraSource`]. Use [`LlamaSession::set_lora_adapters`] to choose different adapters or scales for a session without reloading the model.
+    pub fn with_lora_adapter(mut self, adapter: LoraSource) -> Self {
+        self.lora_adapters.push(adapter);
+        self
+    }
+
+    /// Load a smaller draft model that proposes tokens for [`Llama::stream_text_with_draft`]. The draft model must use the same tokenizer as the model. (Defaults to no draft model)
+    pub fn with_draft_model(mut self, source: source::LlamaSource) -> Self {
+        self.draft = Some(source);
+        self
+    }
+
+    /// Set the number of tokens the draft model proposes before the model verifies them. (Defaults to 4)
+    pub fn with_draft_tokens(mut self, draft_tokens: usize) -> Self {
+        self.draft_tokens = Some(draft_tokens);
+        self
+    }
+
     /// Set the device to run the model with. (Defaults to an accelerator if available, otherwise the CPU)
     pub fn with_device(mut self, device: Device) -> Self {
         self.device = Some(device);
@@ -229,6 +404,72 @@ impl LlamaBuilder {
         }
     }
 
+    /// Download the LoRA adapters and load them into the model.
+    pub(crate) async fn load_lora_adapters(
+        &self,
+        model: &mut Model,
+        device: &Device,
+        mut handler: impl FnMut(ModelLoadingProgress),
+    ) -> anyhow::Result<()> {
+        for source in &self.lora_adapters {
+            let source_display = format!("LoRA adapter ({})", source.adapter);
+            let mut create_progress = ModelLoadingProgress::downloading_progress(source_display);
+            let path = source
+                .adapter(&self.source.cache, |progress| {
+                    handler(create_progress(progress))
+                })
+                .await?;
+            let source_display = format!("LoRA adapter config ({})", source.adapter);
+            let mut create_progress = ModelLoadingProgress::downloading_progress(source_display);
+            let adapter_config = source
+                .adapter_config(&self.source.cache, |progress| {
+                    handler(create_progress(progress))
+                })
+                .await;
+            let adapter = LoraAdapter::load(
+                source,
+                &path,
+                adapter_config.as_deref(),
+                &model.config,
+                device,
+            )?;
+            model.add_lora_adapter(adapter)?;
+        }
+        Ok(())
+    }
+
+    /// Download the draft model and load it if one is set.
+    pub(crate) async fn load_draft_model(
+        &self,
+        device: &Device,
+        mut handler: impl FnMut(ModelLoadingProgress),
+    ) -> anyhow::Result<Option<LlamaModel>> {
+        let Some(source) = &self.draft else {
+            return Ok(None);
+        };
+        let tokenizer = {
+            let source_display = format!("Draft tokenizer ({})", source.tokenizer);
+            let mut create_progress = ModelLoadingProgress::downloading_progress(source_display);
+            source
+                .tokenizer(|progress| handler(create_progress(progress)))
+                .await?
+        };
+        let source_display = format!("Draft model ({})", source.model);
+        let mut create_progress = ModelLoadingProgress::downloading_progress(source_display);
+        let filename = source
+            .model(|progress| handler(create_progress(progress)))
+            .await?;
+        let model = read_model(&filename, source.group_query_attention, device)?;
+        let cache = LlamaCache::new(&model.config);
+        Ok(Some(LlamaModel::new(
+            model,
+            Arc::new(tokenizer),
+            device.clone(),
+            cache,
+            None,
+        )))
+    }
+
     /// Build the model with a handler for progress as the download and loading progresses.
     ///
     /// ```rust, no_run
@@ -284,18 +525,15 @@ impl LlamaBuilder {
         };
         let filename = filename.await??;
 
-        let mut file = std::fs::File::open(&filename)?;
-        let model = match filename.extension().and_then(|v| v.to_str()) {
-            Some("gguf") => {
-                let model = gguf_file::Content::read(&mut file)?;
-                Model::from_gguf(model, &mut file, &device)?
-            }
-            Some("ggml" | "bin") | Some(_) | None => {
-                let model = ggml_file::Content::read(&mut file, &device)?;
-                let gqa = self.source.group_query_attention;
-                Model::from_ggml(model, gqa as usize, &device)?
-            }
-        };
+        let mut model = read_model(&filename, self.source.group_query_attention, &device)?;
+
+        self.load_lora_adapters(&mut model, &device, |progress| {
+            (handler.lock().unwrap())(progress)
+        })
+        .await?;
+        let draft = self
+            .load_draft_model(&device, |progress| (handler.lock().unwrap())(progress))
+            .await?;
 
         let cache = LlamaCache::new(&model.config);
 
@@ -304,7 +542,11 @@ impl LlamaBuilder {
             tokenizer,
             device,
             cache,
+            self.prefix_cache,
+            self.max_batch_size.map_or(8, NonZeroUsize::get),
             self.source.markers,
+            draft,
+            self.draft_tokens,
         ))
     }
 
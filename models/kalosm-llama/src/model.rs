@@ -1,18 +1,18 @@
+use crate::prefix_cache::{CachedPrefix, PrefixCache, PrefixCacheConfig};
 use crate::raw::cache::LlamaCache;
 use crate::{raw::Model, session::LlamaSession};
 use anyhow::{Error as E, Result};
 use kalosm_common::*;
-use kalosm_language_model::SyncModelExt;
-use std::sync::Arc;
-
-use candle_core::{
-    quantized::{ggml_file, gguf_file},
-    DType, Device,
+use kalosm_language_model::{
+    ModelFeedback, SpeculativeDraft, SpeculativeGeneration, SpeculativeSampling, TextGeneration,
 };
+use std::sync::{Arc, Mutex};
+
+use candle_core::{DType, Device};
 use kalosm_language_model::SyncModel;
 use tokenizers::Tokenizer;
 
-use crate::InferenceSettings;
+use crate::{read_model, InferenceSettings};
 
 /// The inner, synchronous Llama model.
 pub struct LlamaModel {
@@ -20,6 +20,9 @@ pub struct LlamaModel {
     device: Device,
     tokenizer: Arc<Tokenizer>,
     cache: LlamaCache,
+    prefix_cache: Option<Mutex<PrefixCache<CachedPrefix>>>,
+    draft: Option<Box<LlamaModel>>,
+    draft_tokens: Option<usize>,
 }
 
 impl SyncModel for LlamaModel {
@@ -47,13 +50,41 @@ impl SyncModel for LlamaModel {
         tokens: &[u32],
         logits: &mut Vec<f32>,
     ) -> anyhow::Result<()> {
-        Self::forward(
-            &self.model,
-            &self.device,
-            tokens,
-            Some(&mut session.cache),
-            logits,
-        )
+        match &self.prefix_cache {
+            // Only new sessions start with a prompt that may be cached. The cached attention depends on the LoRA adapters, so only sessions with the default adapters use the cache
+            Some(prefix_cache)
+                if session.cache.tokens.is_empty()
+                    && !tokens.is_empty()
+                    && session.cache.lora_adapters == self.cache.lora_adapters =>
+            {
+                self.feed_prompt(prefix_cache, session, tokens, logits)
+            }
+            _ => Self::forward(
+                &self.model,
+                &self.device,
+                tokens,
+                Some(&mut session.cache),
+                logits,
+            ),
+        }
+    }
+
+    fn feed_tokens_all_logits(
+        &self,
+        session: &mut Self::Session,
+        tokens: &[u32],
+        into: &mut Vec<Vec<f32>>,
+    ) -> anyhow::Result<()> {
+        if tokens.is_empty() {
+            return Err(anyhow::anyhow!("Cannot run model on empty input"));
+        }
+
+        let logits = self
+            .model
+            .forward_all(tokens, &self.device, Some(&mut session.cache))?;
+        *into = logits.to_dtype(DType::F32)?.to_vec2()?;
+
+        Ok(())
     }
 
     fn stop_token(&self) -> anyhow::Result<u32> {
@@ -68,9 +99,70 @@ impl SyncModel for LlamaModel {
     fn tokenizer(&self) -> Arc<Tokenizer> {
         self.tokenizer.clone()
     }
+
+    fn context_length(&self) -> Option<usize> {
+        Some(self.model.config.context_length)
+    }
 }
 
 impl LlamaModel {
+    /// Feed a prompt into a new session, reusing the attention cache for the longest prefix of the prompt that has been cached.
+    fn feed_prompt(
+        &self,
+        prefix_cache: &Mutex<PrefixCache<CachedPrefix>>,
+        session: &mut LlamaSession,
+        tokens: &[u32],
+        logits: &mut Vec<f32>,
+    ) -> anyhow::Result<()> {
+        let mut remaining = tokens;
+        if let Some((matched, cached)) = prefix_cache.lock().unwrap().get(tokens) {
+            match &cached.logits {
+                Some(cached_logits)
+                    if matched == tokens.len() && cached.cache.tokens.len() == matched =>
+                {
+                    // Cached prefixes are allocated with exactly enough space for their tokens, so the session will copy the cache before it appends anything
+                    session.cache = cached.cache.clone();
+                    logits.clear();
+                    logits.extend_from_slice(cached_logits);
+                    return Ok(());
+                }
+                _ => {
+                    // Always feed at least one token to get the logits for the prompt
+                    let len = matched.min(tokens.len() - 1);
+                    if len > 0 {
+                        tracing::trace!("Reusing {len} cached prompt tokens");
+                        session.cache = cached.cache.prefix(len)?;
+                        remaining = &tokens[len..];
+                    }
+                }
+            }
+        }
+
+        Self::forward(
+            &self.model,
+            &self.device,
+            remaining,
+            Some(&mut session.cache),
+            logits,
+        )?;
+
+        let cache = session.cache.prefix(session.cache.tokens.len())?;
+        let size = cache.memory_usage() + logits.len() * std::mem::size_of::<f32>();
+        let cached = CachedPrefix {
+            cache,
+            logits: Some(logits.clone()),
+        };
+        let mut prefix_cache = prefix_cache.lock().unwrap();
+        prefix_cache.insert(&session.cache.tokens, cached, size);
+        tracing::trace!(
+            "Prefix cache holds {} prompts in {} bytes",
+            prefix_cache.len(),
+            prefix_cache.memory_usage()
+        );
+
+        Ok(())
+    }
+
     fn forward(
         model: &Model,
         device: &Device,
@@ -110,26 +202,22 @@ impl LlamaModel {
             .source
             .model(|progress| handler(create_progress(progress)))
             .await?;
-        let mut file = std::fs::File::open(&filename)?;
-        let model = match filename.extension().and_then(|v| v.to_str()) {
-            Some("gguf") => {
-                let model = gguf_file::Content::read(&mut file)?;
-                Model::from_gguf(model, &mut file, &device)?
-            }
-            Some("ggml" | "bin") | Some(_) | None => {
-                let model = ggml_file::Content::read(&mut file, &device)?;
-                let gqa = builder.source.group_query_attention;
-                Model::from_ggml(model, gqa as usize, &device)?
-            }
-        };
+        let mut model = read_model(&filename, builder.source.group_query_attention, &device)?;
+
+        builder
+            .load_lora_adapters(&mut model, &device, &mut handler)
+            .await?;
+        let draft = builder.load_draft_model(&device, &mut handler).await?;
 
         let cache = LlamaCache::new(&model.config);
-        Ok(Self {
+        Ok(Self::new(
             model,
-            tokenizer: Arc::new(tokenizer),
+            Arc::new(tokenizer),
             device,
             cache,
-        })
+            builder.prefix_cache,
+        )
+        .with_draft(draft, builder.draft_tokens))
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -137,43 +225,271 @@ impl LlamaModel {
         model: Model,
         tokenizer: Arc<Tokenizer>,
         device: Device,
-        cache: LlamaCache,
+        mut cache: LlamaCache,
+        prefix_cache: Option<PrefixCacheConfig>,
     ) -> Self {
+        // New sessions start with every loaded adapter
+        cache.lora_adapters = model.default_lora_adapters();
         Self {
             cache,
             model,
             device,
             tokenizer,
+            prefix_cache: prefix_cache.map(|config| Mutex::new(PrefixCache::new(config))),
+            draft: None,
+            draft_tokens: None,
         }
     }
 
-    pub(crate) fn _infer(
-        &mut self,
+    /// Set the draft model that proposes tokens for [`LlamaModel::start_draft_generation`].
+    pub(crate) fn with_draft(
+        mut self,
+        draft: Option<LlamaModel>,
+        draft_tokens: Option<usize>,
+    ) -> Self {
+        self.draft = draft.map(Box::new);
+        self.draft_tokens = draft_tokens;
+        self
+    }
+
+    /// Start a generation request with tokens proposed by the draft model. Nothing runs until the request is stepped with [`LlamaModel::step_drafts`].
+    pub(crate) fn start_draft_generation(
+        &self,
         settings: InferenceSettings,
-        sampler: std::sync::Arc<std::sync::Mutex<dyn llm_samplers::prelude::Sampler>>,
-        out: tokio::sync::mpsc::UnboundedSender<String>,
-    ) -> Result<()> {
+        sampling: SpeculativeSampling,
+        sender: tokio::sync::mpsc::UnboundedSender<String>,
+    ) -> Result<DraftGeneration> {
+        let Some(draft) = &self.draft else {
+            anyhow::bail!(
+                "The model was built without a draft model. Set one with LlamaBuilder::with_draft_model"
+            );
+        };
         let InferenceSettings {
             prompt,
             sample_len,
             stop_on,
         } = settings;
 
-        let mut session = self.new_session()?;
+        let session = self.new_session()?;
+        let mut draft_session = draft.new_session()?;
+        let generation = SpeculativeGeneration::new(
+            self,
+            &SpeculativeDraft::new(&**draft, &mut draft_session),
+            &prompt,
+            Some(sample_len as u32),
+            stop_on.as_deref(),
+            sampling,
+        )?;
+        Ok(DraftGeneration {
+            session,
+            draft_session,
+            generation,
+            sender,
+        })
+    }
 
-        self.stream_text_with_sampler(
-            &mut session,
-            prompt.as_str(),
+    /// Run one round of draft proposals and verification for every speculative generation. Finished and failed generations are removed, which closes their output streams.
+    ///
+    /// Each round decodes a few tokens of one request, so running a round for every request between steps of the batch keeps speculative requests from stalling the batched requests.
+    pub(crate) fn step_drafts(&self, drafts: &mut Vec<DraftGeneration>) {
+        let Some(draft) = &self.draft else {
+            drafts.clear();
+            return;
+        };
+        for mut state in std::mem::take(drafts) {
+            let mut speculative = SpeculativeDraft::new(&**draft, &mut state.draft_session);
+            if let Some(draft_tokens) = self.draft_tokens {
+                speculative = speculative.with_draft_tokens(draft_tokens);
+            }
+            match state.generation.step(
+                self,
+                &mut state.session,
+                &mut speculative,
+                send_token(&state.sender),
+            ) {
+                Ok(true) => drafts.push(state),
+                Ok(false) => {
+                    if let Err(err) = state.generation.finish(send_token(&state.sender)) {
+                        eprintln!("Error: {}", err);
+                    }
+                }
+                Err(err) => eprintln!("Error: {}", err),
+            }
+        }
+    }
+
+    /// Feed the prompt for a new generation request and sample the first token. Returns `None` if the generation finished after the first token.
+    pub(crate) fn start_generation(
+        &self,
+        settings: InferenceSettings,
+        sampler: Arc<Mutex<dyn llm_samplers::prelude::Sampler>>,
+        sender: tokio::sync::mpsc::UnboundedSender<String>,
+    ) -> Result<Option<BatchedGeneration>> {
+        let InferenceSettings {
+            prompt,
+            sample_len,
+            stop_on,
+        } = settings;
+
+        let encoded = self.tokenizer.encode(prompt, false).map_err(E::msg)?;
+        let tokens = encoded.get_ids();
+        let mut generation = TextGeneration::new(
+            self.tokenizer.clone(),
+            tokens,
+            self.stop_token()?,
             Some(sample_len as u32),
             stop_on.as_deref(),
             sampler,
-            |token| {
-                out.send(token)
-                    .map_err(|_| anyhow::anyhow!("Failed to send token to output channel"))
-                    .map(|_| kalosm_language_model::ModelFeedback::Continue)
-            },
         )?;
 
-        Ok(())
+        let mut session = self.new_session()?;
+        let mut logits = Vec::new();
+        self.feed_tokens(&mut session, tokens, &mut logits)?;
+        match generation.next_token(&logits, send_token(&sender))? {
+            Some(next_token) => Ok(Some(BatchedGeneration {
+                session,
+                generation,
+                next_token,
+                sender,
+            })),
+            None => {
+                generation.finish(send_token(&sender))?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Feed the last sampled token of every generation in the batch into the model with one forward pass for each set of LoRA adapters in the batch, and sample the next token for each generation. Finished generations are removed from the batch.
+    ///
+    /// A generation that fails is removed from the batch and its output stream is closed without affecting the other generations. If the batched forward pass fails, every sequence is retried on its own so only the sequences that fail alone are dropped.
+    pub(crate) fn step_batch(&self, batch: &mut Vec<BatchedGeneration>) {
+        let context_length = self.model.config.context_length;
+        let decode_together = batch.len() > 1;
+        let mut logits: Vec<Result<Vec<f32>>> = batch.iter().map(|_| Ok(Vec::new())).collect();
+
+        // Sequences that fill the context window need to be shifted, which only the single sequence forward pass supports
+        let together: Vec<bool> = batch
+            .iter()
+            .map(|generation| {
+                decode_together && generation.session.cache.tokens.len() < context_length
+            })
+            .collect();
+        for (i, generation) in batch.iter_mut().enumerate() {
+            if !together[i] {
+                logits[i] = self.step_alone(generation);
+            }
+        }
+        // Every sequence in a forward pass runs with the same LoRA adapters, so sequences are decoded together in groups that share an adapter set
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        for i in (0..batch.len()).filter(|&i| together[i]) {
+            let adapters = &batch[i].session.cache.lora_adapters;
+            match groups
+                .iter_mut()
+                .find(|group| &batch[group[0]].session.cache.lora_adapters == adapters)
+            {
+                Some(group) => group.push(i),
+                None => groups.push(vec![i]),
+            }
+        }
+        for indices in groups {
+            let tokens: Vec<u32> = indices.iter().map(|&i| batch[i].next_token).collect();
+            let lengths: Vec<usize> = indices
+                .iter()
+                .map(|&i| batch[i].session.cache.tokens.len())
+                .collect();
+            let batch_logits = {
+                let mut caches: Vec<_> = batch
+                    .iter_mut()
+                    .enumerate()
+                    .filter(|(i, _)| indices.contains(i))
+                    .map(|(_, generation)| &mut generation.session.cache)
+                    .collect();
+                self.model
+                    .forward_batch(&tokens, &mut caches, &self.device)
+                    .and_then(|logits| logits.to_dtype(DType::F32)?.to_vec2::<f32>())
+            };
+            match batch_logits {
+                Ok(batch_logits) => {
+                    for (&i, sequence_logits) in indices.iter().zip(batch_logits) {
+                        logits[i] = Ok(sequence_logits);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Error in batched decoding, retrying each sequence: {}", err);
+                    // The failed pass may have written to some of the caches, so they are rolled back before each sequence is retried alone
+                    for (&i, length) in indices.iter().zip(lengths) {
+                        let generation = &mut batch[i];
+                        logits[i] = match generation.session.cache.truncate(length) {
+                            Ok(()) => self.step_alone(generation),
+                            Err(err) => Err(err.into()),
+                        };
+                    }
+                }
+            }
+        }
+
+        for (mut state, logits) in std::mem::take(batch).into_iter().zip(logits) {
+            // Dropping the generation closes its output stream
+            let logits = match logits {
+                Ok(logits) => logits,
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    continue;
+                }
+            };
+            match state
+                .generation
+                .next_token(&logits, send_token(&state.sender))
+            {
+                Ok(Some(next_token)) => {
+                    state.next_token = next_token;
+                    batch.push(state);
+                }
+                Ok(None) => {
+                    if let Err(err) = state.generation.finish(send_token(&state.sender)) {
+                        eprintln!("Error: {}", err);
+                    }
+                }
+                Err(err) => eprintln!("Error: {}", err),
+            }
+        }
+    }
+
+    /// Feed the last sampled token of one generation into the model on its own.
+    fn step_alone(&self, generation: &mut BatchedGeneration) -> Result<Vec<f32>> {
+        let mut logits = Vec::new();
+        self.feed_tokens(
+            &mut generation.session,
+            &[generation.next_token],
+            &mut logits,
+        )?;
+        Ok(logits)
+    }
+}
+
+/// A generation request that is decoded with tokens proposed by the draft model. See [`LlamaModel::step_drafts`].
+pub(crate) struct DraftGeneration {
+    session: LlamaSession,
+    draft_session: LlamaSession,
+    generation: SpeculativeGeneration,
+    sender: tokio::sync::mpsc::UnboundedSender<String>,
+}
+
+/// A generation request that is decoded in a batch with other requests. See [`LlamaModel::step_batch`].
+pub(crate) struct BatchedGeneration {
+    session: LlamaSession,
+    generation: TextGeneration,
+    next_token: u32,
+    sender: tokio::sync::mpsc::UnboundedSender<String>,
+}
+
+fn send_token(
+    sender: &tokio::sync::mpsc::UnboundedSender<String>,
+) -> impl FnMut(String) -> anyhow::Result<ModelFeedback> + '_ {
+    |token| {
+        sender
+            .send(token)
+            .map_err(|_| anyhow::anyhow!("Failed to send token to output channel"))
+            .map(|_| ModelFeedback::Continue)
     }
 }
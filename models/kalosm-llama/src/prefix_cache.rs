@@ -0,0 +1,309 @@
+use crate::raw::cache::LlamaCache;
+
+/// Settings for the prefix cache of a Llama model. See [`LlamaBuilder::with_prefix_cache`](crate::LlamaBuilder::with_prefix_cache).
+///
+/// The prefix cache saves the attention cache of each prompt the model is run on. When a new prompt starts with the same tokens as a cached prompt (like a shared system prompt or few-shot examples), the model copies the cached attention for those tokens instead of recomputing them.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm_llama::prelude::*;
+/// use kalosm_llama::PrefixCacheConfig;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let model = Llama::builder()
+///         .with_source(LlamaSource::llama_3_1_8b_chat())
+///         // Keep up to 2 GiB of cached prompts
+///         .with_prefix_cache(PrefixCacheConfig::default().with_max_memory(2 * 1024 * 1024 * 1024))
+///         .build()
+///         .await
+///         .unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixCacheConfig {
+    max_memory: usize,
+    max_entries: usize,
+}
+
+impl Default for PrefixCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_memory: 1024 * 1024 * 1024,
+            max_entries: 64,
+        }
+    }
+}
+
+impl PrefixCacheConfig {
+    /// Set the maximum number of bytes the cached prompts can take up. (Defaults to 1 GiB)
+    ///
+    /// When the cache is full, the least recently used prompts are evicted. Prompts that are larger than the limit are never cached.
+    pub fn with_max_memory(mut self, max_memory: usize) -> Self {
+        self.max_memory = max_memory;
+        self
+    }
+
+    /// Set the maximum number of prompts to cache. (Defaults to 64)
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+}
+
+/// A cached prompt: the attention cache after feeding the prompt, and the logits for the next token if they are known.
+pub(crate) struct CachedPrefix {
+    pub(crate) cache: LlamaCache,
+    pub(crate) logits: Option<Vec<f32>>,
+}
+
+/// A radix tree of cached values keyed by token sequences with least recently used eviction.
+///
+/// Values are only stored at the leaves of the tree. A value is usable for any prefix of its key, so when a new key extends a cached key, the shorter key is replaced.
+pub(crate) struct PrefixCache<T> {
+    config: PrefixCacheConfig,
+    root: Node<T>,
+    memory: usize,
+    entries: usize,
+    tick: u64,
+}
+
+impl<T> PrefixCache<T> {
+    pub(crate) fn new(config: PrefixCacheConfig) -> Self {
+        Self {
+            config,
+            root: Node::default(),
+            memory: 0,
+            entries: 0,
+            tick: 0,
+        }
+    }
+
+    /// Find the cached value that shares the longest prefix with `tokens`. Returns the length of the shared prefix and the value. The value's key may be longer than the shared prefix.
+    pub(crate) fn get(&mut self, tokens: &[u32]) -> Option<(usize, &T)> {
+        self.tick += 1;
+        let (matched, node) = self.root.find(tokens, 0);
+        if matched == 0 {
+            return None;
+        }
+        let entry = node.first_entry()?;
+        entry.last_used = self.tick;
+        Some((matched, &entry.value))
+    }
+
+    /// Cache a value that takes up `size` bytes for `tokens`, and evict the least recently used values if the cache is over its limits.
+    pub(crate) fn insert(&mut self, tokens: &[u32], value: T, size: usize) {
+        if tokens.is_empty() || size > self.config.max_memory || self.config.max_entries == 0 {
+            return;
+        }
+        self.tick += 1;
+        let entry = Entry {
+            value,
+            size,
+            last_used: self.tick,
+        };
+        let (removed, inserted) = self.root.insert(tokens, entry);
+        if let Some(removed) = removed {
+            self.memory -= removed.size;
+            self.entries -= 1;
+        }
+        if inserted {
+            self.memory += size;
+            self.entries += 1;
+        }
+
+        while self.memory > self.config.max_memory || self.entries > self.config.max_entries {
+            let Some((_, key)) = self.root.least_recently_used() else {
+                break;
+            };
+            if let Some(removed) = self.root.remove(&key) {
+                self.memory -= removed.size;
+                self.entries -= 1;
+            }
+        }
+    }
+
+    /// The number of bytes the cached values take up.
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.memory
+    }
+
+    /// The number of cached values.
+    pub(crate) fn len(&self) -> usize {
+        self.entries
+    }
+}
+
+struct Entry<T> {
+    value: T,
+    size: usize,
+    last_used: u64,
+}
+
+struct Edge<T> {
+    tokens: Vec<u32>,
+    node: Node<T>,
+}
+
+struct Node<T> {
+    children: Vec<Edge<T>>,
+    entry: Option<Entry<T>>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self {
+            children: Vec::new(),
+            entry: None,
+        }
+    }
+}
+
+impl<T> Node<T> {
+    fn child_index(&self, token: u32) -> Option<usize> {
+        self.children
+            .iter()
+            .position(|edge| edge.tokens[0] == token)
+    }
+
+    /// Walk down the tree as far as `tokens` matches. Returns the number of matched tokens and the node whose subtree only contains keys that start with the matched tokens.
+    fn find(&mut self, tokens: &[u32], matched: usize) -> (usize, &mut Self) {
+        let Some(index) = tokens.first().and_then(|token| self.child_index(*token)) else {
+            return (matched, self);
+        };
+        let edge = &mut self.children[index];
+        let common = common_prefix(&edge.tokens, tokens);
+        if common < edge.tokens.len() {
+            return (matched + common, &mut edge.node);
+        }
+        edge.node.find(&tokens[common..], matched + common)
+    }
+
+    fn first_entry(&mut self) -> Option<&mut Entry<T>> {
+        if self.entry.is_some() {
+            return self.entry.as_mut();
+        }
+        self.children
+            .iter_mut()
+            .find_map(|edge| edge.node.first_entry())
+    }
+
+    /// Insert an entry. Returns the entry that was replaced, and whether the new entry was inserted. The new entry is not inserted if a longer key already covers it.
+    fn insert(&mut self, tokens: &[u32], entry: Entry<T>) -> (Option<Entry<T>>, bool) {
+        if tokens.is_empty() {
+            if !self.children.is_empty() {
+                return (None, false);
+            }
+            return (self.entry.replace(entry), true);
+        }
+
+        // Any value at this node is for a prefix of the new key, so the new value replaces it
+        let removed = self.entry.take();
+        let Some(index) = self.child_index(tokens[0]) else {
+            self.children.push(Edge {
+                tokens: tokens.to_vec(),
+                node: Node {
+                    children: Vec::new(),
+                    entry: Some(entry),
+                },
+            });
+            return (removed, true);
+        };
+
+        let edge = &mut self.children[index];
+        let common = common_prefix(&edge.tokens, tokens);
+        if common < edge.tokens.len() {
+            if common == tokens.len() {
+                return (removed, false);
+            }
+            // Split the edge where the keys diverge
+            let suffix = edge.tokens.split_off(common);
+            let child = std::mem::take(&mut edge.node);
+            edge.node.children.push(Edge {
+                tokens: suffix,
+                node: child,
+            });
+        }
+        let (inner_removed, inserted) = edge.node.insert(&tokens[common..], entry);
+        (removed.or(inner_removed), inserted)
+    }
+
+    fn least_recently_used(&self) -> Option<(u64, Vec<u32>)> {
+        let mut oldest = self
+            .entry
+            .as_ref()
+            .map(|entry| (entry.last_used, Vec::new()));
+        for edge in &self.children {
+            if let Some((last_used, suffix)) = edge.node.least_recently_used() {
+                if !matches!(&oldest, Some((oldest, _)) if *oldest <= last_used) {
+                    let mut key = edge.tokens.clone();
+                    key.extend(suffix);
+                    oldest = Some((last_used, key));
+                }
+            }
+        }
+        oldest
+    }
+
+    /// Remove the entry for a key and merge any nodes that are no longer needed.
+    fn remove(&mut self, tokens: &[u32]) -> Option<Entry<T>> {
+        if tokens.is_empty() {
+            return self.entry.take();
+        }
+        let index = self.child_index(tokens[0])?;
+        let edge = &mut self.children[index];
+        let rest = tokens.strip_prefix(edge.tokens.as_slice())?;
+        let removed = edge.node.remove(rest);
+        if edge.node.entry.is_none() {
+            match edge.node.children.len() {
+                0 => {
+                    self.children.swap_remove(index);
+                }
+                1 => {
+                    let child = edge.node.children.pop().unwrap();
+                    edge.tokens.extend(child.tokens);
+                    edge.node = child.node;
+                }
+                _ => {}
+            }
+        }
+        removed
+    }
+}
+
+fn common_prefix(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[test]
+fn prefix_cache_matches_and_evicts() {
+    let mut cache = PrefixCache::new(PrefixCacheConfig::default().with_max_memory(30));
+    cache.insert(&[1, 2, 3, 4], "a", 10);
+    cache.insert(&[1, 2, 5], "b", 10);
+
+    // Shared prefixes match any value below the point the keys diverge
+    assert_eq!(cache.get(&[1, 2, 3, 4, 5]), Some((4, &"a")));
+    assert_eq!(cache.get(&[1, 2, 3, 9]), Some((3, &"a")));
+    assert_eq!(cache.get(&[1, 2, 5]), Some((3, &"b")));
+    assert_eq!(cache.get(&[7]), None);
+
+    // A longer key replaces the keys it extends, and shorter keys are covered by longer ones
+    cache.insert(&[1, 2, 5, 6], "c", 10);
+    cache.insert(&[1, 2], "d", 10);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.memory_usage(), 20);
+    assert_eq!(cache.get(&[1, 2, 5, 6]), Some((4, &"c")));
+
+    // "a" is the least recently used, so it is evicted first
+    cache.insert(&[8, 9], "e", 10);
+    cache.insert(&[8, 10], "f", 10);
+    assert_eq!(cache.len(), 3);
+    assert_eq!(cache.memory_usage(), 30);
+    assert_eq!(cache.get(&[1, 2, 3]), Some((2, &"c")));
+    assert_eq!(cache.get(&[8, 10]), Some((2, &"f")));
+    assert_eq!(cache.get(&[8, 9]), Some((2, &"e")));
+
+    // Values larger than the limit are never cached
+    cache.insert(&[11], "g", 31);
+    assert_eq!(cache.get(&[11]), None);
+}
@@ -1,3 +1,4 @@
+use super::lora::{LayerLora, LoraProjection};
 use super::rope::RopeCache;
 use super::silu::fast_cpu_silu;
 use candle_core::{quantized::QMatMul, Module, Tensor};
@@ -12,10 +13,10 @@ pub enum FeedForwardVariant {
 }
 
 impl FeedForwardVariant {
-    pub(crate) fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+    pub(crate) fn forward(&self, x: &Tensor, lora: &LayerLora) -> candle_core::Result<Tensor> {
         match self {
-            FeedForwardVariant::Llama(ffn) => ffn.forward(x),
-            FeedForwardVariant::Phi(ffn) => ffn.forward(x),
+            FeedForwardVariant::Llama(ffn) => ffn.forward(x, lora),
+            FeedForwardVariant::Phi(ffn) => ffn.forward(x, lora),
         }
     }
 }
@@ -27,8 +28,8 @@ pub struct PhiFeedForward {
 }
 
 impl PhiFeedForward {
-    pub(crate) fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
-        let up_states = x.apply(&self.up)?;
+    pub(crate) fn forward(&self, x: &Tensor, lora: &LayerLora) -> candle_core::Result<Tensor> {
+        let up_states = lora.apply(LoraProjection::Up, x, x.apply(&self.up)?)?;
         let gate = up_states.narrow(D::Minus1, 0, self.feed_forward_length)?;
         let up_states = up_states.narrow(
             D::Minus1,
@@ -37,7 +38,11 @@ impl PhiFeedForward {
         )?;
         let gate = fast_cpu_silu(&gate)?;
         let up_states = (up_states * gate)?;
-        up_states.apply(&self.down)
+        lora.apply(
+            LoraProjection::Down,
+            &up_states,
+            up_states.apply(&self.down)?,
+        )
     }
 }
 
@@ -48,29 +53,32 @@ pub struct LlamaFeedForward {
 }
 
 impl LlamaFeedForward {
-    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+    fn forward(&self, x: &Tensor, lora: &LayerLora) -> candle_core::Result<Tensor> {
         let device = x.device();
         if matches!(device, Device::Cpu) {
             std::thread::scope(|scope| {
                 let w1 = scope.spawn(|| {
-                    let w1 = self.feed_forward_w1.forward(x)?;
+                    let w1 =
+                        lora.apply(LoraProjection::Gate, x, self.feed_forward_w1.forward(x)?)?;
                     fast_cpu_silu(&w1)
                 });
 
-                let w3 = self.feed_forward_w3.forward(x)?;
+                let w3 = lora.apply(LoraProjection::Up, x, self.feed_forward_w3.forward(x)?)?;
                 let w1 = w1
                     .join()
                     .map_err(|_| candle_core::Error::Msg("Failed to join thread".to_string()))??;
 
-                self.feed_forward_w2.forward(&(&w1 * w3)?)
+                let x = (&w1 * w3)?;
+                lora.apply(LoraProjection::Down, &x, self.feed_forward_w2.forward(&x)?)
             })
         } else {
-            let w1 = self.feed_forward_w1.forward(x)?;
+            let w1 = lora.apply(LoraProjection::Gate, x, self.feed_forward_w1.forward(x)?)?;
             let w1 = fast_cpu_silu(&w1)?;
 
-            let w3 = self.feed_forward_w3.forward(x)?;
+            let w3 = lora.apply(LoraProjection::Up, x, self.feed_forward_w3.forward(x)?)?;
 
-            self.feed_forward_w2.forward(&(&w1 * w3)?)
+            let x = (&w1 * w3)?;
+            lora.apply(LoraProjection::Down, &x, self.feed_forward_w2.forward(&x)?)
         }
     }
 }
@@ -102,7 +110,8 @@ impl SeparateAttention {
         num_key_value_heads: usize,
         hidden_states: &Tensor,
         rope_cache: &RopeCache,
-        start_pos: usize,
+        positions: &[usize],
+        lora: &LayerLora,
     ) -> candle_core::Result<(Tensor, Tensor, Tensor)> {
         let b_sz = hidden_states.dims()[0];
         let seq_len = hidden_states.dims()[1];
@@ -111,7 +120,11 @@ impl SeparateAttention {
         if matches!(device, Device::Cpu) {
             std::thread::scope(|s| -> Result<_, candle_core::Error> {
                 let query_states = s.spawn(|| {
-                    let mut query_states = self.attention_wq.forward(hidden_states)?;
+                    let mut query_states = lora.apply(
+                        LoraProjection::Query,
+                        hidden_states,
+                        self.attention_wq.forward(hidden_states)?,
+                    )?;
 
                     if let Some(bias) = &self.bias {
                         query_states = query_states.broadcast_add(&bias.bias_q)?;
@@ -122,7 +135,11 @@ impl SeparateAttention {
                         .transpose(1, 2)
                 });
                 let key_states = s.spawn(|| {
-                    let mut key_states = self.attention_wk.forward(hidden_states)?;
+                    let mut key_states = lora.apply(
+                        LoraProjection::Key,
+                        hidden_states,
+                        self.attention_wk.forward(hidden_states)?,
+                    )?;
 
                     if let Some(bias) = &self.bias {
                         key_states = key_states.broadcast_add(&bias.bias_k)?;
@@ -133,7 +150,11 @@ impl SeparateAttention {
                         .transpose(1, 2)
                 });
                 let value_states = s.spawn(|| {
-                    let mut value_states = self.attention_wv.forward(hidden_states)?;
+                    let mut value_states = lora.apply(
+                        LoraProjection::Value,
+                        hidden_states,
+                        self.attention_wv.forward(hidden_states)?,
+                    )?;
 
                     if let Some(bias) = &self.bias {
                         value_states = value_states.broadcast_add(&bias.bias_v)?;
@@ -152,9 +173,9 @@ impl SeparateAttention {
                 })??;
 
                 let (query_states, key_states) = if self.interleaved_rope {
-                    rope_cache.forward_i(&query_states, &key_states, start_pos)?
+                    rope_cache.forward_i(&query_states, &key_states, positions)?
                 } else {
-                    rope_cache.forward(&query_states, &key_states, start_pos)?
+                    rope_cache.forward(&query_states, &key_states, positions)?
                 };
 
                 let value_states = value_states.join().map_err(|_| {
@@ -165,7 +186,11 @@ impl SeparateAttention {
             })
         } else {
             let query_states = {
-                let mut query_states = self.attention_wq.forward(hidden_states)?;
+                let mut query_states = lora.apply(
+                    LoraProjection::Query,
+                    hidden_states,
+                    self.attention_wq.forward(hidden_states)?,
+                )?;
 
                 if let Some(bias) = &self.bias {
                     query_states = query_states.broadcast_add(&bias.bias_q)?;
@@ -176,7 +201,11 @@ impl SeparateAttention {
                     .transpose(1, 2)?
             };
             let key_states = {
-                let mut key_states = self.attention_wk.forward(hidden_states)?;
+                let mut key_states = lora.apply(
+                    LoraProjection::Key,
+                    hidden_states,
+                    self.attention_wk.forward(hidden_states)?,
+                )?;
 
                 if let Some(bias) = &self.bias {
                     key_states = key_states.broadcast_add(&bias.bias_k)?;
@@ -187,7 +216,11 @@ impl SeparateAttention {
                     .transpose(1, 2)?
             };
             let value_states = {
-                let mut value_states = self.attention_wv.forward(hidden_states)?;
+                let mut value_states = lora.apply(
+                    LoraProjection::Value,
+                    hidden_states,
+                    self.attention_wv.forward(hidden_states)?,
+                )?;
 
                 if let Some(bias) = &self.bias {
                     value_states = value_states.broadcast_add(&bias.bias_v)?;
@@ -199,9 +232,9 @@ impl SeparateAttention {
             };
 
             let (query_states, key_states) = if self.interleaved_rope {
-                rope_cache.forward_i(&query_states, &key_states, start_pos)?
+                rope_cache.forward_i(&query_states, &key_states, positions)?
             } else {
-                rope_cache.forward(&query_states, &key_states, start_pos)?
+                rope_cache.forward(&query_states, &key_states, positions)?
             };
 
             Ok((query_states, key_states, value_states))
@@ -221,11 +254,16 @@ impl GroupedAttention {
         num_key_value_heads: usize,
         x: &Tensor,
         rope_cache: &RopeCache,
-        start_pos: usize,
+        positions: &[usize],
+        lora: &LayerLora,
     ) -> candle_core::Result<(Tensor, Tensor, Tensor)> {
         let b_sz = x.dims()[0];
         let seq_len = x.dims()[1];
-        let qkv = self.attention_qkv.forward(x)?;
+        let qkv = lora.apply(
+            LoraProjection::QueryKeyValue,
+            x,
+            self.attention_qkv.forward(x)?,
+        )?;
 
         let query_pos = num_heads * head_dim;
         let query_states = qkv.narrow(D::Minus1, 0, query_pos)?;
@@ -247,7 +285,7 @@ impl GroupedAttention {
             .transpose(1, 2)?;
 
         let (query_states, key_states) =
-            rope_cache.forward(&query_states, &key_states, start_pos)?;
+            rope_cache.forward(&query_states, &key_states, positions)?;
 
         Ok((query_states, key_states, value_states))
     }
@@ -273,10 +311,65 @@ impl LlamaAttention {
         attention_mask: Option<&AttentionMask>,
         start_pos: usize,
         cache: Option<&mut KvCache>,
+        lora: &LayerLora,
     ) -> candle_core::Result<Tensor> {
-        let bsz = hidden_states.dims()[0];
-        let q_len = hidden_states.dims()[1];
-        let hidden_size = self.hidden_size;
+        let (query_states, key_states, value_states) =
+            self.query_key_value(hidden_states, &[start_pos], lora)?;
+
+        let (key_states, value_states) = match cache {
+            None => (key_states, value_states),
+            Some(cache) => cache.append(&key_states, &value_states)?,
+        };
+
+        self.attend(
+            &query_states,
+            &key_states,
+            &value_states,
+            attention_mask,
+            lora,
+        )
+    }
+
+    /// Run attention for one decoding step of a batch of independent sequences. Each sequence feeds a single token at its own position and appends to its own cache.
+    ///
+    /// The projections run once for the whole batch, but each sequence attends to its own cache. The caches have different lengths, so this avoids padding and copying every cache into one tensor each step.
+    pub(crate) fn forward_batch(
+        &self,
+        hidden_states: &Tensor,
+        positions: &[usize],
+        caches: &mut [&mut KvCache],
+        lora: &LayerLora,
+    ) -> candle_core::Result<Tensor> {
+        let (query_states, key_states, value_states) =
+            self.query_key_value(hidden_states, positions, lora)?;
+
+        let mut outputs = Vec::with_capacity(caches.len());
+        for (i, cache) in caches.iter_mut().enumerate() {
+            let (key, value) =
+                cache.append(&key_states.narrow(0, i, 1)?, &value_states.narrow(0, i, 1)?)?;
+            // A single new token can attend to every token in its own cache, so no mask is needed
+            outputs.push(self.scaled_attention(
+                &query_states.narrow(0, i, 1)?,
+                &key,
+                &value,
+                None,
+            )?);
+        }
+        let attn_output = Tensor::cat(&outputs, 0)?;
+
+        lora.apply(
+            LoraProjection::Output,
+            &attn_output,
+            self.attention_wo.forward(&attn_output)?,
+        )
+    }
+
+    fn query_key_value(
+        &self,
+        hidden_states: &Tensor,
+        positions: &[usize],
+        lora: &LayerLora,
+    ) -> candle_core::Result<(Tensor, Tensor, Tensor)> {
         let num_heads = self.n_head;
         let head_dim = self.head_dim;
         let num_key_value_heads = self.n_kv_head;
@@ -289,7 +382,8 @@ impl LlamaAttention {
                 num_key_value_heads,
                 hidden_states,
                 &self.rope_cache,
-                start_pos,
+                positions,
+                lora,
             )?,
             AttentionVariant::Grouped(ref attention) => attention.forward(
                 num_heads,
@@ -297,17 +391,47 @@ impl LlamaAttention {
                 num_key_value_heads,
                 hidden_states,
                 &self.rope_cache,
-                start_pos,
+                positions,
+                lora,
             )?,
         };
 
-        let key_states = repeat_kv(key_states.clone(), num_key_value_groups)?;
+        let key_states = repeat_kv(key_states, num_key_value_groups)?;
         let value_states = repeat_kv(value_states, num_key_value_groups)?;
 
-        let (key_states, value_states) = match cache {
-            None => (key_states, value_states),
-            Some(cache) => cache.append(&key_states, &value_states)?,
-        };
+        Ok((query_states, key_states, value_states))
+    }
+
+    fn attend(
+        &self,
+        query_states: &Tensor,
+        key_states: &Tensor,
+        value_states: &Tensor,
+        attention_mask: Option<&AttentionMask>,
+        lora: &LayerLora,
+    ) -> candle_core::Result<Tensor> {
+        let attn_output =
+            self.scaled_attention(query_states, key_states, value_states, attention_mask)?;
+
+        lora.apply(
+            LoraProjection::Output,
+            &attn_output,
+            self.attention_wo.forward(&attn_output)?,
+        )
+    }
+
+    /// Run scaled dot product attention and merge the heads into a tensor with the shape `(batch, tokens, hidden)`.
+    fn scaled_attention(
+        &self,
+        query_states: &Tensor,
+        key_states: &Tensor,
+        value_states: &Tensor,
+        attention_mask: Option<&AttentionMask>,
+    ) -> candle_core::Result<Tensor> {
+        let (bsz, _, q_len, _) = query_states.dims4()?;
+        let hidden_size = self.hidden_size;
+        let num_heads = self.n_head;
+        let head_dim = self.head_dim;
 
         let mut attn_weights = (query_states.matmul(&key_states.t()?)? / (head_dim as f64).sqrt())?;
 
@@ -317,7 +441,7 @@ impl LlamaAttention {
 
         attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
 
-        let mut attn_output = attn_weights.matmul(&value_states)?;
+        let mut attn_output = attn_weights.matmul(value_states)?;
 
         if attn_output.dims() != [bsz, num_heads, q_len, head_dim] {
             return Err(candle_core::Error::Msg(format!(
@@ -329,11 +453,7 @@ impl LlamaAttention {
 
         attn_output = attn_output.transpose(1, 2)?;
 
-        attn_output = attn_output.reshape(&[bsz, q_len, hidden_size])?;
-
-        attn_output = self.attention_wo.forward(&attn_output)?;
-
-        Ok(attn_output)
+        attn_output.reshape(&[bsz, q_len, hidden_size])
     }
 }
 
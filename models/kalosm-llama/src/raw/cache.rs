@@ -14,6 +14,8 @@ pub struct LlamaCache {
     max_seq_len: usize,
     pub(crate) tokens: Vec<u32>,
     pub(crate) blocks: Vec<KvCache>,
+    /// The names of the LoRA adapters the cached attention was computed with and the scale of each adapter
+    pub(crate) lora_adapters: Vec<(String, f32)>,
 }
 
 impl LlamaCache {
@@ -28,6 +30,7 @@ impl LlamaCache {
             max_seq_len,
             tokens: Vec::new(),
             blocks,
+            lora_adapters: Vec::new(),
         }
     }
 
@@ -38,6 +41,58 @@ impl LlamaCache {
         }
     }
 
+    /// Remove every token after the first `len` tokens from the cache.
+    pub(crate) fn truncate(&mut self, len: usize) -> candle_core::Result<()> {
+        for block in &mut self.blocks {
+            block.truncate(len)?;
+        }
+        self.tokens.truncate(len);
+        Ok(())
+    }
+
+    /// Copy the first `len` tokens of this cache into a new cache. The copy doesn't share any memory with this cache, and it is allocated with exactly enough space for `len` tokens, so appending to a clone of the copy will always reallocate instead of writing into the shared tensors.
+    pub(crate) fn prefix(&self, len: usize) -> candle_core::Result<Self> {
+        let mut blocks = Vec::with_capacity(self.blocks.len());
+        for block in &self.blocks {
+            let mut cache = KvCache::new(CONCAT_DIMENSION, self.max_seq_len);
+            if len > 0 {
+                if let (Ok(Some(k)), Ok(Some(v))) = (block.cache().k(), block.cache().v()) {
+                    let raw = cache.cache_mut();
+                    *raw = candle_nn::kv_cache::KvCache::new(CONCAT_DIMENSION, len);
+                    raw.append(
+                        &k.narrow(CONCAT_DIMENSION, 0, len)?.contiguous()?,
+                        &v.narrow(CONCAT_DIMENSION, 0, len)?.contiguous()?,
+                    )?;
+                }
+            }
+            blocks.push(cache);
+        }
+        Ok(Self {
+            max_seq_len: self.max_seq_len,
+            tokens: self.tokens[..len].to_vec(),
+            blocks,
+            lora_adapters: self.lora_adapters.clone(),
+        })
+    }
+
+    /// The number of bytes the tensors in this cache take up, including any space allocated for future tokens.
+    pub(crate) fn memory_usage(&self) -> usize {
+        let tensor_size = |cache: &Cache| {
+            cache
+                .all_data()
+                .as_ref()
+                .map(|data| data.elem_count() * data.dtype().size_in_bytes())
+                .unwrap_or_default()
+        };
+        self.blocks
+            .iter()
+            .map(|block| {
+                tensor_size(block.cache().k_cache()) + tensor_size(block.cache().v_cache())
+            })
+            .sum::<usize>()
+            + self.tokens.len() * std::mem::size_of::<u32>()
+    }
+
     /// Get the tensor map for this cache. This can be used to save the cache to disk.
     pub fn get_tensor_map(&self, device: &Device) -> HashMap<String, Tensor> {
         let mut map = HashMap::with_capacity(self.blocks.len());
@@ -55,6 +110,16 @@ impl LlamaCache {
             "llama.cache.max_seq_len".to_string(),
             Tensor::new(self.max_seq_len as u32, device).unwrap(),
         );
+        for (i, (name, scale)) in self.lora_adapters.iter().enumerate() {
+            map.insert(
+                format!("llama.cache.lora_adapters.{}.name", i),
+                Tensor::from_iter(name.bytes(), device).unwrap(),
+            );
+            map.insert(
+                format!("llama.cache.lora_adapters.{}.scale", i),
+                Tensor::new(*scale, device).unwrap(),
+            );
+        }
         map
     }
 
@@ -68,6 +133,21 @@ impl LlamaCache {
             .get("llama.cache.max_seq_len")
             .and_then(|max_seq_len| max_seq_len.to_scalar::<u32>().ok())
             .unwrap_or(2048) as usize;
+        let mut lora_adapters = Vec::new();
+        while let (Some(name), Some(scale)) = (
+            map.get(&format!(
+                "llama.cache.lora_adapters.{}.name",
+                lora_adapters.len()
+            )),
+            map.get(&format!(
+                "llama.cache.lora_adapters.{}.scale",
+                lora_adapters.len()
+            )),
+        ) {
+            let name = String::from_utf8(name.to_vec1()?)
+                .map_err(|err| candle_core::Error::Msg(err.to_string()))?;
+            lora_adapters.push((name, scale.to_scalar::<f32>()?));
+        }
         let mut blocks = Vec::with_capacity(24);
         for (k, v) in map {
             if let Some(i) = k.strip_prefix("llama.cache.blocks.") {
@@ -119,6 +199,26 @@ impl LlamaCache {
             tokens,
             blocks,
             max_seq_len,
+            lora_adapters,
         })
     }
 }
+
+#[test]
+fn tensor_map_keeps_lora_adapters() {
+    let config = LlamaConfig {
+        rope_theta: 10000.,
+        context_length: 16,
+        head_dimension: 4,
+        n_head: 1,
+        n_kv_head: 1,
+        n_layer: 2,
+        interleaved_rope: true,
+    };
+    let mut cache = LlamaCache::new(&config);
+    cache.tokens = vec![1, 2, 3];
+    cache.lora_adapters = vec![("support".to_string(), 0.5), ("ünïcode".to_string(), 1.)];
+    let loaded = LlamaCache::from_tensor_map(cache.get_tensor_map(&Device::Cpu)).unwrap();
+    assert_eq!(loaded.tokens, cache.tokens);
+    assert_eq!(loaded.lora_adapters, cache.lora_adapters);
+}
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, Tensor};
+
+use super::LlamaConfig;
+use crate::LoraSource;
+
+/// A weight of the model that a LoRA adapter can update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum LoraProjection {
+    Query,
+    Key,
+    Value,
+    /// The fused query, key and value projection of phi models
+    QueryKeyValue,
+    Output,
+    Gate,
+    /// The up projection, or the fused gate and up projection of phi models
+    Up,
+    Down,
+}
+
+impl LoraProjection {
+    /// Find the projection for a module name in either GGUF (`attn_q`) or PEFT (`q_proj`) naming.
+    fn from_module_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "attn_q" | "q_proj" => Self::Query,
+            "attn_k" | "k_proj" => Self::Key,
+            "attn_v" | "v_proj" => Self::Value,
+            "attn_qkv" | "qkv_proj" => Self::QueryKeyValue,
+            "attn_output" | "o_proj" => Self::Output,
+            "ffn_gate" | "gate_proj" => Self::Gate,
+            "ffn_up" | "up_proj" | "gate_up_proj" => Self::Up,
+            "ffn_down" | "down_proj" => Self::Down,
+            _ => return None,
+        })
+    }
+}
+
+/// Which of the two low rank matrices a tensor holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LoraMatrix {
+    A,
+    B,
+}
+
+/// Parse the layer, projection and matrix from the name of a LoRA tensor. Supports GGUF names like `blk.0.attn_q.weight.lora_a` and PEFT names like `base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight`.
+fn parse_tensor_name(name: &str) -> Option<(usize, LoraProjection, LoraMatrix)> {
+    let segments: Vec<_> = name.split('.').collect();
+    let layer = segments
+        .windows(2)
+        .find(|window| window[0] == "blk" || window[0] == "layers")?[1]
+        .parse()
+        .ok()?;
+    let projection = segments
+        .iter()
+        .find_map(|segment| LoraProjection::from_module_name(segment))?;
+    let matrix = segments
+        .iter()
+        .find_map(|segment| match segment.to_lowercase().as_str() {
+            "lora_a" => Some(LoraMatrix::A),
+            "lora_b" => Some(LoraMatrix::B),
+            _ => None,
+        })?;
+    Some((layer, projection, matrix))
+}
+
+/// Reorder the rows of a PEFT query or key `lora_B` matrix the same way llama.cpp's `convert_hf_to_gguf.py` permutes the base weights for interleaved rope.
+fn permute_for_interleaved_rope(b: &Tensor, n_head: usize) -> candle_core::Result<Tensor> {
+    let (out, rank) = b.dims2()?;
+    b.reshape((n_head, 2, out / n_head / 2, rank))?
+        .transpose(1, 2)?
+        .reshape((out, rank))
+}
+
+/// Read `lora_alpha` from a PEFT `adapter_config.json` file.
+fn read_peft_alpha(path: &Path) -> anyhow::Result<Option<f32>> {
+    let config: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(config
+        .get("lora_alpha")
+        .and_then(|alpha| alpha.as_f64())
+        .map(|alpha| alpha as f32))
+}
+
+/// The low rank update for a single weight: `x -> x A^T B^T * alpha / rank`.
+pub(crate) struct LoraWeight {
+    /// `A^T` with the shape `(in, rank)`
+    a: Tensor,
+    /// `B^T * alpha / rank` with the shape `(rank, out)`
+    b: Tensor,
+}
+
+impl LoraWeight {
+    fn new(a: Tensor, b: Tensor, alpha: Option<f32>) -> anyhow::Result<Self> {
+        let (rank, _) = a.dims2()?;
+        let (_, b_rank) = b.dims2()?;
+        if rank != b_rank {
+            anyhow::bail!("The LoRA A matrix has rank {rank}, but the B matrix has rank {b_rank}");
+        }
+        let scaling = alpha.map_or(1., |alpha| alpha / rank as f32);
+        Ok(Self {
+            a: a.t()?.contiguous()?,
+            b: b.t()?.affine(scaling as f64, 0.)?.contiguous()?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        x.broadcast_matmul(&self.a)?.broadcast_matmul(&self.b)
+    }
+}
+
+/// The LoRA weights of one adapter for one layer of the model.
+#[derive(Default)]
+pub(crate) struct LoraLayer {
+    weights: HashMap<LoraProjection, LoraWeight>,
+}
+
+/// A LoRA adapter loaded on top of the base weights of the model.
+pub(crate) struct LoraAdapter {
+    pub(crate) name: String,
+    /// The scale new sessions use for this adapter
+    pub(crate) scale: f32,
+    layers: Vec<LoraLayer>,
+}
+
+impl LoraAdapter {
+    /// Load an adapter from a GGUF-LoRA file (if the file has a `gguf` extension) or a PEFT safetensors file. The alpha of a PEFT adapter is read from its `adapter_config.json` if it isn't set in the source.
+    pub(crate) fn load(
+        source: &LoraSource,
+        path: &Path,
+        adapter_config: Option<&Path>,
+        config: &LlamaConfig,
+        device: &Device,
+    ) -> anyhow::Result<Self> {
+        let n_layer = config.n_layer;
+        let extension = path.extension().and_then(|v| v.to_str());
+        let peft = extension != Some("gguf");
+        let (tensors, alpha) = match extension {
+            Some("gguf") => {
+                let mut file = std::fs::File::open(path)?;
+                let content = gguf_file::Content::read(&mut file)?;
+                let alpha = content
+                    .metadata
+                    .get("adapter.lora.alpha")
+                    .and_then(|alpha| alpha.to_f32().ok())
+                    // llama.cpp writes an alpha of 0 when the adapter doesn't set one
+                    .filter(|alpha| *alpha > 0.);
+                let mut tensors = HashMap::with_capacity(content.tensor_infos.len());
+                for name in content.tensor_infos.keys() {
+                    let tensor = content.tensor(&mut file, name, device)?;
+                    tensors.insert(name.clone(), tensor.dequantize(device)?);
+                }
+                (tensors, source.alpha.or(alpha))
+            }
+            _ => {
+                let alpha = match (source.alpha, adapter_config) {
+                    (Some(alpha), _) => alpha,
+                    (None, Some(adapter_config)) => read_peft_alpha(adapter_config)?
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "The adapter_config.json of the LoRA adapter {} doesn't set lora_alpha",
+                                source.name
+                            )
+                        })?,
+                    (None, None) => anyhow::bail!(
+                        "The LoRA adapter {} doesn't have an adapter_config.json next to it. Set the alpha of the adapter with LoraSource::with_alpha",
+                        source.name
+                    ),
+                };
+                (candle_core::safetensors::load(path, device)?, Some(alpha))
+            }
+        };
+
+        let mut matrices = HashMap::new();
+        for (name, tensor) in tensors {
+            let Some((layer, projection, matrix)) = parse_tensor_name(&name) else {
+                tracing::trace!("Skipping unknown LoRA tensor {name}");
+                continue;
+            };
+            if layer >= n_layer {
+                anyhow::bail!(
+                    "The LoRA adapter {} has weights for layer {layer}, but the model only has {n_layer} layers",
+                    source.name
+                );
+            }
+            let entry = matrices.entry((layer, projection)).or_insert((None, None));
+            let tensor = tensor.to_dtype(DType::F32)?;
+            match matrix {
+                LoraMatrix::A => entry.0 = Some(tensor),
+                LoraMatrix::B => entry.1 = Some(tensor),
+            }
+        }
+        if matrices.is_empty() {
+            anyhow::bail!(
+                "The LoRA adapter {} doesn't contain any LoRA weights",
+                source.name
+            );
+        }
+
+        let mut layers: Vec<_> = (0..n_layer).map(|_| LoraLayer::default()).collect();
+        for ((layer, projection), matrices) in matrices {
+            let (Some(a), Some(mut b)) = matrices else {
+                anyhow::bail!(
+                    "The LoRA adapter {} is missing the A or B matrix for {projection:?} in layer {layer}",
+                    source.name
+                );
+            };
+            // GGUF adapters are converted with the same permutation as the base weights, but PEFT adapters use the Hugging Face layout
+            if peft && config.interleaved_rope {
+                match projection {
+                    LoraProjection::Query => b = permute_for_interleaved_rope(&b, config.n_head)?,
+                    LoraProjection::Key => b = permute_for_interleaved_rope(&b, config.n_kv_head)?,
+                    _ => {}
+                }
+            }
+            layers[layer]
+                .weights
+                .insert(projection, LoraWeight::new(a, b, alpha)?);
+        }
+
+        Ok(Self {
+            name: source.name.clone(),
+            scale: source.scale,
+            layers,
+        })
+    }
+}
+
+/// The adapters that are active for one layer in a forward pass with the scale of each adapter.
+pub(crate) struct LayerLora<'a> {
+    layers: Vec<(&'a LoraLayer, f32)>,
+}
+
+impl<'a> LayerLora<'a> {
+    pub(crate) fn new(adapters: &[(&'a LoraAdapter, f32)], layer: usize) -> Self {
+        Self {
+            layers: adapters
+                .iter()
+                .map(|(adapter, scale)| (&adapter.layers[layer], *scale))
+                .collect(),
+        }
+    }
+
+    /// Add the update of every active adapter for a projection to the output of the base weight.
+    pub(crate) fn apply(
+        &self,
+        projection: LoraProjection,
+        x: &Tensor,
+        base: Tensor,
+    ) -> candle_core::Result<Tensor> {
+        let mut output = base;
+        for (layer, scale) in &self.layers {
+            if let Some(weight) = layer.weights.get(&projection) {
+                output = (output + weight.forward(x)?.affine(*scale as f64, 0.)?)?;
+            }
+        }
+        Ok(output)
+    }
+}
+
+#[test]
+fn parses_lora_tensor_names() {
+    assert_eq!(
+        parse_tensor_name("blk.3.attn_q.weight.lora_a"),
+        Some((3, LoraProjection::Query, LoraMatrix::A))
+    );
+    assert_eq!(
+        parse_tensor_name("blk.0.ffn_down.weight.lora_b"),
+        Some((0, LoraProjection::Down, LoraMatrix::B))
+    );
+    assert_eq!(
+        parse_tensor_name("base_model.model.model.layers.12.self_attn.o_proj.lora_B.weight"),
+        Some((12, LoraProjection::Output, LoraMatrix::B))
+    );
+    assert_eq!(
+        parse_tensor_name("base_model.model.model.layers.1.mlp.gate_up_proj.lora_A.weight"),
+        Some((1, LoraProjection::Up, LoraMatrix::A))
+    );
+    assert_eq!(parse_tensor_name("blk.0.attn_q.weight"), None);
+}
+
+#[test]
+fn lora_update_matches_merged_weight() {
+    let device = Device::Cpu;
+    let (input, output, rank, alpha, scale) = (6, 5, 2, 4., 0.5);
+    let w = Tensor::randn(0f32, 1., (output, input), &device).unwrap();
+    let a = Tensor::randn(0f32, 1., (rank, input), &device).unwrap();
+    let b = Tensor::randn(0f32, 1., (output, rank), &device).unwrap();
+    let x = Tensor::randn(0f32, 1., (1, 3, input), &device).unwrap();
+
+    let weight = LoraWeight::new(a.clone(), b.clone(), Some(alpha)).unwrap();
+    let layer = LoraLayer {
+        weights: HashMap::from([(LoraProjection::Value, weight)]),
+    };
+    let lora = LayerLora {
+        layers: vec![(&layer, scale)],
+    };
+    let base = x.broadcast_matmul(&w.t().unwrap()).unwrap();
+    let output = lora.apply(LoraProjection::Value, &x, base).unwrap();
+
+    // W + scale * (alpha / rank) * B A
+    let merged = (&w
+        + b.matmul(&a)
+            .unwrap()
+            .affine((scale * alpha / rank as f32) as f64, 0.)
+            .unwrap())
+    .unwrap();
+    let expected = x.broadcast_matmul(&merged.t().unwrap()).unwrap();
+    let difference = (output - expected)
+        .unwrap()
+        .abs()
+        .unwrap()
+        .max_all()
+        .unwrap()
+        .to_scalar::<f32>()
+        .unwrap();
+    assert!(difference < 1e-4, "{difference}");
+}
+
+#[test]
+fn peft_adapters_match_the_gguf_layout() {
+    let device = Device::Cpu;
+    let directory = std::env::temp_dir().join(format!("kalosm-lora-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("adapter_model.safetensors");
+    let adapter_config = directory.join("adapter_config.json");
+
+    // One head with a head dimension of 4 and a rank of 1
+    let a = Tensor::ones((1, 4), DType::F32, &device).unwrap();
+    let b = Tensor::new(&[[0f32], [1.], [2.], [3.]], &device).unwrap();
+    let prefix = "base_model.model.model.layers.0.self_attn";
+    candle_core::safetensors::save(
+        &HashMap::from([
+            (format!("{prefix}.q_proj.lora_A.weight"), a.clone()),
+            (format!("{prefix}.q_proj.lora_B.weight"), b.clone()),
+            (format!("{prefix}.v_proj.lora_A.weight"), a),
+            (format!("{prefix}.v_proj.lora_B.weight"), b),
+        ]),
+        &path,
+    )
+    .unwrap();
+    std::fs::write(&adapter_config, r#"{"r": 1, "lora_alpha": 2}"#).unwrap();
+
+    let config = LlamaConfig {
+        rope_theta: 10000.,
+        context_length: 16,
+        head_dimension: 4,
+        n_head: 1,
+        n_kv_head: 1,
+        n_layer: 1,
+        interleaved_rope: true,
+    };
+    let source = LoraSource::new("test", kalosm_common::FileSource::Local(path.clone()));
+    let adapter =
+        LoraAdapter::load(&source, &path, Some(&adapter_config), &config, &device).unwrap();
+    let b = |projection| {
+        adapter.layers[0].weights[&projection]
+            .b
+            .flatten_all()
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap()
+    };
+    // The query rows are reordered like convert_hf_to_gguf.py and scaled by alpha / rank
+    assert_eq!(b(LoraProjection::Query), [0., 4., 2., 6.]);
+    assert_eq!(b(LoraProjection::Value), [0., 2., 4., 6.]);
+
+    // Without an adapter_config.json the alpha must be set explicitly
+    assert!(LoraAdapter::load(&source, &path, None, &config, &device).is_err());
+    let adapter = LoraAdapter::load(&source.with_alpha(1.), &path, None, &config, &device).unwrap();
+    assert_eq!(
+        adapter.layers[0].weights[&LoraProjection::Value]
+            .b
+            .flatten_all()
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap(),
+        [0., 1., 2., 3.]
+    );
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
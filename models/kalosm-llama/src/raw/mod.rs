@@ -17,10 +17,12 @@ use kalosm_common::MaskCache;
 
 mod attention_layer;
 pub mod cache;
+pub(crate) mod lora;
 mod rope;
 mod silu;
 
 use cache::LlamaCache;
+use lora::{LayerLora, LoraAdapter};
 
 fn decode_norm(tensor: QTensor, eps: f64) -> candle_core::Result<RmsNorm> {
     RmsNorm::from_qtensor(tensor, eps)
@@ -31,8 +33,11 @@ pub struct LlamaConfig {
     rope_theta: f32,
     pub(crate) context_length: usize,
     head_dimension: usize,
-    n_head: usize,
+    pub(crate) n_head: usize,
+    pub(crate) n_kv_head: usize,
     pub(crate) n_layer: usize,
+    /// If the query and key weights are permuted for interleaved rope like llama.cpp does for llama models
+    pub(crate) interleaved_rope: bool,
 }
 
 impl LlamaConfig {
@@ -48,6 +53,7 @@ pub struct Model {
     norm: RmsNorm,
     output: QMatMul,
     masks: MaskCache,
+    lora_adapters: Vec<LoraAdapter>,
 }
 
 impl Model {
@@ -62,8 +68,10 @@ impl Model {
             rope_theta: 10000.,
             head_dimension: head_dim,
             n_head: ct.hparams.n_head as usize,
+            n_kv_head: ct.hparams.n_head as usize / gqa,
             n_layer,
             context_length: 4096,
+            interleaved_rope: true,
         };
         let rope = RopeCache::new(&config, DType::F32, device)?;
         let tok_embeddings = ct.remove("tok_embeddings.weight")?;
@@ -85,7 +93,7 @@ impl Model {
                 attention_wq: QMatMul::from_qtensor(attention_wq)?,
                 attention_wk: QMatMul::from_qtensor(attention_wk)?,
                 attention_wv: QMatMul::from_qtensor(attention_wv)?,
-                interleaved_rope: true,
+                interleaved_rope: config.interleaved_rope,
                 bias: None,
             });
             let feed_forward_variant = FeedForwardVariant::Llama(LlamaFeedForward {
@@ -100,7 +108,7 @@ impl Model {
                 feed_forward_variant,
                 ffn_norm: decode_norm(ffn_norm, 1e-5)?,
                 n_head: ct.hparams.n_head as usize,
-                n_kv_head: ct.hparams.n_head as usize / gqa,
+                n_kv_head: config.n_kv_head,
                 head_dim: (ct.hparams.n_embd / ct.hparams.n_head) as usize,
                 hidden_size: config.hidden_size(),
                 rope_cache: rope.clone(),
@@ -113,6 +121,7 @@ impl Model {
             norm: decode_norm(ct.remove("norm.weight")?, 1e-5)?,
             output: QMatMul::from_qtensor(output)?,
             masks: Default::default(),
+            lora_adapters: Vec::new(),
         })
     }
 
@@ -149,13 +158,16 @@ impl Model {
 
         let context_length = md_get(".context_length")?.to_u32()? as usize;
         let head_dim = embedding_length / head_count;
+        let architecture = md_get("general.architecture")?.to_string()?;
 
         let config = LlamaConfig {
             rope_theta: rope_freq_base,
             context_length,
             head_dimension: head_dim,
             n_head: head_count,
+            n_kv_head: head_count_kv,
             n_layer: block_count,
+            interleaved_rope: architecture != "qwen2",
         };
 
         let rope = RopeCache::new(&config, DType::F32, device)?;
@@ -191,12 +203,11 @@ impl Model {
                     } else {
                         None
                     };
-                    let architecture = ct.metadata["general.architecture"].to_string().unwrap();
                     let separate = SeparateAttention {
                         attention_wq: QMatMul::from_qtensor(q)?,
                         attention_wk: QMatMul::from_qtensor(k)?,
                         attention_wv: QMatMul::from_qtensor(v)?,
-                        interleaved_rope: architecture != "qwen2",
+                        interleaved_rope: config.interleaved_rope,
                         bias,
                     };
                     AttentionVariant::Separate(separate)
@@ -253,10 +264,70 @@ impl Model {
             norm,
             output: QMatMul::from_qtensor(output)?,
             masks: Default::default(),
+            lora_adapters: Vec::new(),
         })
     }
 
+    /// Add a LoRA adapter to the model. The adapter is only applied to sessions that select it.
+    pub(crate) fn add_lora_adapter(&mut self, adapter: LoraAdapter) -> anyhow::Result<()> {
+        if self.lora_adapters.iter().any(|a| a.name == adapter.name) {
+            anyhow::bail!("A LoRA adapter named {} is already loaded", adapter.name);
+        }
+        self.lora_adapters.push(adapter);
+        Ok(())
+    }
+
+    /// The names and default scales of the loaded LoRA adapters.
+    pub(crate) fn default_lora_adapters(&self) -> Vec<(String, f32)> {
+        self.lora_adapters
+            .iter()
+            .map(|adapter| (adapter.name.clone(), adapter.scale))
+            .collect()
+    }
+
+    /// Find the loaded adapters for the adapter names a session selected.
+    fn active_lora_adapters(&self, selected: &[(String, f32)]) -> Result<Vec<(&LoraAdapter, f32)>> {
+        selected
+            .iter()
+            .map(|(name, scale)| {
+                match self
+                    .lora_adapters
+                    .iter()
+                    .find(|adapter| &adapter.name == name)
+                {
+                    Some(adapter) => Ok((adapter, *scale)),
+                    None => candle_core::bail!("No LoRA adapter named {name} is loaded"),
+                }
+            })
+            .collect()
+    }
+
     pub fn forward(
+        &self,
+        tokens: &[u32],
+        device: &Device,
+        cache: Option<&mut LlamaCache>,
+    ) -> Result<Tensor> {
+        let x = self.hidden_states(tokens, device, cache)?;
+        let x = x.i((.., tokens.len() - 1, ..))?;
+        self.output.forward(&x)
+    }
+
+    /// Run the model and return the logits after every token in `tokens` with the shape `(tokens, vocab)`.
+    pub fn forward_all(
+        &self,
+        tokens: &[u32],
+        device: &Device,
+        cache: Option<&mut LlamaCache>,
+    ) -> Result<Tensor> {
+        let x = self.hidden_states(tokens, device, cache)?;
+        let len = x.dim(1)?;
+        let x = x.narrow(1, len - tokens.len(), tokens.len())?.squeeze(0)?;
+        self.output.forward(&x)
+    }
+
+    /// Run every layer of the model and return the normalized hidden states for the tokens that were processed.
+    fn hidden_states(
         &self,
         tokens: &[u32],
         device: &Device,
@@ -289,9 +360,16 @@ impl Model {
             (Tensor::new(tokens, device)?.unsqueeze(0)?, index_pos)
         };
         let mask = self.masks.get_mask(seq_len, index_pos, device)?;
+        let lora_adapters = self.active_lora_adapters(
+            cache
+                .as_ref()
+                .map(|c| c.lora_adapters.as_slice())
+                .unwrap_or_default(),
+        )?;
 
         let mut layer_in = self.tok_embeddings.forward(&x)?;
         for (i, layer) in self.layers.iter().enumerate() {
+            let lora = LayerLora::new(&lora_adapters, i);
             let x = layer_in;
             let residual = &x;
             let x = layer.attention_norm.forward(&x)?;
@@ -300,6 +378,7 @@ impl Model {
                 Some(&mask),
                 index_pos,
                 cache.as_mut().map(|c| &mut c.blocks[i]),
+                &lora,
             )?;
             let x = (attn + residual)?;
 
@@ -307,10 +386,172 @@ impl Model {
             let residual = &x;
             let x = layer.ffn_norm.forward(&x)?;
 
-            layer_in = (&layer.feed_forward_variant.forward(&x)? + residual)?;
+            layer_in = (&layer.feed_forward_variant.forward(&x, &lora)? + residual)?;
+        }
+        self.norm.forward(&layer_in)
+    }
+
+    /// Run one decoding step for a batch of independent sequences. Each sequence feeds a single token into its own cache, and every cache must have room for one more token. Returns the logits for each sequence with the shape `(batch, vocab)`.
+    ///
+    /// The projections and feed forward layers run on the whole batch, but attention runs once per sequence against its own cache. A single attention call would need every cache padded to the longest sequence with a per-sequence mask, and [`MaskCache`] only builds causal masks for one sequence without padding. Padding would also copy every cache on every step, while a decoding step only attends from one token, so the sequences need no mask at all.
+    pub fn forward_batch(
+        &self,
+        tokens: &[u32],
+        caches: &mut [&mut LlamaCache],
+        device: &Device,
+    ) -> Result<Tensor> {
+        if tokens.len() != caches.len() {
+            candle_core::bail!(
+                "Expected one token per cache, but got {} tokens for {} caches",
+                tokens.len(),
+                caches.len()
+            );
+        }
+        let positions: Vec<usize> = caches.iter().map(|cache| cache.tokens.len()).collect();
+        if positions
+            .iter()
+            .any(|position| position + 1 > self.config.context_length)
+        {
+            candle_core::bail!("Batched sequences cannot be longer than the context length");
+        }
+        if caches
+            .iter()
+            .any(|cache| cache.lora_adapters != caches[0].lora_adapters)
+        {
+            candle_core::bail!("Batched sequences must use the same LoRA adapters");
+        }
+        for (cache, token) in caches.iter_mut().zip(tokens) {
+            cache.tokens.push(*token);
+        }
+        let lora_adapters = self.active_lora_adapters(
+            caches
+                .first()
+                .map(|cache| cache.lora_adapters.as_slice())
+                .unwrap_or_default(),
+        )?;
+
+        let x = Tensor::new(tokens, device)?.unsqueeze(1)?;
+        let mut layer_in = self.tok_embeddings.forward(&x)?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            let lora = LayerLora::new(&lora_adapters, i);
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let mut blocks: Vec<_> = caches
+                .iter_mut()
+                .map(|cache| &mut cache.blocks[i])
+                .collect();
+            let attn = layer.forward_batch(&x, &positions, &mut blocks, &lora)?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+
+            layer_in = (&layer.feed_forward_variant.forward(&x, &lora)? + residual)?;
         }
         let x = self.norm.forward(&layer_in)?;
-        let x = x.i((.., seq_len - 1, ..))?;
+        let x = x.i((.., 0, ..))?;
         self.output.forward(&x)
     }
 }
+
+#[cfg(test)]
+impl Model {
+    /// A tiny model with random weights for tests.
+    pub(crate) fn random(device: &Device) -> Result<Self> {
+        let (vocab, hidden, n_head, n_kv_head, feed_forward, n_layer) = (32, 16, 4, 2, 24, 2);
+        let head_dim = hidden / n_head;
+        let config = LlamaConfig {
+            rope_theta: 10000.,
+            context_length: 64,
+            head_dimension: head_dim,
+            n_head,
+            n_kv_head,
+            n_layer,
+            interleaved_rope: true,
+        };
+        let rope = RopeCache::new(&config, DType::F32, device)?;
+        let weight = |rows: usize, columns: usize| {
+            let weight = Tensor::randn(0f32, 0.5, (rows, columns), device)?;
+            QMatMul::from_qtensor(QTensor::quantize(&weight, GgmlDType::F32)?)
+        };
+        let norm = || {
+            let weight = Tensor::ones(hidden, DType::F32, device)?;
+            decode_norm(QTensor::quantize(&weight, GgmlDType::F32)?, 1e-5)
+        };
+        let mut layers = Vec::with_capacity(n_layer);
+        for _ in 0..n_layer {
+            layers.push(LlamaAttention {
+                attention_variant: AttentionVariant::Separate(SeparateAttention {
+                    attention_wq: weight(hidden, hidden)?,
+                    attention_wk: weight(n_kv_head * head_dim, hidden)?,
+                    attention_wv: weight(n_kv_head * head_dim, hidden)?,
+                    interleaved_rope: config.interleaved_rope,
+                    bias: None,
+                }),
+                attention_wo: weight(hidden, hidden)?,
+                attention_norm: norm()?,
+                feed_forward_variant: FeedForwardVariant::Llama(LlamaFeedForward {
+                    feed_forward_w1: weight(feed_forward, hidden)?,
+                    feed_forward_w2: weight(hidden, feed_forward)?,
+                    feed_forward_w3: weight(feed_forward, hidden)?,
+                }),
+                ffn_norm: norm()?,
+                n_head,
+                n_kv_head,
+                head_dim,
+                hidden_size: config.hidden_size(),
+                rope_cache: rope.clone(),
+            });
+        }
+        Ok(Self {
+            config,
+            tok_embeddings: Embedding::new(
+                Tensor::randn(0f32, 1., (vocab, hidden), device)?,
+                hidden,
+            ),
+            layers,
+            norm: norm()?,
+            output: weight(vocab, hidden)?,
+            masks: Default::default(),
+            lora_adapters: Vec::new(),
+        })
+    }
+}
+
+#[test]
+fn batched_decoding_matches_single_sequences() {
+    let device = Device::Cpu;
+    let model = Model::random(&device).unwrap();
+    let prompts: [&[u32]; 3] = [&[1, 2, 3, 4, 5, 6, 7], &[8], &[9, 10, 11]];
+    let next_tokens = [12, 13, 14];
+
+    let mut caches = Vec::new();
+    let mut expected = Vec::new();
+    for (prompt, token) in prompts.iter().zip(next_tokens) {
+        let mut cache = LlamaCache::new(&model.config);
+        model.forward(prompt, &device, Some(&mut cache)).unwrap();
+        // Decode the next token alone in a copy of the cache that doesn't share memory with the original
+        let mut single = cache.prefix(prompt.len()).unwrap();
+        let logits = model.forward(&[token], &device, Some(&mut single)).unwrap();
+        expected.push(logits.squeeze(0).unwrap().to_vec1::<f32>().unwrap());
+        caches.push(cache);
+    }
+
+    let mut cache_refs: Vec<_> = caches.iter_mut().collect();
+    let batched = model
+        .forward_batch(&next_tokens, &mut cache_refs, &device)
+        .unwrap()
+        .to_vec2::<f32>()
+        .unwrap();
+    for (batched, expected) in batched.iter().zip(&expected) {
+        assert!(batched
+            .iter()
+            .zip(expected)
+            .all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+    for (cache, prompt) in caches.iter().zip(prompts) {
+        assert_eq!(cache.tokens.len(), prompt.len() + 1);
+    }
+}
@@ -35,24 +35,37 @@ impl RopeCache {
         Ok(Self { sin, cos })
     }
 
+    /// Apply the rotary embedding to the queries and keys. `positions` holds the position of the first token of each sequence in the batch, or a single position shared by the whole batch.
     fn forward_with_embed(
         &self,
         q: &Tensor,
         k: &Tensor,
-        start_pos: usize,
+        positions: &[usize],
         apply_rotary_emb: fn(&Tensor, &Tensor, &Tensor) -> candle_core::Result<Tensor>,
     ) -> candle_core::Result<(Tensor, Tensor)> {
-        let apply_rotary_emb = |sin: &Tensor, cos: &Tensor, x: &Tensor, index_pos| {
+        let apply_rotary_emb_at = |x: &Tensor, index_pos| {
             let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
-            let cos = cos.narrow(0, index_pos, seq_len)?;
-            let sin = sin.narrow(0, index_pos, seq_len)?;
+            let cos = self.cos.narrow(0, index_pos, seq_len)?;
+            let sin = self.sin.narrow(0, index_pos, seq_len)?;
             apply_rotary_emb(&x.contiguous()?, &cos, &sin)
         };
+        let apply_rotary_emb = |x: &Tensor| match positions {
+            [index_pos] => apply_rotary_emb_at(x, *index_pos),
+            // Each sequence in the batch starts at a different position
+            _ => {
+                let sequences = positions
+                    .iter()
+                    .enumerate()
+                    .map(|(i, index_pos)| apply_rotary_emb_at(&x.narrow(0, i, 1)?, *index_pos))
+                    .collect::<candle_core::Result<Vec<_>>>()?;
+                Tensor::cat(&sequences, 0)
+            }
+        };
         let device = q.device();
         let (q, k) = if matches!(device, Device::Cpu) {
             std::thread::scope(|s| {
-                let q = s.spawn(|| apply_rotary_emb(&self.sin, &self.cos, q, start_pos));
-                let k = apply_rotary_emb(&self.sin, &self.cos, k, start_pos)?;
+                let q = s.spawn(|| apply_rotary_emb(q));
+                let k = apply_rotary_emb(k)?;
                 candle_core::Result::Ok((
                     q.join()
                         .map_err(|e| candle_core::Error::Msg(format!("Error in q: {:?}", e)))??,
@@ -60,8 +73,8 @@ impl RopeCache {
                 ))
             })?
         } else {
-            let q = apply_rotary_emb(&self.sin, &self.cos, q, start_pos)?;
-            let k = apply_rotary_emb(&self.sin, &self.cos, k, start_pos)?;
+            let q = apply_rotary_emb(q)?;
+            let k = apply_rotary_emb(k)?;
             (q, k)
         };
 
@@ -72,18 +85,18 @@ impl RopeCache {
         &self,
         q: &Tensor,
         k: &Tensor,
-        start_pos: usize,
+        positions: &[usize],
     ) -> candle_core::Result<(Tensor, Tensor)> {
-        self.forward_with_embed(q, k, start_pos, candle_nn::rotary_emb::rope)
+        self.forward_with_embed(q, k, positions, candle_nn::rotary_emb::rope)
     }
 
     pub fn forward_i(
         &self,
         q: &Tensor,
         k: &Tensor,
-        start_pos: usize,
+        positions: &[usize],
     ) -> candle_core::Result<(Tensor, Tensor)> {
-        self.forward_with_embed(q, k, start_pos, candle_nn::rotary_emb::rope_i)
+        self.forward_with_embed(q, k, positions, candle_nn::rotary_emb::rope_i)
     }
 }
 
@@ -94,7 +107,9 @@ fn test_rope_cache() {
         context_length: 6,
         head_dimension: 2,
         n_head: 0,
+        n_kv_head: 0,
         n_layer: 0,
+        interleaved_rope: true,
     };
     let device = Device::cuda_if_available(0).unwrap();
     let cache = RopeCache::new(&config, DType::F32, &device).unwrap();
@@ -143,3 +158,46 @@ fn test_rope_cache() {
         .unwrap();
     assert!(sin_error < 1e-2);
 }
+
+#[test]
+fn test_batched_rope() {
+    let config = LlamaConfig {
+        rope_theta: 5000.,
+        context_length: 6,
+        head_dimension: 4,
+        n_head: 0,
+        n_kv_head: 0,
+        n_layer: 0,
+        interleaved_rope: true,
+    };
+    let device = Device::Cpu;
+    let cache = RopeCache::new(&config, DType::F32, &device).unwrap();
+
+    let q = Tensor::arange(0f32, 16., &device)
+        .unwrap()
+        .reshape((2, 1, 2, 4))
+        .unwrap();
+    let k = (&q * 2.).unwrap();
+    let (batch_q, batch_k) = cache.forward(&q, &k, &[1, 3]).unwrap();
+    for (i, position) in [1, 3].into_iter().enumerate() {
+        let (single_q, single_k) = cache
+            .forward(
+                &q.narrow(0, i, 1).unwrap(),
+                &k.narrow(0, i, 1).unwrap(),
+                &[position],
+            )
+            .unwrap();
+        for (batch, single) in [(&batch_q, single_q), (&batch_k, single_k)] {
+            let batch = batch.narrow(0, i, 1).unwrap();
+            let error: f32 = (batch - single)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .sum_all()
+                .unwrap()
+                .to_scalar()
+                .unwrap();
+            assert!(error < 1e-5);
+        }
+    }
+}
@@ -37,6 +37,14 @@ impl Session for LlamaSession {
     {
         Ok(self.clone())
     }
+
+    fn rollback(&mut self, tokens: usize) -> anyhow::Result<()> {
+        let len = self.cache.tokens.len();
+        if tokens > len {
+            anyhow::bail!("Cannot roll back {tokens} tokens from a session with {len} tokens");
+        }
+        Ok(self.cache.truncate(len - tokens)?)
+    }
 }
 
 impl LlamaSession {
@@ -51,6 +59,29 @@ impl LlamaSession {
         Ok(())
     }
 
+    /// The LoRA adapters this session runs with and the scale of each adapter. New sessions use every adapter the model was built with at the scale set in its [`LoraSource`](crate::LoraSource).
+    pub fn lora_adapters(&self) -> &[(String, f32)] {
+        &self.cache.lora_adapters
+    }
+
+    /// Choose the LoRA adapters this session runs with by name, and the scale of each adapter. The adapters must be loaded with [`LlamaBuilder::with_lora_adapter`](crate::LlamaBuilder::with_lora_adapter). Swapping adapters doesn't reload the weights of the model.
+    ///
+    /// The attention cached in the session was computed with the previous adapters, so the session is cleared if the adapters change.
+    pub fn set_lora_adapters<S: Into<String>>(
+        &mut self,
+        adapters: impl IntoIterator<Item = (S, f32)>,
+    ) -> candle_core::Result<()> {
+        let adapters: Vec<(String, f32)> = adapters
+            .into_iter()
+            .map(|(name, scale)| (name.into(), scale))
+            .collect();
+        if adapters != self.cache.lora_adapters {
+            self.cache.truncate(0)?;
+            self.cache.lora_adapters = adapters;
+        }
+        Ok(())
+    }
+
     /// Create a cache from a tensor map. This can be used to load a cache from disk.
     pub fn from_tensor_map(map: HashMap<String, Tensor>) -> candle_core::Result<Self> {
         Ok(Self {
@@ -833,6 +833,107 @@ impl LlamaSource {
     }
 }
 
+/// A source for a LoRA adapter that is loaded on top of the base weights of a Llama model. See [`LlamaBuilder::with_lora_adapter`](crate::LlamaBuilder::with_lora_adapter).
+///
+/// Adapters can be GGUF-LoRA files (with a `gguf` extension) like the files llama.cpp's `convert_lora_to_gguf.py` creates, or PEFT safetensors files.
+///
+/// # Example
+/// ```rust, no_run
+/// use kalosm_llama::prelude::*;
+/// use kalosm_llama::{FileSource, LoraSource};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let model = Llama::builder()
+///         .with_source(LlamaSource::llama_3_1_8b_chat())
+///         .with_lora_adapter(
+///             LoraSource::new(
+///                 "support",
+///                 FileSource::local("./adapters/support/adapter_model.safetensors".into()),
+///             )
+///             // Overrides the lora_alpha in the adapter_config.json next to the weights
+///             .with_alpha(32.)
+///             .with_scale(0.8),
+///         )
+///         .build()
+///         .await
+///         .unwrap();
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct LoraSource {
+    pub(crate) name: String,
+    pub(crate) adapter: FileSource,
+    pub(crate) scale: f32,
+    pub(crate) alpha: Option<f32>,
+}
+
+impl LoraSource {
+    /// Create a new source for a LoRA adapter. The name identifies the adapter when choosing the adapters for a session with [`LlamaSession::set_lora_adapters`](crate::LlamaSession::set_lora_adapters).
+    pub fn new(name: impl Into<String>, adapter: FileSource) -> Self {
+        Self {
+            name: name.into(),
+            adapter,
+            scale: 1.,
+            alpha: None,
+        }
+    }
+
+    /// Set the scale the adapter is applied with in new sessions. (Defaults to 1.0)
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Set the LoRA alpha of the adapter. The update of the adapter is scaled by `alpha / rank`. (Defaults to the alpha in the GGUF metadata or the `lora_alpha` in the `adapter_config.json` next to a PEFT safetensors adapter)
+    pub fn with_alpha(mut self, alpha: f32) -> Self {
+        self.alpha = Some(alpha);
+        self
+    }
+
+    pub(crate) async fn adapter(
+        &self,
+        cache: &kalosm_common::Cache,
+        progress: impl FnMut(f32),
+    ) -> anyhow::Result<std::path::PathBuf> {
+        cache.get(&self.adapter, progress).await
+    }
+
+    /// Download the PEFT `adapter_config.json` next to a safetensors adapter if the alpha isn't set. GGUF adapters store the alpha in their metadata instead.
+    pub(crate) async fn adapter_config(
+        &self,
+        cache: &kalosm_common::Cache,
+        progress: impl FnMut(f32),
+    ) -> Option<std::path::PathBuf> {
+        if self.alpha.is_some() {
+            return None;
+        }
+        let config = match &self.adapter {
+            FileSource::HuggingFace {
+                model_id,
+                revision,
+                file,
+            } => {
+                if file.ends_with(".gguf") {
+                    return None;
+                }
+                let file = match file.rsplit_once('/') {
+                    Some((directory, _)) => format!("{directory}/adapter_config.json"),
+                    None => "adapter_config.json".to_string(),
+                };
+                FileSource::huggingface(model_id.clone(), revision.clone(), file)
+            }
+            FileSource::Local(path) => {
+                let config = path.with_file_name("adapter_config.json");
+                let gguf = path.extension().and_then(|v| v.to_str()) == Some("gguf");
+                return (!gguf && config.exists()).then_some(config);
+            }
+        };
+        // Adapters without a config need the alpha set with LoraSource::with_alpha
+        cache.get(&config, progress).await.ok()
+    }
+}
+
 impl Default for LlamaSource {
     fn default() -> Self {
         Self::llama_13b()
@@ -0,0 +1,198 @@
+use std::sync::{Arc, RwLock};
+
+use candle_core::{IndexOp, Tensor};
+use candle_nn::{Linear, Module, VarBuilder};
+use kalosm_common::*;
+use tokenizers::{Encoding, PaddingParams, Tokenizer, TruncationParams, TruncationStrategy};
+
+use crate::raw::DTYPE;
+use crate::{BertFiles, BertModel, BertSource, Config};
+
+/// The maximum number of query and passage pairs scored in one forward pass
+const MAX_BATCH_SIZE: usize = 32;
+
+/// A builder for a [`BertCrossEncoder`]
+pub struct BertCrossEncoderBuilder {
+    source: BertSource,
+    cache: kalosm_common::Cache,
+}
+
+impl Default for BertCrossEncoderBuilder {
+    fn default() -> Self {
+        Self {
+            source: BertSource::ms_marco_mini_lm_l6_v2_cross_encoder(),
+            cache: Default::default(),
+        }
+    }
+}
+
+impl BertCrossEncoderBuilder {
+    /// Set the source of the model. The model must be a bert model with a sequence classification head.
+    pub fn with_source(mut self, source: BertSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Set the cache location to use for the model (defaults DATA_DIR/kalosm/cache)
+    pub fn with_cache(mut self, cache: kalosm_common::Cache) -> Self {
+        self.cache = cache;
+
+        self
+    }
+
+    /// Build the model
+    pub async fn build(self) -> anyhow::Result<BertCrossEncoder> {
+        self.build_with_loading_handler(ModelLoadingProgress::multi_bar_loading_indicator())
+            .await
+    }
+
+    /// Build the model with a loading handler
+    pub async fn build_with_loading_handler(
+        self,
+        mut loading_handler: impl FnMut(ModelLoadingProgress) + Send + 'static,
+    ) -> anyhow::Result<BertCrossEncoder> {
+        let BertFiles {
+            config_filename,
+            tokenizer_filename,
+            weights_filename,
+        } = BertFiles::download(&self.source, &self.cache, &mut loading_handler).await?;
+
+        let config = std::fs::read_to_string(config_filename)?;
+        let raw_config: serde_json::Value = serde_json::from_str(&config)?;
+        let labels = raw_config
+            .get("id2label")
+            .and_then(|labels| labels.as_object())
+            .map(|labels| labels.len())
+            .unwrap_or(1);
+        let model_type = raw_config
+            .get("model_type")
+            .and_then(|model_type| model_type.as_str())
+            .unwrap_or("bert")
+            .to_string();
+        let config: Config = serde_json::from_value(raw_config)?;
+
+        let device = accelerated_device_if_available()?;
+        let vb =
+            unsafe { VarBuilder::from_mmaped_safetensors(&[&weights_filename], DTYPE, &device)? };
+        let model = BertModel::load(vb.clone(), &config)?;
+        let hidden_size = model.embedding_dim();
+        let pooler =
+            candle_nn::linear(hidden_size, hidden_size, vb.pp("pooler.dense")).or_else(|_| {
+                candle_nn::linear(
+                    hidden_size,
+                    hidden_size,
+                    vb.pp(format!("{model_type}.pooler.dense")),
+                )
+            })?;
+        let classifier = candle_nn::linear(hidden_size, labels, vb.pp("classifier"))?;
+
+        let mut tokenizer =
+            Tokenizer::from_file(&tokenizer_filename).map_err(anyhow::Error::msg)?;
+        tokenizer.with_padding(None);
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: 512,
+                strategy: TruncationStrategy::OnlySecond,
+                ..Default::default()
+            }))
+            .map_err(anyhow::Error::msg)?;
+
+        Ok(BertCrossEncoder {
+            model: Arc::new(model),
+            pooler: Arc::new(pooler),
+            classifier: Arc::new(classifier),
+            tokenizer: Arc::new(RwLock::new(tokenizer)),
+        })
+    }
+}
+
+/// A bert cross encoder. Cross encoders read a query and a passage together and score how relevant the passage is to the query. They are slower than comparing embeddings, but much more accurate, so they are often used to rerank the top results of a vector search.
+///
+/// # Example
+/// ```rust, no_run
+/// use rbert::*;
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let cross_encoder = BertCrossEncoder::new().await?;
+///     let scores = cross_encoder.score_batch(
+///         "What is the capital of France?",
+///         &["Paris is the capital of France", "France is in Europe"],
+///     )?;
+///     println!("scores {:?}", scores);
+///
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct BertCrossEncoder {
+    model: Arc<BertModel>,
+    pooler: Arc<Linear>,
+    classifier: Arc<Linear>,
+    tokenizer: Arc<RwLock<Tokenizer>>,
+}
+
+impl BertCrossEncoder {
+    /// Create a new [`BertCrossEncoderBuilder`]
+    pub fn builder() -> BertCrossEncoderBuilder {
+        BertCrossEncoderBuilder::default()
+    }
+
+    /// Create a new default cross encoder
+    pub async fn new() -> anyhow::Result<Self> {
+        Self::builder().build().await
+    }
+
+    /// Score how relevant each passage is to the query. Higher scores are more relevant. The scores are raw logits, so they are only comparable between passages scored by the same model.
+    pub fn score_batch(&self, query: &str, passages: &[&str]) -> anyhow::Result<Vec<f32>> {
+        let mut scores = Vec::with_capacity(passages.len());
+        for passages in passages.chunks(MAX_BATCH_SIZE) {
+            let encodings = {
+                let tokenizer_read = self.tokenizer.read().unwrap();
+                tokenizer_read.encode_batch(
+                    passages
+                        .iter()
+                        .map(|passage| (query, *passage))
+                        .collect::<Vec<_>>(),
+                    true,
+                )
+            }
+            .map_err(anyhow::Error::msg)?;
+            scores.extend(maybe_autoreleasepool(|| self.score_encodings(encodings))?);
+        }
+        Ok(scores)
+    }
+
+    fn score_encodings(&self, mut encodings: Vec<Encoding>) -> anyhow::Result<Vec<f32>> {
+        let device = &self.model.device;
+        let pp = PaddingParams {
+            strategy: tokenizers::PaddingStrategy::BatchLongest,
+            ..Default::default()
+        };
+        tokenizers::pad_encodings(&mut encodings, &pp).map_err(anyhow::Error::msg)?;
+
+        let stack = |get: fn(&Encoding) -> &[u32]| {
+            let rows = encodings
+                .iter()
+                .map(|encoding| Ok(Tensor::new(get(encoding), device)?))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            anyhow::Ok(Tensor::stack(&rows, 0)?)
+        };
+        let token_ids = stack(Encoding::get_ids)?;
+        // Unlike embedding, the token type ids matter here. They tell the model which tokens are from the query and which are from the passage.
+        let token_type_ids = stack(Encoding::get_type_ids)?;
+        let attention_mask = stack(Encoding::get_attention_mask)?;
+
+        let hidden_states =
+            self.model
+                .forward(&token_ids, &token_type_ids, Some(&attention_mask), false)?;
+        let cls = hidden_states.i((.., 0, ..))?;
+        let pooled = self.pooler.forward(&cls)?.tanh()?;
+        let logits = self.classifier.forward(&pooled)?;
+        let (_, labels) = logits.dims2()?;
+        // Models with a single label output a relevance score directly. Models with more labels use the last label for relevant passages.
+        let scores = logits.i((.., labels - 1))?.to_vec1::<f32>()?;
+
+        Ok(scores)
+    }
+}
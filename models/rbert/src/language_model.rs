@@ -3,8 +3,8 @@ use crate::BertBuilder;
 use crate::Pooling;
 use kalosm_common::*;
 pub use kalosm_language_model::{
-    Embedder, EmbedderCacheExt, EmbedderExt, Embedding, EmbeddingInput, EmbeddingVariant,
-    ModelBuilder, VectorSpace,
+    BatchingConfig, Embedder, EmbedderCacheExt, EmbedderExt, Embedding, EmbeddingInput,
+    EmbeddingVariant, ModelBuilder, VectorSpace,
 };
 use serde::Deserialize;
 use serde::Serialize;
@@ -26,18 +26,21 @@ impl ModelBuilder for BertBuilder {
 }
 
 impl Bert {
-    /// Embed a sentence with a specific pooling strategy.
+    /// Embed a sentence with a specific pooling strategy. The normalization and truncation of the model are still used.
     pub fn embed_with_pooling(
         &self,
         input: &str,
         pooling: Pooling,
     ) -> anyhow::Result<Embedding<BertSpace>> {
         let mut tensors = self.embed_batch_raw(vec![input], pooling)?;
+        let tensor = tensors
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("Bert returned no embedding for the input"))?;
 
-        Ok(Embedding::new(tensors.pop().unwrap()))
+        Ok(Embedding::new(tensor))
     }
 
-    /// Embed a batch of sentences with a specific pooling strategy.
+    /// Embed a batch of sentences with a specific pooling strategy. The normalization and truncation of the model are still used.
     pub fn embed_batch_with_pooling(
         &self,
         inputs: Vec<&str>,
@@ -54,63 +57,83 @@ impl Bert {
     }
 }
 
-impl Embedder for Bert {
-    type VectorSpace = BertSpace;
-
-    fn embed_for(
-        &self,
-        input: EmbeddingInput,
-    ) -> BoxedFuture<'_, anyhow::Result<Embedding<Self::VectorSpace>>> {
+impl Bert {
+    /// Get the text the model embeds for an input. Queries start with the search prefix of the model if it has one.
+    fn input_text(&self, input: EmbeddingInput) -> String {
         match (&*self.embedding_search_prefix, input.variant) {
             (Some(prefix), EmbeddingVariant::Query) => {
                 let mut new_input = prefix.clone();
                 new_input.push_str(&input.text);
-                self.embed_string(new_input)
+                new_input
             }
-            _ => self.embed_string(input.text),
+            _ => input.text,
         }
     }
 
-    fn embed_vec_for(
-        &self,
+    /// Embed a batch of inputs on a blocking thread.
+    pub(crate) async fn embed_inputs(
+        self,
         inputs: Vec<EmbeddingInput>,
-    ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
-        let inputs = inputs
-            .into_iter()
-            .map(
-                |input| match (&*self.embedding_search_prefix, input.variant) {
-                    (Some(prefix), EmbeddingVariant::Query) => {
-                        let mut new_input = prefix.clone();
-                        new_input.push_str(&input.text);
-                        new_input
-                    }
-                    _ => input.text,
-                },
-            )
-            .collect::<Vec<_>>();
-        self.embed_vec(inputs)
+    ) -> anyhow::Result<Vec<Embedding<BertSpace>>> {
+        tokio::task::spawn_blocking(move || {
+            let inputs = inputs
+                .into_iter()
+                .map(|input| self.input_text(input))
+                .collect::<Vec<_>>();
+            let inputs_borrowed = inputs.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+            self.embed_batch_with_pooling(inputs_borrowed, self.pooling)
+        })
+        .await?
     }
+}
 
-    fn embed_string(&self, input: String) -> BoxedFuture<'_, anyhow::Result<Embedding<BertSpace>>> {
-        Box::pin(async move {
-            let self_clone = self.clone();
-            tokio::task::spawn_blocking(move || self_clone.embed_with_pooling(&input, Pooling::CLS))
-                .await?
-        })
+impl Embedder for Bert {
+    type VectorSpace = BertSpace;
+
+    fn embed_for(
+        &self,
+        input: EmbeddingInput,
+    ) -> BoxedFuture<'_, anyhow::Result<Embedding<Self::VectorSpace>>> {
+        match &self.scheduler {
+            // Concurrent requests are collected into batches by the scheduler. Inputs that are too long are rejected first so they don't fail the batch they would be grouped with
+            Some(scheduler) => Box::pin(async move {
+                self.check_input_length(&self.input_text(input.clone()))?;
+                scheduler.embed(input).await
+            }),
+            None => Box::pin(async move {
+                let mut embeddings = self.clone().embed_inputs(vec![input]).await?;
+                embeddings
+                    .pop()
+                    .ok_or_else(|| anyhow::anyhow!("Bert returned no embedding for the input"))
+            }),
+        }
     }
 
     fn embed_vec(
         &self,
         inputs: Vec<String>,
-    ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<BertSpace>>>> {
-        Box::pin(async move {
-            let self_clone = self.clone();
-            tokio::task::spawn_blocking(move || {
-                let inputs_borrowed = inputs.iter().map(|s| s.as_str()).collect::<Vec<_>>();
-                self_clone.embed_batch_with_pooling(inputs_borrowed, Pooling::CLS)
-            })
-            .await?
-        })
+    ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        self.embed_vec_for(
+            inputs
+                .into_iter()
+                .map(|text| EmbeddingInput::new(text, EmbeddingVariant::Document))
+                .collect(),
+        )
+    }
+
+    fn embed_vec_for(
+        &self,
+        inputs: Vec<EmbeddingInput>,
+    ) -> BoxedFuture<'_, anyhow::Result<Vec<Embedding<Self::VectorSpace>>>> {
+        match &self.scheduler {
+            Some(scheduler) => Box::pin(async move {
+                for input in &inputs {
+                    self.check_input_length(&self.input_text(input.clone()))?;
+                }
+                scheduler.embed_vec(inputs).await
+            }),
+            None => Box::pin(self.clone().embed_inputs(inputs)),
+        }
     }
 }
 
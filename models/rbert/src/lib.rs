@@ -54,12 +54,20 @@ use std::sync::{Arc, RwLock};
 
 use candle_core::{IndexOp, Tensor};
 use candle_nn::VarBuilder;
-use tokenizers::{Encoding, PaddingParams, Tokenizer};
+use kalosm_language_model::{BatchingConfig, EmbeddingInput, EmbeddingScheduler};
+use tokenizers::{
+    Encoding, PaddingParams, Tokenizer, TruncationDirection, TruncationParams, TruncationStrategy,
+};
 
+mod cross_encoder;
 mod language_model;
 mod raw;
 mod source;
+#[cfg(test)]
+#[path = "../../../interfaces/language-model/src/test_util.rs"]
+mod test_util;
 
+pub use crate::cross_encoder::*;
 pub use crate::language_model::*;
 use crate::raw::DTYPE;
 pub use crate::raw::{BertModel, Config};
@@ -70,6 +78,10 @@ pub use crate::source::*;
 pub struct BertBuilder {
     source: BertSource,
     cache: kalosm_common::Cache,
+    batching: BatchingConfig,
+    pooling: Option<Pooling>,
+    normalize: Option<bool>,
+    truncation: Option<Truncation>,
 }
 
 impl BertBuilder {
@@ -79,6 +91,30 @@ impl BertBuilder {
         self
     }
 
+    /// Set how concurrent embedding requests are grouped into batches. Requests that arrive close together are embedded in one batch of inputs with a similar length.
+    pub fn with_batching(mut self, batching: BatchingConfig) -> Self {
+        self.batching = batching;
+        self
+    }
+
+    /// Set the pooling strategy used to combine the token embeddings into one embedding. Defaults to the pooling of the [`BertSource`].
+    pub fn with_pooling(mut self, pooling: Pooling) -> Self {
+        self.pooling = Some(pooling);
+        self
+    }
+
+    /// Set whether embeddings are normalized to a length of one. Defaults to the normalization of the [`BertSource`]. Sources that do not set a normalization only normalize mean pooled embeddings.
+    pub fn with_normalization(mut self, normalize: bool) -> Self {
+        self.normalize = Some(normalize);
+        self
+    }
+
+    /// Set how inputs that are longer than the model supports are handled. Defaults to the truncation of the [`BertSource`].
+    pub fn with_truncation(mut self, truncation: Truncation) -> Self {
+        self.truncation = Some(truncation);
+        self
+    }
+
     /// Build the model
     pub async fn build(self) -> anyhow::Result<Bert> {
         self.build_with_loading_handler(ModelLoadingProgress::multi_bar_loading_indicator())
@@ -128,7 +164,7 @@ impl BertBuilder {
 }
 
 /// The pooling strategy to use when embedding text.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Pooling {
     /// Take the mean embedding value for all tokens (except padding)
     Mean,
@@ -136,6 +172,22 @@ pub enum Pooling {
     CLS,
 }
 
+/// How to handle inputs with more tokens than the model supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Truncation {
+    /// Return an error for inputs that are too long
+    Error,
+    /// Drop tokens from the start of the input and keep the end
+    TruncateHead,
+    /// Drop tokens from the end of the input and keep the start
+    TruncateTail,
+    /// Split the input into overlapping windows that each fit in the model, embed every window and average the embeddings
+    SlidingWindow {
+        /// The number of tokens each window shares with the previous window
+        overlap: usize,
+    },
+}
+
 /// A bert embedding model. The main interface for this model is [`EmbedderExt`].
 ///
 /// # Example
@@ -179,6 +231,11 @@ pub struct Bert {
     embedding_search_prefix: Arc<Option<String>>,
     model: Arc<BertModel>,
     tokenizer: Arc<RwLock<Tokenizer>>,
+    scheduler: Option<EmbeddingScheduler<BertSpace>>,
+    pooling: Pooling,
+    normalize: Option<bool>,
+    truncation: Truncation,
+    max_tokens: usize,
 }
 
 impl Bert {
@@ -200,39 +257,45 @@ impl Bert {
             .await
     }
 
+    /// Get a copy of the tokenizer the model uses to split text into tokens. The copy never truncates text, so it can be used to check if text fits in [`Bert::max_tokens`].
+    pub fn tokenizer(&self) -> Tokenizer {
+        let mut tokenizer = self.tokenizer.read().unwrap().clone();
+        // Removing truncation can't fail
+        _ = tokenizer.with_truncation(None);
+        tokenizer
+    }
+
+    /// Get the maximum number of tokens (including special tokens) the model can embed at once. Longer inputs are handled with the [`Truncation`] strategy of the model.
+    pub fn max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    /// Get the pooling strategy the model uses to combine token embeddings.
+    pub fn pooling(&self) -> Pooling {
+        self.pooling
+    }
+
     async fn from_builder(
         builder: BertBuilder,
         mut progress_handler: impl FnMut(ModelLoadingProgress) + Send + 'static,
     ) -> anyhow::Result<Self> {
-        let BertBuilder { source, cache } = builder;
-        let BertSource {
-            config,
-            tokenizer,
-            model,
-            search_embedding_prefix,
-        } = source;
-
-        let source = format!("Config ({})", config);
-        let mut create_progress = ModelLoadingProgress::downloading_progress(source);
-        let config_filename = cache
-            .get(&config, |progress| {
-                progress_handler(create_progress(progress))
-            })
-            .await?;
-        let tokenizer_source = format!("Tokenizer ({})", tokenizer);
-        let mut create_progress = ModelLoadingProgress::downloading_progress(tokenizer_source);
-        let tokenizer_filename = cache
-            .get(&tokenizer, |progress| {
-                progress_handler(create_progress(progress))
-            })
-            .await?;
-        let model_source = format!("Model ({})", model);
-        let mut create_progress = ModelLoadingProgress::downloading_progress(model_source);
-        let weights_filename = cache
-            .get(&model, |progress| {
-                progress_handler(create_progress(progress))
-            })
-            .await?;
+        let BertBuilder {
+            source,
+            cache,
+            batching,
+            pooling,
+            normalize,
+            truncation,
+        } = builder;
+        let pooling = pooling.unwrap_or(source.pooling);
+        let normalize = normalize.or(source.normalize);
+        let truncation = truncation.unwrap_or(source.truncation);
+        let search_embedding_prefix = source.search_embedding_prefix.clone();
+        let BertFiles {
+            config_filename,
+            tokenizer_filename,
+            weights_filename,
+        } = BertFiles::download(&source, &cache, &mut progress_handler).await?;
 
         let config = std::fs::read_to_string(config_filename)?;
         let config: Config = serde_json::from_str(&config)?;
@@ -241,15 +304,56 @@ impl Bert {
         let vb =
             unsafe { VarBuilder::from_mmaped_safetensors(&[&weights_filename], DTYPE, &device)? };
         let model = BertModel::load(vb, &config)?;
+        let max_tokens = config.max_position_embeddings();
         let mut tokenizer =
             Tokenizer::from_file(&tokenizer_filename).map_err(anyhow::Error::msg)?;
         tokenizer.with_padding(None);
+        tokenizer
+            .with_truncation(truncation_params(truncation, max_tokens))
+            .map_err(anyhow::Error::msg)?;
 
-        Ok(Bert {
+        let mut bert = Bert {
             tokenizer: Arc::new(RwLock::new(tokenizer)),
             model: Arc::new(model),
             embedding_search_prefix: Arc::new(search_embedding_prefix),
+            scheduler: None,
+            pooling,
+            normalize,
+            truncation,
+            max_tokens,
+        };
+
+        // The scheduler embeds batches with a copy of the model without a scheduler, so the background task stops once every copy of this model is dropped
+        let worker = bert.clone();
+        let tokenizer = bert.tokenizer.clone();
+        let scheduler = EmbeddingScheduler::new(batching, move |inputs: Vec<EmbeddingInput>| {
+            worker.clone().embed_inputs(inputs)
         })
+        .with_token_counter(move |text| {
+            tokenizer
+                .read()
+                .unwrap()
+                .encode(text, true)
+                .map(|encoding| encoding.len())
+                .unwrap_or(text.len() / 4 + 1)
+        });
+        bert.scheduler = Some(scheduler);
+
+        Ok(bert)
+    }
+
+    /// Check that an input fits in the model if the model returns an error for inputs that are too long. Other truncation strategies accept any input.
+    pub(crate) fn check_input_length(&self, text: &str) -> anyhow::Result<()> {
+        if self.truncation != Truncation::Error {
+            return Ok(());
+        }
+        let encoding = self
+            .tokenizer
+            .read()
+            .unwrap()
+            .encode(text, true)
+            .map_err(anyhow::Error::msg)?;
+        check_encoding_length(&encoding, self.max_tokens)
     }
 
     /// Embed a batch of sentences
@@ -262,13 +366,17 @@ impl Bert {
         // The batch size limit (input length * memory per token)
         let limit = embedding_dim * 512usize.pow(2) * 2;
 
-        // The sentences we are embedding may have a very different length. First we sort them so that similar length sentences are grouped together in the same batch to reduce the overhead of padding.
         let encodings = {
             let tokenizer_read = self.tokenizer.read().unwrap();
             tokenizer_read.encode_batch(sentences, true)
         }
         .map_err(anyhow::Error::msg)?;
-        let mut encodings_with_indices = encodings.into_iter().enumerate().collect::<Vec<_>>();
+
+        let n_inputs = encodings.len();
+        let (window_inputs, windows) = split_windows(encodings, self.truncation, self.max_tokens)?;
+
+        // The windows we are embedding may have a very different length. First we sort them so that similar length windows are grouped together in the same batch to reduce the overhead of padding.
+        let mut encodings_with_indices = windows.into_iter().enumerate().collect::<Vec<_>>();
 
         encodings_with_indices.sort_unstable_by_key(|(_, encoding)| encoding.len());
 
@@ -308,7 +416,27 @@ impl Bert {
                 combined[*i] = Some(embedding);
             }
         }
-        Ok(combined.into_iter().map(|x| x.unwrap()).collect())
+
+        // Average the windows of each input back into one embedding
+        let mut input_windows = vec![Vec::new(); n_inputs];
+        for (input, embedding) in window_inputs.into_iter().zip(combined) {
+            input_windows[input].push(embedding.unwrap());
+        }
+        input_windows
+            .into_iter()
+            .map(|mut windows| {
+                let embedding = if windows.len() == 1 {
+                    windows.pop().unwrap()
+                } else {
+                    Tensor::cat(&windows, 0)?.mean_keepdim(0)?
+                };
+                if self.normalize.unwrap_or(pooling == Pooling::Mean) {
+                    normalize_l2(&embedding)
+                } else {
+                    Ok(embedding)
+                }
+            })
+            .collect()
     }
 
     fn embed_batch_raw_inner(
@@ -349,30 +477,184 @@ impl Bert {
             self.model
                 .forward(&token_ids, &token_type_ids, Some(&attention_mask), false)?;
 
-        let (_n_sentence, n_tokens, _hidden_size) = embeddings.dims3()?;
-
-        match pooling {
-            Pooling::Mean => {
-                // Take the mean embedding value for all tokens (except padding)
-                let embeddings = embeddings.mul(
-                    &attention_mask
-                        .to_dtype(DTYPE)?
-                        .unsqueeze(2)?
-                        .broadcast_as(embeddings.shape())?,
-                )?;
-                let embeddings = (embeddings.sum(1)? / (n_tokens as f64))?;
-                let embeddings = normalize_l2(&embeddings)?;
-                Ok(embeddings.chunk(n_sentences, 0)?)
-            }
-            Pooling::CLS => {
-                // Index into the first token of each sentence which is the CLS token that contains the sentence embedding
-                let indexed_embeddings = embeddings.i((.., 0, ..))?;
-                Ok(indexed_embeddings.chunk(n_sentences, 0)?)
+        let embeddings = pool(&embeddings, &attention_mask, pooling)?;
+        Ok(embeddings.chunk(n_sentences, 0)?)
+    }
+}
+
+/// Get the truncation parameters for the tokenizer. The tokenizer truncates long inputs after adding special tokens. With a sliding window, the rest of the input is kept in the overflowing encodings
+fn truncation_params(truncation: Truncation, max_tokens: usize) -> Option<TruncationParams> {
+    let (direction, stride) = match truncation {
+        Truncation::Error => return None,
+        Truncation::TruncateHead => (TruncationDirection::Left, 0),
+        Truncation::TruncateTail => (TruncationDirection::Right, 0),
+        Truncation::SlidingWindow { overlap } => (TruncationDirection::Right, overlap),
+    };
+    Some(TruncationParams {
+        direction,
+        max_length: max_tokens,
+        strategy: TruncationStrategy::LongestFirst,
+        stride,
+    })
+}
+
+fn check_encoding_length(encoding: &Encoding, max_tokens: usize) -> anyhow::Result<()> {
+    if encoding.len() > max_tokens {
+        anyhow::bail!(
+            "The input has {} tokens, but the model can only embed {} tokens at once",
+            encoding.len(),
+            max_tokens
+        );
+    }
+    Ok(())
+}
+
+/// Split each input into the windows of tokens the model embeds. Unless the model uses a sliding window, each input is one window. Returns the index of the input each window belongs to along with the windows.
+fn split_windows(
+    encodings: Vec<Encoding>,
+    truncation: Truncation,
+    max_tokens: usize,
+) -> anyhow::Result<(Vec<usize>, Vec<Encoding>)> {
+    let mut window_inputs = Vec::with_capacity(encodings.len());
+    let mut windows = Vec::with_capacity(encodings.len());
+    for (index, mut encoding) in encodings.into_iter().enumerate() {
+        check_encoding_length(&encoding, max_tokens)?;
+        let overflowing = encoding.take_overflowing();
+        window_inputs.push(index);
+        windows.push(encoding);
+        if let Truncation::SlidingWindow { .. } = truncation {
+            for window in overflowing {
+                window_inputs.push(index);
+                windows.push(window);
             }
         }
     }
+    Ok((window_inputs, windows))
+}
+
+/// Combine the token embeddings of a batch with the shape (batch, tokens, hidden) into one embedding per sequence with the shape (batch, hidden).
+fn pool(embeddings: &Tensor, attention_mask: &Tensor, pooling: Pooling) -> anyhow::Result<Tensor> {
+    match pooling {
+        Pooling::Mean => {
+            // Take the mean embedding value for all tokens (except padding)
+            let attention_mask = attention_mask.to_dtype(embeddings.dtype())?.unsqueeze(2)?;
+            let embeddings = embeddings.broadcast_mul(&attention_mask)?.sum(1)?;
+            let token_counts = attention_mask.sum(1)?;
+            Ok(embeddings.broadcast_div(&token_counts)?)
+        }
+        // Index into the first token of each sentence which is the CLS token that contains the sentence embedding
+        Pooling::CLS => Ok(embeddings.i((.., 0, ..))?),
+    }
+}
+
+/// The files for a bert model downloaded from a [`BertSource`]
+pub(crate) struct BertFiles {
+    pub(crate) config_filename: std::path::PathBuf,
+    pub(crate) tokenizer_filename: std::path::PathBuf,
+    pub(crate) weights_filename: std::path::PathBuf,
+}
+
+impl BertFiles {
+    pub(crate) async fn download(
+        source: &BertSource,
+        cache: &kalosm_common::Cache,
+        mut progress_handler: impl FnMut(ModelLoadingProgress),
+    ) -> anyhow::Result<Self> {
+        let BertSource {
+            config,
+            tokenizer,
+            model,
+            ..
+        } = source;
+
+        let source = format!("Config ({})", config);
+        let mut create_progress = ModelLoadingProgress::downloading_progress(source);
+        let config_filename = cache
+            .get(config, |progress| {
+                progress_handler(create_progress(progress))
+            })
+            .await?;
+        let tokenizer_source = format!("Tokenizer ({})", tokenizer);
+        let mut create_progress = ModelLoadingProgress::downloading_progress(tokenizer_source);
+        let tokenizer_filename = cache
+            .get(tokenizer, |progress| {
+                progress_handler(create_progress(progress))
+            })
+            .await?;
+        let model_source = format!("Model ({})", model);
+        let mut create_progress = ModelLoadingProgress::downloading_progress(model_source);
+        let weights_filename = cache
+            .get(model, |progress| {
+                progress_handler(create_progress(progress))
+            })
+            .await?;
+
+        Ok(Self {
+            config_filename,
+            tokenizer_filename,
+            weights_filename,
+        })
+    }
 }
 
 fn normalize_l2(v: &Tensor) -> anyhow::Result<Tensor> {
     Ok(v.broadcast_div(&v.sqr()?.sum_keepdim(1)?.sqrt()?)?)
 }
+
+#[test]
+fn truncation_strategies_fit_inputs_in_the_model() {
+    // A tokenizer with one token for every word
+    let tokenizer =
+        crate::test_util::word_level_tokenizer(&["[UNK]", "a", "b", "c", "d", "e", "f", "g"]);
+    let windows = |truncation: Truncation, text: &str| {
+        let mut tokenizer = tokenizer.clone();
+        tokenizer
+            .with_truncation(truncation_params(truncation, 4))
+            .unwrap();
+        let encoding = tokenizer.encode(text, true).unwrap();
+        split_windows(vec![encoding], truncation, 4).map(|(inputs, windows)| {
+            let windows = windows
+                .iter()
+                .map(|window| window.get_ids().to_vec())
+                .collect::<Vec<_>>();
+            (inputs, windows)
+        })
+    };
+
+    assert!(windows(Truncation::Error, "a b c d e f g").is_err());
+    assert_eq!(
+        windows(Truncation::Error, "a b c d").unwrap(),
+        (vec![0], vec![vec![1, 2, 3, 4]])
+    );
+    assert_eq!(
+        windows(Truncation::TruncateHead, "a b c d e f g").unwrap(),
+        (vec![0], vec![vec![4, 5, 6, 7]])
+    );
+    assert_eq!(
+        windows(Truncation::TruncateTail, "a b c d e f g").unwrap(),
+        (vec![0], vec![vec![1, 2, 3, 4]])
+    );
+    assert_eq!(
+        windows(Truncation::SlidingWindow { overlap: 1 }, "a b c d e f g").unwrap(),
+        (vec![0, 0], vec![vec![1, 2, 3, 4], vec![4, 5, 6, 7]])
+    );
+}
+
+#[test]
+fn mean_pooling_skips_padding() {
+    let device = candle_core::Device::Cpu;
+    let embeddings = Tensor::new(
+        &[
+            [[1f32, 2.], [3., 4.], [100., 100.]],
+            [[2., 0.], [4., 2.], [6., 4.]],
+        ],
+        &device,
+    )
+    .unwrap();
+    let attention_mask = Tensor::new(&[[1u32, 1, 0], [1, 1, 1]], &device).unwrap();
+
+    let mean = pool(&embeddings, &attention_mask, Pooling::Mean).unwrap();
+    assert_eq!(mean.to_vec2::<f32>().unwrap(), [[2., 3.], [4., 2.]]);
+    let cls = pool(&embeddings, &attention_mask, Pooling::CLS).unwrap();
+    assert_eq!(cls.to_vec2::<f32>().unwrap(), [[1., 2.], [2., 0.]]);
+}
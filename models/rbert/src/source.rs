@@ -1,14 +1,21 @@
 use kalosm_common::FileSource;
 
+use crate::{Pooling, Truncation};
+
 const SNOWFLAKE_EMBEDDING_PREFIX: &str =
     "Represent this sentence for searching relevant passages: ";
 
 /// A the source of a [`crate::Bert`] model
+///
+/// Each preset uses the pooling and normalization the model was trained with. The BGE and Snowflake Arctic presets normalize CLS embeddings to a length of one, MiniLM normalizes mean pooled embeddings and the cross encoder preset leaves its scores as they are. Inputs that are too long for the model are truncated at the end by default.
 pub struct BertSource {
     pub(crate) search_embedding_prefix: Option<String>,
     pub(crate) config: FileSource,
     pub(crate) tokenizer: FileSource,
     pub(crate) model: FileSource,
+    pub(crate) pooling: Pooling,
+    pub(crate) normalize: Option<bool>,
+    pub(crate) truncation: Truncation,
 }
 
 impl BertSource {
@@ -40,6 +47,24 @@ impl BertSource {
         self
     }
 
+    /// Set the pooling strategy used to combine the token embeddings into one embedding for the whole input
+    pub fn with_pooling(mut self, pooling: Pooling) -> Self {
+        self.pooling = pooling;
+        self
+    }
+
+    /// Set whether embeddings are normalized to a length of one. By default only mean pooled embeddings are normalized
+    pub fn with_normalization(mut self, normalize: bool) -> Self {
+        self.normalize = Some(normalize);
+        self
+    }
+
+    /// Set how inputs that are longer than the maximum number of tokens the model supports are handled
+    pub fn with_truncation(mut self, truncation: Truncation) -> Self {
+        self.truncation = truncation;
+        self
+    }
+
     /// Set the prefix to use when embedding search queries
     pub(crate) fn with_search_embedding_prefix(
         mut self,
@@ -67,6 +92,7 @@ impl BertSource {
                 "refs/pr/5".to_string(),
                 "config.json".to_string(),
             ))
+            .with_normalization(true)
     }
 
     /// Create a new [`BertSource`] with the BGE base english preset
@@ -87,6 +113,7 @@ impl BertSource {
                 "refs/pr/1".to_string(),
                 "config.json".to_string(),
             ))
+            .with_normalization(true)
     }
 
     /// Create a new [`BertSource`] with the BGE small english preset
@@ -108,6 +135,10 @@ impl BertSource {
                 "model.safetensors".to_string(),
             ),
             search_embedding_prefix: None,
+            // BGE models are trained with the normalized CLS token embedding
+            pooling: Pooling::CLS,
+            normalize: Some(true),
+            truncation: Truncation::TruncateTail,
         }
     }
 
@@ -129,6 +160,8 @@ impl BertSource {
                 "refs/pr/21".to_string(),
                 "config.json".to_string(),
             ))
+            // MiniLM is trained with the mean of the token embeddings
+            .with_pooling(Pooling::Mean)
     }
 
     /// Create a new [`BertSource`] with the [snowflake-arctic-embed-xs](https://huggingface.co/Snowflake/snowflake-arctic-embed-xs) model
@@ -150,6 +183,7 @@ impl BertSource {
                 "model.safetensors".to_string(),
             ))
             .with_search_embedding_prefix(SNOWFLAKE_EMBEDDING_PREFIX.to_string())
+            .with_normalization(true)
     }
 
     /// Create a new [`BertSource`] with the [snowflake-arctic-embed-s](https://huggingface.co/Snowflake/snowflake-arctic-embed-s) model
@@ -171,6 +205,7 @@ impl BertSource {
                 "config.json".to_string(),
             ))
             .with_search_embedding_prefix(SNOWFLAKE_EMBEDDING_PREFIX.to_string())
+            .with_normalization(true)
     }
 
     /// Create a new [`BertSource`] with the [snowflake-arctic-embed-m](https://huggingface.co/Snowflake/snowflake-arctic-embed-m) model
@@ -192,6 +227,7 @@ impl BertSource {
                 "model.safetensors".to_string(),
             ))
             .with_search_embedding_prefix(SNOWFLAKE_EMBEDDING_PREFIX.to_string())
+            .with_normalization(true)
     }
 
     /// Create a new [`BertSource`] with the [snowflake-arctic-embed-m-long](https://huggingface.co/Snowflake/snowflake-arctic-embed-m-long) model
@@ -215,6 +251,7 @@ impl BertSource {
                 "config.json".to_string(),
             ))
             .with_search_embedding_prefix(SNOWFLAKE_EMBEDDING_PREFIX.to_string())
+            .with_normalization(true)
     }
 
     /// Create a new [`BertSource`] with the [snowflake-arctic-embed-l](https://huggingface.co/Snowflake/snowflake-arctic-embed-l) model
@@ -236,6 +273,28 @@ impl BertSource {
                 "config.json".to_string(),
             ))
             .with_search_embedding_prefix(SNOWFLAKE_EMBEDDING_PREFIX.to_string())
+            .with_normalization(true)
+    }
+
+    /// Create a new [`BertSource`] with the [ms-marco-MiniLM-L-6-v2](https://huggingface.co/cross-encoder/ms-marco-MiniLM-L-6-v2) cross encoder. This source should be used with [`crate::BertCrossEncoder`].
+    pub fn ms_marco_mini_lm_l6_v2_cross_encoder() -> Self {
+        Self::default()
+            .with_model(FileSource::huggingface(
+                "cross-encoder/ms-marco-MiniLM-L-6-v2".to_string(),
+                "main".to_string(),
+                "model.safetensors".to_string(),
+            ))
+            .with_tokenizer(FileSource::huggingface(
+                "cross-encoder/ms-marco-MiniLM-L-6-v2".to_string(),
+                "main".to_string(),
+                "tokenizer.json".to_string(),
+            ))
+            .with_config(FileSource::huggingface(
+                "cross-encoder/ms-marco-MiniLM-L-6-v2".to_string(),
+                "main".to_string(),
+                "config.json".to_string(),
+            ))
+            .with_normalization(false)
     }
 }
 